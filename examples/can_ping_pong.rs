@@ -11,13 +11,9 @@ mod app {
     use core::sync::atomic::{AtomicUsize, Ordering};
     use defmt::*;
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::can_shield::{timing, tx::TxQueue, Can1};
     use stm32f4xx_hal::{
-        can::Can,
-        gpio::{
-            gpioa::{PA11, PA12, PA5},
-            Alternate, Output, PushPull,
-        },
-        pac::CAN1,
+        gpio::{gpioa::PA5, Output, PushPull},
         prelude::*,
     };
 
@@ -29,7 +25,8 @@ mod app {
     // Needed even if we don't use it
     #[shared]
     struct Shared {
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        can1: Can1,
+        tx1: TxQueue,
     }
 
     // Holds the local resources (used by a single task)
@@ -67,13 +64,13 @@ mod app {
 
         // Initialize variables for can_send
         let mut test_frame: [u8; 8] = [0; 8];
-        test_frame[0] = 'H' as u8;
-        test_frame[1] = 'e' as u8;
-        test_frame[2] = 'j' as u8;
-        test_frame[3] = 's' as u8;
-        test_frame[4] = 'a' as u8;
-        test_frame[5] = '!' as u8;
-        test_frame[6] = ' ' as u8;
+        test_frame[0] = b'H';
+        test_frame[1] = b'e';
+        test_frame[2] = b'j';
+        test_frame[3] = b's';
+        test_frame[4] = b'a';
+        test_frame[5] = b'!';
+        test_frame[6] = b' ';
 
         // Set up CAN device 1
         let mut can1 = {
@@ -88,9 +85,8 @@ mod app {
 
             info!("CAN1, waiting for 11 recessive bits...");
             bxcan::Can::builder(can)
-                // APB1 (PCLK1): 45MHz, Bit rate: 1MBit/s, Sample Point 87.5%
-                // Value was calculated with http://www.bittiming.can-wiki.info/
-                .set_bit_timing(0x001b0002)
+                // Bit rate: 1MBit/s, Sample Point 87.5%, derived from the actual APB1 (PCLK1) clock
+                .set_bit_timing(timing::calculate(clocks.pclk1(), 1_000_000, 875).unwrap().btr())
                 .set_automatic_retransmit(true)
                 // .set_silent(true)
                 .enable()
@@ -101,6 +97,7 @@ mod app {
         can1.enable_interrupts({
             use bxcan::Interrupts as If;
             If::FIFO0_MESSAGE_PENDING | If::FIFO0_FULL | If::FIFO0_OVERRUN
+                | If::TRANSMIT_MAILBOX_EMPTY
         });
 
         // Configure filters so that can frames can be received.
@@ -118,7 +115,10 @@ mod app {
         blink::spawn_after(1.secs()).ok();
         can_send::spawn_after(1.secs()).ok();
         (
-            Shared { can1 },
+            Shared {
+                can1,
+                tx1: TxQueue::new(),
+            },
             Local { led, test_frame },
             init::Monotonics(mono),
         )
@@ -141,8 +141,8 @@ mod app {
     }
 
     // send a meesage via CAN
-    #[task(shared = [can1], local = [test_frame], priority=2)]
-    fn can_send(mut ctx: can_send::Context) {
+    #[task(shared = [can1, tx1], local = [test_frame], priority=2)]
+    fn can_send(ctx: can_send::Context) {
         let test_frame = ctx.local.test_frame;
         let id: u16 = 0x500;
 
@@ -151,20 +151,34 @@ mod app {
 
         info!("Sending frame with first byte: {}", test_frame[0]);
 
-        ctx.shared.can1.lock(|can1| can1.transmit(&frame).unwrap());
+        let queued = (ctx.shared.can1, ctx.shared.tx1).lock(|can1, tx1| tx1.transmit(can1, &frame));
+        if let Err(error) = queued {
+            warn!("Frame dropped: {}", error);
+        }
+    }
+
+    // refill the transmit mailboxes from the queue
+    #[task(binds = CAN1_TX, shared = [can1, tx1], priority=2)]
+    fn can_transmit(ctx: can_transmit::Context) {
+        (ctx.shared.can1, ctx.shared.tx1).lock(|can1, tx1| tx1.on_interrupt(can1));
     }
 
     // receive a message via CAN
     #[task(binds = CAN1_RX0, shared = [can1])]
     fn can_receive(ctx: can_receive::Context) {
         let mut can1 = ctx.shared.can1;
-        let frame = can1.lock(|can1| can1.receive().unwrap());
-
-        info!(
-            "Received frame with first byte: {}",
-            frame.data().unwrap().first().unwrap()
-        );
-
-        can_send::spawn().ok();
+        loop {
+            match can1.lock(|can1| can1.receive()) {
+                Ok(frame) => {
+                    // Remote frames and empty frames have no first byte
+                    let first = frame.data().and_then(|data| data.first().copied());
+                    info!("Received frame with first byte: {}", first);
+                    can_send::spawn().ok();
+                }
+                Err(nb::Error::WouldBlock) => break,
+                // The frames after the lost ones are still in the FIFO
+                Err(nb::Error::Other(_)) => warn!("CAN1 receive FIFO overrun, frames lost"),
+            }
+        }
     }
 }
@@ -0,0 +1,192 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
+mod app {
+    use bxcan::StandardId;
+    use core::cell::RefCell;
+    use cortex_m::interrupt::{self, Mutex};
+    use defmt::*;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use stm32f446_rtic::{
+        can_shield::{
+            rx::{self, RxConsumer, RxProducer, RxQueue},
+            CanShield, Channel, Shield,
+        },
+        time::{
+            sync::{SyncConfig, SyncMaster, SyncSlave},
+            Met, MissionClock,
+        },
+    };
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<180_000_000>; // 180 MHz
+
+    // Flash one board as the master and the others as slaves
+    const MASTER: bool = true;
+
+    // SYNC and FOLLOW_UP frames go out on this ID, ahead of every other frame
+    const SYNC_ID: u16 = 0x080;
+
+    // Time between two SYNC frames, also short enough for the mission clock to see every wrap
+    const SYNC_PERIOD_MS: u32 = 1000;
+
+    // The MET for every task, the monotonic timer wraps after 23 s
+    static CLOCK: Mutex<RefCell<MissionClock<180_000_000>>> =
+        Mutex::new(RefCell::new(MissionClock::new()));
+
+    fn mission_now() -> Met {
+        interrupt::free(|cs| CLOCK.borrow(cs).borrow_mut().update(monotonics::now()))
+    }
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        shield: CanShield,
+        master: SyncMaster,
+        slave: SyncSlave,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        rx1_producer: RxProducer<'static>,
+        rx1_consumer: RxConsumer<'static>,
+    }
+
+    // The init function is called in the beginning of the program
+    // The receive queue is an init local so it lives for the whole program
+    #[init(local = [rx1_queue: RxQueue = RxQueue::new()])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        info!("init");
+
+        // Cortex-M peripherals
+        let mut _core: cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device: stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(180.MHz()).freeze();
+
+        let gpioa = _device.GPIOA.split();
+        let gpiob = _device.GPIOB.split();
+
+        // enable tracing and the cycle counter for the monotonic timer and the CAN sync timeout
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up both CAN devices, giving up if a bus stays silent for a second
+        let shield = CanShield::builder(&clocks)
+            .sync_timeout(1000.millis())
+            .build_rev1(
+                gpioa.pa12,
+                gpioa.pa11,
+                gpiob.pb13,
+                gpiob.pb5,
+                _device.CAN1,
+                _device.CAN2,
+            )
+            .unwrap();
+
+        // Only the role of this board is used, the slaves count as unsynchronized after 3 periods
+        let config = SyncConfig::new(Channel::Can1, StandardId::new(SYNC_ID).unwrap())
+            .timeout((3 * SYNC_PERIOD_MS).millis());
+
+        let (rx1_producer, rx1_consumer) = rx::split(ctx.local.rx1_queue, Channel::Can1);
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
+
+        tick::spawn().ok();
+
+        info!("Init done!");
+        (
+            Shared {
+                shield,
+                master: SyncMaster::new(config),
+                slave: SyncSlave::new(config),
+            },
+            Local {
+                rx1_producer,
+                rx1_consumer,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // the master sends a SYNC frame, the slaves report the master's time and the sync quality
+    #[task(shared = [shield, master, slave], priority = 1)]
+    fn tick(ctx: tick::Context) {
+        let now = mission_now();
+
+        if MASTER {
+            (ctx.shared.shield, ctx.shared.master).lock(|shield, master| {
+                if let Err(error) = master.send_sync(shield) {
+                    warn!("SYNC not sent: {}", error);
+                }
+            });
+        } else {
+            let mut slave = ctx.shared.slave;
+            let (master_time, stats) = slave.lock(|slave| (slave.to_master(now), slave.stats()));
+            match master_time {
+                Some(master_time) => info!("MET {}, master MET {}", now, master_time),
+                None => warn!("MET {}, not synchronized", now),
+            }
+            info!("{}", stats);
+        }
+
+        tick::spawn_after(SYNC_PERIOD_MS.millis()).ok();
+    }
+
+    // log every other frame
+    #[task(local = [rx1_consumer], priority = 2)]
+    fn receive(ctx: receive::Context) {
+        while let Some(frame) = ctx.local.rx1_consumer.receive() {
+            info!("received {}", frame);
+        }
+    }
+
+    // send the FOLLOW_UP frame once the SYNC frame has left, then refill the mailboxes
+    #[task(binds = CAN1_TX, shared = [shield, master], priority = 3)]
+    fn can1_transmit(ctx: can1_transmit::Context) {
+        let now = mission_now();
+
+        (ctx.shared.shield, ctx.shared.master).lock(|shield, master| {
+            if let Err(error) = master.on_tx_interrupt(shield, now) {
+                warn!("FOLLOW_UP not sent: {}", error);
+            }
+            shield.on_tx_interrupt(Channel::Can1);
+        });
+    }
+
+    // note the arrival of SYNC frames and queue every other frame
+    #[task(binds = CAN1_RX0, shared = [shield, slave], local = [rx1_producer], priority = 3)]
+    fn can1_receive(ctx: can1_receive::Context) {
+        let now = mission_now();
+        let producer = ctx.local.rx1_producer;
+
+        let queued = (ctx.shared.shield, ctx.shared.slave).lock(|shield, slave| {
+            producer.drain_with(shield.channel(Channel::Can1), |frame| {
+                slave.on_frame(frame, now)
+            })
+        });
+
+        if queued > 0 {
+            receive::spawn().ok();
+        }
+    }
+}
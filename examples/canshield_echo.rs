@@ -6,22 +6,16 @@ use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
 #[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
 mod app {
     use bxcan::{Data, Frame, StandardId};
-    use core::{
-        str,
-        sync::atomic::{AtomicUsize, Ordering},
-    };
+    use core::str;
     use defmt::*;
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
-    use stm32f446_rtic::can_shield::CanShield;
+    use stm32f446_rtic::can_shield::{
+        health::HealthMonitor,
+        rx::{self, RxConsumer, RxProducer, RxQueue},
+        CanShield, Channel, Shield,
+    };
     use stm32f4xx_hal::{
-        can::Can,
-        gpio::{
-            gpioa::{PA11, PA12, PA5},
-            gpiob::{PB13, PB5},
-            Alternate, Output, PushPull,
-        },
-        pac::CAN1,
-        pac::CAN2,
+        gpio::{gpioa::PA5, Output, PushPull},
         prelude::*,
     };
 
@@ -29,12 +23,14 @@ mod app {
     #[monotonic(binds = SysTick, default = true)]
     type MyMono = DwtSystick<180_000_000>; // 180 MHz
 
+    type Monitor = HealthMonitor<180_000_000>;
+
     // Holds the shared resources (used by multiple tasks)
     // Needed even if we don't use it
     #[shared]
     struct Shared {
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
-        can2: bxcan::Can<Can<CAN2, (PB13<Alternate<9>>, PB5<Alternate<9>>)>>,
+        shield: CanShield,
+        health: [Monitor; 2],
     }
 
     // Holds the local resources (used by a single task)
@@ -42,13 +38,15 @@ mod app {
     #[local]
     struct Local {
         led: PA5<Output<PushPull>>,
+        rx1_producer: RxProducer<'static>,
+        rx2_producer: RxProducer<'static>,
+        rx1_consumer: RxConsumer<'static>,
+        rx2_consumer: RxConsumer<'static>,
     }
 
-    // Atomic counter
-    static COUNTER: AtomicUsize = AtomicUsize::new(0);
-
     // The init function is called in the beginning of the program
-    #[init]
+    // The receive queues are init locals so they live for the whole program
+    #[init(local = [rx1_queue: RxQueue = RxQueue::new(), rx2_queue: RxQueue = RxQueue::new()])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         info!("init");
 
@@ -70,30 +68,45 @@ mod app {
         let gpiob = _device.GPIOB.split();
         let led = gpioa.pa5.into_push_pull_output();
 
-        // Set up CAN device 1
-        let shield = CanShield::new_rev1(
-            gpioa.pa12,
-            gpioa.pa11,
-            gpiob.pb13,
-            gpiob.pb5,
-            _device.CAN1,
-            _device.CAN2,
-        )
-        .unwrap();
-
-        let mut can1 = shield.can1;
-        let mut can2 = shield.can2;
-
-        // enable tracing and the cycle counter for the monotonic timer
+        // enable tracing and the cycle counter for the monotonic timer and the CAN sync timeout
         _core.DCB.enable_trace();
         _core.DWT.enable_cycle_counter();
 
+        // Set up both CAN devices, giving up if a bus stays silent for a second
+        let shield = CanShield::builder(&clocks)
+            .sync_timeout(1000.millis())
+            .build_rev1(
+                gpioa.pa12,
+                gpioa.pa11,
+                gpiob.pb13,
+                gpiob.pb5,
+                _device.CAN1,
+                _device.CAN2,
+            )
+            .unwrap();
+
+        let health = Channel::ALL.map(|ch| shield.health_monitor(ch));
+
+        let (rx1_producer, rx1_consumer) = rx::split(ctx.local.rx1_queue, Channel::Can1);
+        let (rx2_producer, rx2_consumer) = rx::split(ctx.local.rx2_queue, Channel::Can2);
+
         // Set up the monotonic timer
         let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
 
         info!("Init done!");
         blink::spawn_after(1.secs()).ok();
-        (Shared { can1, can2 }, Local { led }, init::Monotonics(mono))
+        health_check::spawn_after(100.millis()).ok();
+        (
+            Shared { shield, health },
+            Local {
+                led,
+                rx1_producer,
+                rx2_producer,
+                rx1_consumer,
+                rx2_consumer,
+            },
+            init::Monotonics(mono),
+        )
     }
 
     // The idle function is called when there is nothing else to do
@@ -112,62 +125,113 @@ mod app {
         blink::spawn_after(1.secs()).ok();
     }
 
+    // follow the error state of both channels and restart them after bus-off if needed
+    #[task(shared = [shield, health])]
+    fn health_check(ctx: health_check::Context) {
+        (ctx.shared.shield, ctx.shared.health).lock(|shield, health| {
+            for monitor in health {
+                monitor.update(shield.channel(monitor.channel()), monotonics::now());
+            }
+        });
+
+        health_check::spawn_after(100.millis()).ok();
+    }
+
+    // error state change on CAN1
+    #[task(binds = CAN1_SCE, shared = [shield, health], priority=3)]
+    fn can1_error(ctx: can1_error::Context) {
+        (ctx.shared.shield, ctx.shared.health).lock(|shield, health| {
+            health[0].update(shield.channel(Channel::Can1), monotonics::now())
+        });
+    }
+
+    // error state change on CAN2
+    #[task(binds = CAN2_SCE, shared = [shield, health], priority=3)]
+    fn can2_error(ctx: can2_error::Context) {
+        (ctx.shared.shield, ctx.shared.health).lock(|shield, health| {
+            health[1].update(shield.channel(Channel::Can2), monotonics::now())
+        });
+    }
+
     // send a meesage via CAN
-    #[task(shared = [can1, can2], priority=2)]
-    fn can_send(mut ctx: can_send::Context, _ch: u8, _data: Data) {
+    #[task(shared = [shield], priority=2, capacity=8)]
+    fn can_send(mut ctx: can_send::Context, ch: Channel, data: Data) {
         let id: u16 = 0x500;
 
-        let frame = Frame::new_data(StandardId::new(id).unwrap(), _data);
+        let frame = Frame::new_data(StandardId::new(id).unwrap(), data);
 
         info!(
             "Sending frame: {}",
-            str::from_utf8(&frame.clone().data().unwrap()).unwrap_or("Invalid UTF-8")
+            str::from_utf8(frame.data().unwrap()).unwrap_or("Invalid UTF-8")
         );
 
-        // Send the frame
-        match _ch {
-            1 => {
-                ctx.shared.can1.lock(|can1| can1.transmit(&frame).unwrap());
-            }
-            2 => {
-                ctx.shared.can2.lock(|can2| can2.transmit(&frame).unwrap());
-            }
-            _ => {
-                error!("Invalid channel");
-            }
+        // Queue the frame on the channel it came from, the TX interrupt sends it
+        let queued = ctx.shared.shield.lock(|shield| shield.transmit(ch, &frame));
+        if let Err(error) = queued {
+            warn!("{}, frame dropped: {}", ch, error);
         }
     }
 
-    // receive a message via CAN1
-    #[task(binds = CAN1_RX0, shared = [can1])]
-    fn can1_receive(ctx: can1_receive::Context) {
-        let mut can1 = ctx.shared.can1;
-        let frame = can1.lock(|can1| can1.receive().unwrap());
-
-        let data = frame.data().unwrap();
+    // echo every buffered frame back on the channel it was received on
+    #[task(local = [rx1_consumer, rx2_consumer])]
+    fn echo(ctx: echo::Context) {
+        for frames in [ctx.local.rx1_consumer, ctx.local.rx2_consumer] {
+            while let Some(frame) = frames.receive() {
+                let Some(data) = frame.data() else {
+                    continue;
+                };
+
+                info!(
+                    "Received frame: {}",
+                    str::from_utf8(data).unwrap_or("Invalid UTF-8")
+                );
+
+                if can_send::spawn(frames.channel(), *data).is_err() {
+                    warn!("{}, echo dropped", frames.channel());
+                }
+            }
+        }
 
-        info!(
-            "Received frame: {}",
-            str::from_utf8(data).unwrap_or("Invalid UTF-8")
-        );
+        debug!("CAN1 RX: {}", rx::stats(Channel::Can1));
+        debug!("CAN2 RX: {}", rx::stats(Channel::Can2));
+    }
 
-        can_send::spawn(1, data.clone()).ok();
+    // refill the CAN1 transmit mailboxes from its queue
+    #[task(binds = CAN1_TX, shared = [shield], priority=3)]
+    fn can1_transmit(mut ctx: can1_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can1));
     }
 
-    // receive a message via CAN2
-    // Note: CAN2_RX1 is used instead of CAN2_RX0 because CAN2 is set up to use FIFO 1 in the CanShield implementation
-    #[task(binds = CAN2_RX1, shared = [can2])]
-    fn can2_receive(ctx: can2_receive::Context) {
-        let mut can2 = ctx.shared.can2;
-        let frame = can2.lock(|can2| can2.receive().unwrap());
+    // refill the CAN2 transmit mailboxes from its queue
+    #[task(binds = CAN2_TX, shared = [shield], priority=3)]
+    fn can2_transmit(mut ctx: can2_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can2));
+    }
 
-        let data = frame.data().unwrap();
+    // move frames from the CAN1 hardware FIFO into its queue
+    #[task(binds = CAN1_RX0, shared = [shield], local = [rx1_producer], priority=3)]
+    fn can1_receive(mut ctx: can1_receive::Context) {
+        let producer = ctx.local.rx1_producer;
+        ctx.shared
+            .shield
+            .lock(|shield| producer.drain(shield.channel(Channel::Can1)));
 
-        info!(
-            "Received frame: {}",
-            str::from_utf8(data).unwrap_or("Invalid UTF-8")
-        );
+        echo::spawn().ok();
+    }
 
-        can_send::spawn(2, data.clone()).ok();
+    // move frames from the CAN2 hardware FIFO into its queue
+    // Note: CAN2_RX1 is used instead of CAN2_RX0 because CAN2 is set up to use FIFO 1 in the CanShield implementation
+    #[task(binds = CAN2_RX1, shared = [shield], local = [rx2_producer], priority=3)]
+    fn can2_receive(mut ctx: can2_receive::Context) {
+        let producer = ctx.local.rx2_producer;
+        ctx.shared
+            .shield
+            .lock(|shield| producer.drain(shield.channel(Channel::Can2)));
+
+        echo::spawn().ok();
     }
 }
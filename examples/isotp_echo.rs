@@ -0,0 +1,227 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
+mod app {
+    use bxcan::StandardId;
+    use defmt::*;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use heapless::Vec;
+    use stm32f446_rtic::{
+        can_shield::{
+            rx::{self, RxConsumer, RxProducer, RxQueue},
+            CanShield, Channel, Shield,
+        },
+        isotp::{IsoTpConfig, IsoTpLink, StMin, MAX_MESSAGE_LEN},
+    };
+    use stm32f4xx_hal::prelude::*;
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<180_000_000>; // 180 MHz
+
+    type Link = IsoTpLink<180_000_000>;
+
+    // Requests come in on this ID, responses go out on the other one
+    const REQUEST_ID: u16 = 0x7e0;
+    const RESPONSE_ID: u16 = 0x7e8;
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        shield: CanShield,
+        links: [Link; 2],
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        rx1_producer: RxProducer<'static>,
+        rx2_producer: RxProducer<'static>,
+        rx1_consumer: RxConsumer<'static>,
+        rx2_consumer: RxConsumer<'static>,
+    }
+
+    // The init function is called in the beginning of the program
+    // The receive queues are init locals so they live for the whole program
+    #[init(local = [rx1_queue: RxQueue = RxQueue::new(), rx2_queue: RxQueue = RxQueue::new()])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        info!("init");
+
+        // Cortex-M peripherals
+        let mut _core: cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device: stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(180.MHz()).freeze();
+
+        let gpioa = _device.GPIOA.split();
+        let gpiob = _device.GPIOB.split();
+
+        // enable tracing and the cycle counter for the monotonic timer and the CAN sync timeout
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up both CAN devices, giving up if a bus stays silent for a second
+        let shield = CanShield::builder(&clocks)
+            .sync_timeout(1000.millis())
+            .build_rev1(
+                gpioa.pa12,
+                gpioa.pa11,
+                gpiob.pb13,
+                gpiob.pb5,
+                _device.CAN1,
+                _device.CAN2,
+            )
+            .unwrap();
+
+        // One link per channel, asking the sender for blocks of 8 frames at least 1 ms apart
+        let links = Channel::ALL.map(|ch| {
+            let config = IsoTpConfig::new(
+                ch,
+                StandardId::new(RESPONSE_ID).unwrap(),
+                StandardId::new(REQUEST_ID).unwrap(),
+            )
+            .block_size(8)
+            .st_min(StMin::millis(1));
+            Link::new(config)
+        });
+
+        let (rx1_producer, rx1_consumer) = rx::split(ctx.local.rx1_queue, Channel::Can1);
+        let (rx2_producer, rx2_consumer) = rx::split(ctx.local.rx2_queue, Channel::Can2);
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
+
+        info!("Init done!");
+        (
+            Shared { shield, links },
+            Local {
+                rx1_producer,
+                rx2_producer,
+                rx1_consumer,
+                rx2_consumer,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // hand every buffered frame to the link of its channel and echo complete messages
+    #[task(shared = [shield, links], local = [rx1_consumer, rx2_consumer, message: Vec<u8, MAX_MESSAGE_LEN> = Vec::new()], priority = 2)]
+    fn receive(ctx: receive::Context) {
+        let message = ctx.local.message;
+
+        (ctx.shared.shield, ctx.shared.links).lock(|shield, links| {
+            for frames in [ctx.local.rx1_consumer, ctx.local.rx2_consumer] {
+                let link = &mut links[frames.channel() as usize];
+
+                while let Some(frame) = frames.receive() {
+                    match link.on_frame(shield, &frame, monotonics::now()) {
+                        Ok(Some(data)) => {
+                            info!("{}, received {} bytes", frames.channel(), data.len());
+                            message.clear();
+                            message.extend_from_slice(data).ok();
+                        }
+                        Ok(None) => continue,
+                        Err(error) => {
+                            warn!("{}, receive failed: {}", frames.channel(), error);
+                            continue;
+                        }
+                    }
+
+                    if let Err(error) = link.send(shield, message, monotonics::now()) {
+                        warn!("{}, echo dropped: {}", frames.channel(), error);
+                    }
+                }
+            }
+        });
+
+        poll::spawn().ok();
+    }
+
+    // send due consecutive frames and check the timeouts, then sleep until the next deadline
+    #[task(shared = [shield, links], local = [next: Option<poll::SpawnHandle> = None], priority = 2, capacity = 4)]
+    fn poll(ctx: poll::Context) {
+        let mut deadline: Option<<MyMono as rtic::Monotonic>::Instant> = None;
+
+        (ctx.shared.shield, ctx.shared.links).lock(|shield, links| {
+            for link in links {
+                let next = loop {
+                    match link.poll(shield, monotonics::now()) {
+                        Ok(next) => break next,
+                        Err(error) => warn!("{}, ISO-TP error: {}", link.channel(), error),
+                    }
+                };
+                deadline = match (deadline, next) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+        });
+
+        // Only the latest wake-up is kept
+        if let Some(handle) = ctx.local.next.take() {
+            handle.cancel().ok();
+        }
+        if let Some(at) = deadline {
+            *ctx.local.next = poll::spawn_at(at).ok();
+        }
+    }
+
+    // refill the CAN1 transmit mailboxes from its queue
+    #[task(binds = CAN1_TX, shared = [shield], priority = 3)]
+    fn can1_transmit(mut ctx: can1_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can1));
+
+        // the next consecutive frame may be due
+        poll::spawn().ok();
+    }
+
+    // refill the CAN2 transmit mailboxes from its queue
+    #[task(binds = CAN2_TX, shared = [shield], priority = 3)]
+    fn can2_transmit(mut ctx: can2_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can2));
+
+        poll::spawn().ok();
+    }
+
+    // move frames from the CAN1 hardware FIFO into its queue
+    #[task(binds = CAN1_RX0, shared = [shield], local = [rx1_producer], priority = 3)]
+    fn can1_receive(mut ctx: can1_receive::Context) {
+        let producer = ctx.local.rx1_producer;
+        ctx.shared
+            .shield
+            .lock(|shield| producer.drain(shield.channel(Channel::Can1)));
+
+        receive::spawn().ok();
+    }
+
+    // move frames from the CAN2 hardware FIFO into its queue
+    // Note: CAN2_RX1 is used because CAN2 is set up to use FIFO 1 in the CanShield implementation
+    #[task(binds = CAN2_RX1, shared = [shield], local = [rx2_producer], priority = 3)]
+    fn can2_receive(mut ctx: can2_receive::Context) {
+        let producer = ctx.local.rx2_producer;
+        ctx.shared
+            .shield
+            .lock(|shield| producer.drain(shield.channel(Channel::Can2)));
+
+        receive::spawn().ok();
+    }
+}
@@ -0,0 +1,1062 @@
+#![no_main]
+#![no_std]
+
+use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
+
+#[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [USART1, USART2])]
+mod app {
+    use bxcan::{Fifo, StandardId};
+    use core::{
+        cell::RefCell,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+    use cortex_m::interrupt::{self, Mutex};
+    use cortex_m::peripheral::SCB;
+    use defmt::*;
+    use dwt_systick_monotonic::{DwtSystick, ExtU32};
+    use fugit::MillisDurationU32;
+    use stm32f446_rtic::{
+        can_shield::{
+            health::{ErrorStatus, RecoveryPolicy},
+            rx::{self, RxConsumer, RxProducer, RxQueue},
+            CanShield, Channel, ChannelConfig, Shield,
+        },
+        ccsds::{CdsFormat, Cuc, CucFormat},
+        eventlog::EventLog,
+        fdir::{Check, Fdir, Monitor, Observations, Recover, Recovery, Rule},
+        flash::InternalFlash,
+        heartbeat::{HeartbeatConfig, HeartbeatService, PresenceEvent, MAX_NODE_ID},
+        isotp::{IsoTpConfig, IsoTpLink},
+        mode::{Condition, Mode, ModeActions, ModeManager, Status, Transition},
+        nvstore::{ParamStore, ParameterDef},
+        pus::{
+            event::{Events, Severity},
+            housekeeping::{Housekeeping, Parameters, Value},
+            mode::ModeService,
+            parameter::ParameterService,
+            ping::Ping,
+            scheduler::Scheduler,
+            storage::EventLogService,
+            time::TimeManagement,
+            Dispatcher, Reporter, TmChannel, TmQueue,
+        },
+        reset::{self, BootRecord},
+        time::{self, Met, MissionClock, RtcBackup},
+        watchdog::{Supervisor, TaskId},
+    };
+    use stm32f4xx_hal::{
+        adc::{
+            config::{AdcConfig, SampleTime},
+            Adc, Temperature,
+        },
+        flash::LockedFlash,
+        pac::ADC1,
+        prelude::*,
+        rtc::Rtc,
+        signature::{VtempCal110, VtempCal30},
+        watchdog::IndependentWatchdog,
+    };
+
+    // Needed for scheduling monotonic tasks
+    #[monotonic(binds = SysTick, default = true)]
+    type MyMono = DwtSystick<180_000_000>; // 180 MHz
+
+    type Link = IsoTpLink<180_000_000>;
+
+    // Telecommands come in on this ID, telemetry goes out on the other one
+    const TC_ID: u16 = 0x7e0;
+    const TM_ID: u16 = 0x7e8;
+
+    // Application process ID of this node
+    const APID: u16 = 0x010;
+
+    // Stored parameters, read at boot: the node ID of the heartbeats and their period. The other
+    // nodes on CAN1 are watched through their heartbeats. The ranges keep ground from storing a
+    // value the next boot cannot start with
+    const NODE_ID: u16 = 0x0200;
+    const HEARTBEAT_MS: u16 = 0x0201;
+    const STORED: &[ParameterDef] = &[
+        ParameterDef::new(NODE_ID, Value::U8(0x10)).range(Value::U8(1), Value::U8(MAX_NODE_ID)),
+        ParameterDef::new(HEARTBEAT_MS, Value::U16(1000))
+            .range(Value::U16(100), Value::U16(10_000)),
+    ];
+
+    // The flash sectors of the parameter store and the event log, see memory.x
+    const STORE_SECTORS: [u8; 2] = [6, 7];
+    const LOG_SECTORS: [u8; 3] = [1, 2, 3];
+
+    // Housekeeping parameters
+    const UPTIME: u16 = 0x0100;
+    const TELECOMMANDS: u16 = 0x0101;
+    const TEMPERATURE: u16 = 0x0102;
+    const CAN1_TEC: u16 = 0x0110;
+    const CAN1_REC: u16 = 0x0111;
+    const CAN2_TEC: u16 = 0x0112;
+    const CAN2_REC: u16 = 0x0113;
+
+    // Housekeeping structures, the status every second and the CAN errors every 10 seconds
+    const SID_STATUS: u16 = 1;
+    const SID_CAN: u16 = 2;
+
+    // Length of a collection interval
+    const COLLECTION_MS: u32 = 100;
+
+    // Event reported once after reset, with the summary of the boot record as auxiliary data
+    const EVENT_BOOT: u16 = 0x0001;
+
+    // Events of the presence table, with the node ID as auxiliary data
+    const EVENT_NODE_JOINED: u16 = 0x0002;
+    const EVENT_NODE_LOST: u16 = 0x0003;
+
+    // Event reported after the boot event if the last run crashed, with the crash record
+    const EVENT_CRASH: u16 = 0x0004;
+
+    // Event of every mode change, with the old and the new mode and the condition of the change
+    const EVENT_MODE: u16 = 0x0005;
+
+    // Ground moves the node between all modes. It falls back to safe mode when it boots too often
+    // in a row, without a run that settled, or when it is cut off from both CAN buses
+    const MAX_BOOTS: u32 = 3;
+    const MODES: &[Transition] = &[
+        Transition::request(Mode::Nominal, Mode::Safe),
+        Transition::request(Mode::Nominal, Mode::Maintenance),
+        Transition::request(Mode::Safe, Mode::Nominal),
+        Transition::request(Mode::Safe, Mode::Maintenance),
+        Transition::request(Mode::Maintenance, Mode::Nominal),
+        Transition::request(Mode::Maintenance, Mode::Safe),
+        Transition::when(Mode::Nominal, Mode::Safe, Condition::Boots(MAX_BOOTS)),
+        Transition::when(Mode::Nominal, Mode::Safe, Condition::BusOff),
+        Transition::when(Mode::Maintenance, Mode::Safe, Condition::BusOff),
+    ];
+
+    // A run settles once it lasted this long, then the boots are counted from 0 again
+    const SETTLE_MS: u32 = 60_000;
+
+    // Boots in a row, from the boot record
+    static BOOTS: AtomicU32 = AtomicU32::new(0);
+
+    // The collect task only runs outside of safe mode. Every start of the collection gets a new
+    // epoch, the collect tasks of an old one stop
+    static COLLECT_EPOCH: AtomicU32 = AtomicU32::new(0);
+
+    // Faults raised and cleared by the FDIR monitors, with the fault and the recovery actions
+    // taken as auxiliary data
+    const EVENT_FAULT: u16 = 0x0006;
+    const EVENT_FAULT_CLEARED: u16 = 0x0007;
+
+    // The FDIR monitors run once per interval, so the persistence of a monitor is in intervals
+    const FDIR_MS: u32 = 1000;
+    const FAULT_TEMPERATURE: u16 = 0x0100;
+    const FAULT_CAN1_ERRORS: u16 = 0x0101;
+    const FAULT_CAN1_BUS_OFF: u16 = 0x0102;
+    const FAULT_COLLECT_LATE: u16 = 0x0103;
+    const MONITORS: [Monitor; 4] = [
+        Monitor::new(
+            FAULT_TEMPERATURE,
+            Check::Limit {
+                parameter: TEMPERATURE,
+                low: -20.0,
+                high: 85.0,
+            },
+        )
+        .persistence(5),
+        Monitor::new(
+            FAULT_CAN1_ERRORS,
+            Check::ErrorCounters {
+                channel: Channel::Can1,
+                limit: 127,
+            },
+        )
+        .persistence(3)
+        .severity(Severity::Low),
+        Monitor::new(FAULT_CAN1_BUS_OFF, Check::BusOff(Channel::Can1))
+            .persistence(2)
+            .severity(Severity::High),
+        // The collect task is the first one of the supervisor. It may be late by an erase, beyond
+        // that FDIR reboots the node before the watchdog does, so the fault is in the event log
+        Monitor::new(
+            FAULT_COLLECT_LATE,
+            Check::Deadline {
+                task: TaskId::new(0),
+                deadline: MillisDurationU32::millis(ERASE_MAX_MS + COLLECTION_MS),
+            },
+        )
+        .severity(Severity::High),
+    ];
+
+    // Too hot goes to safe mode, CAN1 is restarted after bus-off and the errors are only reported
+    const RULES: &[Rule] = &[
+        Rule::new(FAULT_TEMPERATURE, Recovery::Mode(Mode::Safe)),
+        Rule::new(FAULT_CAN1_BUS_OFF, Recovery::RestartCan(Channel::Can1)),
+        Rule::new(FAULT_COLLECT_LATE, Recovery::Reboot),
+    ];
+
+    // Longest erase of a flash sector the receive task may start, 128 KiB at x8, see `flash`. It
+    // stalls every task
+    const ERASE_MAX_MS: u32 = 4000;
+    // The supervisor kicks the IWDG while the periodic tasks check in within their deadline, which
+    // outlasts the erase and the longest sleep of a task
+    const SUPERVISE_MS: u32 = 500;
+    const DEADLINE_MS: u32 = ERASE_MAX_MS + RELEASE_CHECK_MS;
+    // The IWDG resets the node unless it is kicked within this time. It outlasts the erase and the
+    // period of the supervisor even if the LSI runs at 47 kHz instead of 32 kHz
+    const LSI_KHZ: u32 = 32;
+    const LSI_MAX_KHZ: u32 = 47;
+    const WATCHDOG_MS: u32 = ((ERASE_MAX_MS + SUPERVISE_MS) * LSI_MAX_KHZ).div_ceil(LSI_KHZ);
+
+    // Counts the telecommands received, atomic like the counters of the other examples
+    static TC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+    // Longest sleep of the release task, so the mission clock sees every wrap of the monotonic timer
+    const RELEASE_CHECK_MS: u32 = 1000;
+
+    // The MET for every task, the monotonic timer wraps after 23 s
+    static CLOCK: Mutex<RefCell<MissionClock<180_000_000>>> =
+        Mutex::new(RefCell::new(MissionClock::new()));
+
+    // The mission time of the telecommand schedule
+    fn mission_now() -> Met {
+        interrupt::free(|cs| CLOCK.borrow(cs).borrow_mut().update(monotonics::now()))
+    }
+
+    // The report timestamps
+    fn now() -> Cuc {
+        time::to_cuc(mission_now(), CucFormat::DEFAULT)
+    }
+
+    // Holds the shared resources (used by multiple tasks)
+    #[shared]
+    struct Shared {
+        shield: CanShield,
+        link: Link,
+        channel: TmChannel,
+        tm: TmQueue<16>,
+        housekeeping: Housekeeping<Parameters<8>, 4>,
+        events: Events<8>,
+        scheduler: Scheduler<8>,
+        timekeeping: TimeManagement,
+        heartbeat: HeartbeatService<16>,
+        flash: RefCell<InternalFlash>,
+        store: ParamStore<4>,
+        log: EventLog<3>,
+        supervisor: Supervisor<4>,
+        modes: Modes,
+    }
+
+    // Holds the local resources (used by a single task)
+    #[local]
+    struct Local {
+        rx1_producer: RxProducer<'static>,
+        rx1_consumer: RxConsumer<'static>,
+        adc: Adc<ADC1>,
+        iwdg: IndependentWatchdog,
+        collect_task: TaskId,
+        release_task: TaskId,
+        heartbeat_task: TaskId,
+    }
+
+    // The init function is called in the beginning of the program
+    // The receive queue is an init local so it lives for the whole program
+    #[init(local = [rx1_queue: RxQueue = RxQueue::new()])]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+        info!("init");
+
+        // Cortex-M peripherals
+        let mut _core: cortex_m::Peripherals = ctx.core;
+
+        // Device specific peripherals
+        let mut _device: stm32f4xx_hal::pac::Peripherals = ctx.device;
+
+        // What the last run left behind, before the reset flags are gone
+        let last = reset::take(&_device.RCC).unwrap();
+        info!("Boot {} after {}", last.boots, last.cause);
+        if let Some(crash) = last.crash {
+            error!("The last run crashed: {}", crash);
+        }
+
+        BOOTS.store(last.boots, Ordering::Relaxed);
+
+        // Set up the system clock.
+        let rcc = _device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(180.MHz()).freeze();
+
+        let gpioa = _device.GPIOA.split();
+        let gpiob = _device.GPIOB.split();
+
+        // enable tracing and the cycle counter for the monotonic timer and the CAN sync timeout
+        _core.DCB.enable_trace();
+        _core.DWT.enable_cycle_counter();
+
+        // Set up both CAN devices, the services are only offered on CAN1
+        // FDIR restarts CAN1 after bus-off
+        let backoff = (2 * FDIR_MS).millis();
+        let shield = CanShield::builder(&clocks)
+            .can1(
+                ChannelConfig::new(Fifo::Fifo0)
+                    .accept_all()
+                    .recovery(RecoveryPolicy::Manual { backoff }),
+            )
+            .sync_timeout(1000.millis())
+            .build_rev1(
+                gpioa.pa12,
+                gpioa.pa11,
+                gpiob.pb13,
+                gpiob.pb5,
+                _device.CAN1,
+                _device.CAN2,
+            )
+            .unwrap();
+
+        let link = Link::new(IsoTpConfig::new(
+            Channel::Can1,
+            StandardId::new(TM_ID).unwrap(),
+            StandardId::new(TC_ID).unwrap(),
+        ));
+
+        // The MCU temperature sensor
+        let mut adc = Adc::adc1(_device.ADC1, true, AdcConfig::default());
+        adc.enable_temperature_and_vref();
+
+        let mut parameters = Parameters::new();
+        for (id, initial) in [
+            (UPTIME, Value::U32(0)),
+            (TELECOMMANDS, Value::U32(0)),
+            (TEMPERATURE, Value::F32(0.0)),
+            (CAN1_TEC, Value::U8(0)),
+            (CAN1_REC, Value::U8(0)),
+            (CAN2_TEC, Value::U8(0)),
+            (CAN2_REC, Value::U8(0)),
+        ] {
+            parameters.register(id, initial).unwrap();
+        }
+
+        // Ground can change these and add more by telecommand
+        let mut housekeeping = Housekeeping::new(parameters);
+        housekeeping
+            .define(SID_STATUS, &[UPTIME, TELECOMMANDS, TEMPERATURE], 10)
+            .unwrap();
+        housekeeping
+            .define(SID_CAN, &[CAN1_TEC, CAN1_REC, CAN2_TEC, CAN2_REC], 100)
+            .unwrap();
+        housekeeping.enable(SID_STATUS).unwrap();
+        housekeeping.enable(SID_CAN).unwrap();
+
+        let (rx1_producer, rx1_consumer) = rx::split(ctx.local.rx1_queue, Channel::Can1);
+
+        // The stored parameters, or their defaults if the flash fails
+        let mut flash = InternalFlash::new(LockedFlash::new(_device.FLASH));
+        let mut store = ParamStore::new(STORE_SECTORS, STORED);
+        if let Err(error) = store.mount(&mut flash) {
+            error!("Parameter store not mounted: {}", error);
+        }
+        let mut log = EventLog::new(LOG_SECTORS);
+        if let Err(error) = log.mount(&mut flash) {
+            error!("Event log not mounted: {}", error);
+        }
+        // The definitions fix the types
+        let (Some(Value::U8(node)), Some(Value::U16(period))) =
+            (store.get(NODE_ID), store.get(HEARTBEAT_MS))
+        else {
+            defmt::unreachable!()
+        };
+        info!("Node {}, heartbeat every {} ms", node, period);
+
+        // The MET continues from the RTC, unless the backup domain lost power. Without a running
+        // RTC it starts from 0 at every boot
+        let mut backup = RtcBackup::new(Rtc::new(_device.RTC, &mut _device.PWR), clocks.hclk());
+        let boot = backup.restore().unwrap_or_else(|error| {
+            error!("RTC not responding: {}", error);
+            Met::from_ticks(0)
+        });
+        interrupt::free(|cs| CLOCK.borrow(cs).borrow_mut().set_boot_time(boot));
+        info!("MET at boot: {} s", boot.ticks() / 1_000_000);
+
+        // The receive task only runs when frames arrive, the periodic tasks are watched
+        let mut supervisor = Supervisor::new();
+        let deadline = DEADLINE_MS.millis();
+        let collect_task = supervisor.register("collect", deadline, boot).unwrap();
+        let release_task = supervisor.register("release", deadline, boot).unwrap();
+        let heartbeat_deadline = (u32::from(period) + DEADLINE_MS).millis();
+        let heartbeat_task = supervisor
+            .register("heartbeat", heartbeat_deadline, boot)
+            .unwrap();
+        let mut iwdg = IndependentWatchdog::new(_device.IWDG);
+        iwdg.stop_on_debug(&_device.DBGMCU, true);
+        iwdg.start(WATCHDOG_MS.millis());
+
+        // Set up the monotonic timer
+        let mono = DwtSystick::new(&mut _core.DCB, _core.DWT, _core.SYST, clocks.hclk().to_Hz());
+
+        // The node starts in nominal mode, the supervise task checks the conditions of the
+        // transitions
+        let mut heartbeat = HeartbeatService::new(
+            HeartbeatConfig::new(Channel::Can1, node).period(u32::from(period).millis()),
+            boot,
+        );
+        heartbeat.set_mode(Mode::Nominal as u8);
+        let modes = Modes {
+            manager: ModeManager::new(MODES, Mode::Nominal),
+            collect: collect_task,
+        };
+
+        info!("Init done!");
+        boot::spawn(last).ok();
+        collect::spawn_after(COLLECTION_MS.millis(), 0).ok();
+        release::spawn().ok();
+        heartbeat::spawn().ok();
+        supervise::spawn().ok();
+        fdir::spawn_after(FDIR_MS.millis()).ok();
+        (
+            Shared {
+                shield,
+                link,
+                channel: TmChannel::new(APID, CucFormat::DEFAULT),
+                tm: TmQueue::new(),
+                housekeeping,
+                events: Events::new(),
+                scheduler: Scheduler::new(CucFormat::DEFAULT),
+                timekeeping: TimeManagement::new(CucFormat::DEFAULT, CdsFormat::DEFAULT),
+                heartbeat,
+                flash: RefCell::new(flash),
+                store,
+                log,
+                supervisor,
+                modes,
+            },
+            Local {
+                rx1_producer,
+                rx1_consumer,
+                adc,
+                iwdg,
+                collect_task,
+                release_task,
+                heartbeat_task,
+            },
+            init::Monotonics(mono),
+        )
+    }
+
+    // The idle function is called when there is nothing else to do
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    // tell ground the node is up, and how the last run ended
+    #[task(shared = [channel, tm, events, flash, log])]
+    fn boot(ctx: boot::Context, last: BootRecord) {
+        (
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.events,
+            ctx.shared.flash,
+            ctx.shared.log,
+        )
+            .lock(|channel, tm, events, flash, log| {
+                let mut reporter = channel.reporter(tm, now());
+                let flash = flash.get_mut();
+                record(
+                    events,
+                    &mut reporter,
+                    log,
+                    flash,
+                    Severity::Informative,
+                    EVENT_BOOT,
+                    &last.summary(),
+                );
+                // Too long for the event log, which has the summary
+                if let Some(crash) = last.crash {
+                    let data = crash.to_bytes();
+                    if let Err(error) =
+                        events.report(&mut reporter, Severity::High, EVENT_CRASH, &data)
+                    {
+                        warn!("Crash event dropped: {}", error);
+                    }
+                }
+            });
+
+        poll::spawn().ok();
+    }
+
+    // update the presence table, reassemble telecommands and hand them to the services
+    // Note: storing a parameter can erase a 128 KiB flash sector, which stalls the CPU for 2 s,
+    // up to 4 s. The deadlines and the watchdog allow for it, see `ERASE_MAX_MS`
+    #[task(shared = [shield, link, channel, tm, housekeeping, events, scheduler, timekeeping, heartbeat, flash, store, log, supervisor, modes], local = [rx1_consumer, ping: Ping = Ping], priority = 2)]
+    fn receive(ctx: receive::Context) {
+        let frames = ctx.local.rx1_consumer;
+        let ping = ctx.local.ping;
+
+        (
+            ctx.shared.shield,
+            ctx.shared.link,
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.housekeeping,
+            ctx.shared.events,
+            ctx.shared.scheduler,
+            ctx.shared.timekeeping,
+            ctx.shared.heartbeat,
+            ctx.shared.flash,
+            ctx.shared.store,
+            ctx.shared.log,
+            ctx.shared.supervisor,
+            ctx.shared.modes,
+        )
+            .lock(
+                |shield,
+                 link,
+                 channel,
+                 tm,
+                 housekeeping,
+                 events,
+                 scheduler,
+                 timekeeping,
+                 heartbeat,
+                 flash,
+                 store,
+                 log,
+                 supervisor,
+                 modes| {
+                    while let Some(frame) = frames.receive() {
+                        if let Some(event) =
+                            heartbeat.on_frame(Channel::Can1, &frame, mission_now())
+                        {
+                            let mut reporter = channel.reporter(tm, now());
+                            let flash = flash.get_mut();
+                            report_presence(events, &mut reporter, log, flash, event);
+                            continue;
+                        }
+
+                        let tc = match link.on_frame(shield, &frame, monotonics::now()) {
+                            Ok(Some(tc)) => tc,
+                            Ok(None) => continue,
+                            Err(error) => {
+                                warn!("Telecommand lost: {}", error);
+                                continue;
+                            }
+                        };
+                        TC_COUNT.fetch_add(1, Ordering::Relaxed);
+
+                        // The services are only borrowed for this telecommand, changed
+                        // parameters take effect after the next reset. Both stores share the
+                        // flash.
+                        let mut reporter = channel.reporter(tm, now());
+                        {
+                            let flash = &*flash;
+                            let mut shared = flash;
+                            let mut parameters = ParameterService::new(&mut *store, &mut shared);
+                            let mut storage = EventLogService::new(&*log, &flash);
+                            let mut tasks = Tasks {
+                                supervisor: &mut *supervisor,
+                                heartbeat: &mut *heartbeat,
+                                collect: modes.collect,
+                            };
+                            let mut mode = ModeService::new(&mut modes.manager, &mut tasks);
+                            let mut dispatcher = Dispatcher::<8>::new(APID);
+                            dispatcher.register(&mut *ping).unwrap();
+                            dispatcher.register(&mut *housekeeping).unwrap();
+                            dispatcher.register(&mut *events).unwrap();
+                            dispatcher.register(&mut *scheduler).unwrap();
+                            dispatcher.register(&mut *timekeeping).unwrap();
+                            dispatcher.register(&mut parameters).unwrap();
+                            dispatcher.register(&mut storage).unwrap();
+                            dispatcher.register(&mut mode).unwrap();
+
+                            if let Err(error) = dispatcher.dispatch(tc, &mut reporter) {
+                                warn!("Telecommand not executed: {}", error);
+                            }
+                        }
+
+                        // A requested mode change is reported like the others
+                        let flash = flash.get_mut();
+                        report_modes(events, &mut reporter, log, flash, &mut modes.manager);
+                    }
+                },
+            );
+
+        // the schedule may have changed
+        release::spawn().ok();
+        poll::spawn().ok();
+    }
+
+    // execute the scheduled telecommands that are due, then sleep until the next release
+    #[task(shared = [channel, tm, housekeeping, events, scheduler, timekeeping, supervisor], local = [release_task, ping: Ping = Ping, next: Option<release::SpawnHandle> = None], capacity = 4)]
+    fn release(ctx: release::Context) {
+        let ping = ctx.local.ping;
+        let task = *ctx.local.release_task;
+
+        let next = (
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.housekeeping,
+            ctx.shared.events,
+            ctx.shared.scheduler,
+            ctx.shared.timekeeping,
+            ctx.shared.supervisor,
+        )
+            .lock(
+                |channel, tm, housekeeping, events, scheduler, timekeeping, supervisor| {
+                    while let Some(tc) = scheduler.pop_due(mission_now()) {
+                        let mut dispatcher = Dispatcher::<4>::new(APID);
+                        dispatcher.register(&mut *ping).unwrap();
+                        dispatcher.register(&mut *housekeeping).unwrap();
+                        dispatcher.register(&mut *events).unwrap();
+                        dispatcher.register(&mut *timekeeping).unwrap();
+
+                        let mut reporter = channel.reporter(tm, now());
+                        if let Err(error) = dispatcher.dispatch(&tc, &mut reporter) {
+                            warn!("Scheduled telecommand not executed: {}", error);
+                        }
+                    }
+                    supervisor.check_in(task, mission_now());
+                    scheduler.next_release()
+                },
+            );
+
+        // Only the latest wake-up is kept
+        if let Some(handle) = ctx.local.next.take() {
+            handle.cancel().ok();
+        }
+        let sleep = next
+            .and_then(|at| at.checked_duration_since(mission_now()))
+            .map_or(RELEASE_CHECK_MS, |sleep| {
+                (sleep.to_millis() as u32).clamp(1, RELEASE_CHECK_MS)
+            });
+        *ctx.local.next = release::spawn_after(sleep.millis()).ok();
+
+        poll::spawn().ok();
+    }
+
+    // send the heartbeat of this node and report the nodes that fell silent
+    #[task(shared = [shield, channel, tm, events, heartbeat, flash, log, supervisor], local = [heartbeat_task])]
+    fn heartbeat(ctx: heartbeat::Context) {
+        let task = *ctx.local.heartbeat_task;
+        let next = (
+            ctx.shared.shield,
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.events,
+            ctx.shared.heartbeat,
+            ctx.shared.flash,
+            ctx.shared.log,
+            ctx.shared.supervisor,
+        )
+            .lock(
+                |shield, channel, tm, events, heartbeat, flash, log, supervisor| {
+                    let now = mission_now();
+                    while let Some(event) = heartbeat.expire(now) {
+                        let mut reporter =
+                            channel.reporter(tm, time::to_cuc(now, CucFormat::DEFAULT));
+                        report_presence(events, &mut reporter, log, flash.get_mut(), event);
+                    }
+                    supervisor.check_in(task, now);
+                    heartbeat.poll(shield, now)
+                },
+            );
+
+        // The period is shorter than a wrap of the monotonic timer
+        let sleep = next
+            .checked_duration_since(mission_now())
+            .map_or(1, |sleep| sleep.to_millis() as u32);
+        heartbeat::spawn_after(sleep.millis()).ok();
+
+        poll::spawn().ok();
+    }
+
+    // turn a change of the presence table into an event report and a log record
+    fn report_presence(
+        events: &mut Events<8>,
+        reporter: &mut Reporter<'_>,
+        log: &mut EventLog<3>,
+        flash: &mut InternalFlash,
+        event: PresenceEvent,
+    ) {
+        let (id, severity, node) = match event {
+            PresenceEvent::Joined(heartbeat) => {
+                (EVENT_NODE_JOINED, Severity::Informative, heartbeat.node)
+            }
+            PresenceEvent::TimedOut { node } => (EVENT_NODE_LOST, Severity::Medium, node),
+        };
+        record(events, reporter, log, flash, severity, id, &[node]);
+    }
+
+    // report the mode changes to ground and keep them in the event log
+    fn report_modes(
+        events: &mut Events<8>,
+        reporter: &mut Reporter<'_>,
+        log: &mut EventLog<3>,
+        flash: &mut InternalFlash,
+        modes: &mut ModeManager<'static>,
+    ) {
+        while let Some(change) = modes.pop_change() {
+            info!("Mode {} -> {}", change.from, change.to);
+            let data = change.to_bytes();
+            record(
+                events,
+                reporter,
+                log,
+                flash,
+                change.severity(),
+                EVENT_MODE,
+                &data,
+            );
+        }
+    }
+
+    // report an event to ground and keep it in the event log, which survives resets
+    // Note: moving on to the next log sector erases it, which stalls the CPU for up to 800 ms
+    fn record(
+        events: &mut Events<8>,
+        reporter: &mut Reporter<'_>,
+        log: &mut EventLog<3>,
+        flash: &mut InternalFlash,
+        severity: Severity,
+        id: u16,
+        data: &[u8],
+    ) {
+        if let Err(error) = log.log(flash, mission_now(), severity, id, data) {
+            warn!("Event {} not logged: {}", id, error);
+        }
+        if let Err(error) = events.report(reporter, severity, id, data) {
+            warn!("Event {} dropped: {}", id, error);
+        }
+    }
+
+    // sample the parameters and send the housekeeping reports that are due, once per collection interval
+    // Note: the collection of an old epoch stops, see `Tasks`
+    #[task(shared = [shield, channel, tm, housekeeping, supervisor], local = [adc, collect_task], capacity = 2)]
+    fn collect(ctx: collect::Context, epoch: u32) {
+        if epoch != COLLECT_EPOCH.load(Ordering::Relaxed) {
+            return;
+        }
+        let task = *ctx.local.collect_task;
+        collect::spawn_after(COLLECTION_MS.millis(), epoch).ok();
+
+        // Temperature in °C from the factory calibration at 30 °C and 110 °C
+        let sample = f32::from(ctx.local.adc.convert(&Temperature, SampleTime::Cycles_480));
+        let cal30 = f32::from(VtempCal30::get().read());
+        let cal110 = f32::from(VtempCal110::get().read());
+        let temperature = (110.0 - 30.0) * (sample - cal30) / (cal110 - cal30) + 30.0;
+
+        (
+            ctx.shared.shield,
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.housekeeping,
+            ctx.shared.supervisor,
+        )
+            .lock(|shield, channel, tm, housekeeping, supervisor| {
+                let can1 = shield.error_status(Channel::Can1);
+                let can2 = shield.error_status(Channel::Can2);
+                let uptime = monotonics::now().duration_since_epoch().to_secs();
+
+                let parameters = housekeeping.source_mut();
+                for (id, value) in [
+                    (UPTIME, Value::U32(uptime)),
+                    (TELECOMMANDS, Value::U32(TC_COUNT.load(Ordering::Relaxed))),
+                    (TEMPERATURE, Value::F32(temperature)),
+                    (CAN1_TEC, Value::U8(can1.tec)),
+                    (CAN1_REC, Value::U8(can1.rec)),
+                    (CAN2_TEC, Value::U8(can2.tec)),
+                    (CAN2_REC, Value::U8(can2.rec)),
+                ] {
+                    parameters.set(id, value).ok();
+                }
+
+                let mut reporter = channel.reporter(tm, now());
+                if let Err(error) = housekeeping.tick(&mut reporter) {
+                    warn!("Housekeeping report dropped: {}", error);
+                }
+                supervisor.check_in(task, mission_now());
+            });
+
+        poll::spawn().ok();
+    }
+
+    // kick the watchdog while the periodic tasks check in, at the lowest priority so a task that
+    // hogs the CPU starves this one too, and take the transitions to safe mode
+    #[task(shared = [shield, channel, tm, events, heartbeat, flash, log, supervisor, modes], local = [iwdg, polls: u32 = 0])]
+    fn supervise(ctx: supervise::Context) {
+        supervise::spawn_after(SUPERVISE_MS.millis()).ok();
+
+        // The boots in a row end with a run that lasts
+        let polls = ctx.local.polls;
+        *polls += 1;
+        if *polls == SETTLE_MS / SUPERVISE_MS {
+            info!(
+                "Run settled after {} boots",
+                BOOTS.swap(0, Ordering::Relaxed)
+            );
+            reset::settle();
+        }
+
+        let iwdg = ctx.local.iwdg;
+        (
+            ctx.shared.shield,
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.events,
+            ctx.shared.heartbeat,
+            ctx.shared.flash,
+            ctx.shared.log,
+            ctx.shared.supervisor,
+            ctx.shared.modes,
+        )
+            .lock(
+                |shield, channel, tm, events, heartbeat, flash, log, supervisor, modes| {
+                    let status = Status {
+                        boots: BOOTS.load(Ordering::Relaxed),
+                        bus: [
+                            shield.error_status(Channel::Can1).state,
+                            shield.error_status(Channel::Can2).state,
+                        ],
+                    };
+                    let mut tasks = Tasks {
+                        supervisor: &mut *supervisor,
+                        heartbeat,
+                        collect: modes.collect,
+                    };
+                    if modes.manager.update(&status, &mut tasks) {
+                        let mut reporter = channel.reporter(tm, now());
+                        report_modes(
+                            events,
+                            &mut reporter,
+                            log,
+                            flash.get_mut(),
+                            &mut modes.manager,
+                        );
+                    }
+
+                    if let Err(starved) = supervisor.poll(mission_now(), iwdg) {
+                        error!(
+                            "Task {} starved, the watchdog resets the node",
+                            starved.name
+                        );
+                        // Named in the boot record of the next run
+                        reset::record(&starved.crash());
+                    }
+                },
+            );
+    }
+
+    // The mode manager, with the task of the supervisor that stops in safe mode
+    pub struct Modes {
+        manager: ModeManager<'static>,
+        collect: TaskId,
+    }
+
+    // what runs in each mode: the housekeeping collection stops in safe mode, and the heartbeats
+    // tell the other nodes the mode
+    struct Tasks<'a> {
+        supervisor: &'a mut Supervisor<4>,
+        heartbeat: &'a mut HeartbeatService<16>,
+        collect: TaskId,
+    }
+
+    impl ModeActions for Tasks<'_> {
+        fn exit(&mut self, mode: Mode) {
+            if mode != Mode::Safe {
+                COLLECT_EPOCH.fetch_add(1, Ordering::Relaxed);
+                self.supervisor.pause(self.collect);
+            }
+        }
+
+        fn enter(&mut self, mode: Mode) {
+            self.heartbeat.set_mode(mode as u8);
+            if mode != Mode::Safe {
+                let epoch = COLLECT_EPOCH.fetch_add(1, Ordering::Relaxed) + 1;
+                self.supervisor.resume(self.collect, mission_now());
+                collect::spawn(epoch).ok();
+            }
+        }
+    }
+
+    // check the FDIR monitors, take the recovery actions of the faults they raise and report them
+    #[task(shared = [shield, channel, tm, housekeeping, events, heartbeat, flash, log, supervisor, modes], local = [faults: Fdir<'static, 4> = Fdir::new(&MONITORS, RULES)])]
+    fn fdir(ctx: fdir::Context) {
+        fdir::spawn_after(FDIR_MS.millis()).ok();
+
+        let faults = ctx.local.faults;
+        let reboot = (
+            ctx.shared.shield,
+            ctx.shared.channel,
+            ctx.shared.tm,
+            ctx.shared.housekeeping,
+            ctx.shared.events,
+            ctx.shared.heartbeat,
+            ctx.shared.flash,
+            ctx.shared.log,
+            ctx.shared.supervisor,
+            ctx.shared.modes,
+        )
+            .lock(
+                |shield,
+                 channel,
+                 tm,
+                 housekeeping,
+                 events,
+                 heartbeat,
+                 flash,
+                 log,
+                 supervisor,
+                 modes| {
+                    let mut node = Node {
+                        shield,
+                        parameters: housekeeping.source(),
+                        supervisor,
+                        heartbeat,
+                        modes: &mut *modes,
+                        now: mission_now(),
+                        reboot: false,
+                    };
+                    faults.run(&mut node);
+                    let reboot = node.reboot;
+
+                    let mut reporter = channel.reporter(tm, now());
+                    let flash = flash.get_mut();
+                    while let Some(event) = faults.pop_event() {
+                        let id = match event.raised {
+                            true => EVENT_FAULT,
+                            false => EVENT_FAULT_CLEARED,
+                        };
+                        let data = event.to_bytes();
+                        record(events, &mut reporter, log, flash, event.severity, id, &data);
+                    }
+                    report_modes(events, &mut reporter, log, flash, &mut modes.manager);
+                    reboot
+                },
+            );
+
+        if reboot {
+            error!("Rebooting");
+            SCB::sys_reset();
+        }
+        poll::spawn().ok();
+    }
+
+    // what the FDIR monitors see, and the recovery actions they take
+    struct Node<'a> {
+        shield: &'a mut CanShield,
+        parameters: &'a Parameters<8>,
+        supervisor: &'a mut Supervisor<4>,
+        heartbeat: &'a mut HeartbeatService<16>,
+        modes: &'a mut Modes,
+        now: Met,
+        // Once the faults are in the event log
+        reboot: bool,
+    }
+
+    impl Observations for Node<'_> {
+        fn parameter(&self, id: u16) -> Option<Value> {
+            self.parameters.get(id)
+        }
+
+        fn error_status(&self, channel: Channel) -> ErrorStatus {
+            self.shield.error_status(channel)
+        }
+
+        fn since_check_in(&self, task: TaskId) -> Option<MillisDurationU32> {
+            self.supervisor.since_check_in(task, self.now)
+        }
+    }
+
+    impl Recover for Node<'_> {
+        fn recover(&mut self, fault: u16, action: Recovery) {
+            warn!("Fault {=u16:#06x}, recovery: {}", fault, action);
+            match action {
+                Recovery::RestartCan(channel) => {
+                    if self.shield.channel(channel).restart().is_err() {
+                        warn!("{} did not restart, the bus is not idle", channel);
+                    }
+                }
+                // The services are only offered on CAN1
+                Recovery::SwitchBus(channel) => warn!("No redundant bus, {} not used", channel),
+                Recovery::Mode(mode) => {
+                    let mut tasks = Tasks {
+                        supervisor: &mut *self.supervisor,
+                        heartbeat: &mut *self.heartbeat,
+                        collect: self.modes.collect,
+                    };
+                    if let Err(error) = self.modes.manager.recover(mode, &mut tasks) {
+                        warn!("Mode not changed: {}", error);
+                    }
+                }
+                Recovery::Reboot => self.reboot = true,
+            }
+        }
+    }
+
+    // send the next telemetry packet and check the ISO-TP timeouts, then sleep until the next deadline
+    #[task(shared = [shield, link, tm], local = [next: Option<poll::SpawnHandle> = None], priority = 2, capacity = 4)]
+    fn poll(ctx: poll::Context) {
+        let deadline =
+            (ctx.shared.shield, ctx.shared.link, ctx.shared.tm).lock(|shield, link, tm| {
+                if !link.is_sending() {
+                    if let Some(packet) = tm.front() {
+                        match link.send(shield, packet, monotonics::now()) {
+                            Ok(()) => {
+                                tm.pop();
+                            }
+                            Err(error) => warn!("Telemetry not sent: {}", error),
+                        }
+                    }
+                }
+
+                loop {
+                    match link.poll(shield, monotonics::now()) {
+                        Ok(next) => break next,
+                        Err(error) => warn!("ISO-TP error: {}", error),
+                    }
+                }
+            });
+
+        // Only the latest wake-up is kept
+        if let Some(handle) = ctx.local.next.take() {
+            handle.cancel().ok();
+        }
+        if let Some(at) = deadline {
+            *ctx.local.next = poll::spawn_at(at).ok();
+        }
+    }
+
+    // refill the CAN1 transmit mailboxes from its queue
+    #[task(binds = CAN1_TX, shared = [shield], priority = 3)]
+    fn can1_transmit(mut ctx: can1_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can1));
+
+        // the next consecutive frame or telemetry packet may be due
+        poll::spawn().ok();
+    }
+
+    // refill the CAN2 transmit mailboxes from its queue
+    #[task(binds = CAN2_TX, shared = [shield], priority = 3)]
+    fn can2_transmit(mut ctx: can2_transmit::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| shield.on_tx_interrupt(Channel::Can2));
+    }
+
+    // move frames from the CAN1 hardware FIFO into its queue
+    #[task(binds = CAN1_RX0, shared = [shield], local = [rx1_producer], priority = 3)]
+    fn can1_receive(mut ctx: can1_receive::Context) {
+        let producer = ctx.local.rx1_producer;
+        ctx.shared
+            .shield
+            .lock(|shield| producer.drain(shield.channel(Channel::Can1)));
+
+        receive::spawn().ok();
+    }
+
+    // nothing is served on CAN2, so its frames are dropped
+    // Note: CAN2_RX1 is used because CAN2 is set up to use FIFO 1 in the CanShield implementation
+    #[task(binds = CAN2_RX1, shared = [shield], priority = 3)]
+    fn can2_receive(mut ctx: can2_receive::Context) {
+        ctx.shared
+            .shield
+            .lock(|shield| while shield.channel(Channel::Can2).receive().is_ok() {});
+    }
+}
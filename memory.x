@@ -1,11 +1,28 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
-  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+  /* Sector 0 holds the vector table, sectors 1 to 3 the event log and sectors 4 and 5 the code.
+     Sectors 6 and 7 hold the parameter store, see `flash` */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  /* The last kilobyte of RAM keeps the crash record over resets, see `reset` */
+  RAM : ORIGIN = 0x20000000, LENGTH = 127K
+  RETAINED : ORIGIN = 0x2001FC00, LENGTH = 1K
 }
 
+/* Neither loaded nor cleared at boot. Before the sections of link.x, so its `.uninit` does not
+   take the record */
+SECTIONS
+{
+  .uninit.retained (NOLOAD) : ALIGN(4)
+  {
+    *(.uninit.retained .uninit.retained.*);
+  } > RETAINED
+}
+
+/* The code starts at sector 4, after the event log */
+_stext = ORIGIN(FLASH) + 64K;
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
-/* _stack_start = ORIGIN(RAM) + LENGTH(RAM); */
\ No newline at end of file
+/* _stack_start = ORIGIN(RAM) + LENGTH(RAM); */
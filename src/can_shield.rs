@@ -0,0 +1,480 @@
+use bxcan::{Fifo, Frame, Interrupts};
+use cortex_m::peripheral::DWT;
+use defmt::{info, warn, Format};
+use fugit::MillisDurationU32;
+use stm32f4xx_hal::{
+    can::Can,
+    gpio::{Alternate, PA11, PA12, PB13, PB5},
+    pac::{CAN1, CAN2},
+    prelude::_stm32f4xx_hal_can_CanExt,
+    rcc::Clocks,
+    time::Hertz,
+};
+
+pub mod channel;
+pub mod filters;
+pub mod health;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod redundancy;
+pub mod rx;
+pub mod timing;
+pub mod tx;
+
+pub use channel::{CanChannel, Channel, ChannelStatus, FifoOverrun, RestartTimeout};
+
+use filters::{FilterError, FilterPlan, FilterRule, FilterSet};
+use health::{ErrorStatus, HealthMonitor, RecoveryPolicy};
+use timing::{BitTiming, TimingError};
+use tx::{TxError, TxQueue, TxStats};
+
+/// CAN1 on the shield (TX: PA12, RX: PA11).
+pub type Can1 = bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>;
+
+/// CAN2 on the shield (TX: PB13, RX: PB5).
+pub type Can2 = bxcan::Can<Can<CAN2, (PB13<Alternate<9>>, PB5<Alternate<9>>)>>;
+
+/// Errors returned while configuring and enabling the shield.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CanShieldError {
+    /// A controller did not see 11 recessive bits on its bus before the sync timeout expired.
+    BusSyncTimeout(Channel),
+    /// No bit timing matches the requested bitrate and sample point at the current PCLK1.
+    InvalidBitTiming(TimingError),
+    /// A raw bit timing was calculated for a different PCLK1 than the one the shield runs at.
+    ClockMismatch { expected_hz: u32, actual_hz: u32 },
+    /// The filters of both channels do not fit into the shared filter banks.
+    FilterBankOverflow(FilterError),
+    /// A sync timeout was requested, but the DWT cycle counter is not running.
+    CycleCounterDisabled,
+}
+
+impl From<TimingError> for CanShieldError {
+    fn from(error: TimingError) -> Self {
+        Self::InvalidBitTiming(error)
+    }
+}
+
+impl From<FilterError> for CanShieldError {
+    fn from(error: FilterError) -> Self {
+        Self::FilterBankOverflow(error)
+    }
+}
+
+/// Configuration of a single CAN controller on the shield.
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    bitrate: u32,
+    sample_point: u16,
+    silent: bool,
+    loopback: bool,
+    automatic_retransmit: bool,
+    fifo: Fifo,
+    filters: FilterSet,
+    raw_timing: Option<(u32, Hertz)>,
+    recovery: RecoveryPolicy,
+}
+
+impl ChannelConfig {
+    /// Creates a configuration delivering frames to `fifo`.
+    ///
+    /// Defaults to 1 Mbit/s, a sample point of 87.5 %, automatic retransmission and automatic
+    /// bus-off recovery. No filters are enabled, so nothing is received until
+    /// [`ChannelConfig::filter`], [`ChannelConfig::filters`] or [`ChannelConfig::accept_all`] is
+    /// used.
+    pub fn new(fifo: Fifo) -> Self {
+        Self {
+            bitrate: 1_000_000,
+            sample_point: 875,
+            silent: false,
+            loopback: false,
+            automatic_retransmit: true,
+            fifo,
+            filters: FilterSet::new(),
+            raw_timing: None,
+            recovery: RecoveryPolicy::Automatic,
+        }
+    }
+
+    /// Sets the bitrate in bit/s.
+    pub fn bitrate(mut self, bitrate: u32) -> Self {
+        self.bitrate = bitrate;
+        self.raw_timing = None;
+        self
+    }
+
+    /// Sets the sample point in per mille of the bit time, e.g. `875` for 87.5 %.
+    pub fn sample_point(mut self, permille: u16) -> Self {
+        self.sample_point = permille;
+        self.raw_timing = None;
+        self
+    }
+
+    /// Uses a precalculated `CAN_BTR` value instead of calculating one from the bitrate.
+    ///
+    /// `pclk1` is the APB1 clock the value was calculated for. Validation fails with
+    /// [`CanShieldError::ClockMismatch`] if the shield runs at a different clock.
+    pub fn raw_bit_timing(mut self, btr: u32, pclk1: Hertz) -> Self {
+        self.raw_timing = Some((btr, pclk1));
+        self
+    }
+
+    /// Enables or disables silent mode (the TX pin is disconnected).
+    pub fn silent(mut self, enabled: bool) -> Self {
+        self.silent = enabled;
+        self
+    }
+
+    /// Enables or disables loopback mode (TX is internally connected to RX).
+    pub fn loopback(mut self, enabled: bool) -> Self {
+        self.loopback = enabled;
+        self
+    }
+
+    /// Enables or disables automatic retransmission of frames that lost arbitration or
+    /// were not acknowledged.
+    pub fn automatic_retransmit(mut self, enabled: bool) -> Self {
+        self.automatic_retransmit = enabled;
+        self
+    }
+
+    /// Sets how the controller leaves the bus-off state.
+    pub fn recovery(mut self, policy: RecoveryPolicy) -> Self {
+        self.recovery = policy;
+        self
+    }
+
+    /// Sets the receive FIFO that accepted frames are delivered to.
+    pub fn fifo(mut self, fifo: Fifo) -> Self {
+        self.fifo = fifo;
+        self
+    }
+
+    /// Adds an acceptance rule.
+    pub fn filter(mut self, rule: FilterRule) -> Self {
+        self.filters.push(rule);
+        self
+    }
+
+    /// Replaces all acceptance rules.
+    pub fn filters(mut self, filters: FilterSet) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Adds a rule accepting every frame.
+    pub fn accept_all(self) -> Self {
+        self.filter(FilterRule::Any)
+    }
+
+    /// Checks the bit timing against the given PCLK1 and returns the timing to use.
+    pub fn validate(&self, pclk1: Hertz) -> Result<BitTiming, CanShieldError> {
+        match self.raw_timing {
+            Some((btr, expected)) if expected == pclk1 => Ok(BitTiming::from_btr(btr)),
+            Some((_, expected)) => Err(CanShieldError::ClockMismatch {
+                expected_hz: expected.raw(),
+                actual_hz: pclk1.raw(),
+            }),
+            None => Ok(timing::calculate(pclk1, self.bitrate, self.sample_point)?),
+        }
+    }
+
+    fn configure<I: bxcan::Instance>(
+        &self,
+        builder: bxcan::CanBuilder<I>,
+        timing: BitTiming,
+    ) -> bxcan::CanBuilder<I> {
+        builder
+            .set_bit_timing(timing.btr())
+            .set_silent(self.silent)
+            .set_loopback(self.loopback)
+            .set_automatic_retransmit(self.automatic_retransmit)
+    }
+
+    /// RX interrupts of the chosen FIFO, plus the TX interrupt driving the transmit queue.
+    fn interrupts(&self) -> Interrupts {
+        let rx = match self.fifo {
+            Fifo::Fifo0 => {
+                Interrupts::FIFO0_MESSAGE_PENDING | Interrupts::FIFO0_FULL | Interrupts::FIFO0_OVERRUN
+            }
+            Fifo::Fifo1 => {
+                Interrupts::FIFO1_MESSAGE_PENDING | Interrupts::FIFO1_FULL | Interrupts::FIFO1_OVERRUN
+            }
+        };
+        rx | Interrupts::TRANSMIT_MAILBOX_EMPTY
+    }
+}
+
+/// Builder for a [`CanShield`].
+///
+/// By default both controllers run at 1 Mbit/s and accept all frames, CAN1 into FIFO 0 and CAN2
+/// into FIFO 1, which matches [`CanShield::new_rev1`].
+#[derive(Debug, Clone)]
+pub struct CanShieldBuilder {
+    pclk1: Hertz,
+    hclk: Hertz,
+    sync_timeout: Option<MillisDurationU32>,
+    can1: ChannelConfig,
+    can2: ChannelConfig,
+}
+
+impl CanShieldBuilder {
+    pub fn new(clocks: &Clocks) -> Self {
+        Self {
+            pclk1: clocks.pclk1(),
+            hclk: clocks.hclk(),
+            sync_timeout: None,
+            can1: ChannelConfig::new(Fifo::Fifo0).accept_all(),
+            can2: ChannelConfig::new(Fifo::Fifo1).accept_all(),
+        }
+    }
+
+    /// Replaces the configuration of CAN1.
+    pub fn can1(mut self, config: ChannelConfig) -> Self {
+        self.can1 = config;
+        self
+    }
+
+    /// Replaces the configuration of CAN2.
+    pub fn can2(mut self, config: ChannelConfig) -> Self {
+        self.can2 = config;
+        self
+    }
+
+    /// Gives up enabling a controller if its bus has not been idle for 11 bits within `timeout`.
+    ///
+    /// The timeout is measured with the DWT cycle counter, which must be enabled before
+    /// building. Without a timeout, building blocks until both buses are in sync.
+    pub fn sync_timeout(mut self, timeout: MillisDurationU32) -> Self {
+        self.sync_timeout = Some(timeout);
+        self
+    }
+
+    /// Checks both channel configurations without touching the hardware.
+    pub fn validate(&self) -> Result<(), CanShieldError> {
+        self.prepare().map(|_| ())
+    }
+
+    /// Returns the bit timings of both channels and the filter bank layout.
+    fn prepare(&self) -> Result<(BitTiming, BitTiming, FilterPlan), CanShieldError> {
+        let timing1 = self.can1.validate(self.pclk1)?;
+        let timing2 = self.can2.validate(self.pclk1)?;
+        let plan = filters::plan(&self.can1.filters, &self.can2.filters)?;
+        Ok((timing1, timing2, plan))
+    }
+
+    /// Validates the configuration and enables both controllers on a rev. 1 shield.
+    ///
+    /// Blocks until each bus has seen 11 recessive bits, or until the sync timeout expires. The
+    /// peripherals are consumed even if enabling fails.
+    pub fn build_rev1(
+        self,
+        pa12: PA12,
+        pa11: PA11,
+        pb13: PB13,
+        pb5: PB5,
+        can1: CAN1,
+        can2: CAN2,
+    ) -> Result<CanShield, CanShieldError> {
+        let (timing1, timing2, plan) = self.prepare()?;
+
+        let timeout_cycles = match self.sync_timeout {
+            Some(_) if !DWT::cycle_counter_enabled() => {
+                return Err(CanShieldError::CycleCounterDisabled)
+            }
+            Some(timeout) => {
+                let cycles = u64::from(self.hclk.raw()) * u64::from(timeout.to_millis()) / 1000;
+                Some(cycles.min(u64::from(u32::MAX)) as u32)
+            }
+            None => None,
+        };
+
+        let mut can1: Can1 = {
+            let rx = pa11.into_alternate::<9>();
+            let tx = pa12.into_alternate::<9>();
+
+            let can = can1.can((tx, rx));
+
+            info!("CAN1, waiting for 11 recessive bits...");
+            let builder = self.can1.configure(bxcan::Can::builder(can), timing1);
+            enable(builder, Channel::Can1, timeout_cycles)
+                .inspect_err(|_| warn!("CAN1, no sync with the bus"))?
+        };
+
+        can1.enable_interrupts(self.can1.interrupts());
+        can1.enable_error_interrupts();
+        can1.set_automatic_bus_off(self.can1.recovery == RecoveryPolicy::Automatic);
+
+        let mut can2: Can2 = {
+            let rx = pb5.into_alternate::<9>();
+            let tx = pb13.into_alternate::<9>();
+
+            let can = can2.can((tx, rx));
+
+            info!("CAN2, waiting for 11 recessive bits...");
+            let builder = self.can2.configure(bxcan::Can::builder(can), timing2);
+            enable(builder, Channel::Can2, timeout_cycles)
+                .inspect_err(|_| warn!("CAN2, no sync with the bus"))?
+        };
+
+        can2.enable_interrupts(self.can2.interrupts());
+        can2.enable_error_interrupts();
+        can2.set_automatic_bus_off(self.can2.recovery == RecoveryPolicy::Automatic);
+
+        // CAN2 has no filters of its own, its banks are configured through CAN1.
+        {
+            let mut filters = can1.modify_filters();
+            filters.set_split(plan.split()).clear();
+
+            for (index, bank) in plan.can1().iter().enumerate() {
+                filters.enable_bank(index as u8, self.can1.fifo, *bank);
+            }
+
+            let mut slave_filters = filters.slave_filters();
+            slave_filters.clear();
+
+            for (index, bank) in plan.can2().iter().enumerate() {
+                slave_filters.enable_bank(plan.split() + index as u8, self.can2.fifo, *bank);
+            }
+        }
+
+        Ok(CanShield {
+            can1,
+            can2,
+            tx: [TxQueue::new(), TxQueue::new()],
+            recovery: [self.can1.recovery, self.can2.recovery],
+        })
+    }
+}
+
+/// Leaves initialization mode and waits for the controller to sync with the bus.
+///
+/// `timeout_cycles` is measured in DWT cycles, `None` waits forever.
+fn enable<I: bxcan::Instance>(
+    builder: bxcan::CanBuilder<I>,
+    channel: Channel,
+    timeout_cycles: Option<u32>,
+) -> Result<bxcan::Can<I>, CanShieldError> {
+    let mut can = builder.leave_disabled();
+    let start = DWT::cycle_count();
+
+    loop {
+        match can.enable_non_blocking() {
+            Ok(()) => return Ok(can),
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(never)) => match never {},
+        }
+
+        if let Some(timeout) = timeout_cycles {
+            if DWT::cycle_count().wrapping_sub(start) >= timeout {
+                return Err(CanShieldError::BusSyncTimeout(channel));
+            }
+        }
+    }
+}
+
+/// Both channels of a shield together with their transmit queues.
+///
+/// Implemented by [`CanShield`] and, with the `mock` feature, by the in-memory
+/// [`MockShield`](mock::MockShield), so code on top of the shield can be tested on the host.
+pub trait Shield {
+    /// Returns the controller behind `channel`.
+    fn channel(&mut self, channel: Channel) -> &mut dyn CanChannel;
+
+    /// Returns the controller behind `channel` and its transmit queue.
+    fn split_tx(&mut self, channel: Channel) -> (&mut dyn CanChannel, &mut TxQueue);
+
+    /// Returns the transmit queue of `channel`.
+    fn tx_queue(&self, channel: Channel) -> &TxQueue;
+
+    /// Returns the error counters and bus state of `channel`.
+    fn error_status(&self, channel: Channel) -> ErrorStatus;
+
+    /// Queues `frame` for transmission on `channel`, ordered by its priority.
+    ///
+    /// Frames sent directly through [`Shield::channel`] bypass the queue. Returns
+    /// [`TxError::QueueFull`] if the frame has to be sent again later.
+    fn transmit(&mut self, channel: Channel, frame: &Frame) -> Result<(), TxError> {
+        let (can, queue) = self.split_tx(channel);
+        queue.transmit(can, frame)
+    }
+
+    /// Refills the transmit mailboxes of `channel`. Call this from its TX interrupt
+    /// (`CAN1_TX` or `CAN2_TX`).
+    fn on_tx_interrupt(&mut self, channel: Channel) -> usize {
+        let (can, queue) = self.split_tx(channel);
+        queue.on_interrupt(can)
+    }
+
+    /// Number of frames of `channel` waiting for a free mailbox.
+    fn tx_pending(&self, channel: Channel) -> usize {
+        self.tx_queue(channel).len()
+    }
+
+    /// Returns the transmit counters of `channel`.
+    fn tx_stats(&self, channel: Channel) -> TxStats {
+        self.tx_queue(channel).stats()
+    }
+}
+
+pub struct CanShield {
+    pub can1: Can1,
+    pub can2: Can2,
+    tx: [TxQueue; 2],
+    recovery: [RecoveryPolicy; 2],
+}
+
+impl CanShield {
+    /// Returns a builder with the default rev. 1 configuration.
+    pub fn builder(clocks: &Clocks) -> CanShieldBuilder {
+        CanShieldBuilder::new(clocks)
+    }
+
+    /// Creates a health monitor for `channel` using its configured recovery policy.
+    pub fn health_monitor<const HZ: u32>(&self, channel: Channel) -> HealthMonitor<HZ> {
+        HealthMonitor::new(channel, self.recovery[channel as usize])
+    }
+
+    /// Enables both controllers at 1 Mbit/s, accepting all frames.
+    ///
+    /// CAN1 receives into FIFO 0 and CAN2 into FIFO 1.
+    pub fn new_rev1(
+        pa12: PA12,
+        pa11: PA11,
+        pb13: PB13,
+        pb5: PB5,
+        can1: CAN1,
+        can2: CAN2,
+        clocks: &Clocks,
+    ) -> Result<Self, CanShieldError> {
+        Self::builder(clocks).build_rev1(pa12, pa11, pb13, pb5, can1, can2)
+    }
+}
+
+impl Shield for CanShield {
+    fn channel(&mut self, channel: Channel) -> &mut dyn CanChannel {
+        match channel {
+            Channel::Can1 => &mut self.can1,
+            Channel::Can2 => &mut self.can2,
+        }
+    }
+
+    fn split_tx(&mut self, channel: Channel) -> (&mut dyn CanChannel, &mut TxQueue) {
+        let queue = &mut self.tx[channel as usize];
+        match channel {
+            Channel::Can1 => (&mut self.can1, queue),
+            Channel::Can2 => (&mut self.can2, queue),
+        }
+    }
+
+    fn tx_queue(&self, channel: Channel) -> &TxQueue {
+        &self.tx[channel as usize]
+    }
+
+    fn error_status(&self, channel: Channel) -> ErrorStatus {
+        match channel {
+            Channel::Can1 => self.can1.error_status(),
+            Channel::Can2 => self.can2.error_status(),
+        }
+    }
+}
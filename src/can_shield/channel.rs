@@ -0,0 +1,195 @@
+//! A common interface to both controllers of the shield.
+
+use core::convert::Infallible;
+
+use bxcan::Frame;
+use defmt::Format;
+use stm32f4xx_hal::pac::{can1::RegisterBlock, CAN1, CAN2};
+
+use super::{health::ErrorStatus, Can1, Can2};
+
+/// One of the two CAN controllers on the shield.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Channel {
+    Can1,
+    Can2,
+}
+
+impl Channel {
+    /// Both channels, CAN1 first.
+    pub const ALL: [Channel; 2] = [Channel::Can1, Channel::Can2];
+
+    /// The channel on the other transceiver.
+    pub fn other(self) -> Self {
+        match self {
+            Channel::Can1 => Channel::Can2,
+            Channel::Can2 => Channel::Can1,
+        }
+    }
+
+    /// Register block of the controller.
+    ///
+    /// Only use it for reads without side effects, or for writes while holding the `&mut` of
+    /// the controller.
+    pub(crate) fn registers(self) -> &'static RegisterBlock {
+        // NOTE(unsafe) CAN1 and CAN2 share the register layout. Writes only happen through
+        // `CanChannel` methods taking `&mut self`, which own the controller.
+        unsafe {
+            match self {
+                Channel::Can1 => &*CAN1::ptr(),
+                Channel::Can2 => &*CAN2::ptr(),
+            }
+        }
+    }
+}
+
+/// A frame was lost because a receive FIFO overflowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FifoOverrun;
+
+/// A controller did not enter initialization mode during a restart, because its bus never
+/// became idle, e.g. while it is stuck dominant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RestartTimeout;
+
+/// Polls of `CAN_MSR` a restart waits for the controller to enter initialization mode.
+///
+/// Each poll is at least four HCLK cycles on APB1, so this is more than 4 ms at 180 MHz, longer
+/// than the longest frame at 125 kbit/s.
+const RESTART_POLLS: u32 = 200_000;
+
+/// Snapshot of a controller's state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ChannelStatus {
+    /// No frame is waiting in the transmit mailboxes.
+    pub transmitter_idle: bool,
+    /// Frames waiting in the two receive FIFOs.
+    pub rx_pending: u8,
+    /// The controller is bus-off and does not take part in bus traffic.
+    pub bus_off: bool,
+}
+
+/// Operations shared by both controllers, so tasks can route frames by [`Channel`] instead of
+/// naming the concrete `bxcan::Can` types.
+///
+/// The `mock` feature adds an in-memory implementation for host tests.
+pub trait CanChannel {
+    /// The controller this is.
+    fn channel(&self) -> Channel;
+
+    /// Puts a frame in a free transmit mailbox.
+    ///
+    /// If all mailboxes are full and `frame` has a higher priority than one of the pending
+    /// frames, that frame is taken out of its mailbox and returned so it can be sent again later.
+    fn transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Infallible>;
+
+    /// Returns a received frame from either receive FIFO.
+    ///
+    /// Returns `Err(Other)` when a frame was lost due to a FIFO overrun.
+    fn receive(&mut self) -> nb::Result<Frame, FifoOverrun>;
+
+    /// Returns the current state of the controller.
+    fn status(&self) -> ChannelStatus;
+
+    /// Returns `WouldBlock` until every pending frame has left the transmit mailboxes.
+    fn flush(&mut self) -> nb::Result<(), Infallible>;
+
+    /// Acknowledges the transmit mailbox empty interrupt.
+    fn clear_tx_interrupt(&mut self);
+
+    /// Returns the error counters and the fault confinement state.
+    fn error_status(&self) -> ErrorStatus;
+
+    /// Enables the status change interrupts for the error warning, error passive and bus-off
+    /// states (the SCE interrupt).
+    fn enable_error_interrupts(&mut self);
+
+    /// Acknowledges the SCE error interrupt.
+    fn clear_error_interrupt(&mut self);
+
+    /// Enables or disables automatic recovery from bus-off.
+    fn set_automatic_bus_off(&mut self, enabled: bool);
+
+    /// Takes a bus-off controller through initialization mode, so it rejoins the bus after
+    /// 128 times 11 recessive bits.
+    ///
+    /// The controller only enters initialization mode once the bus is idle. If that does not
+    /// happen within a few milliseconds the request is withdrawn and `RestartTimeout` returned,
+    /// so a stuck bus cannot block the caller.
+    fn restart(&mut self) -> Result<(), RestartTimeout>;
+}
+
+macro_rules! impl_can_channel {
+    ($ty:ty, $channel:expr) => {
+        impl CanChannel for $ty {
+            fn channel(&self) -> Channel {
+                $channel
+            }
+
+            fn transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Infallible> {
+                bxcan::Can::transmit(self, frame).map(|status| status.dequeued_frame().cloned())
+            }
+
+            fn receive(&mut self) -> nb::Result<Frame, FifoOverrun> {
+                bxcan::Can::receive(self).map_err(|error| error.map(|_| FifoOverrun))
+            }
+
+            fn status(&self) -> ChannelStatus {
+                let can = $channel.registers();
+                ChannelStatus {
+                    transmitter_idle: self.is_transmitter_idle(),
+                    rx_pending: can.rfr[0].read().fmp().bits() + can.rfr[1].read().fmp().bits(),
+                    bus_off: can.esr.read().boff().bit_is_set(),
+                }
+            }
+
+            fn flush(&mut self) -> nb::Result<(), Infallible> {
+                if self.is_transmitter_idle() {
+                    Ok(())
+                } else {
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+
+            fn clear_tx_interrupt(&mut self) {
+                bxcan::Can::clear_tx_interrupt(self)
+            }
+
+            fn error_status(&self) -> ErrorStatus {
+                ErrorStatus::from_esr($channel.registers().esr.read().bits())
+            }
+
+            fn enable_error_interrupts(&mut self) {
+                self.enable_interrupt(bxcan::Interrupt::Error);
+                $channel
+                    .registers()
+                    .ier
+                    .modify(|_, w| w.ewgie().set_bit().epvie().set_bit().bofie().set_bit());
+            }
+
+            fn clear_error_interrupt(&mut self) {
+                // The other bits of MSR are read-only or cleared by writing 1.
+                $channel.registers().msr.write(|w| w.erri().set_bit());
+            }
+
+            fn set_automatic_bus_off(&mut self, enabled: bool) {
+                $channel.registers().mcr.modify(|_, w| w.abom().bit(enabled));
+            }
+
+            fn restart(&mut self) -> Result<(), RestartTimeout> {
+                let can = $channel.registers();
+                can.mcr.modify(|_, w| w.inrq().set_bit());
+                let entered = (0..RESTART_POLLS).any(|_| can.msr.read().inak().bit_is_set());
+                can.mcr.modify(|_, w| w.inrq().clear_bit());
+                if entered {
+                    Ok(())
+                } else {
+                    Err(RestartTimeout)
+                }
+            }
+        }
+    };
+}
+
+impl_can_channel!(Can1, Channel::Can1);
+impl_can_channel!(Can2, Channel::Can2);
@@ -0,0 +1,247 @@
+//! Declarative acceptance filters for the 28 filter banks shared by CAN1 and CAN2.
+//!
+//! Each channel lists the identifiers and ID/mask ranges it wants in a [`FilterSet`]. [`plan`]
+//! packs both sets into as few banks as possible and picks the split between CAN1 (master) and
+//! CAN2 (slave) banks:
+//!
+//! | Rule                  | Bank mode     | Rules per bank |
+//! |-----------------------|---------------|----------------|
+//! | exact standard ID     | `ListEntry16` | 4              |
+//! | exact extended ID     | `ListEntry32` | 2              |
+//! | standard ID/mask      | `Mask16`      | 2              |
+//! | extended ID/mask      | `Mask32`      | 1              |
+//!
+//! Leftover standard IDs are moved into free `Mask16` or `ListEntry32` slots when that saves a
+//! bank. All rules accept data frames only. Nothing in here touches the hardware, so it can be
+//! tested on the host.
+
+use bxcan::{
+    filter::{BankConfig, ListEntry16, ListEntry32, Mask16, Mask32},
+    ExtendedId, Frame, Id, StandardId,
+};
+use defmt::{warn, Format};
+use heapless::Vec;
+
+/// Number of filter banks shared by CAN1 and CAN2.
+pub const FILTER_BANKS: usize = 28;
+
+/// Maximum number of rules in a single [`FilterSet`].
+pub const MAX_RULES: usize = 64;
+
+/// A single acceptance rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterRule {
+    /// Accept every frame. Makes all other rules of the set redundant.
+    Any,
+    /// Accept a single standard ID.
+    Standard(StandardId),
+    /// Accept a single extended ID.
+    Extended(ExtendedId),
+    /// Accept standard IDs where `incoming & mask == id & mask`.
+    StandardMask { id: StandardId, mask: StandardId },
+    /// Accept extended IDs where `incoming & mask == id & mask`.
+    ExtendedMask { id: ExtendedId, mask: ExtendedId },
+}
+
+/// Reasons the filter sets do not fit into the filter banks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FilterError {
+    /// More than [`MAX_RULES`] rules were added to one set.
+    TooManyRules,
+    /// The packed sets need more than [`FILTER_BANKS`] banks.
+    TooManyBanks { required: usize },
+}
+
+/// The acceptance rules of one channel.
+#[derive(Debug, Clone, Default)]
+pub struct FilterSet {
+    rules: Vec<FilterRule, MAX_RULES>,
+    overflow: bool,
+}
+
+impl FilterSet {
+    /// Creates an empty set, which rejects every frame.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a set accepting every frame.
+    pub fn accept_all() -> Self {
+        Self::new().with(FilterRule::Any)
+    }
+
+    /// Adds a rule. Duplicates are ignored.
+    pub fn push(&mut self, rule: FilterRule) -> &mut Self {
+        if !self.rules.contains(&rule) && self.rules.push(rule).is_err() {
+            self.overflow = true;
+        }
+        self
+    }
+
+    /// Adds a rule, builder style.
+    pub fn with(mut self, rule: FilterRule) -> Self {
+        self.push(rule);
+        self
+    }
+
+    /// The rules added so far.
+    pub fn rules(&self) -> &[FilterRule] {
+        &self.rules
+    }
+
+    /// Returns `true` if the filter banks of the set let `frame` through.
+    pub fn accepts(&self, frame: &Frame) -> bool {
+        // Only the accept-all bank lets remote frames through, the others match data frames
+        if frame.is_remote_frame() {
+            return self.rules.contains(&FilterRule::Any);
+        }
+
+        self.rules.iter().any(|rule| match (*rule, frame.id()) {
+            (FilterRule::Any, _) => true,
+            (FilterRule::Standard(id), Id::Standard(incoming)) => id == incoming,
+            (FilterRule::Extended(id), Id::Extended(incoming)) => id == incoming,
+            (FilterRule::StandardMask { id, mask }, Id::Standard(incoming)) => {
+                (incoming.as_raw() ^ id.as_raw()) & mask.as_raw() == 0
+            }
+            (FilterRule::ExtendedMask { id, mask }, Id::Extended(incoming)) => {
+                (incoming.as_raw() ^ id.as_raw()) & mask.as_raw() == 0
+            }
+            _ => false,
+        })
+    }
+
+    /// Packs the set into filter banks, appending as many as fit to `banks`.
+    ///
+    /// Returns the number of banks the set needs.
+    fn pack(&self, banks: &mut Vec<BankConfig, FILTER_BANKS>) -> Result<usize, FilterError> {
+        if self.overflow {
+            return Err(FilterError::TooManyRules);
+        }
+
+        let mut packer = Packer { banks, required: 0 };
+
+        if self.rules.contains(&FilterRule::Any) {
+            packer.push(Mask32::accept_all().into());
+            return Ok(packer.required);
+        }
+
+        let mut standard: Vec<StandardId, MAX_RULES> = Vec::new();
+        let mut extended: Vec<ExtendedId, MAX_RULES> = Vec::new();
+        let mut standard_masks: Vec<Mask16, MAX_RULES> = Vec::new();
+
+        // The capacities match `self.rules`, so none of these pushes can fail.
+        for rule in &self.rules {
+            match *rule {
+                FilterRule::Any => {}
+                FilterRule::Standard(id) => {
+                    standard.push(id).ok();
+                }
+                FilterRule::Extended(id) => {
+                    extended.push(id).ok();
+                }
+                FilterRule::StandardMask { id, mask } => {
+                    let mut filter = Mask16::frames_with_std_id(id, mask);
+                    filter.data_frames_only();
+                    standard_masks.push(filter).ok();
+                }
+                FilterRule::ExtendedMask { id, mask } => {
+                    let mut filter = Mask32::frames_with_ext_id(id, mask);
+                    filter.data_frames_only();
+                    packer.push(filter.into());
+                }
+            }
+        }
+
+        // Standard IDs that would end up alone in a half-empty `ListEntry16` bank are cheaper in
+        // the free slot of an odd `Mask16` or `ListEntry32` bank.
+        let leftover = standard.len() % 4;
+        let free_slots = standard_masks.len() % 2 + extended.len() % 2;
+        if leftover > 0 && leftover <= free_slots {
+            for _ in 0..leftover {
+                let id = standard.pop().unwrap();
+                if standard_masks.len() % 2 == 1 {
+                    let mut filter = Mask16::frames_with_std_id(id, StandardId::MAX);
+                    filter.data_frames_only();
+                    standard_masks.push(filter).ok();
+                } else {
+                    // `extended` is odd here, the ID goes into the second list entry.
+                    let entry = ListEntry32::data_frames_with_id(id);
+                    packer.push_list32_pair(extended.pop().unwrap(), entry);
+                }
+            }
+        }
+
+        for chunk in standard.chunks(4) {
+            // Unused list entries repeat the first ID.
+            let entry = |i: usize| ListEntry16::data_frames_with_id(chunk[i.min(chunk.len() - 1)]);
+            packer.push([entry(0), entry(1), entry(2), entry(3)].into());
+        }
+        for chunk in extended.chunks(2) {
+            let last = chunk[chunk.len() - 1];
+            packer.push_list32_pair(chunk[0], ListEntry32::data_frames_with_id(last));
+        }
+        for chunk in standard_masks.chunks(2) {
+            packer.push([chunk[0], chunk[chunk.len() - 1]].into());
+        }
+
+        Ok(packer.required)
+    }
+}
+
+/// Filter banks for both channels, as computed by [`plan`].
+#[derive(Debug, Clone)]
+pub struct FilterPlan {
+    can1: Vec<BankConfig, FILTER_BANKS>,
+    can2: Vec<BankConfig, FILTER_BANKS>,
+}
+
+impl FilterPlan {
+    /// Banks for CAN1, starting at bank 0.
+    pub fn can1(&self) -> &[BankConfig] {
+        &self.can1
+    }
+
+    /// Banks for CAN2, starting at bank [`FilterPlan::split`].
+    pub fn can2(&self) -> &[BankConfig] {
+        &self.can2
+    }
+
+    /// Index of the first bank owned by CAN2.
+    pub fn split(&self) -> u8 {
+        self.can1.len() as u8
+    }
+}
+
+/// Packs the filter sets of both channels into the shared filter banks.
+pub fn plan(can1: &FilterSet, can2: &FilterSet) -> Result<FilterPlan, FilterError> {
+    let mut plan = FilterPlan {
+        can1: Vec::new(),
+        can2: Vec::new(),
+    };
+
+    let required = can1.pack(&mut plan.can1)? + can2.pack(&mut plan.can2)?;
+
+    if required > FILTER_BANKS {
+        warn!("Filters need {} banks, only {} available", required, FILTER_BANKS);
+        return Err(FilterError::TooManyBanks { required });
+    }
+
+    Ok(plan)
+}
+
+/// Collects banks and counts how many are needed, even if they don't all fit.
+struct Packer<'a> {
+    banks: &'a mut Vec<BankConfig, FILTER_BANKS>,
+    required: usize,
+}
+
+impl Packer<'_> {
+    fn push(&mut self, bank: BankConfig) {
+        self.required += 1;
+        self.banks.push(bank).ok();
+    }
+
+    fn push_list32_pair(&mut self, first: ExtendedId, second: ListEntry32) {
+        self.push([ListEntry32::data_frames_with_id(first), second].into());
+    }
+}
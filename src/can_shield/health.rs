@@ -0,0 +1,241 @@
+//! Error counter monitoring and bus-off recovery.
+//!
+//! The controller tracks transmit and receive errors in the TEC and REC counters and moves
+//! through the fault confinement states of ISO 11898-1:
+//!
+//! ```text
+//! error active --(TEC or REC ≥ 96)--> error warning --(> 127)--> error passive --(TEC > 255)--> bus-off
+//! ```
+//!
+//! A bus-off controller takes no part in bus traffic until it has seen 128 times 11 recessive
+//! bits. With [`RecoveryPolicy::Automatic`] the controller starts that sequence on its own. With
+//! [`RecoveryPolicy::Manual`] it stays bus-off until [`HealthMonitor::update`] restarts it after
+//! the backoff, which keeps a node with a broken transceiver from disturbing the bus too often.
+//!
+//! The controller raises the SCE interrupt (`CAN1_SCE`/`CAN2_SCE`) when it enters the warning,
+//! passive or bus-off state, but not when it leaves them. Call [`HealthMonitor::update`] from the
+//! interrupt and periodically, so recoveries and the backoff are seen as well.
+
+use defmt::{info, warn, Format};
+use fugit::{MillisDurationU32, TimerInstantU32};
+
+use super::{CanChannel, Channel};
+
+/// Fault confinement state of a controller, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum BusState {
+    /// Both error counters are below 96.
+    ErrorActive,
+    /// An error counter is at or above 96.
+    ErrorWarning,
+    /// An error counter is above 127. The controller only sends passive error flags.
+    ErrorPassive,
+    /// The transmit error counter went above 255. The controller does not take part in bus
+    /// traffic.
+    BusOff,
+}
+
+/// Type of the last error the controller saw on the bus (`LEC` field of `CAN_ESR`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LastError {
+    None,
+    Stuff,
+    Form,
+    Acknowledgement,
+    BitRecessive,
+    BitDominant,
+    Crc,
+    /// Set by software, the hardware never reports it.
+    Software,
+}
+
+impl LastError {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0 => LastError::None,
+            1 => LastError::Stuff,
+            2 => LastError::Form,
+            3 => LastError::Acknowledgement,
+            4 => LastError::BitRecessive,
+            5 => LastError::BitDominant,
+            6 => LastError::Crc,
+            _ => LastError::Software,
+        }
+    }
+}
+
+/// Error counters and state of a controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ErrorStatus {
+    /// Transmit error counter.
+    pub tec: u8,
+    /// Receive error counter.
+    pub rec: u8,
+    pub last_error: LastError,
+    pub state: BusState,
+}
+
+impl ErrorStatus {
+    /// Decodes a `CAN_ESR` register value.
+    pub const fn from_esr(esr: u32) -> Self {
+        let state = if esr & (1 << 2) != 0 {
+            BusState::BusOff
+        } else if esr & (1 << 1) != 0 {
+            BusState::ErrorPassive
+        } else if esr & 1 != 0 {
+            BusState::ErrorWarning
+        } else {
+            BusState::ErrorActive
+        };
+
+        Self {
+            tec: (esr >> 16) as u8,
+            rec: (esr >> 24) as u8,
+            last_error: LastError::from_bits((esr >> 4) as u8),
+            state,
+        }
+    }
+}
+
+/// How a controller leaves the bus-off state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub enum RecoveryPolicy {
+    /// The controller rejoins the bus on its own (automatic bus-off management).
+    #[default]
+    Automatic,
+    /// The controller stays bus-off until it is restarted `backoff` after entering bus-off, and
+    /// again every `backoff` while it keeps failing.
+    ///
+    /// A restart starts the 128 times 11 recessive bits over, so `backoff` has to be longer than
+    /// that (1.4 ms at 1 Mbit/s, 11.3 ms at 125 kbit/s).
+    Manual { backoff: MillisDurationU32 },
+}
+
+/// Counts of state changes seen by a [`HealthMonitor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct HealthStats {
+    /// Entries into the error warning state.
+    pub error_warning: u32,
+    /// Entries into the error passive state.
+    pub error_passive: u32,
+    /// Entries into the bus-off state.
+    pub bus_off: u32,
+    /// Manual restarts out of bus-off.
+    pub restarts: u32,
+    /// Manual restarts the controller did not acknowledge, see [`CanChannel::restart`].
+    pub failed_restarts: u32,
+}
+
+/// Follows the error state of one channel and applies its [`RecoveryPolicy`].
+///
+/// `HZ` is the tick rate of the instants passed to [`HealthMonitor::update`], usually the RTIC
+/// monotonic.
+pub struct HealthMonitor<const HZ: u32> {
+    channel: Channel,
+    policy: RecoveryPolicy,
+    state: BusState,
+    bus_off_since: Option<TimerInstantU32<HZ>>,
+    stats: HealthStats,
+}
+
+impl<const HZ: u32> HealthMonitor<HZ> {
+    /// Creates a monitor for `channel`.
+    ///
+    /// The policy must match the one the controller was configured with, see
+    /// [`ChannelConfig::recovery`](super::ChannelConfig::recovery).
+    pub const fn new(channel: Channel, policy: RecoveryPolicy) -> Self {
+        Self {
+            channel,
+            policy,
+            state: BusState::ErrorActive,
+            bus_off_since: None,
+            stats: HealthStats {
+                error_warning: 0,
+                error_passive: 0,
+                bus_off: 0,
+                restarts: 0,
+                failed_restarts: 0,
+            },
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn policy(&self) -> RecoveryPolicy {
+        self.policy
+    }
+
+    /// The state seen by the last update.
+    pub fn state(&self) -> BusState {
+        self.state
+    }
+
+    pub fn stats(&self) -> HealthStats {
+        self.stats
+    }
+
+    /// Acknowledges the error interrupt, reads the error state and restarts a bus-off controller
+    /// once its backoff has expired.
+    pub fn update(
+        &mut self,
+        can: &mut (impl CanChannel + ?Sized),
+        now: TimerInstantU32<HZ>,
+    ) -> ErrorStatus {
+        debug_assert!(can.channel() == self.channel);
+
+        can.clear_error_interrupt();
+        let status = can.error_status();
+
+        if status.state != self.state {
+            self.transition(status);
+        }
+
+        if status.state == BusState::BusOff {
+            let since = *self.bus_off_since.get_or_insert(now);
+
+            if let RecoveryPolicy::Manual { backoff } = self.policy {
+                let elapsed = now.checked_duration_since(since).map_or(0, |d| d.to_millis());
+                if elapsed >= backoff.to_millis() {
+                    info!("{}, restarting after bus-off", self.channel);
+                    match can.restart() {
+                        Ok(()) => self.stats.restarts += 1,
+                        Err(_) => {
+                            warn!("{}, restart timed out, bus not idle", self.channel);
+                            self.stats.failed_restarts += 1;
+                        }
+                    }
+                    self.bus_off_since = Some(now);
+                }
+            }
+        } else {
+            self.bus_off_since = None;
+        }
+
+        status
+    }
+
+    fn transition(&mut self, status: ErrorStatus) {
+        match status.state {
+            BusState::ErrorActive => {}
+            BusState::ErrorWarning => self.stats.error_warning += 1,
+            BusState::ErrorPassive => self.stats.error_passive += 1,
+            BusState::BusOff => self.stats.bus_off += 1,
+        }
+
+        if status.state > self.state {
+            warn!(
+                "{}, {} -> {} (TEC {}, REC {}, last error {})",
+                self.channel, self.state, status.state, status.tec, status.rec, status.last_error
+            );
+        } else {
+            info!(
+                "{}, {} -> {} (TEC {}, REC {})",
+                self.channel, self.state, status.state, status.tec, status.rec
+            );
+        }
+
+        self.state = status.state;
+    }
+}
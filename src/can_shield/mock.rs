@@ -0,0 +1,515 @@
+//! In-memory CAN buses for host tests.
+//!
+//! Only available with the `mock` feature, which needs std. A [`MockBus`] connects any number of
+//! [`MockChannel`]s. Frames wait in the three transmit mailboxes of their channel until
+//! [`MockBus::step`] runs one arbitration round: the highest priority frame on the bus wins and
+//! is delivered to every other channel whose [`FilterSet`] accepts it. Each channel has a receive
+//! FIFO of three frames which overruns like the hardware one, and error counters that follow the
+//! fault confinement rules closely enough to test health monitoring and failover.
+//!
+//! ```ignore
+//! let bus1 = MockBus::new();
+//! let bus2 = MockBus::new();
+//! let mut shield = MockShield::new(&bus1, &bus2);
+//! let mut peer = bus1.attach(Channel::Can1);
+//!
+//! shield.transmit(Channel::Can1, &frame)?;
+//! bus1.run();
+//! assert_eq!(peer.receive(), Ok(frame));
+//! ```
+//!
+//! Not modelled: bit timing, remote frame replies, silent and loopback mode, and disabled
+//! automatic retransmission.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+use bxcan::Frame;
+use core::convert::Infallible;
+
+use super::{
+    filters::FilterSet,
+    health::{BusState, ErrorStatus, LastError},
+    tx::TxQueue,
+    CanChannel, Channel, ChannelStatus, FifoOverrun, RestartTimeout, Shield,
+};
+
+/// Depth of the receive FIFO of a [`MockChannel`], as on the bxCAN.
+pub const FIFO_DEPTH: usize = 3;
+
+/// Outcome of one arbitration round on a [`MockBus`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Round {
+    /// No channel had a frame to send.
+    Idle,
+    /// The frame was sent and delivered to every channel accepting it.
+    Delivered(Frame),
+    /// Sending the frame failed. It stays in its mailbox and is retried in the next round.
+    Error(Frame),
+}
+
+struct Node {
+    channel: Channel,
+    mailboxes: [Option<Frame>; 3],
+    fifo: VecDeque<Frame>,
+    overrun: bool,
+    filters: FilterSet,
+    tec: u16,
+    rec: u16,
+    last_error: LastError,
+    bus_off: bool,
+    automatic_bus_off: bool,
+    recovering: bool,
+    tx_interrupt: bool,
+    error_interrupts: bool,
+    error_interrupt: bool,
+    /// The bus is stuck dominant, see [`MockBus::set_stuck`].
+    stuck: bool,
+}
+
+impl Node {
+    fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            mailboxes: [None, None, None],
+            fifo: VecDeque::with_capacity(FIFO_DEPTH),
+            overrun: false,
+            filters: FilterSet::accept_all(),
+            tec: 0,
+            rec: 0,
+            last_error: LastError::None,
+            bus_off: false,
+            automatic_bus_off: true,
+            recovering: false,
+            tx_interrupt: false,
+            error_interrupts: false,
+            error_interrupt: false,
+            stuck: false,
+        }
+    }
+
+    fn esr(&self) -> u32 {
+        let warning = self.tec >= 96 || self.rec >= 96;
+        let passive = self.tec > 127 || self.rec > 127;
+        let last_error = match self.last_error {
+            LastError::None => 0,
+            LastError::Stuff => 1,
+            LastError::Form => 2,
+            LastError::Acknowledgement => 3,
+            LastError::BitRecessive => 4,
+            LastError::BitDominant => 5,
+            LastError::Crc => 6,
+            LastError::Software => 7,
+        };
+
+        u32::from(self.rec.min(255)) << 24
+            | u32::from(self.tec.min(255)) << 16
+            | last_error << 4
+            | u32::from(self.bus_off) << 2
+            | u32::from(passive) << 1
+            | u32::from(warning)
+    }
+
+    fn state(&self) -> BusState {
+        ErrorStatus::from_esr(self.esr()).state
+    }
+
+    /// Sets the error counters, entering bus-off above 255 and raising the error interrupt when
+    /// the state gets worse.
+    fn set_counters(&mut self, tec: u16, rec: u16) {
+        let before = self.state();
+        self.tec = tec;
+        self.rec = rec;
+        if tec > 255 {
+            self.bus_off = true;
+            self.recovering = false;
+        }
+        if self.error_interrupts && self.state() > before {
+            self.error_interrupt = true;
+        }
+    }
+
+    fn recover(&mut self) {
+        self.bus_off = false;
+        self.recovering = false;
+        self.tec = 0;
+        self.rec = 0;
+    }
+
+    fn deliver(&mut self, frame: &Frame) {
+        self.set_counters(self.tec, self.rec.saturating_sub(1));
+
+        if !self.filters.accepts(frame) {
+            return;
+        }
+        if self.fifo.len() == FIFO_DEPTH {
+            self.overrun = true;
+        } else {
+            self.fifo.push_back(frame.clone());
+        }
+    }
+}
+
+struct BusInner {
+    nodes: Vec<Rc<RefCell<Node>>>,
+    errors: u32,
+    stuck: bool,
+    log: Vec<Frame>,
+}
+
+/// A simulated CAN bus. Clones refer to the same bus.
+#[derive(Clone)]
+pub struct MockBus {
+    inner: Rc<RefCell<BusInner>>,
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(BusInner {
+                nodes: Vec::new(),
+                errors: 0,
+                stuck: false,
+                log: Vec::new(),
+            })),
+        }
+    }
+
+    /// Connects a new controller to the bus. It accepts every frame until
+    /// [`MockChannel::set_filters`] is used.
+    pub fn attach(&self, channel: Channel) -> MockChannel {
+        let mut inner = self.inner.borrow_mut();
+        let node = Rc::new(RefCell::new(Node::new(channel)));
+        node.borrow_mut().stuck = inner.stuck;
+        inner.nodes.push(node.clone());
+        MockChannel { node }
+    }
+
+    /// Lets the next `count` rounds fail with a form error.
+    ///
+    /// The sender's transmit error counter goes up by 8 and the receive error counters of the
+    /// others by 1, and the frame is retried in the next round.
+    pub fn inject_errors(&self, count: u32) {
+        self.inner.borrow_mut().errors += count;
+    }
+
+    /// Holds the bus dominant, e.g. because of a shorted transceiver.
+    ///
+    /// While stuck no round completes, bus-off controllers do not recover and
+    /// [`CanChannel::restart`] times out.
+    pub fn set_stuck(&self, stuck: bool) {
+        let mut inner = self.inner.borrow_mut();
+        inner.stuck = stuck;
+        for node in &inner.nodes {
+            node.borrow_mut().stuck = stuck;
+        }
+    }
+
+    /// Runs one arbitration round.
+    ///
+    /// Bus-off controllers that recover automatically, or were restarted, rejoin the bus first.
+    /// A frame without another controller on the bus to acknowledge it fails with an
+    /// acknowledgement error.
+    pub fn step(&self) -> Round {
+        let mut inner = self.inner.borrow_mut();
+
+        if inner.stuck {
+            return Round::Idle;
+        }
+
+        for node in &inner.nodes {
+            let mut node = node.borrow_mut();
+            if node.bus_off && (node.automatic_bus_off || node.recovering) {
+                node.recover();
+            }
+        }
+
+        // Ties between controllers go to the one attached first.
+        let mut winner: Option<(usize, usize, Frame)> = None;
+        for (index, node) in inner.nodes.iter().enumerate() {
+            let node = node.borrow();
+            if node.bus_off {
+                continue;
+            }
+            for (mailbox, frame) in node.mailboxes.iter().enumerate() {
+                let Some(frame) = frame else { continue };
+                if winner
+                    .as_ref()
+                    .is_none_or(|(_, _, best)| frame.priority() > best.priority())
+                {
+                    winner = Some((index, mailbox, frame.clone()));
+                }
+            }
+        }
+
+        let Some((sender, mailbox, frame)) = winner else {
+            return Round::Idle;
+        };
+
+        let listeners = inner
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(index, node)| *index != sender && !node.borrow().bus_off)
+            .count();
+
+        if listeners == 0 {
+            let mut guard = inner.nodes[sender].borrow_mut();
+            let node = &mut *guard;
+            // An error passive transmitter does not count acknowledgement errors any further.
+            let tec = if node.tec > 127 {
+                node.tec
+            } else {
+                node.tec + 8
+            };
+            node.last_error = LastError::Acknowledgement;
+            node.set_counters(tec, node.rec);
+            return Round::Error(frame);
+        }
+
+        if inner.errors > 0 {
+            inner.errors -= 1;
+            for (index, node) in inner.nodes.iter().enumerate() {
+                let mut guard = node.borrow_mut();
+                let node = &mut *guard;
+                if node.bus_off {
+                    continue;
+                }
+                node.last_error = LastError::Form;
+                if index == sender {
+                    node.set_counters(node.tec + 8, node.rec);
+                } else {
+                    node.set_counters(node.tec, node.rec + 1);
+                }
+            }
+            return Round::Error(frame);
+        }
+
+        for (index, node) in inner.nodes.iter().enumerate() {
+            let mut guard = node.borrow_mut();
+            let node = &mut *guard;
+            if index == sender {
+                node.mailboxes[mailbox] = None;
+                node.tx_interrupt = true;
+                node.set_counters(node.tec.saturating_sub(1), node.rec);
+            } else if !node.bus_off {
+                node.deliver(&frame);
+            }
+        }
+
+        inner.log.push(frame.clone());
+        Round::Delivered(frame)
+    }
+
+    /// Runs rounds until no frame is left or a round fails. Returns the number of frames sent.
+    pub fn run(&self) -> usize {
+        let mut sent = 0;
+        while let Round::Delivered(_) = self.step() {
+            sent += 1;
+        }
+        sent
+    }
+
+    /// Takes the frames sent on the bus so far, in the order they won arbitration.
+    pub fn take_log(&self) -> Vec<Frame> {
+        core::mem::take(&mut self.inner.borrow_mut().log)
+    }
+}
+
+/// A simulated controller attached to a [`MockBus`].
+pub struct MockChannel {
+    node: Rc<RefCell<Node>>,
+}
+
+impl MockChannel {
+    /// Replaces the acceptance filters.
+    pub fn set_filters(&mut self, filters: FilterSet) {
+        self.node.borrow_mut().filters = filters;
+    }
+
+    /// Sets the error counters, e.g. to put the controller into error passive. A transmit error
+    /// counter above 255 makes it bus-off.
+    pub fn set_error_counters(&mut self, tec: u16, rec: u16) {
+        self.node.borrow_mut().set_counters(tec, rec);
+    }
+
+    /// Number of frames waiting in the transmit mailboxes.
+    pub fn pending(&self) -> usize {
+        self.node.borrow().mailboxes.iter().flatten().count()
+    }
+
+    /// The TX interrupt is pending and not cleared yet.
+    pub fn tx_interrupt_pending(&self) -> bool {
+        self.node.borrow().tx_interrupt
+    }
+
+    /// The SCE error interrupt is pending and not cleared yet.
+    pub fn error_interrupt_pending(&self) -> bool {
+        self.node.borrow().error_interrupt
+    }
+}
+
+impl CanChannel for MockChannel {
+    fn channel(&self) -> Channel {
+        self.node.borrow().channel
+    }
+
+    fn transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Infallible> {
+        let mut node = self.node.borrow_mut();
+
+        // Same rule as the hardware: only frames with a higher priority than all pending ones,
+        // so frames with the same ID keep their order.
+        let pending = node.mailboxes.iter().flatten();
+        if pending
+            .map(Frame::priority)
+            .any(|priority| priority >= frame.priority())
+        {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        if let Some(free) = node.mailboxes.iter_mut().find(|mailbox| mailbox.is_none()) {
+            *free = Some(frame.clone());
+            return Ok(None);
+        }
+
+        let lowest = node
+            .mailboxes
+            .iter_mut()
+            .min_by_key(|mailbox| mailbox.as_ref().map(Frame::priority))
+            .expect("three mailboxes");
+        Ok(lowest.replace(frame.clone()))
+    }
+
+    fn receive(&mut self) -> nb::Result<Frame, FifoOverrun> {
+        let mut node = self.node.borrow_mut();
+
+        if node.overrun {
+            node.overrun = false;
+            return Err(nb::Error::Other(FifoOverrun));
+        }
+        node.fifo.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn status(&self) -> ChannelStatus {
+        let node = self.node.borrow();
+        ChannelStatus {
+            transmitter_idle: node.mailboxes.iter().all(Option::is_none),
+            rx_pending: node.fifo.len() as u8,
+            bus_off: node.bus_off,
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        if self.pending() == 0 {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    fn clear_tx_interrupt(&mut self) {
+        self.node.borrow_mut().tx_interrupt = false;
+    }
+
+    fn error_status(&self) -> ErrorStatus {
+        ErrorStatus::from_esr(self.node.borrow().esr())
+    }
+
+    fn enable_error_interrupts(&mut self) {
+        self.node.borrow_mut().error_interrupts = true;
+    }
+
+    fn clear_error_interrupt(&mut self) {
+        self.node.borrow_mut().error_interrupt = false;
+    }
+
+    fn set_automatic_bus_off(&mut self, enabled: bool) {
+        self.node.borrow_mut().automatic_bus_off = enabled;
+    }
+
+    fn restart(&mut self) -> Result<(), RestartTimeout> {
+        let mut node = self.node.borrow_mut();
+        if node.stuck {
+            return Err(RestartTimeout);
+        }
+        if node.bus_off {
+            node.recovering = true;
+        }
+        Ok(())
+    }
+}
+
+/// A shield whose two channels are attached to mock buses.
+pub struct MockShield {
+    pub can1: MockChannel,
+    pub can2: MockChannel,
+    tx: [TxQueue; 2],
+}
+
+impl MockShield {
+    /// Attaches CAN1 to `bus1` and CAN2 to `bus2`, with error interrupts enabled.
+    pub fn new(bus1: &MockBus, bus2: &MockBus) -> Self {
+        let mut can1 = bus1.attach(Channel::Can1);
+        let mut can2 = bus2.attach(Channel::Can2);
+        can1.enable_error_interrupts();
+        can2.enable_error_interrupts();
+
+        Self {
+            can1,
+            can2,
+            tx: [TxQueue::new(), TxQueue::new()],
+        }
+    }
+
+    /// Runs the TX interrupt handler of every channel with a pending TX interrupt. Returns the
+    /// number of frames moved into mailboxes.
+    pub fn service_tx_interrupts(&mut self) -> usize {
+        let mut loaded = 0;
+        for channel in Channel::ALL {
+            if self.pending_interrupt(channel) {
+                loaded += self.on_tx_interrupt(channel);
+            }
+        }
+        loaded
+    }
+
+    fn pending_interrupt(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Can1 => self.can1.tx_interrupt_pending(),
+            Channel::Can2 => self.can2.tx_interrupt_pending(),
+        }
+    }
+}
+
+impl Shield for MockShield {
+    fn channel(&mut self, channel: Channel) -> &mut dyn CanChannel {
+        match channel {
+            Channel::Can1 => &mut self.can1,
+            Channel::Can2 => &mut self.can2,
+        }
+    }
+
+    fn split_tx(&mut self, channel: Channel) -> (&mut dyn CanChannel, &mut TxQueue) {
+        let queue = &mut self.tx[channel as usize];
+        match channel {
+            Channel::Can1 => (&mut self.can1, queue),
+            Channel::Can2 => (&mut self.can2, queue),
+        }
+    }
+
+    fn tx_queue(&self, channel: Channel) -> &TxQueue {
+        &self.tx[channel as usize]
+    }
+
+    fn error_status(&self, channel: Channel) -> ErrorStatus {
+        match channel {
+            Channel::Can1 => self.can1.error_status(),
+            Channel::Can2 => self.can2.error_status(),
+        }
+    }
+}
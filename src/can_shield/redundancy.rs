@@ -0,0 +1,302 @@
+//! CAN1 and CAN2 as a redundant pair of buses.
+//!
+//! A [`RedundantBus`] sends on the active channel, which starts out as the primary, and fails
+//! over to the other channel when the active one goes bus-off or has not received anything
+//! within the heartbeat timeout. With [`RedundancyMode::Both`] every frame goes out on both
+//! buses instead.
+//!
+//! Receivers see the same frame on both buses. [`RedundantBus::on_receive`] drops the second
+//! copy by comparing the ID and a sequence byte in the payload, so senders have to count that
+//! byte up for every frame of an ID. The last [`DEDUP_DEPTH`] sequence numbers of each ID are
+//! remembered, so one bus may lag behind the other by that many frames.
+
+use bxcan::{Frame, Id};
+use defmt::{info, warn, Format};
+use fugit::{MillisDurationU32, TimerInstantU32};
+use heapless::{Deque, Vec};
+
+use super::{health::BusState, tx::TxError, Channel, Shield};
+
+/// Number of IDs the duplicate filter remembers. The least recently seen ID is forgotten first.
+pub const DEDUP_LEN: usize = 32;
+
+/// Number of sequence numbers the duplicate filter remembers per ID, i.e. how many frames one bus
+/// may lag behind the other.
+pub const DEDUP_DEPTH: usize = 8;
+
+/// Which buses frames are sent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RedundancyMode {
+    /// Send on the active channel only, failing over when it becomes unhealthy.
+    Failover,
+    /// Send every frame on both channels.
+    Both,
+}
+
+/// Configuration of a [`RedundantBus`].
+#[derive(Debug, Clone, Copy)]
+pub struct RedundancyConfig {
+    primary: Channel,
+    mode: RedundancyMode,
+    heartbeat_timeout: Option<MillisDurationU32>,
+    fail_back: bool,
+    sequence_byte: u8,
+    dedup_window: MillisDurationU32,
+}
+
+impl RedundancyConfig {
+    /// Creates a configuration with `primary` as the preferred channel.
+    ///
+    /// Defaults to [`RedundancyMode::Failover`] on bus-off only, no fail back, the sequence number
+    /// in the first data byte and a duplicate window of 100 ms.
+    pub fn new(primary: Channel) -> Self {
+        Self {
+            primary,
+            mode: RedundancyMode::Failover,
+            heartbeat_timeout: None,
+            fail_back: false,
+            sequence_byte: 0,
+            dedup_window: MillisDurationU32::millis(100),
+        }
+    }
+
+    pub fn mode(mut self, mode: RedundancyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Also fails over when nothing was received on the active channel for `timeout`.
+    ///
+    /// The bus needs periodic traffic for this, e.g. heartbeats of the other nodes.
+    pub fn heartbeat_timeout(mut self, timeout: MillisDurationU32) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Switches back to the primary as soon as it is healthy again.
+    pub fn fail_back(mut self, enabled: bool) -> Self {
+        self.fail_back = enabled;
+        self
+    }
+
+    /// Sets the index of the data byte holding the sequence number.
+    pub fn sequence_byte(mut self, index: u8) -> Self {
+        self.sequence_byte = index;
+        self
+    }
+
+    /// Frames with the same ID and sequence number are duplicates if they arrive within `window`.
+    ///
+    /// Keep it shorter than the time an ID needs to send 256 frames, or new frames are dropped
+    /// once the sequence number wraps.
+    pub fn dedup_window(mut self, window: MillisDurationU32) -> Self {
+        self.dedup_window = window;
+        self
+    }
+}
+
+/// Counters of a [`RedundantBus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct RedundancyStats {
+    /// Switches of the active channel.
+    pub failovers: u32,
+    /// Received frames dropped as duplicates.
+    pub duplicates: u32,
+}
+
+struct Seen<const HZ: u32> {
+    id: Id,
+    /// Recent sequence numbers and when they were first received, oldest first.
+    sequences: Deque<(u8, TimerInstantU32<HZ>), DEDUP_DEPTH>,
+    at: TimerInstantU32<HZ>,
+}
+
+impl<const HZ: u32> Seen<HZ> {
+    fn new(id: Id) -> Self {
+        Self {
+            id,
+            sequences: Deque::new(),
+            at: TimerInstantU32::from_ticks(0),
+        }
+    }
+
+    /// Returns `false` if `sequence` was received within `window`, otherwise remembers it.
+    fn record(&mut self, sequence: u8, now: TimerInstantU32<HZ>, window: u32) -> bool {
+        self.at = now;
+
+        let recent = |at: TimerInstantU32<HZ>| {
+            now.checked_duration_since(at).map_or(0, |d| d.to_millis()) < window
+        };
+        if self
+            .sequences
+            .iter()
+            .any(|&(seen, at)| seen == sequence && recent(at))
+        {
+            return false;
+        }
+
+        if self.sequences.is_full() {
+            self.sequences.pop_front();
+        }
+        // Cannot fail, there is room after the pop.
+        let _ = self.sequences.push_back((sequence, now));
+        true
+    }
+}
+
+/// Failover and duplicate filtering for the two channels of a [`Shield`].
+///
+/// `HZ` is the tick rate of the instants passed in, usually the RTIC monotonic.
+pub struct RedundantBus<const HZ: u32> {
+    config: RedundancyConfig,
+    active: Channel,
+    started: Option<TimerInstantU32<HZ>>,
+    last_heard: [Option<TimerInstantU32<HZ>>; 2],
+    seen: Vec<Seen<HZ>, DEDUP_LEN>,
+    stats: RedundancyStats,
+}
+
+impl<const HZ: u32> RedundantBus<HZ> {
+    pub fn new(config: RedundancyConfig) -> Self {
+        Self {
+            config,
+            active: config.primary,
+            started: None,
+            last_heard: [None; 2],
+            seen: Vec::new(),
+            stats: RedundancyStats::default(),
+        }
+    }
+
+    /// The channel frames are currently sent on.
+    pub fn active(&self) -> Channel {
+        self.active
+    }
+
+    pub fn stats(&self) -> RedundancyStats {
+        self.stats
+    }
+
+    /// Makes `channel` the active channel, e.g. on ground command.
+    pub fn select(&mut self, channel: Channel) {
+        if channel != self.active {
+            info!("Redundant bus, switched to {}", channel);
+            self.active = channel;
+        }
+    }
+
+    /// Reads the bus state of both channels and fails over if needed. Returns the active channel.
+    ///
+    /// Call this periodically, e.g. together with the health monitors.
+    pub fn update(
+        &mut self,
+        shield: &(impl Shield + ?Sized),
+        now: TimerInstantU32<HZ>,
+    ) -> Channel {
+        let states = Channel::ALL.map(|channel| shield.error_status(channel).state);
+        self.evaluate(states, now)
+    }
+
+    /// Fails over based on the given bus states, indexed by [`Channel`]. Returns the active
+    /// channel.
+    pub fn evaluate(&mut self, states: [BusState; 2], now: TimerInstantU32<HZ>) -> Channel {
+        let started = *self.started.get_or_insert(now);
+        let healthy = |channel: Channel| {
+            if states[channel as usize] == BusState::BusOff {
+                return false;
+            }
+            match self.config.heartbeat_timeout {
+                Some(timeout) => {
+                    // Channels that have not received anything yet get a timeout from the start.
+                    let heard = self.last_heard[channel as usize].unwrap_or(started);
+                    let silent = now
+                        .checked_duration_since(heard)
+                        .map_or(0, |d| d.to_millis());
+                    silent < timeout.to_millis()
+                }
+                None => true,
+            }
+        };
+
+        let active = self.active;
+        let primary = self.config.primary;
+
+        let next = if !healthy(active) && healthy(active.other()) {
+            active.other()
+        } else if self.config.fail_back && active != primary && healthy(primary) {
+            primary
+        } else {
+            active
+        };
+
+        if next != active {
+            warn!("Redundant bus, failover from {} to {}", active, next);
+            self.active = next;
+            self.stats.failovers += 1;
+        }
+
+        self.active
+    }
+
+    /// Queues `frame` on the active channel, or on both channels in [`RedundancyMode::Both`].
+    ///
+    /// In [`RedundancyMode::Both`] this only fails if neither channel accepted the frame.
+    pub fn transmit(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        frame: &Frame,
+    ) -> Result<(), TxError> {
+        match self.config.mode {
+            RedundancyMode::Failover => shield.transmit(self.active, frame),
+            RedundancyMode::Both => {
+                let first = shield.transmit(self.active, frame);
+                let second = shield.transmit(self.active.other(), frame);
+                first.or(second)
+            }
+        }
+    }
+
+    /// Records a frame received on `channel`. Returns `false` if it is a duplicate of a frame
+    /// already received and should be dropped.
+    ///
+    /// Frames too short to hold the sequence byte, and remote frames, are never duplicates.
+    pub fn on_receive(
+        &mut self,
+        channel: Channel,
+        frame: &Frame,
+        now: TimerInstantU32<HZ>,
+    ) -> bool {
+        self.last_heard[channel as usize] = Some(now);
+
+        let Some(&sequence) = frame
+            .data()
+            .and_then(|data| data.get(usize::from(self.config.sequence_byte)))
+        else {
+            return true;
+        };
+
+        let id = frame.id();
+        let window = self.config.dedup_window.to_millis();
+
+        let index = match self.seen.iter().position(|seen| seen.id == id) {
+            Some(index) => index,
+            None => {
+                if self.seen.is_full() {
+                    let oldest = (0..self.seen.len())
+                        .min_by_key(|&index| self.seen[index].at)
+                        .expect("DEDUP_LEN is not zero");
+                    self.seen.swap_remove(oldest);
+                }
+                // Cannot fail, there is room after the removal.
+                let _ = self.seen.push(Seen::new(id));
+                self.seen.len() - 1
+            }
+        };
+
+        let new = self.seen[index].record(sequence, now, window);
+        if !new {
+            self.stats.duplicates += 1;
+        }
+        new
+    }
+}
@@ -0,0 +1,171 @@
+//! Interrupt-driven receive buffering.
+//!
+//! Each hardware FIFO holds only three frames. The RX interrupt of a channel drains its FIFO into
+//! a [`RxQueue`] through a [`RxProducer`], and lower priority tasks take frames out through the
+//! matching [`RxConsumer`]. Frames lost because the hardware FIFO or the queue overflowed are
+//! counted per channel instead of being reported as errors, see [`stats`].
+//!
+//! The queues are usually created as `init` locals, which gives them the `'static` lifetime RTIC
+//! resources need:
+//!
+//! ```ignore
+//! #[init(local = [rx1_queue: RxQueue = RxQueue::new()])]
+//! fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
+//!     let (rx1_producer, rx1_consumer) = rx::split(ctx.local.rx1_queue, Channel::Can1);
+//!     // ...
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bxcan::Frame;
+use defmt::{warn, Format};
+use heapless::spsc::{Consumer, Producer, Queue};
+
+use super::{CanChannel, Channel};
+
+/// Size of a receive queue. A heapless queue holds one element less than its size.
+pub const RX_QUEUE_LEN: usize = 32;
+
+/// Software receive queue of one channel.
+pub type RxQueue = Queue<Frame, RX_QUEUE_LEN>;
+
+/// Receive counters of one channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct RxStats {
+    /// Frames taken out of the hardware FIFO.
+    pub received: u32,
+    /// Frames lost because the hardware FIFO overflowed before it was drained.
+    pub fifo_overruns: u32,
+    /// Frames dropped because the software queue was full.
+    pub queue_overruns: u32,
+}
+
+struct Counters {
+    received: AtomicU32,
+    fifo_overruns: AtomicU32,
+    queue_overruns: AtomicU32,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            received: AtomicU32::new(0),
+            fifo_overruns: AtomicU32::new(0),
+            queue_overruns: AtomicU32::new(0),
+        }
+    }
+}
+
+static COUNTERS: [Counters; 2] = [Counters::new(), Counters::new()];
+
+fn counters(channel: Channel) -> &'static Counters {
+    &COUNTERS[channel as usize]
+}
+
+/// Returns the receive counters of `channel`.
+pub fn stats(channel: Channel) -> RxStats {
+    let counters = counters(channel);
+    RxStats {
+        received: counters.received.load(Ordering::Relaxed),
+        fifo_overruns: counters.fifo_overruns.load(Ordering::Relaxed),
+        queue_overruns: counters.queue_overruns.load(Ordering::Relaxed),
+    }
+}
+
+/// Resets the receive counters of `channel` to zero.
+pub fn reset_stats(channel: Channel) {
+    let counters = counters(channel);
+    counters.received.store(0, Ordering::Relaxed);
+    counters.fifo_overruns.store(0, Ordering::Relaxed);
+    counters.queue_overruns.store(0, Ordering::Relaxed);
+}
+
+/// Splits `queue` into the interrupt side and the task side of `channel`.
+pub fn split(queue: &mut RxQueue, channel: Channel) -> (RxProducer<'_>, RxConsumer<'_>) {
+    let (producer, consumer) = queue.split();
+    (
+        RxProducer { channel, producer },
+        RxConsumer { channel, consumer },
+    )
+}
+
+/// Interrupt side of a receive queue.
+pub struct RxProducer<'a> {
+    channel: Channel,
+    producer: Producer<'a, Frame, RX_QUEUE_LEN>,
+}
+
+impl RxProducer<'_> {
+    /// Moves every frame waiting in the hardware FIFO into the queue.
+    ///
+    /// Call this from the RX interrupt of the channel. Reading the FIFO also clears its
+    /// message pending, full and overrun interrupt conditions. Returns the number of frames queued.
+    pub fn drain(&mut self, can: &mut (impl CanChannel + ?Sized)) -> usize {
+        self.drain_with(can, |_| false)
+    }
+
+    /// Like [`drain`](Self::drain), but hands every frame to `intercept` first and only queues
+    /// the frames it returns `false` for.
+    ///
+    /// This lets the interrupt handle frames that need the instant they arrived, like the SYNC
+    /// frames of [`time::sync`](crate::time::sync).
+    pub fn drain_with(
+        &mut self,
+        can: &mut (impl CanChannel + ?Sized),
+        mut intercept: impl FnMut(&Frame) -> bool,
+    ) -> usize {
+        debug_assert!(can.channel() == self.channel);
+
+        let counters = counters(self.channel);
+        let mut queued = 0;
+
+        loop {
+            match can.receive() {
+                Ok(frame) => {
+                    counters.received.fetch_add(1, Ordering::Relaxed);
+                    if intercept(&frame) {
+                        continue;
+                    }
+                    if self.producer.enqueue(frame).is_ok() {
+                        queued += 1;
+                    } else {
+                        counters.queue_overruns.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(nb::Error::Other(_)) => {
+                    counters.fifo_overruns.fetch_add(1, Ordering::Relaxed);
+                    warn!("{}, RX FIFO overrun", self.channel);
+                }
+                Err(nb::Error::WouldBlock) => return queued,
+            }
+        }
+    }
+}
+
+/// Task side of a receive queue.
+pub struct RxConsumer<'a> {
+    channel: Channel,
+    consumer: Consumer<'a, Frame, RX_QUEUE_LEN>,
+}
+
+impl RxConsumer<'_> {
+    /// The channel the frames were received on.
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Takes the oldest frame out of the queue.
+    pub fn receive(&mut self) -> Option<Frame> {
+        self.consumer.dequeue()
+    }
+
+    /// Number of frames waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.consumer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.consumer.ready()
+    }
+}
@@ -0,0 +1,157 @@
+//! Bit timing calculation for the bxCAN peripheral.
+//!
+//! A bit is split into time quanta (tq) of `prescaler / PCLK1` seconds:
+//!
+//! ```text
+//! | SYNC (1 tq) | BS1 (1..=16 tq) | BS2 (1..=8 tq) |
+//!                                 ^ sample point
+//! ```
+//!
+//! [`calculate`] searches every prescaler/segment combination for the given clock and returns the
+//! one closest to the requested bitrate and sample point. Nothing in here touches the hardware, so
+//! it can be tested on the host.
+
+use defmt::Format;
+use fugit::HertzU32;
+
+/// Highest bitrate allowed by ISO 11898.
+pub const MAX_BITRATE: u32 = 1_000_000;
+
+/// Largest accepted deviation from the requested bitrate, in parts per million.
+pub const MAX_BITRATE_ERROR_PPM: u32 = 5_000;
+
+const MIN_TQ_PER_BIT: u32 = 8;
+const MAX_TQ_PER_BIT: u32 = 25;
+const MAX_PRESCALER: u32 = 1024;
+const MAX_TSEG1: u32 = 16;
+const MAX_TSEG2: u32 = 8;
+const MAX_SJW: u32 = 4;
+
+/// Reasons no bit timing could be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TimingError {
+    /// The bitrate is zero or above [`MAX_BITRATE`].
+    InvalidBitrate,
+    /// The sample point is outside 50.0 % to 90.0 %.
+    InvalidSamplePoint,
+    /// PCLK1 is too slow to give at least 8 tq per bit at the requested bitrate.
+    ClockTooSlow,
+    /// No prescaler gets within [`MAX_BITRATE_ERROR_PPM`] of the requested bitrate.
+    NoSolution,
+}
+
+/// A decoded `CAN_BTR` bit timing. All fields are in time quanta, not register encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct BitTiming {
+    /// PCLK1 divider, `1..=1024`.
+    pub prescaler: u16,
+    /// Time segment before the sample point, `1..=16`.
+    pub tseg1: u8,
+    /// Time segment after the sample point, `1..=8`.
+    pub tseg2: u8,
+    /// Resynchronization jump width, `1..=4`.
+    pub sjw: u8,
+}
+
+impl BitTiming {
+    /// Decodes the timing fields of a `CAN_BTR` value. Mode bits are ignored.
+    pub const fn from_btr(btr: u32) -> Self {
+        Self {
+            prescaler: (btr & 0x3ff) as u16 + 1,
+            tseg1: ((btr >> 16) & 0xf) as u8 + 1,
+            tseg2: ((btr >> 20) & 0x7) as u8 + 1,
+            sjw: ((btr >> 24) & 0x3) as u8 + 1,
+        }
+    }
+
+    /// Encodes the timing as a `CAN_BTR` value, without silent or loopback bits.
+    pub const fn btr(&self) -> u32 {
+        (self.sjw as u32 - 1) << 24
+            | (self.tseg2 as u32 - 1) << 20
+            | (self.tseg1 as u32 - 1) << 16
+            | (self.prescaler as u32 - 1)
+    }
+
+    /// Number of time quanta in one bit.
+    pub const fn tq_per_bit(&self) -> u32 {
+        1 + self.tseg1 as u32 + self.tseg2 as u32
+    }
+
+    /// Resulting bitrate in bit/s for the given PCLK1.
+    pub const fn bitrate(&self, pclk1: HertzU32) -> u32 {
+        pclk1.raw() / (self.prescaler as u32 * self.tq_per_bit())
+    }
+
+    /// Sample point in per mille of the bit time.
+    pub const fn sample_point(&self) -> u16 {
+        ((1 + self.tseg1 as u32) * 1000 / self.tq_per_bit()) as u16
+    }
+}
+
+/// Finds the bit timing closest to `bitrate` (bit/s) and `sample_point` (per mille) for the
+/// given PCLK1.
+///
+/// Candidates are ranked by bitrate error first and sample point error second; ties go to the
+/// candidate with more time quanta per bit. SJW is set to the largest value BS2 allows.
+pub fn calculate(
+    pclk1: HertzU32,
+    bitrate: u32,
+    sample_point: u16,
+) -> Result<BitTiming, TimingError> {
+    if bitrate == 0 || bitrate > MAX_BITRATE {
+        return Err(TimingError::InvalidBitrate);
+    }
+    if !(500..=900).contains(&sample_point) {
+        return Err(TimingError::InvalidSamplePoint);
+    }
+
+    let pclk1 = pclk1.raw();
+    if pclk1 / bitrate < MIN_TQ_PER_BIT {
+        return Err(TimingError::ClockTooSlow);
+    }
+
+    // (bitrate error in ppm, sample point error in per mille, timing)
+    let mut best: Option<(u32, u32, BitTiming)> = None;
+
+    for tq in (MIN_TQ_PER_BIT..=MAX_TQ_PER_BIT).rev() {
+        let divider = u64::from(bitrate) * u64::from(tq);
+        let prescaler = ((u64::from(pclk1) + divider / 2) / divider) as u32;
+        if !(1..=MAX_PRESCALER).contains(&prescaler) {
+            continue;
+        }
+
+        let actual = pclk1 / (prescaler * tq);
+        let bitrate_error = (u64::from(actual.abs_diff(bitrate)) * 1_000_000
+            / u64::from(bitrate)) as u32;
+
+        // Sample point position in tq (counting the sync segment), kept inside the segment limits.
+        let min_tseg1 = tq.saturating_sub(1 + MAX_TSEG2).max(1);
+        let max_tseg1 = (tq - 2).min(MAX_TSEG1);
+        let ideal = (u32::from(sample_point) * tq + 500) / 1000;
+        let tseg1 = ideal.saturating_sub(1).clamp(min_tseg1, max_tseg1);
+        let tseg2 = tq - 1 - tseg1;
+
+        let timing = BitTiming {
+            prescaler: prescaler as u16,
+            tseg1: tseg1 as u8,
+            tseg2: tseg2 as u8,
+            sjw: tseg2.min(MAX_SJW) as u8,
+        };
+        let sample_point_error = u32::from(timing.sample_point().abs_diff(sample_point));
+
+        let better = match best {
+            None => true,
+            Some((best_bitrate_error, best_sample_point_error, _)) => {
+                (bitrate_error, sample_point_error) < (best_bitrate_error, best_sample_point_error)
+            }
+        };
+        if better {
+            best = Some((bitrate_error, sample_point_error, timing));
+        }
+    }
+
+    match best {
+        Some((bitrate_error, _, timing)) if bitrate_error <= MAX_BITRATE_ERROR_PPM => Ok(timing),
+        _ => Err(TimingError::NoSolution),
+    }
+}
@@ -0,0 +1,191 @@
+//! Software transmit queue on top of the three bxCAN mailboxes.
+//!
+//! Frames are kept ordered by CAN arbitration priority and moved into the mailboxes whenever one
+//! frees up. The mailboxes only accept a frame with a higher priority than every frame already
+//! pending, so frames never overtake frames with the same ID. When all three mailboxes are busy
+//! with lower priority frames, the lowest one is taken out again and put back into the queue, so
+//! neither side gets lost. Callers get [`TxError::QueueFull`] as backpressure once the queue is
+//! full.
+//!
+//! [`TxQueue::refill`] has to run from the TX interrupt of the channel, see
+//! [`TxQueue::on_interrupt`].
+
+use core::cmp::{Ordering, Reverse};
+
+use bxcan::{Frame, FramePriority};
+use defmt::Format;
+use heapless::binary_heap::{BinaryHeap, Max};
+
+use super::CanChannel;
+
+/// Number of frames a [`TxQueue`] holds on top of the three mailboxes.
+pub const TX_QUEUE_LEN: usize = 32;
+
+/// Sequence numbers for new frames start here; frames taken back out of a mailbox count down
+/// from just below, so they go ahead of every queued frame with the same priority.
+const FIRST_SEQUENCE: u64 = 1 << 32;
+
+/// Reasons a frame is not accepted for transmission.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TxError {
+    /// The software queue is full. Try again after the TX interrupt has made room.
+    QueueFull,
+}
+
+/// Transmit counters of one queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct TxStats {
+    /// Frames accepted by [`TxQueue::transmit`].
+    pub queued: u32,
+    /// Frames moved into a mailbox, counting re-queued frames each time.
+    pub loaded: u32,
+    /// Frames taken out of a mailbox to make room for a higher priority frame.
+    pub requeued: u32,
+    /// Frames rejected with [`TxError::QueueFull`].
+    pub rejected: u32,
+}
+
+struct Pending {
+    priority: FramePriority,
+    sequence: Reverse<u64>,
+    frame: Frame,
+}
+
+impl Pending {
+    fn key(&self) -> (FramePriority, Reverse<u64>) {
+        (self.priority, self.sequence)
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    /// Higher priority first, then older frames first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Priority ordered transmit queue for one channel.
+pub struct TxQueue {
+    frames: BinaryHeap<Pending, Max, TX_QUEUE_LEN>,
+    next_sequence: u64,
+    next_requeued_sequence: u64,
+    stats: TxStats,
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxQueue {
+    pub const fn new() -> Self {
+        Self {
+            frames: BinaryHeap::new(),
+            next_sequence: FIRST_SEQUENCE,
+            next_requeued_sequence: FIRST_SEQUENCE - 1,
+            stats: TxStats {
+                queued: 0,
+                loaded: 0,
+                requeued: 0,
+                rejected: 0,
+            },
+        }
+    }
+
+    /// Queues `frame` and moves as many frames as possible into the mailboxes of `can`.
+    pub fn transmit(
+        &mut self,
+        can: &mut (impl CanChannel + ?Sized),
+        frame: &Frame,
+    ) -> Result<(), TxError> {
+        let pending = Pending {
+            priority: frame.priority(),
+            sequence: Reverse(self.next_sequence),
+            frame: frame.clone(),
+        };
+
+        if self.frames.push(pending).is_err() {
+            self.stats.rejected += 1;
+            return Err(TxError::QueueFull);
+        }
+
+        self.next_sequence += 1;
+        self.stats.queued += 1;
+        self.refill(can);
+        Ok(())
+    }
+
+    /// Moves queued frames into free mailboxes, highest priority first.
+    ///
+    /// Returns the number of frames loaded into a mailbox.
+    pub fn refill(&mut self, can: &mut (impl CanChannel + ?Sized)) -> usize {
+        let mut loaded = 0;
+
+        while let Some(pending) = self.frames.peek() {
+            match can.transmit(&pending.frame) {
+                Ok(displaced) => {
+                    self.frames.pop();
+                    loaded += 1;
+                    self.stats.loaded += 1;
+
+                    if let Some(frame) = displaced {
+                        // The frame just popped left room for this one.
+                        self.frames
+                            .push(Pending {
+                                priority: frame.priority(),
+                                sequence: Reverse(self.next_requeued_sequence),
+                                frame,
+                            })
+                            .ok();
+                        self.next_requeued_sequence -= 1;
+                        self.stats.requeued += 1;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(never)) => match never {},
+            }
+        }
+
+        loaded
+    }
+
+    /// Handles the TX interrupt: acknowledges it and refills the mailboxes.
+    pub fn on_interrupt(&mut self, can: &mut (impl CanChannel + ?Sized)) -> usize {
+        can.clear_tx_interrupt();
+        self.refill(can)
+    }
+
+    /// Number of frames waiting for a mailbox.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the transmit counters.
+    pub fn stats(&self) -> TxStats {
+        self.stats
+    }
+
+    /// Drops every queued frame. Frames already in a mailbox are not affected.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
@@ -0,0 +1,344 @@
+//! CCSDS Space Packets (CCSDS 133.0-B-2) for telemetry and telecommands.
+//!
+//! A packet is a 6-octet primary header followed by the packet data field:
+//!
+//! ```text
+//! | version | type | sec. hdr | APID | seq. flags | seq. count | data length | data field |
+//! |    3    |  1   |    1     |  11  |     2      |     14     |     16      | 1 - 65536  |
+//! ```
+//!
+//! With the secondary header flag set, the data field starts with a [`Cuc`] timestamp in the
+//! mission's [`CucFormat`], followed by the user data. Calendar-like times go in the user data as
+//! [`Cds`] times.
+//!
+//! [`SpacePacket`] reads a packet straight from a receive buffer and [`PacketBuilder`] writes one
+//! straight into a transmit buffer, neither copies the payload anywhere else. The encoded packet
+//! goes over any transport, e.g. an [`IsoTpLink`](crate::isotp::IsoTpLink) message:
+//!
+//! ```ignore
+//! let mut buf = [0; 64];
+//! let packet = PacketBuilder::telemetry(HK_APID)
+//!     .sequence_count(counter.next_count())
+//!     .timestamp(CucFormat::DEFAULT, now)
+//!     .build(&report, &mut buf)?;
+//! link.send(&mut shield, packet, monotonics::now())?;
+//! ```
+
+use defmt::Format;
+
+pub mod cds;
+pub mod cuc;
+
+pub use cds::{Cds, CdsFormat};
+pub use cuc::{Cuc, CucFormat};
+
+/// Length of the primary header.
+pub const PRIMARY_HEADER_LEN: usize = 6;
+
+/// Longest packet data field.
+pub const MAX_DATA_LEN: usize = 65536;
+
+/// Highest application process ID.
+pub const MAX_APID: u16 = 0x7ff;
+
+/// Application process ID of idle packets.
+pub const IDLE_APID: u16 = 0x7ff;
+
+/// The sequence count wraps after this value.
+pub const MAX_SEQUENCE_COUNT: u16 = 0x3fff;
+
+/// Reasons a packet cannot be parsed or built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CcsdsError {
+    /// The buffer is shorter than the `required` number of octets.
+    BufferTooShort { required: usize },
+    /// The packet version number is not 0.
+    UnsupportedVersion(u8),
+    /// The APID is larger than [`MAX_APID`].
+    InvalidApid(u16),
+    /// The data field is empty or longer than [`MAX_DATA_LEN`].
+    InvalidDataLength,
+    /// The packet has no secondary header.
+    NoSecondaryHeader,
+    /// A time field holds a value out of its range.
+    InvalidTime,
+}
+
+/// Telemetry or telecommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PacketType {
+    Telemetry,
+    Telecommand,
+}
+
+/// Position of the packet in a group of segmented user data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SequenceFlags {
+    Continuation,
+    First,
+    Last,
+    Unsegmented,
+}
+
+impl SequenceFlags {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => SequenceFlags::Continuation,
+            0b01 => SequenceFlags::First,
+            0b10 => SequenceFlags::Last,
+            _ => SequenceFlags::Unsegmented,
+        }
+    }
+}
+
+/// The fields of a primary header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PrimaryHeader {
+    pub packet_type: PacketType,
+    pub secondary_header: bool,
+    pub apid: u16,
+    pub sequence_flags: SequenceFlags,
+    /// Sequence count of telemetry, or packet name of telecommands.
+    pub sequence_count: u16,
+    /// Length of the packet data field in octets, 1 to [`MAX_DATA_LEN`].
+    pub data_len: usize,
+}
+
+impl PrimaryHeader {
+    /// Encodes the header. Out of range fields are truncated to their width.
+    pub const fn to_bytes(&self) -> [u8; PRIMARY_HEADER_LEN] {
+        let packet_type = match self.packet_type {
+            PacketType::Telemetry => 0,
+            PacketType::Telecommand => 1,
+        };
+        let id = packet_type << 12 | (self.secondary_header as u16) << 11 | (self.apid & MAX_APID);
+        let sequence =
+            (self.sequence_flags as u16) << 14 | (self.sequence_count & MAX_SEQUENCE_COUNT);
+        let length = self.data_len.wrapping_sub(1) as u16;
+
+        let [id_hi, id_lo] = id.to_be_bytes();
+        let [seq_hi, seq_lo] = sequence.to_be_bytes();
+        let [len_hi, len_lo] = length.to_be_bytes();
+        [id_hi, id_lo, seq_hi, seq_lo, len_hi, len_lo]
+    }
+
+    /// Decodes a header.
+    pub const fn from_bytes(bytes: &[u8; PRIMARY_HEADER_LEN]) -> Result<Self, CcsdsError> {
+        let version = bytes[0] >> 5;
+        if version != 0 {
+            return Err(CcsdsError::UnsupportedVersion(version));
+        }
+
+        let id = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let sequence = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let length = u16::from_be_bytes([bytes[4], bytes[5]]);
+
+        Ok(Self {
+            packet_type: if id & 1 << 12 == 0 {
+                PacketType::Telemetry
+            } else {
+                PacketType::Telecommand
+            },
+            secondary_header: id & 1 << 11 != 0,
+            apid: id & MAX_APID,
+            sequence_flags: SequenceFlags::from_bits((sequence >> 14) as u8),
+            sequence_count: sequence & MAX_SEQUENCE_COUNT,
+            data_len: length as usize + 1,
+        })
+    }
+
+    /// Length of the whole packet.
+    pub const fn packet_len(&self) -> usize {
+        PRIMARY_HEADER_LEN + self.data_len
+    }
+}
+
+/// A packet in a byte buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpacePacket<'a> {
+    header: PrimaryHeader,
+    bytes: &'a [u8],
+}
+
+impl<'a> SpacePacket<'a> {
+    /// Reads the packet at the start of `bytes`. Bytes after the packet are ignored, the next
+    /// packet of a stream starts at [`SpacePacket::packet_len`].
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, CcsdsError> {
+        let header =
+            bytes
+                .first_chunk::<PRIMARY_HEADER_LEN>()
+                .ok_or(CcsdsError::BufferTooShort {
+                    required: PRIMARY_HEADER_LEN,
+                })?;
+        let header = PrimaryHeader::from_bytes(header)?;
+
+        let required = header.packet_len();
+        let bytes = bytes
+            .get(..required)
+            .ok_or(CcsdsError::BufferTooShort { required })?;
+
+        Ok(Self { header, bytes })
+    }
+
+    pub fn header(&self) -> PrimaryHeader {
+        self.header
+    }
+
+    pub fn apid(&self) -> u16 {
+        self.header.apid
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        self.header.packet_type
+    }
+
+    pub fn sequence_count(&self) -> u16 {
+        self.header.sequence_count
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.header.apid == IDLE_APID
+    }
+
+    /// The encoded packet.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Length of the encoded packet.
+    pub fn packet_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The packet data field, including the secondary header if there is one.
+    pub fn data_field(&self) -> &'a [u8] {
+        &self.bytes[PRIMARY_HEADER_LEN..]
+    }
+
+    /// Splits the data field into the timestamp of the secondary header and the user data.
+    pub fn secondary_header(&self, format: CucFormat) -> Result<(Cuc, &'a [u8]), CcsdsError> {
+        if !self.header.secondary_header {
+            return Err(CcsdsError::NoSecondaryHeader);
+        }
+
+        let data = self.data_field();
+        let time = Cuc::decode(format, data).map_err(|_| CcsdsError::InvalidDataLength)?;
+        Ok((time, &data[format.encoded_len()..]))
+    }
+}
+
+/// Writes packets into a buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketBuilder {
+    packet_type: PacketType,
+    apid: u16,
+    sequence_flags: SequenceFlags,
+    sequence_count: u16,
+    timestamp: Option<(CucFormat, Cuc)>,
+}
+
+impl PacketBuilder {
+    /// Starts an unsegmented telemetry packet without secondary header.
+    pub const fn telemetry(apid: u16) -> Self {
+        Self::new(PacketType::Telemetry, apid)
+    }
+
+    /// Starts an unsegmented telecommand packet without secondary header.
+    pub const fn telecommand(apid: u16) -> Self {
+        Self::new(PacketType::Telecommand, apid)
+    }
+
+    const fn new(packet_type: PacketType, apid: u16) -> Self {
+        Self {
+            packet_type,
+            apid,
+            sequence_flags: SequenceFlags::Unsegmented,
+            sequence_count: 0,
+            timestamp: None,
+        }
+    }
+
+    pub const fn sequence_flags(mut self, flags: SequenceFlags) -> Self {
+        self.sequence_flags = flags;
+        self
+    }
+
+    /// Sets the sequence count, or the packet name of a telecommand. Only the low 14 bits are
+    /// used.
+    pub const fn sequence_count(mut self, count: u16) -> Self {
+        self.sequence_count = count;
+        self
+    }
+
+    /// Adds a secondary header with `time` encoded in `format`.
+    pub const fn timestamp(mut self, format: CucFormat, time: Cuc) -> Self {
+        self.timestamp = Some((format, time));
+        self
+    }
+
+    /// Writes the packet with `user_data` to the start of `buf`. Returns the encoded packet.
+    pub fn build<'b>(&self, user_data: &[u8], buf: &'b mut [u8]) -> Result<&'b [u8], CcsdsError> {
+        let len = self.build_with(user_data.len(), buf, |data| {
+            data.copy_from_slice(user_data);
+        })?;
+        Ok(&buf[..len])
+    }
+
+    /// Writes a packet with `user_len` octets of user data to the start of `buf`, letting `fill`
+    /// write the user data in place. Returns the length of the packet.
+    pub fn build_with(
+        &self,
+        user_len: usize,
+        buf: &mut [u8],
+        fill: impl FnOnce(&mut [u8]),
+    ) -> Result<usize, CcsdsError> {
+        if self.apid > MAX_APID {
+            return Err(CcsdsError::InvalidApid(self.apid));
+        }
+
+        let time_len = self.timestamp.map_or(0, |(format, _)| format.encoded_len());
+        let data_len = time_len + user_len;
+        if data_len == 0 || data_len > MAX_DATA_LEN {
+            return Err(CcsdsError::InvalidDataLength);
+        }
+
+        let header = PrimaryHeader {
+            packet_type: self.packet_type,
+            secondary_header: self.timestamp.is_some(),
+            apid: self.apid,
+            sequence_flags: self.sequence_flags,
+            sequence_count: self.sequence_count,
+            data_len,
+        };
+        let required = header.packet_len();
+        let packet = buf
+            .get_mut(..required)
+            .ok_or(CcsdsError::BufferTooShort { required })?;
+
+        let (primary, data) = packet.split_at_mut(PRIMARY_HEADER_LEN);
+        primary.copy_from_slice(&header.to_bytes());
+        if let Some((format, time)) = self.timestamp {
+            time.encode(format, data)?;
+        }
+        fill(&mut data[time_len..]);
+
+        Ok(required)
+    }
+}
+
+/// Source sequence counter of one APID.
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct SequenceCounter(u16);
+
+impl SequenceCounter {
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Returns the next count, wrapping after [`MAX_SEQUENCE_COUNT`].
+    pub fn next_count(&mut self) -> u16 {
+        let count = self.0;
+        self.0 = (self.0 + 1) & MAX_SEQUENCE_COUNT;
+        count
+    }
+}
@@ -0,0 +1,161 @@
+//! CCSDS Day Segmented Time Code (CDS, CCSDS 301.0-B-4 section 3.3).
+//!
+//! A CDS time is a count of days since the epoch of 1958-01-01 (2 or 3 octets), the
+//! milliseconds of the day (4 octets) and optionally the microseconds of the millisecond
+//! (2 octets). The optional P-field announces the lengths.
+
+use defmt::Format;
+
+use super::CcsdsError;
+
+/// Time code ID of the P-field for a CDS time.
+const CDS_ID: u8 = 0b100;
+
+const MICROS_PER_MILLI: u64 = 1000;
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Milliseconds of a day with a leap second.
+const MAX_MILLIS_OF_DAY: u32 = 86_401_000;
+
+/// Number of day and submillisecond octets of a CDS time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct CdsFormat {
+    long_days: bool,
+    micros: bool,
+}
+
+impl CdsFormat {
+    /// 2 octets of days and 2 octets of microseconds, until 2137 with 1 µs resolution.
+    pub const DEFAULT: Self = Self {
+        long_days: false,
+        micros: true,
+    };
+
+    /// Returns `None` unless `days` is 2 or 3 and `submillis` is 0 or 2. The 4-octet picosecond
+    /// resolution is not supported.
+    pub const fn new(days: u8, submillis: u8) -> Option<Self> {
+        match (days, submillis) {
+            (2 | 3, 0 | 2) => Some(Self {
+                long_days: days == 3,
+                micros: submillis == 2,
+            }),
+            _ => None,
+        }
+    }
+
+    pub const fn day_octets(self) -> u8 {
+        if self.long_days {
+            3
+        } else {
+            2
+        }
+    }
+
+    pub const fn submillisecond_octets(self) -> u8 {
+        if self.micros {
+            2
+        } else {
+            0
+        }
+    }
+
+    /// Length of the encoded time (T-field), without the P-field.
+    pub const fn encoded_len(self) -> usize {
+        (self.day_octets() + 4 + self.submillisecond_octets()) as usize
+    }
+
+    /// The P-field announcing this format with the CCSDS epoch.
+    pub const fn pfield(self) -> u8 {
+        CDS_ID << 4 | (self.long_days as u8) << 2 | self.micros as u8
+    }
+
+    /// Decodes the P-field of a CDS time with the CCSDS epoch.
+    pub const fn from_pfield(pfield: u8) -> Option<Self> {
+        if pfield & 0x80 != 0 || pfield >> 4 & 0b111 != CDS_ID || pfield & 0b1000 != 0 {
+            return None;
+        }
+        let days = if pfield & 0b100 != 0 { 3 } else { 2 };
+        Self::new(days, (pfield & 0b11) * 2)
+    }
+}
+
+impl Default for CdsFormat {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A CDS time, days since 1958-01-01 and the time of that day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Format)]
+pub struct Cds {
+    /// Days since the epoch.
+    pub days: u32,
+    /// Milliseconds of the day.
+    pub millis: u32,
+    /// Microseconds of the millisecond.
+    pub micros: u16,
+}
+
+impl Cds {
+    /// Splits a time in microseconds since the epoch into days, milliseconds and microseconds.
+    /// Every day has 86400 seconds.
+    pub const fn from_micros(micros: u64) -> Self {
+        let millis = micros / MICROS_PER_MILLI;
+        Self {
+            days: (millis / MILLIS_PER_DAY) as u32,
+            millis: (millis % MILLIS_PER_DAY) as u32,
+            micros: (micros % MICROS_PER_MILLI) as u16,
+        }
+    }
+
+    /// Converts the time to microseconds since the epoch.
+    pub const fn to_micros(self) -> u64 {
+        (self.days as u64 * MILLIS_PER_DAY + self.millis as u64) * MICROS_PER_MILLI
+            + self.micros as u64
+    }
+
+    /// Writes the T-field to the start of `buf` and returns its length.
+    ///
+    /// Octets of `days` beyond the format are dropped like for a [`Cuc`](super::Cuc), and so are
+    /// the microseconds if the format has none.
+    pub fn encode(&self, format: CdsFormat, buf: &mut [u8]) -> Result<usize, CcsdsError> {
+        let len = format.encoded_len();
+        let out = buf
+            .get_mut(..len)
+            .ok_or(CcsdsError::BufferTooShort { required: len })?;
+
+        let (days, rest) = out.split_at_mut(usize::from(format.day_octets()));
+        days.copy_from_slice(&self.days.to_be_bytes()[4 - days.len()..]);
+        let (millis, micros) = rest.split_at_mut(4);
+        millis.copy_from_slice(&self.millis.to_be_bytes());
+        if format.micros {
+            micros.copy_from_slice(&self.micros.to_be_bytes());
+        }
+        Ok(len)
+    }
+
+    /// Reads a T-field from the start of `bytes`.
+    pub fn decode(format: CdsFormat, bytes: &[u8]) -> Result<Self, CcsdsError> {
+        let len = format.encoded_len();
+        let field = bytes
+            .get(..len)
+            .ok_or(CcsdsError::BufferTooShort { required: len })?;
+        let (days, rest) = field.split_at(usize::from(format.day_octets()));
+        let (millis, micros) = rest.split_at(4);
+
+        let time = Self {
+            days: days
+                .iter()
+                .fold(0u32, |value, &octet| value << 8 | u32::from(octet)),
+            millis: u32::from_be_bytes([millis[0], millis[1], millis[2], millis[3]]),
+            micros: match micros {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                _ => 0,
+            },
+        };
+        if time.millis >= MAX_MILLIS_OF_DAY || u64::from(time.micros) >= MICROS_PER_MILLI {
+            return Err(CcsdsError::InvalidTime);
+        }
+        Ok(time)
+    }
+}
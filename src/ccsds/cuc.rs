@@ -0,0 +1,135 @@
+//! CCSDS Unsegmented Time Code (CUC, CCSDS 301.0-B-4 section 3.2).
+//!
+//! A CUC time is a binary count of seconds since an epoch (the coarse time, 1 to 4 octets)
+//! followed by a binary fraction of a second (the fine time, 0 to 3 octets). The optional
+//! preamble field (P-field) announces the number of octets of each.
+
+use defmt::Format;
+
+use super::CcsdsError;
+
+/// Time code ID of the P-field for a CUC time with the TAI epoch of 1958-01-01.
+const CUC_TAI_EPOCH: u8 = 0b001;
+
+/// Time code ID of the P-field for a CUC time with an agency-defined epoch.
+const CUC_AGENCY_EPOCH: u8 = 0b010;
+
+/// Number of coarse and fine octets of a CUC time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct CucFormat {
+    coarse: u8,
+    fine: u8,
+}
+
+impl CucFormat {
+    /// 4 octets of seconds and 2 octets of fraction, about 15 µs resolution.
+    pub const DEFAULT: Self = Self { coarse: 4, fine: 2 };
+
+    /// Returns `None` unless `coarse` is 1 to 4 and `fine` is 0 to 3.
+    pub const fn new(coarse: u8, fine: u8) -> Option<Self> {
+        if coarse >= 1 && coarse <= 4 && fine <= 3 {
+            Some(Self { coarse, fine })
+        } else {
+            None
+        }
+    }
+
+    pub const fn coarse_octets(self) -> u8 {
+        self.coarse
+    }
+
+    pub const fn fine_octets(self) -> u8 {
+        self.fine
+    }
+
+    /// Length of the encoded time (T-field), without the P-field.
+    pub const fn encoded_len(self) -> usize {
+        (self.coarse + self.fine) as usize
+    }
+
+    /// The P-field announcing this format with an agency-defined epoch.
+    pub const fn pfield(self) -> u8 {
+        CUC_AGENCY_EPOCH << 4 | (self.coarse - 1) << 2 | self.fine
+    }
+
+    /// Decodes a single-octet P-field of a CUC time with either epoch.
+    pub const fn from_pfield(pfield: u8) -> Option<Self> {
+        let id = pfield >> 4 & 0b111;
+        if pfield & 0x80 != 0 || (id != CUC_TAI_EPOCH && id != CUC_AGENCY_EPOCH) {
+            return None;
+        }
+        Self::new((pfield >> 2 & 0b11) + 1, pfield & 0b11)
+    }
+
+    /// Largest fine time plus one, the number of fine ticks per second.
+    const fn fine_ticks(self) -> u64 {
+        1 << (8 * self.fine as u32)
+    }
+}
+
+impl Default for CucFormat {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A CUC time, seconds and fraction of a second since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Format)]
+pub struct Cuc {
+    /// Seconds since the epoch.
+    pub coarse: u32,
+    /// Fraction of a second in units of 2^-(8 * fine octets) s.
+    pub fine: u32,
+}
+
+impl Cuc {
+    /// Converts a time in microseconds since the epoch to the resolution of `format`, rounding
+    /// down.
+    pub const fn from_micros(micros: u64, format: CucFormat) -> Self {
+        let fraction = micros % 1_000_000;
+        Self {
+            coarse: (micros / 1_000_000) as u32,
+            fine: (fraction * format.fine_ticks() / 1_000_000) as u32,
+        }
+    }
+
+    /// Converts the time to microseconds since the epoch, rounding down.
+    pub const fn to_micros(self, format: CucFormat) -> u64 {
+        self.coarse as u64 * 1_000_000 + self.fine as u64 * 1_000_000 / format.fine_ticks()
+    }
+
+    /// Writes the T-field to the start of `buf` and returns its length.
+    ///
+    /// Octets of `coarse` and `fine` beyond the format are dropped, so the time wraps like a
+    /// counter of that width.
+    pub fn encode(&self, format: CucFormat, buf: &mut [u8]) -> Result<usize, CcsdsError> {
+        let len = format.encoded_len();
+        let out = buf
+            .get_mut(..len)
+            .ok_or(CcsdsError::BufferTooShort { required: len })?;
+
+        let coarse = usize::from(format.coarse);
+        out[..coarse].copy_from_slice(&self.coarse.to_be_bytes()[4 - coarse..]);
+        out[coarse..].copy_from_slice(&self.fine.to_be_bytes()[4 - usize::from(format.fine)..]);
+        Ok(len)
+    }
+
+    /// Reads a T-field from the start of `bytes`.
+    pub fn decode(format: CucFormat, bytes: &[u8]) -> Result<Self, CcsdsError> {
+        let len = format.encoded_len();
+        let field = bytes
+            .get(..len)
+            .ok_or(CcsdsError::BufferTooShort { required: len })?;
+        let (coarse, fine) = field.split_at(usize::from(format.coarse));
+
+        let be = |octets: &[u8]| {
+            octets
+                .iter()
+                .fold(0u32, |value, &octet| value << 8 | u32::from(octet))
+        };
+        Ok(Self {
+            coarse: be(coarse),
+            fine: be(fine),
+        })
+    }
+}
@@ -0,0 +1,330 @@
+//! CubeSat Space Protocol (CSP) over the CAN shield.
+//!
+//! Packets use the 32-bit CSP 1.x header and are carried over CAN with the CAN Fragmentation
+//! Protocol (CFP) of libcsp's CAN interface, see [`cfp`]. Each node has a 5-bit address. A
+//! [`Router`] picks the channel and the next hop for every destination, and a [`CspInterface`]
+//! fragments outgoing packets onto the shield and reassembles incoming ones.
+//!
+//! Each channel sends one packet at a time, a frame whenever the previous one has left the
+//! mailboxes. Call [`CspInterface::poll_tx`] from the TX interrupts after the shield has refilled
+//! its mailboxes.
+//!
+//! ```ignore
+//! let mut router = Router::new(Channel::Can1);
+//! router.set_route(8, Route::direct(Channel::Can2));
+//! let mut csp = CspInterface::<180_000_000>::new(ADDRESS, router);
+//!
+//! csp.send(&mut shield, &packet)?;
+//! // In the TX interrupt:
+//! shield.on_tx_interrupt(channel);
+//! csp.poll_tx(&mut shield);
+//! // For every received frame:
+//! if let Some(packet) = csp.receive(channel, &frame, monotonics::now())? { ... }
+//! ```
+
+use defmt::Format;
+use fugit::TimerInstantU32;
+use heapless::Vec;
+
+use crate::can_shield::{filters::FilterRule, Channel, Shield};
+
+pub mod cfp;
+
+use cfp::{CfpError, Fragmenter, Reassembler};
+
+/// Largest payload of a packet.
+pub const CSP_MTU: usize = 256;
+
+/// Highest node address. Addresses are 5 bits wide.
+pub const MAX_ADDRESS: u8 = 31;
+
+/// Destination address that reaches every node.
+pub const BROADCAST: u8 = 31;
+
+/// Packet priority. CFP does not put it into the frame ID, so it has no effect on arbitration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum Priority {
+    Critical = 0,
+    High = 1,
+    Normal = 2,
+    Low = 3,
+}
+
+/// Header flag bits.
+pub mod flags {
+    /// The packet has an HMAC.
+    pub const HMAC: u8 = 1 << 3;
+    /// The payload is XTEA encrypted.
+    pub const XTEA: u8 = 1 << 2;
+    /// The packet belongs to a reliable datagram protocol connection.
+    pub const RDP: u8 = 1 << 1;
+    /// The payload ends with a CRC32.
+    pub const CRC32: u8 = 1 << 0;
+}
+
+/// The CSP 1.x packet header.
+///
+/// ```text
+///  31 30 | 29   25 | 24   20 | 19    14 | 13    8 | 7      4 | 3    0
+/// prio   |   src   |   dst   |  dport   |  sport  | reserved | flags
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Header {
+    pub priority: Priority,
+    pub src: u8,
+    pub dst: u8,
+    pub dport: u8,
+    pub sport: u8,
+    /// See [`flags`].
+    pub flags: u8,
+}
+
+impl Header {
+    /// Packs the header into its 32-bit wire format.
+    pub const fn to_bits(&self) -> u32 {
+        (self.priority as u32) << 30
+            | (self.src as u32 & 0x1f) << 25
+            | (self.dst as u32 & 0x1f) << 20
+            | (self.dport as u32 & 0x3f) << 14
+            | (self.sport as u32 & 0x3f) << 8
+            | (self.flags as u32 & 0x0f)
+    }
+
+    /// Unpacks a header from its 32-bit wire format.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self {
+            priority: match bits >> 30 {
+                0 => Priority::Critical,
+                1 => Priority::High,
+                2 => Priority::Normal,
+                _ => Priority::Low,
+            },
+            src: (bits >> 25) as u8 & 0x1f,
+            dst: (bits >> 20) as u8 & 0x1f,
+            dport: (bits >> 14) as u8 & 0x3f,
+            sport: (bits >> 8) as u8 & 0x3f,
+            flags: bits as u8 & 0x0f,
+        }
+    }
+}
+
+/// A CSP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub data: Vec<u8, CSP_MTU>,
+}
+
+impl Packet {
+    /// Creates a packet, or returns `None` if `data` is longer than [`CSP_MTU`].
+    pub fn new(header: Header, data: &[u8]) -> Option<Self> {
+        Some(Self {
+            header,
+            data: Vec::from_slice(data).ok()?,
+        })
+    }
+}
+
+/// Where packets for a destination are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Route {
+    pub channel: Channel,
+    /// Address of the node forwarding the packet, `None` if the destination is on the bus.
+    pub via: Option<u8>,
+}
+
+impl Route {
+    /// The destination is on the bus of `channel`.
+    pub const fn direct(channel: Channel) -> Self {
+        Self { channel, via: None }
+    }
+
+    /// The destination is reached through node `via` on the bus of `channel`.
+    pub const fn via(channel: Channel, via: u8) -> Self {
+        Self {
+            channel,
+            via: Some(via),
+        }
+    }
+}
+
+/// Static routing table indexed by destination address.
+#[derive(Debug, Clone)]
+pub struct Router {
+    routes: [Option<Route>; MAX_ADDRESS as usize + 1],
+    default: Option<Route>,
+}
+
+impl Router {
+    /// Creates a table sending every destination directly on `channel`.
+    pub const fn new(channel: Channel) -> Self {
+        Self {
+            routes: [None; MAX_ADDRESS as usize + 1],
+            default: Some(Route::direct(channel)),
+        }
+    }
+
+    /// Sets the route to `dst`, overriding the default route.
+    pub fn set_route(&mut self, dst: u8, route: Route) -> &mut Self {
+        if let Some(entry) = self.routes.get_mut(usize::from(dst)) {
+            *entry = Some(route);
+        }
+        self
+    }
+
+    /// Sets the route for destinations without their own entry. `None` drops their packets.
+    pub fn set_default(&mut self, route: Option<Route>) -> &mut Self {
+        self.default = route;
+        self
+    }
+
+    /// Returns the route to `dst`.
+    pub fn route(&self, dst: u8) -> Option<Route> {
+        self.routes
+            .get(usize::from(dst))
+            .copied()
+            .flatten()
+            .or(self.default)
+    }
+}
+
+/// Reasons a packet is not sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CspError {
+    /// The router has no route to the destination.
+    NoRoute { dst: u8 },
+    /// The channel of the route is still sending the previous packet.
+    Busy,
+    /// A received frame does not fit the packet being reassembled.
+    Cfp(CfpError),
+}
+
+impl From<CfpError> for CspError {
+    fn from(error: CfpError) -> Self {
+        Self::Cfp(error)
+    }
+}
+
+/// Sends and receives CSP packets for one node on both channels of a shield.
+///
+/// `HZ` is the tick rate of the instants passed in, usually the RTIC monotonic.
+pub struct CspInterface<const HZ: u32> {
+    address: u8,
+    router: Router,
+    ident: u16,
+    outgoing: [Option<Fragmenter>; 2],
+    reassembler: Reassembler<HZ>,
+}
+
+impl<const HZ: u32> CspInterface<HZ> {
+    /// Creates the interface of node `address`, which must be at most [`MAX_ADDRESS`].
+    pub fn new(address: u8, router: Router) -> Self {
+        debug_assert!(address <= MAX_ADDRESS);
+
+        Self {
+            address,
+            router,
+            ident: 0,
+            outgoing: [None, None],
+            reassembler: Reassembler::new(),
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn router(&mut self) -> &mut Router {
+        &mut self.router
+    }
+
+    pub fn reassembler(&mut self) -> &mut Reassembler<HZ> {
+        &mut self.reassembler
+    }
+
+    /// Acceptance rule letting through the CFP frames addressed to this node. Broadcasts need
+    /// [`cfp::filter`] with [`BROADCAST`] as well.
+    pub fn filter(&self) -> FilterRule {
+        cfp::filter(self.address)
+    }
+
+    /// Starts sending `packet` on the channel of its route.
+    ///
+    /// The source address in the header is sent as is, so forwarded packets keep their origin.
+    pub fn send(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        packet: &Packet,
+    ) -> Result<(), CspError> {
+        let dst = packet.header.dst;
+        let route = self.router.route(dst).ok_or(CspError::NoRoute { dst })?;
+
+        let outgoing = &mut self.outgoing[route.channel as usize];
+        if outgoing.is_some() {
+            return Err(CspError::Busy);
+        }
+
+        let ident = self.ident;
+        self.ident = (self.ident + 1) & cfp::MAX_IDENT;
+        *outgoing = Some(Fragmenter::new(
+            packet.clone(),
+            route.via.unwrap_or(dst),
+            ident,
+        ));
+
+        self.poll_tx(shield);
+        Ok(())
+    }
+
+    /// Returns `true` if `channel` is still sending a packet.
+    pub fn is_sending(&self, channel: Channel) -> bool {
+        self.outgoing[channel as usize].is_some()
+    }
+
+    /// Queues the next frame on every channel whose previous frame has been sent.
+    pub fn poll_tx(&mut self, shield: &mut (impl Shield + ?Sized)) {
+        for channel in Channel::ALL {
+            let Some(fragmenter) = &mut self.outgoing[channel as usize] else {
+                continue;
+            };
+            if shield.tx_pending(channel) > 0 || !shield.channel(channel).status().transmitter_idle
+            {
+                continue;
+            }
+
+            match fragmenter.next() {
+                Some(frame) => {
+                    // The queue is empty, so the frame is always taken.
+                    shield.transmit(channel, &frame).ok();
+                }
+                None => self.outgoing[channel as usize] = None,
+            }
+        }
+    }
+
+    /// Handles a frame received on `channel`. Returns a packet once its last fragment arrived.
+    ///
+    /// Frames that are not CFP frames for this node or the broadcast address are ignored. A
+    /// packet whose header names another destination reached this node as the next hop, pass it
+    /// to [`CspInterface::send`] to forward it.
+    pub fn receive(
+        &mut self,
+        channel: Channel,
+        frame: &bxcan::Frame,
+        now: TimerInstantU32<HZ>,
+    ) -> Result<Option<Packet>, CspError> {
+        let Some(id) = cfp::CfpId::from_frame(frame) else {
+            return Ok(None);
+        };
+        if id.dst != self.address && id.dst != BROADCAST {
+            return Ok(None);
+        }
+
+        Ok(self.reassembler.push(channel, frame, now)?)
+    }
+
+    /// Drops packets whose reassembly did not finish in time. Returns the number dropped.
+    pub fn expire(&mut self, now: TimerInstantU32<HZ>) -> usize {
+        self.reassembler.expire(now)
+    }
+}
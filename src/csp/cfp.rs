@@ -0,0 +1,399 @@
+//! CAN Fragmentation Protocol, the CSP-over-CAN format of libcsp 1.x (`csp_if_can`).
+//!
+//! Every frame uses a 29-bit extended ID:
+//!
+//! ```text
+//! 28   24 | 23   19 |  18  | 17     10 | 9        0
+//!   src   |   dst   | type |  remain   | identifier
+//! ```
+//!
+//! `dst` is the next hop, `type` is 0 for the first frame of a packet and 1 for the following
+//! ones, `remain` counts the frames still to come and `identifier` is a per-sender packet
+//! counter. The first frame carries the CSP header and the payload length, both big endian,
+//! followed by up to two payload bytes. Following frames carry up to eight payload bytes each.
+
+use bxcan::{Data, ExtendedId, Frame, Id};
+use defmt::{warn, Format};
+use fugit::{MillisDurationU32, TimerInstantU32};
+use heapless::Vec;
+
+use super::{Header, Packet, CSP_MTU};
+use crate::can_shield::{filters::FilterRule, Channel};
+
+/// Highest packet identifier, the counter wraps after it.
+pub const MAX_IDENT: u16 = 0x3ff;
+
+/// Number of packets that can be reassembled at the same time.
+pub const REASSEMBLY_SLOTS: usize = 4;
+
+/// Bytes of the first frame taken by the CSP header and the length.
+const OVERHEAD: usize = 6;
+
+const SRC_SHIFT: u32 = 24;
+const DST_SHIFT: u32 = 19;
+const TYPE_SHIFT: u32 = 18;
+const REMAIN_SHIFT: u32 = 10;
+
+/// Position of a frame in its packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FrameType {
+    Begin,
+    More,
+}
+
+/// The fields of a CFP frame ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct CfpId {
+    pub src: u8,
+    /// Address of the next hop.
+    pub dst: u8,
+    pub frame_type: FrameType,
+    /// Number of frames following this one.
+    pub remain: u8,
+    /// Packet counter of the sender, at most [`MAX_IDENT`].
+    pub ident: u16,
+}
+
+impl CfpId {
+    pub const fn to_raw(&self) -> u32 {
+        let frame_type = match self.frame_type {
+            FrameType::Begin => 0,
+            FrameType::More => 1,
+        };
+
+        (self.src as u32 & 0x1f) << SRC_SHIFT
+            | (self.dst as u32 & 0x1f) << DST_SHIFT
+            | frame_type << TYPE_SHIFT
+            | (self.remain as u32) << REMAIN_SHIFT
+            | (self.ident & MAX_IDENT) as u32
+    }
+
+    pub const fn from_raw(raw: u32) -> Self {
+        Self {
+            src: (raw >> SRC_SHIFT) as u8 & 0x1f,
+            dst: (raw >> DST_SHIFT) as u8 & 0x1f,
+            frame_type: if raw & (1 << TYPE_SHIFT) == 0 {
+                FrameType::Begin
+            } else {
+                FrameType::More
+            },
+            remain: (raw >> REMAIN_SHIFT) as u8,
+            ident: raw as u16 & MAX_IDENT,
+        }
+    }
+
+    /// Decodes the ID of a data frame with an extended ID.
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match frame.id() {
+            Id::Extended(id) if frame.is_data_frame() => Some(Self::from_raw(id.as_raw())),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> ExtendedId {
+        ExtendedId::new(self.to_raw()).expect("CFP IDs have 29 bits")
+    }
+}
+
+/// Acceptance rule for the CFP frames sent to node `address`.
+pub fn filter(address: u8) -> FilterRule {
+    let field = |value: u32| ExtendedId::new(value << DST_SHIFT).expect("5-bit field");
+    FilterRule::ExtendedMask {
+        id: field(u32::from(address & 0x1f)),
+        mask: field(0x1f),
+    }
+}
+
+/// Number of frames needed for a payload of `len` bytes.
+pub const fn frame_count(len: usize) -> usize {
+    (len + OVERHEAD).div_ceil(8)
+}
+
+/// Splits a packet into its frames.
+///
+/// The frames of a packet have to go out in order, but the later ones have lower IDs and win
+/// arbitration against the earlier ones. Queue a frame only once the previous one has left the
+/// mailboxes, as [`CspInterface`](super::CspInterface) does.
+pub struct Fragmenter {
+    packet: Packet,
+    dst: u8,
+    ident: u16,
+    /// Payload bytes sent so far, `None` before the first frame.
+    offset: Option<usize>,
+}
+
+impl Fragmenter {
+    /// Creates the frames of `packet` for the next hop `dst`, tagged with the packet counter
+    /// `ident`.
+    pub fn new(packet: Packet, dst: u8, ident: u16) -> Self {
+        Self {
+            packet,
+            dst,
+            ident,
+            offset: None,
+        }
+    }
+
+    pub fn packet(&self) -> &Packet {
+        &self.packet
+    }
+}
+
+impl Iterator for Fragmenter {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let payload = &self.packet.data;
+        let mut id = CfpId {
+            src: self.packet.header.src,
+            dst: self.dst,
+            frame_type: FrameType::Begin,
+            remain: 0,
+            ident: self.ident,
+        };
+        let mut buf = [0; 8];
+
+        let (len, end) = match self.offset {
+            None => {
+                let count = payload.len().min(8 - OVERHEAD);
+                id.remain = (frame_count(payload.len()) - 1) as u8;
+                buf[..4].copy_from_slice(&self.packet.header.to_bits().to_be_bytes());
+                buf[4..OVERHEAD].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+                buf[OVERHEAD..OVERHEAD + count].copy_from_slice(&payload[..count]);
+                (OVERHEAD + count, count)
+            }
+            Some(offset) if offset < payload.len() => {
+                let count = (payload.len() - offset).min(8);
+                id.frame_type = FrameType::More;
+                id.remain = (payload.len() - offset - count).div_ceil(8) as u8;
+                buf[..count].copy_from_slice(&payload[offset..offset + count]);
+                (count, offset + count)
+            }
+            Some(_) => return None,
+        };
+
+        self.offset = Some(end);
+        let data = Data::new(&buf[..len]).expect("at most 8 bytes");
+        Some(Frame::new_data(id.id(), data))
+    }
+}
+
+/// Reasons a received frame is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CfpError {
+    /// A first frame is too short for the header, or its `remain` does not match the length.
+    Malformed,
+    /// The packet is longer than [`CSP_MTU`].
+    TooLong,
+    /// A following frame arrived without a first frame, e.g. after a timeout.
+    UnexpectedFragment,
+    /// A frame is missing. The partial packet is dropped.
+    OutOfOrder,
+    /// The frames carry more or less payload than the first frame announced.
+    LengthMismatch,
+    /// All [`REASSEMBLY_SLOTS`] are in use.
+    NoBuffer,
+}
+
+/// Counters of a [`Reassembler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct CfpStats {
+    /// Packets completed.
+    pub packets: u32,
+    /// Partial packets dropped because they timed out.
+    pub timeouts: u32,
+    /// Frames dropped with a [`CfpError`].
+    pub errors: u32,
+}
+
+struct Slot<const HZ: u32> {
+    channel: Channel,
+    src: u8,
+    dst: u8,
+    ident: u16,
+    header: Header,
+    length: usize,
+    remain: u8,
+    data: Vec<u8, CSP_MTU>,
+    started: TimerInstantU32<HZ>,
+}
+
+/// Collects the frames of incoming packets.
+///
+/// Frames of one packet are matched by channel, source, destination and identifier, so packets
+/// from several senders and both channels can be reassembled at the same time.
+pub struct Reassembler<const HZ: u32> {
+    slots: Vec<Slot<HZ>, REASSEMBLY_SLOTS>,
+    timeout: MillisDurationU32,
+    stats: CfpStats,
+}
+
+impl<const HZ: u32> Default for Reassembler<HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const HZ: u32> Reassembler<HZ> {
+    /// Creates a reassembler with the libcsp timeout of 1 s.
+    pub const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            timeout: MillisDurationU32::from_ticks(1000),
+            stats: CfpStats {
+                packets: 0,
+                timeouts: 0,
+                errors: 0,
+            },
+        }
+    }
+
+    /// Sets the time from the first frame of a packet until it is dropped if incomplete.
+    pub fn set_timeout(&mut self, timeout: MillisDurationU32) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn stats(&self) -> CfpStats {
+        self.stats
+    }
+
+    /// Number of packets being reassembled.
+    pub fn in_progress(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Adds a frame received on `channel`. Returns the packet once its last frame arrived.
+    ///
+    /// Frames that are not CFP frames are ignored.
+    pub fn push(
+        &mut self,
+        channel: Channel,
+        frame: &Frame,
+        now: TimerInstantU32<HZ>,
+    ) -> Result<Option<Packet>, CfpError> {
+        let result = self.accept(channel, frame, now);
+        match result {
+            Ok(Some(_)) => self.stats.packets += 1,
+            Ok(None) => {}
+            Err(error) => {
+                warn!("CFP frame dropped, {}", error);
+                self.stats.errors += 1;
+            }
+        }
+        result
+    }
+
+    /// Drops packets whose first frame is older than the timeout. Returns the number dropped.
+    pub fn expire(&mut self, now: TimerInstantU32<HZ>) -> usize {
+        let timeout = self.timeout.to_millis();
+        let before = self.slots.len();
+        self.slots.retain(|slot| {
+            let age = now
+                .checked_duration_since(slot.started)
+                .map_or(0, |d| d.to_millis());
+            age < timeout
+        });
+
+        let expired = before - self.slots.len();
+        if expired > 0 {
+            warn!("CFP, {} partial packets timed out", expired);
+            self.stats.timeouts += expired as u32;
+        }
+        expired
+    }
+
+    fn accept(
+        &mut self,
+        channel: Channel,
+        frame: &Frame,
+        now: TimerInstantU32<HZ>,
+    ) -> Result<Option<Packet>, CfpError> {
+        let (Some(id), Some(data)) = (CfpId::from_frame(frame), frame.data()) else {
+            return Ok(None);
+        };
+
+        let position = self.slots.iter().position(|slot| {
+            slot.channel == channel
+                && slot.src == id.src
+                && slot.dst == id.dst
+                && slot.ident == id.ident
+        });
+
+        match id.frame_type {
+            FrameType::Begin => {
+                // A new first frame replaces an unfinished packet with the same identifier.
+                if let Some(index) = position {
+                    self.slots.swap_remove(index);
+                }
+
+                if data.len() < OVERHEAD {
+                    return Err(CfpError::Malformed);
+                }
+                let header =
+                    Header::from_bits(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                let length = usize::from(u16::from_be_bytes([data[4], data[5]]));
+                let payload = &data[OVERHEAD..];
+
+                if length > CSP_MTU {
+                    return Err(CfpError::TooLong);
+                }
+                if usize::from(id.remain) != frame_count(length) - 1 {
+                    return Err(CfpError::Malformed);
+                }
+                if payload.len() != length.min(8 - OVERHEAD) {
+                    return Err(CfpError::LengthMismatch);
+                }
+
+                let slot = Slot {
+                    channel,
+                    src: id.src,
+                    dst: id.dst,
+                    ident: id.ident,
+                    header,
+                    length,
+                    remain: id.remain,
+                    data: Vec::from_slice(payload).expect("at most 2 bytes"),
+                    started: now,
+                };
+
+                if slot.remain == 0 {
+                    return Ok(Some(Packet {
+                        header: slot.header,
+                        data: slot.data,
+                    }));
+                }
+                self.slots.push(slot).map_err(|_| CfpError::NoBuffer)?;
+                Ok(None)
+            }
+            FrameType::More => {
+                let index = position.ok_or(CfpError::UnexpectedFragment)?;
+                let slot = &mut self.slots[index];
+
+                if id.remain.checked_add(1) != Some(slot.remain) {
+                    self.slots.swap_remove(index);
+                    return Err(CfpError::OutOfOrder);
+                }
+                if slot.data.len() + data.len() > slot.length
+                    || slot.data.extend_from_slice(data).is_err()
+                {
+                    self.slots.swap_remove(index);
+                    return Err(CfpError::LengthMismatch);
+                }
+                slot.remain = id.remain;
+
+                if slot.remain > 0 {
+                    return Ok(None);
+                }
+                let slot = self.slots.swap_remove(index);
+                if slot.data.len() != slot.length {
+                    return Err(CfpError::LengthMismatch);
+                }
+                Ok(Some(Packet {
+                    header: slot.header,
+                    data: slot.data,
+                }))
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! An onboard event log in a ring of flash sectors.
+//!
+//! The defmt log is gone after a reset, and in orbit nobody reads it anyway. The [`EventLog`]
+//! keeps a record of every event in flash: its MET, severity, event ID and up to
+//! [`MAX_PARAMS_LEN`] bytes of parameters, numbered with a sequence number. Ground reads ranges
+//! of them back through the [storage service](crate::pus::storage).
+//!
+//! Records are written in order into the sectors of the ring. When a sector is full, the log
+//! moves on to the next one, which is already erased, and erases the one after it. Erasing ahead
+//! keeps an erased sector ready, at the cost of the oldest sector. A log of `S` sectors keeps
+//! between `S - 2` and `S - 1` sectors of records.
+//!
+//! ```text
+//! header: | "EVL1" | lap (u32) | first sequence number (u32) | CRC-16 | 0xFF x 2 |
+//! record: | MET (u64) | event ID | severity | length | parameters (16 bytes) | CRC-16 |
+//! ```
+//!
+//! The header numbers the sectors in the order they were opened and holds the sequence number of
+//! the first record, the others follow from their position. A record torn by a power loss fails
+//! its CRC and is skipped, and so is a torn header. [`EventLog::mount`] erases the sector after
+//! the newest one if a power loss interrupted its erase.
+//!
+//! ```ignore
+//! let mut log = EventLog::new([1, 2, 3]);
+//! log.mount(&mut flash)?;
+//! log.log(&mut flash, now, Severity::Medium, EVENT_NODE_LOST, &[node])?;
+//! ```
+
+use defmt::{warn, Format};
+
+use crate::{
+    flash::{crc16, Flash, FlashError},
+    pus::event::Severity,
+    time::Met,
+};
+
+/// Most parameter bytes of a record.
+pub const MAX_PARAMS_LEN: usize = 16;
+
+const MAGIC: [u8; 4] = *b"EVL1";
+
+/// Length of a sector header.
+const HEADER_LEN: usize = 16;
+
+/// Length of a record.
+const RECORD_LEN: usize = 12 + MAX_PARAMS_LEN + 2;
+
+/// Errors of the event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum LogError {
+    /// The parameters are longer than [`MAX_PARAMS_LEN`].
+    TooLong,
+    /// The log has not been mounted.
+    NotMounted,
+    /// No record with this sequence number is kept.
+    NotFound(u32),
+    /// The record with this sequence number failed its CRC.
+    Corrupted(u32),
+    Flash(FlashError),
+}
+
+impl From<FlashError> for LogError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+/// A record of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct LogEntry {
+    pub seq: u32,
+    pub time: Met,
+    pub severity: Severity,
+    pub id: u16,
+    params: [u8; MAX_PARAMS_LEN],
+    len: u8,
+}
+
+impl LogEntry {
+    pub fn params(&self) -> &[u8] {
+        &self.params[..usize::from(self.len)]
+    }
+
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[..8].copy_from_slice(&self.time.ticks().to_be_bytes());
+        record[8..10].copy_from_slice(&self.id.to_be_bytes());
+        record[10] = self.severity as u8;
+        record[11] = self.len;
+        record[12..12 + MAX_PARAMS_LEN].copy_from_slice(&self.params);
+        let crc = crc16(&record[..RECORD_LEN - 2]);
+        record[RECORD_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+        record
+    }
+
+    /// Reads record `seq`, `None` if it is damaged.
+    fn decode(seq: u32, record: &[u8; RECORD_LEN]) -> Option<Self> {
+        let (data, crc) = record.split_at(RECORD_LEN - 2);
+        if crc16(data) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+        let len = record[11];
+        if usize::from(len) > MAX_PARAMS_LEN {
+            return None;
+        }
+        let mut time = [0; 8];
+        time.copy_from_slice(&record[..8]);
+        let mut params = [0; MAX_PARAMS_LEN];
+        params.copy_from_slice(&record[12..12 + MAX_PARAMS_LEN]);
+
+        Some(Self {
+            seq,
+            time: Met::from_ticks(u64::from_be_bytes(time)),
+            severity: Severity::from_subtype(record[10])?,
+            id: u16::from_be_bytes([record[8], record[9]]),
+            params,
+            len,
+        })
+    }
+}
+
+/// Counters of an event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct LogStats {
+    /// Records written since boot.
+    pub logged: u32,
+    /// Damaged records found on mount.
+    pub corrupted: u32,
+    /// Sectors erased since boot.
+    pub erased: u32,
+}
+
+/// A sector of the ring with a valid header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Opened {
+    lap: u32,
+    first_seq: u32,
+}
+
+/// An event log in the `S` flash sectors of a ring, at least 2 of them.
+pub struct EventLog<const S: usize> {
+    sectors: [u8; S],
+    opened: [Option<Opened>; S],
+    /// Index of the sector records go to, `None` until mounted.
+    head: Option<usize>,
+    /// Next free slot of the head sector.
+    slot: usize,
+    stats: LogStats,
+}
+
+impl<const S: usize> EventLog<S> {
+    /// A log in the flash sectors `sectors`, in ring order. It has to be mounted before use.
+    pub const fn new(sectors: [u8; S]) -> Self {
+        assert!(S >= 2);
+        Self {
+            sectors,
+            opened: [None; S],
+            head: None,
+            slot: 0,
+            stats: LogStats {
+                logged: 0,
+                corrupted: 0,
+                erased: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> LogStats {
+        self.stats
+    }
+
+    /// Finds the newest record and restores the erased sector ahead of it. Sectors without a
+    /// log are formatted.
+    pub fn mount(&mut self, flash: &mut (impl Flash + ?Sized)) -> Result<(), LogError> {
+        self.head = None;
+        for (index, &sector) in self.sectors.iter().enumerate() {
+            self.opened[index] = read_header(flash, sector)?;
+        }
+
+        let newest = (0..S)
+            .filter_map(|index| Some((index, self.opened[index]?.lap)))
+            .max_by_key(|&(_, lap)| lap);
+        let Some((head, _)) = newest else {
+            warn!("No event log found, formatting");
+            let sector = self.sectors[0];
+            if !flash.is_erased(sector)? {
+                self.erase(flash, 0)?;
+            }
+            self.open(flash, 0, 0, 0)?;
+            return self.erase_ahead(flash);
+        };
+
+        // Records are appended, so everything after the last written slot is free
+        let sector = self.sectors[head];
+        let mut slot = 0;
+        for index in 0..slots(flash, sector) {
+            let mut record = [0; RECORD_LEN];
+            flash.read(sector, HEADER_LEN + index * RECORD_LEN, &mut record)?;
+            if record.iter().any(|&byte| byte != 0xff) {
+                slot = index + 1;
+                if LogEntry::decode(0, &record).is_none() {
+                    self.stats.corrupted += 1;
+                }
+            }
+        }
+        self.head = Some(head);
+        self.slot = slot;
+
+        self.erase_ahead(flash)
+    }
+
+    /// Sequence number of the next record.
+    pub fn next_seq(&self) -> u32 {
+        self.head
+            .and_then(|head| self.opened[head])
+            .map_or(0, |opened| opened.first_seq.wrapping_add(self.slot as u32))
+    }
+
+    /// Sequence number of the oldest record kept, equal to [`EventLog::next_seq`] if the log is
+    /// empty.
+    pub fn oldest_seq(&self) -> u32 {
+        let Some(head) = self.head else {
+            return 0;
+        };
+        (1..=S)
+            .filter_map(|step| self.opened[(head + step) % S])
+            .map(|opened| opened.first_seq)
+            .next()
+            .unwrap_or_else(|| self.next_seq())
+    }
+
+    /// Number of records the sectors hold.
+    pub fn capacity(&self, flash: &(impl Flash + ?Sized)) -> usize {
+        self.sectors
+            .iter()
+            .map(|&sector| slots(flash, sector))
+            .sum()
+    }
+
+    /// Appends a record of event `id` at `time` with `params`. Returns its sequence number.
+    ///
+    /// Moving on to the next sector erases the one after it, which stalls the CPU for up to 800 ms
+    /// on a 16 KiB sector of the internal flash, see [`crate::flash`]. If the header of the next
+    /// sector cannot be written, the log has to be mounted again.
+    pub fn log(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        time: Met,
+        severity: Severity,
+        id: u16,
+        params: &[u8],
+    ) -> Result<u32, LogError> {
+        if params.len() > MAX_PARAMS_LEN {
+            return Err(LogError::TooLong);
+        }
+        let mut head = self.head.ok_or(LogError::NotMounted)?;
+        if self.slot >= slots(flash, self.sectors[head]) {
+            let seq = self.next_seq();
+            let lap = self.opened[head].map_or(0, |opened| opened.lap.wrapping_add(1));
+            head = (head + 1) % S;
+            self.open(flash, head, lap, seq)?;
+            self.erase_ahead(flash)?;
+        }
+
+        let seq = self.next_seq();
+        let mut entry = LogEntry {
+            seq,
+            time,
+            severity,
+            id,
+            params: [0; MAX_PARAMS_LEN],
+            len: params.len() as u8,
+        };
+        entry.params[..params.len()].copy_from_slice(params);
+
+        // A failed write still takes its slot, what was programmed is unknown
+        let offset = HEADER_LEN + self.slot * RECORD_LEN;
+        self.slot += 1;
+        flash.program(self.sectors[head], offset, &entry.encode())?;
+        self.stats.logged += 1;
+        Ok(seq)
+    }
+
+    /// Reads record `seq`.
+    pub fn read(&self, flash: &(impl Flash + ?Sized), seq: u32) -> Result<LogEntry, LogError> {
+        let head = self.head.ok_or(LogError::NotMounted)?;
+        let (index, slot) = (0..S)
+            .find_map(|index| {
+                let slot = seq.wrapping_sub(self.opened[index]?.first_seq) as usize;
+                let used = match index == head {
+                    true => self.slot,
+                    false => slots(flash, self.sectors[index]),
+                };
+                (slot < used).then_some((index, slot))
+            })
+            .ok_or(LogError::NotFound(seq))?;
+
+        let mut record = [0; RECORD_LEN];
+        flash.read(
+            self.sectors[index],
+            HEADER_LEN + slot * RECORD_LEN,
+            &mut record,
+        )?;
+        LogEntry::decode(seq, &record).ok_or(LogError::Corrupted(seq))
+    }
+
+    /// Writes the header of sector `index` and makes it the head.
+    fn open(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        index: usize,
+        lap: u32,
+        first_seq: u32,
+    ) -> Result<(), LogError> {
+        let mut header = [0xff; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&lap.to_be_bytes());
+        header[8..12].copy_from_slice(&first_seq.to_be_bytes());
+        let crc = crc16(&header[..12]);
+        header[12..14].copy_from_slice(&crc.to_be_bytes());
+
+        // Records can go nowhere else until the header is written
+        self.head = None;
+        flash.program(self.sectors[index], 0, &header)?;
+        self.opened[index] = Some(Opened { lap, first_seq });
+        self.head = Some(index);
+        self.slot = 0;
+        Ok(())
+    }
+
+    /// Erases the sector after the head, unless it is erased already.
+    fn erase_ahead(&mut self, flash: &mut (impl Flash + ?Sized)) -> Result<(), LogError> {
+        let head = self.head.ok_or(LogError::NotMounted)?;
+        let ahead = (head + 1) % S;
+        if self.opened[ahead].is_none() && flash.is_erased(self.sectors[ahead])? {
+            return Ok(());
+        }
+        self.erase(flash, ahead)
+    }
+
+    fn erase(&mut self, flash: &mut (impl Flash + ?Sized), index: usize) -> Result<(), LogError> {
+        self.opened[index] = None;
+        flash.erase(self.sectors[index])?;
+        self.stats.erased += 1;
+        Ok(())
+    }
+}
+
+/// Number of records `sector` holds.
+fn slots(flash: &(impl Flash + ?Sized), sector: u8) -> usize {
+    flash.sector_len(sector).saturating_sub(HEADER_LEN) / RECORD_LEN
+}
+
+/// The header of `sector`, `None` if it is erased or damaged.
+fn read_header(flash: &(impl Flash + ?Sized), sector: u8) -> Result<Option<Opened>, FlashError> {
+    let mut header = [0; HEADER_LEN];
+    flash.read(sector, 0, &mut header)?;
+
+    let crc = u16::from_be_bytes([header[12], header[13]]);
+    if header[..4] != MAGIC || crc16(&header[..12]) != crc {
+        return Ok(None);
+    }
+    Ok(Some(Opened {
+        lap: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        first_seq: u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+    }))
+}
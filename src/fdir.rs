@@ -0,0 +1,307 @@
+//! Fault detection, isolation and recovery.
+//!
+//! [`Monitor`]s check what the node observes, like housekeeping parameters, the error counters of
+//! the CAN controllers and the check-ins of the supervised tasks. A monitor raises its fault once
+//! its check failed a number of times in a row, its persistence, so a single bad sample is not a
+//! fault yet. The [`Rule`]s map the raised faults to [`Recovery`] actions, which the application
+//! takes with [`Recover`]. Raising and clearing a fault leaves a [`FaultEvent`] for the
+//! application to report, like the changes of the [mode manager](crate::mode):
+//!
+//! ```ignore
+//! const MONITORS: [Monitor; 2] = [
+//!     Monitor::new(0x0100, Check::Limit { parameter: TEMPERATURE, low: -20.0, high: 85.0 })
+//!         .persistence(3),
+//!     Monitor::new(0x0101, Check::BusOff(Channel::Can1)),
+//! ];
+//! const RULES: &[Rule] = &[
+//!     Rule::new(0x0100, Recovery::Mode(Mode::Safe)),
+//!     Rule::new(0x0101, Recovery::RestartCan(Channel::Can1)),
+//! ];
+//! let mut fdir = Fdir::new(&MONITORS, RULES);
+//!
+//! // periodically
+//! fdir.run(&mut node);
+//! while let Some(event) = fdir.pop_event() { ... }
+//! ```
+//!
+//! The node implements [`Observations`] and [`Recover`], so tests can inject the values the
+//! monitors see.
+
+use defmt::Format;
+use fugit::MillisDurationU32;
+use heapless::Deque;
+
+use crate::{
+    can_shield::{
+        health::{BusState, ErrorStatus},
+        Channel,
+    },
+    mode::Mode,
+    pus::{event::Severity, housekeeping::Value},
+    watchdog::TaskId,
+};
+
+/// Most fault events waiting to be reported.
+pub const MAX_PENDING: usize = 8;
+
+/// What the monitors check.
+pub trait Observations {
+    /// The value of housekeeping parameter `id`.
+    fn parameter(&self, id: u16) -> Option<Value>;
+
+    /// The error counters and the state of a CAN controller.
+    fn error_status(&self, channel: Channel) -> ErrorStatus;
+
+    /// Time since a supervised task last checked in, `None` while it is not watched, see
+    /// [`Supervisor::since_check_in`](crate::watchdog::Supervisor::since_check_in).
+    fn since_check_in(&self, task: TaskId) -> Option<MillisDurationU32>;
+}
+
+/// The check of a [`Monitor`].
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Check {
+    /// A housekeeping parameter is within `low..=high`. A missing parameter or a `Bool` fails.
+    Limit { parameter: u16, low: f32, high: f32 },
+    /// Both error counters of a channel are at most `limit`.
+    ErrorCounters { channel: Channel, limit: u8 },
+    /// A channel is not bus-off.
+    BusOff(Channel),
+    /// A supervised task checked in within `deadline`, which is shorter than the deadline at the
+    /// supervisor so there is time to recover before the watchdog resets the node.
+    Deadline {
+        task: TaskId,
+        deadline: MillisDurationU32,
+    },
+}
+
+impl Check {
+    /// Whether the check passes on `observed`.
+    pub fn passes(&self, observed: &(impl Observations + ?Sized)) -> bool {
+        match *self {
+            Check::Limit {
+                parameter,
+                low,
+                high,
+            } => observed
+                .parameter(parameter)
+                .and_then(to_f32)
+                .is_some_and(|value| (low..=high).contains(&value)),
+            Check::ErrorCounters { channel, limit } => {
+                let status = observed.error_status(channel);
+                status.tec <= limit && status.rec <= limit
+            }
+            Check::BusOff(channel) => observed.error_status(channel).state != BusState::BusOff,
+            Check::Deadline { task, deadline } => observed
+                .since_check_in(task)
+                .is_none_or(|since| since <= deadline),
+        }
+    }
+}
+
+fn to_f32(value: Value) -> Option<f32> {
+    Some(match value {
+        Value::Bool(_) => return None,
+        Value::U8(value) => f32::from(value),
+        Value::U16(value) => f32::from(value),
+        Value::U32(value) => value as f32,
+        Value::I8(value) => f32::from(value),
+        Value::I16(value) => f32::from(value),
+        Value::I32(value) => value as f32,
+        Value::F32(value) => value,
+    })
+}
+
+/// Raises fault `fault` when its check failed `persistence` times in a row.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Monitor {
+    pub fault: u16,
+    pub check: Check,
+    pub persistence: u8,
+    pub severity: Severity,
+}
+
+impl Monitor {
+    /// Creates a monitor that raises its fault on the first failed check, with medium severity.
+    pub const fn new(fault: u16, check: Check) -> Self {
+        Self {
+            fault,
+            check,
+            persistence: 1,
+            severity: Severity::Medium,
+        }
+    }
+
+    /// Sets the failed checks in a row that raise the fault, at least 1.
+    pub const fn persistence(mut self, persistence: u8) -> Self {
+        self.persistence = if persistence == 0 { 1 } else { persistence };
+        self
+    }
+
+    /// Sets the severity of the event when the fault is raised.
+    pub const fn severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+}
+
+/// A recovery action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Recovery {
+    /// Restart a CAN controller, to leave bus-off with manual recovery.
+    RestartCan(Channel),
+    /// Send on this channel, see
+    /// [`RedundantBus::select`](crate::can_shield::redundancy::RedundantBus::select).
+    SwitchBus(Channel),
+    /// Go to a mode, see [`ModeManager::recover`](crate::mode::ModeManager::recover).
+    Mode(Mode),
+    /// Reset the node.
+    Reboot,
+}
+
+/// Takes `action` when `fault` is raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Rule {
+    pub fault: u16,
+    pub action: Recovery,
+}
+
+impl Rule {
+    pub const fn new(fault: u16, action: Recovery) -> Self {
+        Self { fault, action }
+    }
+}
+
+/// Takes the recovery actions of the application.
+pub trait Recover {
+    /// Takes `action` for `fault`. The actions of a fault are taken in the order of the rules.
+    fn recover(&mut self, fault: u16, action: Recovery);
+}
+
+/// A fault that was raised or cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FaultEvent {
+    pub fault: u16,
+    /// Raised, or cleared once its check passed again.
+    pub raised: bool,
+    /// The severity of the monitor when raised, informative when cleared.
+    pub severity: Severity,
+    /// Recovery actions taken.
+    pub recoveries: u8,
+}
+
+impl FaultEvent {
+    /// The auxiliary data of the event: the fault (u16) and the recovery actions taken (u8).
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let [high, low] = self.fault.to_be_bytes();
+        [high, low, self.recoveries]
+    }
+}
+
+/// FDIR counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct FdirStats {
+    /// Runs of the monitors.
+    pub runs: u32,
+    /// Faults raised.
+    pub faults: u32,
+    /// Recovery actions taken.
+    pub recoveries: u32,
+    /// Fault events not reported because too many were waiting.
+    pub dropped: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    failed: u8,
+    raised: bool,
+}
+
+/// Runs `N` monitors and takes the recovery actions of the faults they raise.
+pub struct Fdir<'t, const N: usize> {
+    monitors: &'t [Monitor; N],
+    rules: &'t [Rule],
+    states: [State; N],
+    events: Deque<FaultEvent, MAX_PENDING>,
+    stats: FdirStats,
+}
+
+impl<'t, const N: usize> Fdir<'t, N> {
+    pub const fn new(monitors: &'t [Monitor; N], rules: &'t [Rule]) -> Self {
+        Self {
+            monitors,
+            rules,
+            states: [State {
+                failed: 0,
+                raised: false,
+            }; N],
+            events: Deque::new(),
+            stats: FdirStats {
+                runs: 0,
+                faults: 0,
+                recoveries: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> FdirStats {
+        self.stats
+    }
+
+    /// Whether `fault` is raised by any of its monitors.
+    pub fn is_raised(&self, fault: u16) -> bool {
+        self.monitors
+            .iter()
+            .zip(&self.states)
+            .any(|(monitor, state)| monitor.fault == fault && state.raised)
+    }
+
+    /// Checks every monitor once on what `node` observes. A fault that is raised takes the actions
+    /// of its rules, once until it is cleared.
+    pub fn run(&mut self, node: &mut (impl Observations + Recover + ?Sized)) {
+        self.stats.runs += 1;
+        for (monitor, state) in self.monitors.iter().zip(&mut self.states) {
+            let event = if monitor.check.passes(node) {
+                state.failed = 0;
+                if !state.raised {
+                    continue;
+                }
+                state.raised = false;
+                FaultEvent {
+                    fault: monitor.fault,
+                    raised: false,
+                    severity: Severity::Informative,
+                    recoveries: 0,
+                }
+            } else {
+                state.failed = state.failed.saturating_add(1);
+                if state.raised || state.failed < monitor.persistence {
+                    continue;
+                }
+                state.raised = true;
+                self.stats.faults += 1;
+
+                let mut recoveries = 0;
+                for rule in self.rules.iter().filter(|rule| rule.fault == monitor.fault) {
+                    node.recover(monitor.fault, rule.action);
+                    recoveries += 1;
+                }
+                self.stats.recoveries += u32::from(recoveries);
+                FaultEvent {
+                    fault: monitor.fault,
+                    raised: true,
+                    severity: monitor.severity,
+                    recoveries,
+                }
+            };
+            if self.events.push_back(event).is_err() {
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    /// The oldest fault event that was not reported yet.
+    pub fn pop_event(&mut self) -> Option<FaultEvent> {
+        self.events.pop_front()
+    }
+}
@@ -0,0 +1,188 @@
+//! Sector-wise access to NOR flash, for the data kept across resets.
+//!
+//! The internal flash of the STM32F446 has sectors of 16, 64 and 128 KiB. A sector is erased to
+//! `0xFF` as a whole, and programming only clears bits, so data is written once per erase.
+//! [`Flash`] offers just that, so the stores on top of it run against the [`InternalFlash`] on
+//! the target and against the RAM simulator of the `mock` feature on the host.
+//!
+//! `memory.x` keeps the sectors of the stores out of the code:
+//!
+//! | Sector | Offset    | Size         | Use             |
+//! |--------|-----------|--------------|-----------------|
+//! | 0      | 0x00000   | 16 KiB       | vector table    |
+//! | 1 to 3 | 0x04000   | 16 KiB each  | event log       |
+//! | 4, 5   | 0x10000   | 192 KiB      | code            |
+//! | 6, 7   | 0x40000   | 128 KiB each | parameter store |
+//!
+//! The F446 has a single bank, so the CPU stalls while the flash is programmed or erased, and no
+//! interrupt runs. The HAL erases with 8-bit parallelism, for which the datasheet gives these
+//! erase times:
+//!
+//! | Sector size | Typical | Maximum |
+//! |-------------|---------|---------|
+//! | 16 KiB      | 400 ms  | 800 ms  |
+//! | 64 KiB      | 1.2 s   | 2.4 s   |
+//! | 128 KiB     | 2 s     | 4 s     |
+//!
+//! Watchdogs and deadlines have to allow for the maximum of any sector erased at runtime.
+
+use core::cell::RefCell;
+
+use defmt::Format;
+use stm32f4xx_hal::flash::{self as hal, flash_sectors, FlashExt, LockedFlash};
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+/// Reasons an access to the flash failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FlashError {
+    /// The sector does not exist or the access runs past its end.
+    OutOfBounds,
+    /// The flash is write protected.
+    WriteProtection,
+    /// The controller reported a programming sequence, parallelism or alignment error.
+    Programming,
+    /// The operation did not complete.
+    Operation,
+}
+
+impl From<hal::Error> for FlashError {
+    fn from(error: hal::Error) -> Self {
+        match error {
+            hal::Error::WriteProtection => Self::WriteProtection,
+            hal::Error::Operation => Self::Operation,
+            hal::Error::ProgrammingSequence
+            | hal::Error::ProgrammingParallelism
+            | hal::Error::ProgrammingAlignment => Self::Programming,
+        }
+    }
+}
+
+/// Bytes compared at a time by [`Flash::is_erased`].
+const BLANK_CHECK_LEN: usize = 64;
+
+/// A flash made of sectors.
+pub trait Flash {
+    /// Length of `sector` in bytes, 0 if there is no such sector.
+    fn sector_len(&self, sector: u8) -> usize;
+
+    /// Reads `buf.len()` bytes at `offset` of `sector`.
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Sets every byte of `sector` to `0xFF`.
+    ///
+    /// On the [`InternalFlash`] this stalls the CPU for up to 4 s, see the erase times above.
+    fn erase(&mut self, sector: u8) -> Result<(), FlashError>;
+
+    /// Programs `data` at `offset` of `sector`. The bytes should be erased.
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+
+    /// Returns `true` if every byte of `sector` is erased.
+    fn is_erased(&self, sector: u8) -> Result<bool, FlashError> {
+        let len = self.sector_len(sector);
+        let mut buf = [0; BLANK_CHECK_LEN];
+        for offset in (0..len).step_by(BLANK_CHECK_LEN) {
+            let chunk = &mut buf[..BLANK_CHECK_LEN.min(len - offset)];
+            self.read(sector, offset, chunk)?;
+            if chunk.iter().any(|&byte| byte != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// A flash shared by several users at once, such as the services of one dispatch.
+impl<F: Flash + ?Sized> Flash for &RefCell<F> {
+    fn sector_len(&self, sector: u8) -> usize {
+        self.borrow().sector_len(sector)
+    }
+
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.borrow().read(sector, offset, buf)
+    }
+
+    fn erase(&mut self, sector: u8) -> Result<(), FlashError> {
+        self.borrow_mut().erase(sector)
+    }
+
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        self.borrow_mut().program(sector, offset, data)
+    }
+}
+
+/// Byte range of `len` bytes at `offset` in a sector of `sector_len` bytes.
+fn range(
+    sector_len: usize,
+    offset: usize,
+    len: usize,
+) -> Result<core::ops::Range<usize>, FlashError> {
+    let end = offset.checked_add(len).ok_or(FlashError::OutOfBounds)?;
+    if end > sector_len {
+        return Err(FlashError::OutOfBounds);
+    }
+    Ok(offset..end)
+}
+
+/// The internal flash of the MCU.
+pub struct InternalFlash {
+    flash: LockedFlash,
+}
+
+impl InternalFlash {
+    pub fn new(flash: LockedFlash) -> Self {
+        Self { flash }
+    }
+
+    pub fn free(self) -> LockedFlash {
+        self.flash
+    }
+
+    /// Offset and length of `sector` from the start of the flash.
+    fn sector(&self, sector: u8) -> Option<(usize, usize)> {
+        flash_sectors(self.flash.len(), self.flash.dual_bank())
+            .find(|candidate| candidate.number == sector)
+            .map(|sector| (sector.offset, sector.size))
+    }
+}
+
+impl Flash for InternalFlash {
+    fn sector_len(&self, sector: u8) -> usize {
+        self.sector(sector).map_or(0, |(_, len)| len)
+    }
+
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        let (start, len) = self.sector(sector).ok_or(FlashError::OutOfBounds)?;
+        let range = range(len, offset, buf.len())?;
+        buf.copy_from_slice(&self.flash.read()[start + range.start..start + range.end]);
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: u8) -> Result<(), FlashError> {
+        self.sector(sector).ok_or(FlashError::OutOfBounds)?;
+        Ok(self.flash.unlocked().erase(sector)?)
+    }
+
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let (start, len) = self.sector(sector).ok_or(FlashError::OutOfBounds)?;
+        let range = range(len, offset, data.len())?;
+        Ok(self
+            .flash
+            .unlocked()
+            .program(start + range.start, data.iter())?)
+    }
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF), the CRC of CCSDS packets.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
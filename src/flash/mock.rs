@@ -0,0 +1,143 @@
+//! A flash in RAM for host tests.
+//!
+//! Only available with the `mock` feature, which needs std. [`RamFlash`] behaves like NOR flash:
+//! erasing sets a sector to `0xFF` and programming can only clear bits. It can cut the power in
+//! the middle of an operation, to test that the stores survive it:
+//!
+//! ```ignore
+//! let mut flash = RamFlash::stm32f446();
+//! flash.cut_power_after(10);
+//! assert!(store.set(&mut flash, ID, Value::U32(7)).is_err());
+//! flash.restore_power();
+//! store.mount(&mut flash)?;
+//! ```
+
+use std::{vec, vec::Vec};
+
+use super::{range, Flash, FlashError};
+
+/// Sector lengths of the 512 KiB STM32F446.
+const STM32F446_SECTORS: [usize; 8] = [
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    16 * 1024,
+    64 * 1024,
+    128 * 1024,
+    128 * 1024,
+    128 * 1024,
+];
+
+/// A simulated flash.
+pub struct RamFlash {
+    sectors: Vec<Vec<u8>>,
+    erases: Vec<u32>,
+    /// Bytes that can still be programmed before the power fails, `None` while it does not.
+    budget: Option<usize>,
+}
+
+impl RamFlash {
+    /// A flash of erased sectors of the given lengths.
+    pub fn new(sector_lens: &[usize]) -> Self {
+        Self {
+            sectors: sector_lens.iter().map(|&len| vec![0xff; len]).collect(),
+            erases: vec![0; sector_lens.len()],
+            budget: None,
+        }
+    }
+
+    /// A flash with the sectors of the STM32F446.
+    pub fn stm32f446() -> Self {
+        Self::new(&STM32F446_SECTORS)
+    }
+
+    /// The contents of `sector`.
+    pub fn sector(&self, sector: u8) -> &[u8] {
+        &self.sectors[usize::from(sector)]
+    }
+
+    /// Number of times `sector` was erased.
+    pub fn erase_count(&self, sector: u8) -> u32 {
+        self.erases[usize::from(sector)]
+    }
+
+    /// Lets the power fail after `bytes` more bytes were programmed. An erase counts as one byte,
+    /// and one started with a single byte left fails halfway, leaving the second half of the
+    /// sector as it was. Every later access fails with [`FlashError::Operation`] until
+    /// [`RamFlash::restore_power`].
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.budget = Some(bytes);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.budget = None;
+    }
+
+    /// Returns `true` while the power is cut.
+    pub fn is_powered_off(&self) -> bool {
+        self.budget == Some(0)
+    }
+
+    /// Flips the bits of `mask` at `offset` of `sector`, like a failing cell.
+    pub fn corrupt(&mut self, sector: u8, offset: usize, mask: u8) {
+        self.sectors[usize::from(sector)][offset] ^= mask;
+    }
+
+    fn sector_mut(&mut self, sector: u8) -> Result<&mut Vec<u8>, FlashError> {
+        self.sectors
+            .get_mut(usize::from(sector))
+            .ok_or(FlashError::OutOfBounds)
+    }
+}
+
+impl Flash for RamFlash {
+    fn sector_len(&self, sector: u8) -> usize {
+        self.sectors.get(usize::from(sector)).map_or(0, Vec::len)
+    }
+
+    fn read(&self, sector: u8, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        if self.is_powered_off() {
+            return Err(FlashError::Operation);
+        }
+        let bytes = self
+            .sectors
+            .get(usize::from(sector))
+            .ok_or(FlashError::OutOfBounds)?;
+        buf.copy_from_slice(&bytes[range(bytes.len(), offset, buf.len())?]);
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: u8) -> Result<(), FlashError> {
+        let budget = self.budget;
+        let bytes = self.sector_mut(sector)?;
+        match budget {
+            Some(0) => return Err(FlashError::Operation),
+            Some(1) => {
+                let half = bytes.len() / 2;
+                bytes[..half].fill(0xff);
+                self.budget = Some(0);
+                return Err(FlashError::Operation);
+            }
+            _ => bytes.fill(0xff),
+        }
+        self.budget = budget.map(|left| left - 1);
+        self.erases[usize::from(sector)] += 1;
+        Ok(())
+    }
+
+    fn program(&mut self, sector: u8, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let budget = self.budget;
+        let bytes = self.sector_mut(sector)?;
+        let range = range(bytes.len(), offset, data.len())?;
+
+        let written = budget.map_or(data.len(), |left| left.min(data.len()));
+        for (cell, byte) in bytes[range].iter_mut().zip(&data[..written]) {
+            *cell &= byte;
+        }
+        self.budget = budget.map(|left| left - written);
+        if written < data.len() {
+            return Err(FlashError::Operation);
+        }
+        Ok(())
+    }
+}
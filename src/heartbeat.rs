@@ -0,0 +1,337 @@
+//! Heartbeats and a table of the nodes present on a bus.
+//!
+//! Every node broadcasts a [`Heartbeat`] with its node ID, uptime, mode and health flags at a
+//! fixed period, on the ID [`HeartbeatConfig::base_id`] plus its node ID, like the heartbeat of
+//! CANopen. A [`HeartbeatService`] sends the heartbeats of its own node and keeps a presence table
+//! of the others with the MET they were last heard. It reports a [`PresenceEvent`] when a node
+//! joins, and when a node stays silent longer than the timeout.
+//!
+//! ```ignore
+//! let mut heartbeat = HeartbeatService::<16>::new(HeartbeatConfig::new(Channel::Can1, 5), boot);
+//!
+//! // periodically, at the instant it returns
+//! let next = heartbeat.poll(&mut shield, now);
+//! while let Some(event) = heartbeat.expire(now) { ... }
+//! // for every received frame
+//! if let Some(event) = heartbeat.on_frame(channel, &frame, now) { ... }
+//! ```
+
+use bxcan::{Data, Frame, Id, StandardId};
+use defmt::{info, warn, Format};
+use fugit::{MillisDurationU32, TimerDurationU64};
+use heapless::Vec;
+
+use crate::{
+    can_shield::{filters::FilterRule, Channel, Shield},
+    time::Met,
+};
+
+/// Highest node ID. Node IDs are 7 bits wide and 0 is not used.
+pub const MAX_NODE_ID: u8 = 127;
+
+/// Length of an encoded heartbeat.
+const HEARTBEAT_LEN: usize = 8;
+
+/// The heartbeat of one node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Heartbeat {
+    pub node: u8,
+    /// Seconds since the node booted.
+    pub uptime_s: u32,
+    /// Operating mode, defined by the application.
+    pub mode: u8,
+    /// Health flags, defined by the application. 0 means healthy.
+    pub health: u16,
+}
+
+impl Heartbeat {
+    /// The payload: node ID, mode, health flags and uptime, big endian.
+    pub fn encode(&self) -> [u8; HEARTBEAT_LEN] {
+        let mut buf = [0; HEARTBEAT_LEN];
+        buf[0] = self.node;
+        buf[1] = self.mode;
+        buf[2..4].copy_from_slice(&self.health.to_be_bytes());
+        buf[4..].copy_from_slice(&self.uptime_s.to_be_bytes());
+        buf
+    }
+
+    /// Reads a payload, `None` if it has the wrong length or an invalid node ID.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let data: &[u8; HEARTBEAT_LEN] = data.try_into().ok()?;
+        let node = data[0];
+        if node == 0 || node > MAX_NODE_ID {
+            return None;
+        }
+        Some(Self {
+            node,
+            mode: data[1],
+            health: u16::from_be_bytes([data[2], data[3]]),
+            uptime_s: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+}
+
+/// A change of the presence table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PresenceEvent {
+    /// A node was heard for the first time, or again after it timed out.
+    Joined(Heartbeat),
+    /// A node was not heard within the timeout.
+    TimedOut { node: u8 },
+}
+
+/// A node of the presence table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct NodeEntry {
+    /// The last heartbeat of the node.
+    pub heartbeat: Heartbeat,
+    /// MET at which the last heartbeat arrived.
+    pub last_seen: Met,
+    /// `false` once the node timed out, until it is heard again.
+    pub present: bool,
+}
+
+/// Heartbeat counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct HeartbeatStats {
+    /// Heartbeats sent by this node.
+    pub sent: u32,
+    /// Heartbeats not sent because the transmit queue was full.
+    pub tx_errors: u32,
+    /// Heartbeats received from other nodes.
+    pub received: u32,
+    /// Heartbeats of nodes that did not fit into the presence table.
+    pub untracked: u32,
+}
+
+/// Configuration of a [`HeartbeatService`].
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    channel: Channel,
+    node: u8,
+    base_id: u16,
+    period: MillisDurationU32,
+    timeout: MillisDurationU32,
+}
+
+impl HeartbeatConfig {
+    /// Creates a configuration for node `node`, from 1 to [`MAX_NODE_ID`], on `channel`.
+    ///
+    /// Defaults to the CANopen heartbeat IDs from 0x700, a period of 1000 ms and a timeout of
+    /// 3000 ms.
+    pub fn new(channel: Channel, node: u8) -> Self {
+        debug_assert!((1..=MAX_NODE_ID).contains(&node));
+
+        Self {
+            channel,
+            node,
+            base_id: 0x700,
+            period: MillisDurationU32::millis(1000),
+            timeout: MillisDurationU32::millis(3000),
+        }
+    }
+
+    /// ID of node 0. The IDs of the nodes follow it, so its lower 7 bits have to be 0.
+    pub fn base_id(mut self, base_id: u16) -> Self {
+        debug_assert!(base_id & 0x7f == 0 && base_id <= 0x780);
+        self.base_id = base_id;
+        self
+    }
+
+    /// Time between two heartbeats of this node.
+    pub fn period(mut self, period: MillisDurationU32) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Time without a heartbeat after which another node times out. Make it a few periods of
+    /// the slowest node.
+    pub fn timeout(mut self, timeout: MillisDurationU32) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+fn micros(duration: MillisDurationU32) -> TimerDurationU64<1_000_000> {
+    TimerDurationU64::millis(u64::from(duration.to_millis()))
+}
+
+/// Sends the heartbeats of one node and keeps track of up to `N` other nodes.
+pub struct HeartbeatService<const N: usize> {
+    config: HeartbeatConfig,
+    boot: Met,
+    mode: u8,
+    health: u16,
+    next: Option<Met>,
+    nodes: Vec<NodeEntry, N>,
+    stats: HeartbeatStats,
+}
+
+impl<const N: usize> HeartbeatService<N> {
+    /// Creates the service of a node that booted at MET `boot`, which the uptime counts from.
+    pub const fn new(config: HeartbeatConfig, boot: Met) -> Self {
+        Self {
+            config,
+            boot,
+            mode: 0,
+            health: 0,
+            next: None,
+            nodes: Vec::new(),
+            stats: HeartbeatStats {
+                sent: 0,
+                tx_errors: 0,
+                received: 0,
+                untracked: 0,
+            },
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.config.channel
+    }
+
+    pub fn node(&self) -> u8 {
+        self.config.node
+    }
+
+    pub fn stats(&self) -> HeartbeatStats {
+        self.stats
+    }
+
+    /// Sets the mode sent with the next heartbeats.
+    pub fn set_mode(&mut self, mode: u8) {
+        self.mode = mode;
+    }
+
+    /// Sets the health flags sent with the next heartbeats.
+    pub fn set_health(&mut self, health: u16) {
+        self.health = health;
+    }
+
+    /// Acceptance rule letting through the heartbeats of every node.
+    pub fn filter(&self) -> FilterRule {
+        FilterRule::StandardMask {
+            id: StandardId::new(self.config.base_id).expect("11-bit ID"),
+            mask: StandardId::new(0x780).expect("11-bit mask"),
+        }
+    }
+
+    /// The heartbeat of this node at `now`.
+    pub fn heartbeat(&self, now: Met) -> Heartbeat {
+        let uptime = now
+            .checked_duration_since(self.boot)
+            .map_or(0, |uptime| uptime.to_secs());
+        Heartbeat {
+            node: self.config.node,
+            uptime_s: uptime.min(u64::from(u32::MAX)) as u32,
+            mode: self.mode,
+            health: self.health,
+        }
+    }
+
+    /// Sends a heartbeat when it is due. The first one goes out right away.
+    ///
+    /// Returns the MET of the next heartbeat.
+    pub fn poll(&mut self, shield: &mut (impl Shield + ?Sized), now: Met) -> Met {
+        if let Some(next) = self.next.filter(|&next| now < next) {
+            return next;
+        }
+
+        let id =
+            StandardId::new(self.config.base_id + u16::from(self.config.node)).expect("11-bit ID");
+        let data = Data::new(&self.heartbeat(now).encode()).expect("8 bytes");
+        match shield.transmit(self.config.channel, &Frame::new_data(id, data)) {
+            Ok(()) => self.stats.sent += 1,
+            Err(error) => {
+                warn!("{}, heartbeat not sent: {}", self.config.channel, error);
+                self.stats.tx_errors += 1;
+            }
+        }
+
+        let next = now + micros(self.config.period);
+        self.next = Some(next);
+        next
+    }
+
+    /// Handles a frame received on `channel` at `now`. Returns an event if it was the heartbeat
+    /// of a node that joined.
+    ///
+    /// Other frames, heartbeats on other channels and the heartbeats of this node are ignored.
+    pub fn on_frame(&mut self, channel: Channel, frame: &Frame, now: Met) -> Option<PresenceEvent> {
+        if channel != self.config.channel {
+            return None;
+        }
+        let Id::Standard(id) = frame.id() else {
+            return None;
+        };
+        let node = id.as_raw().checked_sub(self.config.base_id)?;
+        if node == 0 || node > u16::from(MAX_NODE_ID) || node == u16::from(self.config.node) {
+            return None;
+        }
+        let heartbeat = Heartbeat::decode(frame.data()?)?;
+        if u16::from(heartbeat.node) != node {
+            return None;
+        }
+        self.stats.received += 1;
+
+        let seen = NodeEntry {
+            heartbeat,
+            last_seen: now,
+            present: true,
+        };
+        let index = self
+            .nodes
+            .iter()
+            .position(|entry| entry.heartbeat.node == heartbeat.node);
+        let joined = match index {
+            Some(index) => !core::mem::replace(&mut self.nodes[index], seen).present,
+            None if self.nodes.push(seen).is_ok() => true,
+            None => {
+                self.stats.untracked += 1;
+                return None;
+            }
+        };
+
+        if !joined {
+            return None;
+        }
+        info!("{}, node {} joined", self.config.channel, heartbeat.node);
+        Some(PresenceEvent::Joined(heartbeat))
+    }
+
+    /// Marks the next node not heard within the timeout at `now` as gone. Call it until it
+    /// returns `None`.
+    pub fn expire(&mut self, now: Met) -> Option<PresenceEvent> {
+        let timeout = micros(self.config.timeout);
+        let entry = self.nodes.iter_mut().find(|entry| {
+            entry.present
+                && now
+                    .checked_duration_since(entry.last_seen)
+                    .is_some_and(|silent| silent > timeout)
+        })?;
+
+        entry.present = false;
+        warn!(
+            "{}, node {} timed out",
+            self.config.channel, entry.heartbeat.node
+        );
+        Some(PresenceEvent::TimedOut {
+            node: entry.heartbeat.node,
+        })
+    }
+
+    /// The nodes heard so far, in the order they joined.
+    pub fn nodes(&self) -> &[NodeEntry] {
+        &self.nodes
+    }
+
+    /// The entry of node `node`, `None` if it was never heard.
+    pub fn entry(&self, node: u8) -> Option<&NodeEntry> {
+        self.nodes.iter().find(|entry| entry.heartbeat.node == node)
+    }
+
+    /// Returns `true` if node `node` is present.
+    pub fn is_present(&self, node: u8) -> bool {
+        self.entry(node).is_some_and(|entry| entry.present)
+    }
+}
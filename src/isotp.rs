@@ -0,0 +1,698 @@
+//! ISO-TP (ISO 15765-2) transport for messages of up to 4095 bytes.
+//!
+//! Messages of up to 7 bytes go out in a single frame. Longer ones start with a first frame
+//! carrying the length, after which the receiver answers with a flow control frame telling the
+//! sender how many consecutive frames it may send (block size) and how far apart (STmin):
+//!
+//! ```text
+//! sender                       receiver
+//!   | -- first frame (FF) -------> |
+//!   | <------ flow control (FC) -- |
+//!   | -- consecutive frame (CF) -> |
+//!   | -- consecutive frame (CF) -> |  block size reached
+//!   | <------ flow control (FC) -- |
+//!   | -- consecutive frame (CF) -> |
+//! ```
+//!
+//! An [`IsoTpLink`] handles one pair of IDs on one channel, sending and receiving at the same
+//! time. Feed it the frames received on its channel with [`IsoTpLink::on_frame`] and call
+//! [`IsoTpLink::poll`] from an RTIC task at the instant it returns, and after every TX
+//! interrupt of the channel. The link supervises the timeouts of the standard:
+//!
+//! * N_As, a frame of the sender has not left the mailboxes in time.
+//! * N_Bs, the sender has not received a flow control frame in time.
+//! * N_Cr, the receiver has not received the next consecutive frame in time.
+
+use bxcan::{Data, Frame, Id};
+use defmt::{warn, Format};
+use fugit::{MillisDurationU32, TimerDurationU32, TimerInstantU32};
+use heapless::Vec;
+
+use crate::can_shield::{Channel, Shield};
+
+/// Longest message, limited by the 12-bit length of the first frame.
+pub const MAX_MESSAGE_LEN: usize = 4095;
+
+/// Longest message that fits into a single frame.
+const SINGLE_FRAME_LEN: usize = 7;
+
+/// Payload bytes of a first frame.
+const FIRST_FRAME_LEN: usize = 6;
+
+/// Payload bytes of a consecutive frame.
+const CONSECUTIVE_FRAME_LEN: usize = 7;
+
+/// Minimum separation time between consecutive frames, as encoded in a flow control frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct StMin(u8);
+
+impl StMin {
+    /// `ms` milliseconds, at most 127.
+    pub const fn millis(ms: u8) -> Self {
+        Self(if ms > 0x7f { 0x7f } else { ms })
+    }
+
+    /// `n` times 100 µs, with `n` from 1 to 9. Larger values give 1 ms.
+    pub const fn hundred_micros(n: u8) -> Self {
+        match n {
+            0 => Self(0),
+            1..=9 => Self(0xf0 + n),
+            _ => Self(1),
+        }
+    }
+
+    pub const fn from_raw(raw: u8) -> Self {
+        Self(raw)
+    }
+
+    pub const fn to_raw(self) -> u8 {
+        self.0
+    }
+
+    /// The separation time in microseconds. Reserved values mean the longest time, 127 ms.
+    pub const fn to_micros(self) -> u32 {
+        match self.0 {
+            0..=0x7f => self.0 as u32 * 1000,
+            0xf1..=0xf9 => (self.0 - 0xf0) as u32 * 100,
+            _ => 127_000,
+        }
+    }
+}
+
+/// Flow status of a flow control frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FlowStatus {
+    /// Send the next block.
+    ContinueToSend,
+    /// Wait for another flow control frame.
+    Wait,
+    /// The message is too long for the receiver, abort.
+    Overflow,
+}
+
+/// Protocol control information, the frame type and its parameters in the first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Pci {
+    Single {
+        len: u8,
+    },
+    First {
+        len: u16,
+    },
+    Consecutive {
+        sequence: u8,
+    },
+    FlowControl {
+        status: FlowStatus,
+        block_size: u8,
+        st_min: StMin,
+    },
+}
+
+impl Pci {
+    /// Decodes the PCI of a frame payload. Returns `None` for frames that are not valid ISO-TP
+    /// frames, these are ignored.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let first = *data.first()?;
+        match first >> 4 {
+            0 => {
+                let len = first & 0x0f;
+                (len > 0 && usize::from(len) < data.len()).then_some(Pci::Single { len })
+            }
+            1 if data.len() == 8 => {
+                let len = u16::from(first & 0x0f) << 8 | u16::from(data[1]);
+                // Shorter messages have to use a single frame.
+                (usize::from(len) > SINGLE_FRAME_LEN).then_some(Pci::First { len })
+            }
+            2 => Some(Pci::Consecutive {
+                sequence: first & 0x0f,
+            }),
+            3 if data.len() >= 3 => {
+                let status = match first & 0x0f {
+                    0 => FlowStatus::ContinueToSend,
+                    1 => FlowStatus::Wait,
+                    2 => FlowStatus::Overflow,
+                    _ => return None,
+                };
+                Some(Pci::FlowControl {
+                    status,
+                    block_size: data[1],
+                    st_min: StMin::from_raw(data[2]),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The timeouts supervised by a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Timeout {
+    /// A frame of the sender did not leave the mailboxes.
+    As,
+    /// The sender did not receive a flow control frame.
+    Bs,
+    /// The receiver did not receive the next consecutive frame.
+    Cr,
+}
+
+/// Reasons a message is not sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum IsoTpError {
+    /// The link is still sending the previous message.
+    Busy,
+    /// The message is longer than [`MAX_MESSAGE_LEN`] or empty.
+    InvalidLength,
+    /// The transmit queue of the channel is full.
+    TxQueueFull,
+    /// A timeout expired, the message was aborted.
+    Timeout(Timeout),
+    /// A consecutive frame with the wrong sequence number arrived, the message was dropped.
+    WrongSequence,
+    /// A consecutive frame carried fewer bytes than the message still needed, the message was
+    /// dropped.
+    ShortFrame,
+    /// A new message started before the last one was complete, the last one was dropped.
+    Interrupted,
+    /// The receiver reported an overflow, the message was aborted.
+    Overflow,
+    /// The receiver sent more wait frames than allowed, the message was aborted.
+    TooManyWaits,
+}
+
+/// Counters of an [`IsoTpLink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct IsoTpStats {
+    /// Messages sent completely.
+    pub sent: u32,
+    /// Messages received completely.
+    pub received: u32,
+    /// Messages aborted or dropped with an error.
+    pub errors: u32,
+}
+
+/// Configuration of an [`IsoTpLink`].
+#[derive(Debug, Clone, Copy)]
+pub struct IsoTpConfig {
+    channel: Channel,
+    tx_id: Id,
+    rx_id: Id,
+    block_size: u8,
+    st_min: StMin,
+    n_as: MillisDurationU32,
+    n_bs: MillisDurationU32,
+    n_cr: MillisDurationU32,
+    max_waits: u8,
+    padding: Option<u8>,
+}
+
+impl IsoTpConfig {
+    /// Creates a configuration sending with `tx_id` and receiving with `rx_id` on `channel`.
+    ///
+    /// Defaults to a block size of 0 (no flow control after the first frame), no separation
+    /// time, 1000 ms for every timeout, at most 10 wait frames and frames padded with `0xCC`.
+    pub fn new(channel: Channel, tx_id: impl Into<Id>, rx_id: impl Into<Id>) -> Self {
+        Self {
+            channel,
+            tx_id: tx_id.into(),
+            rx_id: rx_id.into(),
+            block_size: 0,
+            st_min: StMin::millis(0),
+            n_as: MillisDurationU32::millis(1000),
+            n_bs: MillisDurationU32::millis(1000),
+            n_cr: MillisDurationU32::millis(1000),
+            max_waits: 10,
+            padding: Some(0xcc),
+        }
+    }
+
+    /// Consecutive frames the sender may send before waiting for the next flow control frame,
+    /// 0 for all of them.
+    pub fn block_size(mut self, block_size: u8) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Minimum time the sender has to leave between consecutive frames.
+    pub fn st_min(mut self, st_min: StMin) -> Self {
+        self.st_min = st_min;
+        self
+    }
+
+    /// Time a frame may take to leave the mailboxes (N_As).
+    pub fn n_as(mut self, timeout: MillisDurationU32) -> Self {
+        self.n_as = timeout;
+        self
+    }
+
+    /// Time the sender waits for a flow control frame (N_Bs).
+    pub fn n_bs(mut self, timeout: MillisDurationU32) -> Self {
+        self.n_bs = timeout;
+        self
+    }
+
+    /// Time the receiver waits for the next consecutive frame (N_Cr).
+    pub fn n_cr(mut self, timeout: MillisDurationU32) -> Self {
+        self.n_cr = timeout;
+        self
+    }
+
+    /// Wait frames accepted in a row before the sender gives up.
+    pub fn max_waits(mut self, max_waits: u8) -> Self {
+        self.max_waits = max_waits;
+        self
+    }
+
+    /// Pads every frame to 8 bytes with `byte`, or sends only the used bytes with `None`.
+    pub fn padding(mut self, byte: Option<u8>) -> Self {
+        self.padding = byte;
+        self
+    }
+}
+
+/// What the sender does after a frame has left the mailboxes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AfterConfirm {
+    Done,
+    FlowControl,
+    Consecutive,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TxState<const HZ: u32> {
+    Idle,
+    /// A frame is queued and has to be sent before `deadline` (N_As).
+    Confirm {
+        deadline: TimerInstantU32<HZ>,
+        next: AfterConfirm,
+    },
+    /// Waiting for a flow control frame until `deadline` (N_Bs).
+    FlowControl {
+        deadline: TimerInstantU32<HZ>,
+    },
+    /// The next consecutive frame may be sent at `at`.
+    Consecutive {
+        at: TimerInstantU32<HZ>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RxState<const HZ: u32> {
+    Idle,
+    /// Waiting for the next consecutive frame until `deadline` (N_Cr).
+    Receiving {
+        deadline: TimerInstantU32<HZ>,
+    },
+}
+
+/// Sends and receives ISO-TP messages on one pair of IDs.
+///
+/// `HZ` is the tick rate of the instants passed in, usually the RTIC monotonic.
+pub struct IsoTpLink<const HZ: u32> {
+    config: IsoTpConfig,
+    stats: IsoTpStats,
+
+    tx: Vec<u8, MAX_MESSAGE_LEN>,
+    tx_offset: usize,
+    tx_sequence: u8,
+    /// Consecutive frames left in the block, `None` for no limit.
+    tx_block_left: Option<u8>,
+    tx_st_min: StMin,
+    tx_waits: u8,
+    tx_state: TxState<HZ>,
+
+    rx: Vec<u8, MAX_MESSAGE_LEN>,
+    rx_len: usize,
+    rx_sequence: u8,
+    rx_block_left: u8,
+    rx_state: RxState<HZ>,
+}
+
+impl<const HZ: u32> IsoTpLink<HZ> {
+    pub const fn new(config: IsoTpConfig) -> Self {
+        Self {
+            config,
+            stats: IsoTpStats {
+                sent: 0,
+                received: 0,
+                errors: 0,
+            },
+            tx: Vec::new(),
+            tx_offset: 0,
+            tx_sequence: 0,
+            tx_block_left: None,
+            tx_st_min: StMin(0),
+            tx_waits: 0,
+            tx_state: TxState::Idle,
+            rx: Vec::new(),
+            rx_len: 0,
+            rx_sequence: 0,
+            rx_block_left: 0,
+            rx_state: RxState::Idle,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.config.channel
+    }
+
+    pub fn stats(&self) -> IsoTpStats {
+        self.stats
+    }
+
+    /// Returns `true` while a message is being sent.
+    pub fn is_sending(&self) -> bool {
+        !matches!(self.tx_state, TxState::Idle)
+    }
+
+    /// Returns `true` while a message is being received.
+    pub fn is_receiving(&self) -> bool {
+        !matches!(self.rx_state, RxState::Idle)
+    }
+
+    /// Starts sending `data`. Messages of up to 7 bytes go out at once, longer ones are sent
+    /// by [`IsoTpLink::poll`] as the receiver allows.
+    pub fn send(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        data: &[u8],
+        now: TimerInstantU32<HZ>,
+    ) -> Result<(), IsoTpError> {
+        if self.is_sending() {
+            return Err(IsoTpError::Busy);
+        }
+        if data.is_empty() || data.len() > MAX_MESSAGE_LEN {
+            return Err(IsoTpError::InvalidLength);
+        }
+
+        let mut buf = [0; 8];
+        let (len, next) = if data.len() <= SINGLE_FRAME_LEN {
+            buf[0] = data.len() as u8;
+            buf[1..=data.len()].copy_from_slice(data);
+            (data.len() + 1, AfterConfirm::Done)
+        } else {
+            buf[0] = 0x10 | (data.len() >> 8) as u8;
+            buf[1] = data.len() as u8;
+            buf[2..].copy_from_slice(&data[..FIRST_FRAME_LEN]);
+            (8, AfterConfirm::FlowControl)
+        };
+        self.transmit(shield, &buf[..len])?;
+
+        // `data` is at most MAX_MESSAGE_LEN long.
+        self.tx.clear();
+        self.tx.extend_from_slice(data).ok();
+        self.tx_offset = match next {
+            AfterConfirm::Done => data.len(),
+            _ => FIRST_FRAME_LEN,
+        };
+        self.tx_sequence = 1;
+        self.tx_waits = 0;
+        self.tx_state = TxState::Confirm {
+            deadline: now + ticks(self.config.n_as),
+            next,
+        };
+        Ok(())
+    }
+
+    /// Handles a frame received on the channel of the link. Returns a message once it is
+    /// complete, it stays valid until the next call.
+    ///
+    /// Frames with other IDs and invalid frames are ignored.
+    pub fn on_frame(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        frame: &Frame,
+        now: TimerInstantU32<HZ>,
+    ) -> Result<Option<&[u8]>, IsoTpError> {
+        if frame.id() != self.config.rx_id {
+            return Ok(None);
+        }
+        let Some(data) = frame.data() else {
+            return Ok(None);
+        };
+        let Some(pci) = Pci::decode(data) else {
+            return Ok(None);
+        };
+
+        match pci {
+            Pci::Single { len } => {
+                let interrupted = self.is_receiving();
+                self.rx.clear();
+                self.rx.extend_from_slice(&data[1..=usize::from(len)]).ok();
+                self.rx_state = RxState::Idle;
+                self.complete(interrupted)
+            }
+            Pci::First { len } => {
+                let interrupted = self.is_receiving();
+                self.rx.clear();
+                self.rx.extend_from_slice(&data[2..]).ok();
+                self.rx_len = usize::from(len);
+                self.rx_sequence = 1;
+                self.rx_block_left = self.config.block_size;
+                self.rx_state = RxState::Receiving {
+                    deadline: now + ticks(self.config.n_cr),
+                };
+                self.flow_control(shield)?;
+
+                if interrupted {
+                    return self.fail(IsoTpError::Interrupted);
+                }
+                Ok(None)
+            }
+            Pci::Consecutive { sequence } => {
+                // Consecutive frames outside of a message are ignored.
+                if !self.is_receiving() {
+                    return Ok(None);
+                }
+                if sequence != self.rx_sequence {
+                    self.rx_state = RxState::Idle;
+                    return self.fail(IsoTpError::WrongSequence);
+                }
+
+                let count = (self.rx_len - self.rx.len()).min(CONSECUTIVE_FRAME_LEN);
+                let Some(payload) = data.get(1..=count) else {
+                    self.rx_state = RxState::Idle;
+                    return self.fail(IsoTpError::ShortFrame);
+                };
+                self.rx.extend_from_slice(payload).ok();
+                self.rx_sequence = (self.rx_sequence + 1) & 0x0f;
+
+                if self.rx.len() >= self.rx_len {
+                    self.rx_state = RxState::Idle;
+                    return self.complete(false);
+                }
+
+                self.rx_state = RxState::Receiving {
+                    deadline: now + ticks(self.config.n_cr),
+                };
+                if self.config.block_size > 0 {
+                    self.rx_block_left -= 1;
+                    if self.rx_block_left == 0 {
+                        self.rx_block_left = self.config.block_size;
+                        self.flow_control(shield)?;
+                    }
+                }
+                Ok(None)
+            }
+            Pci::FlowControl {
+                status,
+                block_size,
+                st_min,
+            } => {
+                // Flow control frames are only expected after the first frame or a block. The
+                // answer can be faster than the confirmation of the frame it answers.
+                match self.tx_state {
+                    TxState::FlowControl { .. }
+                    | TxState::Confirm {
+                        next: AfterConfirm::FlowControl,
+                        ..
+                    } => {}
+                    _ => return Ok(None),
+                }
+
+                match status {
+                    FlowStatus::ContinueToSend => {
+                        self.tx_block_left = (block_size > 0).then_some(block_size);
+                        self.tx_st_min = st_min;
+                        self.tx_waits = 0;
+                        self.tx_state = TxState::Consecutive { at: now };
+                        Ok(None)
+                    }
+                    FlowStatus::Wait => {
+                        self.tx_waits += 1;
+                        if self.tx_waits > self.config.max_waits {
+                            self.tx_state = TxState::Idle;
+                            return self.fail(IsoTpError::TooManyWaits);
+                        }
+                        self.tx_state = TxState::FlowControl {
+                            deadline: now + ticks(self.config.n_bs),
+                        };
+                        Ok(None)
+                    }
+                    FlowStatus::Overflow => {
+                        self.tx_state = TxState::Idle;
+                        self.fail(IsoTpError::Overflow)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the next consecutive frame when it is due and checks the timeouts.
+    ///
+    /// Returns the instant of the next call, `None` if the link is idle. After an error, call
+    /// it again right away.
+    pub fn poll(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        now: TimerInstantU32<HZ>,
+    ) -> Result<Option<TimerInstantU32<HZ>>, IsoTpError> {
+        if let RxState::Receiving { deadline } = self.rx_state {
+            if now >= deadline {
+                self.rx_state = RxState::Idle;
+                return self.fail(Timeout::Cr.into());
+            }
+        }
+
+        if let TxState::Confirm { deadline, next } = self.tx_state {
+            let channel = self.config.channel;
+            let sent = shield.tx_pending(channel) == 0
+                && shield.channel(channel).status().transmitter_idle;
+
+            if sent {
+                self.tx_state = match next {
+                    AfterConfirm::Done => {
+                        self.stats.sent += 1;
+                        TxState::Idle
+                    }
+                    AfterConfirm::FlowControl => TxState::FlowControl {
+                        deadline: now + ticks(self.config.n_bs),
+                    },
+                    AfterConfirm::Consecutive => TxState::Consecutive {
+                        at: now + TimerDurationU32::<HZ>::micros(self.tx_st_min.to_micros()),
+                    },
+                };
+            } else if now >= deadline {
+                self.tx_state = TxState::Idle;
+                return self.fail(Timeout::As.into());
+            }
+        }
+
+        match self.tx_state {
+            TxState::FlowControl { deadline } if now >= deadline => {
+                self.tx_state = TxState::Idle;
+                return self.fail(Timeout::Bs.into());
+            }
+            TxState::Consecutive { at } if now >= at => self.send_consecutive(shield, now)?,
+            _ => {}
+        }
+
+        let tx_deadline = match self.tx_state {
+            TxState::Idle => None,
+            TxState::Confirm { deadline, .. } | TxState::FlowControl { deadline } => Some(deadline),
+            TxState::Consecutive { at } => Some(at),
+        };
+        let rx_deadline = match self.rx_state {
+            RxState::Idle => None,
+            RxState::Receiving { deadline } => Some(deadline),
+        };
+
+        Ok(match (tx_deadline, rx_deadline) {
+            (Some(tx), Some(rx)) => Some(tx.min(rx)),
+            (tx, rx) => tx.or(rx),
+        })
+    }
+
+    fn send_consecutive(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        now: TimerInstantU32<HZ>,
+    ) -> Result<(), IsoTpError> {
+        let count = (self.tx.len() - self.tx_offset).min(CONSECUTIVE_FRAME_LEN);
+        let mut buf = [0; 8];
+        buf[0] = 0x20 | self.tx_sequence;
+        buf[1..=count].copy_from_slice(&self.tx[self.tx_offset..self.tx_offset + count]);
+
+        if let Err(error) = self.transmit(shield, &buf[..=count]) {
+            self.tx_state = TxState::Idle;
+            return self.fail(error);
+        }
+
+        self.tx_offset += count;
+        self.tx_sequence = (self.tx_sequence + 1) & 0x0f;
+        if let Some(left) = &mut self.tx_block_left {
+            *left -= 1;
+        }
+
+        let next = if self.tx_offset == self.tx.len() {
+            AfterConfirm::Done
+        } else if self.tx_block_left == Some(0) {
+            AfterConfirm::FlowControl
+        } else {
+            AfterConfirm::Consecutive
+        };
+        self.tx_state = TxState::Confirm {
+            deadline: now + ticks(self.config.n_as),
+            next,
+        };
+        Ok(())
+    }
+
+    /// Sends a flow control frame letting the sender continue.
+    fn flow_control(&mut self, shield: &mut (impl Shield + ?Sized)) -> Result<(), IsoTpError> {
+        let buf = [0x30, self.config.block_size, self.config.st_min.to_raw()];
+        if let Err(error) = self.transmit(shield, &buf) {
+            self.rx_state = RxState::Idle;
+            return self.fail(error);
+        }
+        Ok(())
+    }
+
+    fn transmit(
+        &self,
+        shield: &mut (impl Shield + ?Sized),
+        payload: &[u8],
+    ) -> Result<(), IsoTpError> {
+        let mut buf = [self.config.padding.unwrap_or(0); 8];
+        buf[..payload.len()].copy_from_slice(payload);
+        let len = if self.config.padding.is_some() {
+            8
+        } else {
+            payload.len()
+        };
+
+        let data = Data::new(&buf[..len]).expect("at most 8 bytes");
+        shield
+            .transmit(
+                self.config.channel,
+                &Frame::new_data(self.config.tx_id, data),
+            )
+            .map_err(|_| IsoTpError::TxQueueFull)
+    }
+
+    /// Returns the received message, counting the unfinished one it replaced as an error.
+    fn complete(&mut self, interrupted: bool) -> Result<Option<&[u8]>, IsoTpError> {
+        if interrupted {
+            warn!("{}, ISO-TP message interrupted", self.config.channel);
+            self.stats.errors += 1;
+        }
+        self.stats.received += 1;
+        Ok(Some(&self.rx))
+    }
+
+    fn fail<T>(&mut self, error: IsoTpError) -> Result<T, IsoTpError> {
+        warn!("{}, ISO-TP message aborted: {}", self.config.channel, error);
+        self.stats.errors += 1;
+        Err(error)
+    }
+}
+
+impl From<Timeout> for IsoTpError {
+    fn from(timeout: Timeout) -> Self {
+        Self::Timeout(timeout)
+    }
+}
+
+fn ticks<const HZ: u32>(duration: MillisDurationU32) -> TimerDurationU32<HZ> {
+    TimerDurationU32::<HZ>::millis(duration.to_millis())
+}
@@ -0,0 +1,297 @@
+//! Operating modes of a node and the transitions between them.
+//!
+//! A node runs in [`Mode::Nominal`], falls back to [`Mode::Safe`] when something is wrong and
+//! goes to [`Mode::Maintenance`] for work from the ground. The [`ModeManager`] follows a table of
+//! [`Transition`]s: ground requests some of them through the
+//! [mode management service](crate::pus::mode), and the others are taken when their
+//! [`Condition`] holds. [`ModeActions`] start and stop what runs in a mode, and every transition
+//! leaves a [`ModeChange`] for the application to report as an event:
+//!
+//! ```ignore
+//! const MODES: &[Transition] = &[
+//!     Transition::request(Mode::Safe, Mode::Nominal),
+//!     Transition::when(Mode::Nominal, Mode::Safe, Condition::BusOff),
+//! ];
+//! let mut modes = ModeManager::new(MODES, Mode::Nominal);
+//!
+//! // periodically
+//! modes.update(&status, &mut actions);
+//! while let Some(change) = modes.pop_change() { ... }
+//! ```
+
+use defmt::Format;
+use heapless::Deque;
+
+use crate::{can_shield::health::BusState, pus::event::Severity};
+
+/// Most mode changes waiting to be reported.
+pub const MAX_PENDING: usize = 4;
+
+/// Length of [`ModeChange::to_bytes`].
+pub const CHANGE_LEN: usize = 3;
+
+/// An operating mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Mode {
+    Nominal = 1,
+    /// Only what keeps the node alive and in contact with the ground runs.
+    Safe = 2,
+    /// Ground works on the node.
+    Maintenance = 3,
+}
+
+impl Mode {
+    pub const fn from_u8(mode: u8) -> Option<Self> {
+        match mode {
+            1 => Some(Mode::Nominal),
+            2 => Some(Mode::Safe),
+            3 => Some(Mode::Maintenance),
+            _ => None,
+        }
+    }
+}
+
+/// What the application knows about the node, for the conditions of the transitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Status {
+    /// Boots in a row without a settled run, see [`Retained::settle`](crate::reset::Retained::settle).
+    pub boots: u32,
+    /// Bus state of CAN1 and CAN2.
+    pub bus: [BusState; 2],
+}
+
+/// A condition of an automatic transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Condition {
+    /// At least this many boots in a row.
+    Boots(u32),
+    /// Both CAN channels are bus-off.
+    BusOff,
+}
+
+impl Condition {
+    pub fn holds(&self, status: &Status) -> bool {
+        match *self {
+            Condition::Boots(boots) => status.boots >= boots,
+            Condition::BusOff => status.bus == [BusState::BusOff; 2],
+        }
+    }
+
+    /// Code of the condition in a [`ModeChange`], from 1.
+    pub const fn code(&self) -> u8 {
+        match self {
+            Condition::Boots(_) => 1,
+            Condition::BusOff => 2,
+        }
+    }
+}
+
+/// What makes a transition happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Trigger {
+    /// A request of the ground.
+    Request,
+    /// The condition holds.
+    When(Condition),
+    /// A recovery action of [FDIR](crate::fdir).
+    Recovery,
+}
+
+/// A transition of the table of a [`ModeManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Transition {
+    pub from: Mode,
+    pub to: Mode,
+    pub trigger: Trigger,
+}
+
+impl Transition {
+    /// A transition the ground can request.
+    pub const fn request(from: Mode, to: Mode) -> Self {
+        Self {
+            from,
+            to,
+            trigger: Trigger::Request,
+        }
+    }
+
+    /// A transition taken as soon as `condition` holds.
+    pub const fn when(from: Mode, to: Mode, condition: Condition) -> Self {
+        Self {
+            from,
+            to,
+            trigger: Trigger::When(condition),
+        }
+    }
+}
+
+/// A transition that was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ModeChange {
+    pub from: Mode,
+    pub to: Mode,
+    pub trigger: Trigger,
+}
+
+impl ModeChange {
+    /// Severity of the event: medium when a condition or a recovery forced the node into safe
+    /// mode.
+    pub fn severity(&self) -> Severity {
+        match (self.to, self.trigger) {
+            (Mode::Safe, Trigger::When(_) | Trigger::Recovery) => Severity::Medium,
+            _ => Severity::Informative,
+        }
+    }
+
+    /// The auxiliary data of the event: the old and the new mode, and the code of the condition,
+    /// 0 for a request and 0xff for a recovery.
+    pub fn to_bytes(&self) -> [u8; CHANGE_LEN] {
+        let cause = match self.trigger {
+            Trigger::Request => 0,
+            Trigger::When(condition) => condition.code(),
+            Trigger::Recovery => 0xff,
+        };
+        [self.from as u8, self.to as u8, cause]
+    }
+}
+
+/// Errors of the mode manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ModeError {
+    /// The table has no requested transition from the current mode to this one.
+    NotAllowed(Mode),
+}
+
+/// What the application does when the node leaves and enters a mode, like starting and stopping
+/// tasks.
+pub trait ModeActions {
+    /// Called before the node leaves `mode`.
+    fn exit(&mut self, mode: Mode);
+
+    /// Called once the node is in `mode`.
+    fn enter(&mut self, mode: Mode);
+}
+
+/// Mode manager counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct ModeStats {
+    /// Transitions taken.
+    pub transitions: u32,
+    /// Requests and recoveries without a transition in the table.
+    pub rejected: u32,
+    /// Mode changes not reported because too many were waiting.
+    pub dropped: u32,
+}
+
+/// Keeps the mode of the node and takes the transitions of its table.
+pub struct ModeManager<'t> {
+    table: &'t [Transition],
+    mode: Mode,
+    changes: Deque<ModeChange, MAX_PENDING>,
+    stats: ModeStats,
+}
+
+impl<'t> ModeManager<'t> {
+    /// Creates the manager of a node that starts in `initial`, without calling its entry actions.
+    pub const fn new(table: &'t [Transition], initial: Mode) -> Self {
+        Self {
+            table,
+            mode: initial,
+            changes: Deque::new(),
+            stats: ModeStats {
+                transitions: 0,
+                rejected: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn stats(&self) -> ModeStats {
+        self.stats
+    }
+
+    /// Whether the ground may request `to` in the current mode. The current mode itself is
+    /// always allowed.
+    pub fn allows(&self, to: Mode) -> bool {
+        to == self.mode
+            || self.table.iter().any(|transition| {
+                (transition.from, transition.to, transition.trigger)
+                    == (self.mode, to, Trigger::Request)
+            })
+    }
+
+    /// Goes to `to` at the request of the ground. Requesting the current mode does nothing.
+    pub fn request(
+        &mut self,
+        to: Mode,
+        actions: &mut (impl ModeActions + ?Sized),
+    ) -> Result<(), ModeError> {
+        self.go(to, Trigger::Request, actions)
+    }
+
+    /// Goes to `to` as a recovery action, over a transition the ground could request.
+    pub fn recover(
+        &mut self,
+        to: Mode,
+        actions: &mut (impl ModeActions + ?Sized),
+    ) -> Result<(), ModeError> {
+        self.go(to, Trigger::Recovery, actions)
+    }
+
+    /// Takes the first transition from the current mode whose condition holds, if any. Returns
+    /// whether the mode changed.
+    pub fn update(&mut self, status: &Status, actions: &mut (impl ModeActions + ?Sized)) -> bool {
+        let taken = self.table.iter().find(|transition| {
+            transition.from == self.mode
+                && matches!(transition.trigger, Trigger::When(condition) if condition.holds(status))
+        });
+        match taken {
+            Some(transition) => {
+                self.change(transition.to, transition.trigger, actions);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The oldest mode change that was not reported yet.
+    pub fn pop_change(&mut self) -> Option<ModeChange> {
+        self.changes.pop_front()
+    }
+
+    fn go(
+        &mut self,
+        to: Mode,
+        trigger: Trigger,
+        actions: &mut (impl ModeActions + ?Sized),
+    ) -> Result<(), ModeError> {
+        if !self.allows(to) {
+            self.stats.rejected += 1;
+            return Err(ModeError::NotAllowed(to));
+        }
+        if to != self.mode {
+            self.change(to, trigger, actions);
+        }
+        Ok(())
+    }
+
+    fn change(&mut self, to: Mode, trigger: Trigger, actions: &mut (impl ModeActions + ?Sized)) {
+        let from = self.mode;
+        actions.exit(from);
+        self.mode = to;
+        actions.enter(to);
+
+        self.stats.transitions += 1;
+        if self
+            .changes
+            .push_back(ModeChange { from, to, trigger })
+            .is_err()
+        {
+            self.stats.dropped += 1;
+        }
+    }
+}
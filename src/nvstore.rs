@@ -0,0 +1,535 @@
+//! A wear-levelled store for configuration parameters in two flash sectors.
+//!
+//! The application defines its parameters with their IDs and defaults. Values that differ from
+//! the default are appended as records to the active sector, the last record of a parameter
+//! wins. When the active sector is full, the current values are copied to the other sector,
+//! which becomes the active one, and the full sector is erased. Each sector is thus erased once
+//! per fill, and both wear evenly.
+//!
+//! Any of [`ParamStore::set`], [`ParamStore::reset`] and [`ParamStore::reset_all`] may erase a
+//! sector. On the 128 KiB sectors of the internal flash that stalls the CPU for up to 4 s, see
+//! [`crate::flash`].
+//!
+//! A sector starts with a header: a magic number, a generation counting the copies, and a byte
+//! that is programmed last to mark the sector active. A record holds the parameter ID, the type
+//! and length of the value, the value, and a CRC-16 over all of it:
+//!
+//! ```text
+//! header: | "NVP1" | generation (u32) | active (0x00) | 0xFF x 7 |
+//! record: | ID (u16) | type | length | value | CRC-16 |
+//! ```
+//!
+//! Every step can be cut off by a power loss without losing more than the write in progress. A
+//! torn record fails its CRC and is skipped. A copy that did not finish leaves a sector that was
+//! never marked active, which [`ParamStore::mount`] erases. If the power fails before the full
+//! sector is erased, both are active and the one with the newer generation wins.
+//!
+//! Parameters can be limited to a range. Values outside of it are rejected, and stored ones that
+//! are no longer valid read as the default.
+//!
+//! ```ignore
+//! const DEFINITIONS: &[ParameterDef] = &[
+//!     ParameterDef::new(NODE_ID, Value::U8(0x10)).range(Value::U8(1), Value::U8(127)),
+//!     ParameterDef::new(HEARTBEAT_MS, Value::U16(1000)),
+//! ];
+//! let mut store = ParamStore::<2>::new([6, 7], DEFINITIONS);
+//! store.mount(&mut flash)?;
+//! store.set(&mut flash, HEARTBEAT_MS, Value::U16(500))?;
+//! ```
+
+use defmt::{warn, Format};
+use heapless::Vec;
+
+use crate::{
+    flash::{crc16, Flash, FlashError},
+    pus::housekeeping::{ParameterSource, Value},
+};
+
+const MAGIC: [u8; 4] = *b"NVP1";
+
+/// Length of a sector header.
+const HEADER_LEN: usize = 16;
+
+/// Offset of the byte marking a sector active.
+const ACTIVE_OFFSET: usize = 8;
+
+/// ID, type and length of a record.
+const RECORD_HEADER_LEN: usize = 4;
+
+const CRC_LEN: usize = 2;
+
+/// Longest value, a 32-bit one.
+const MAX_VALUE_LEN: usize = 4;
+
+const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + MAX_VALUE_LEN + CRC_LEN;
+
+/// Type of a record that returns its parameter to the default.
+const TOMBSTONE: u8 = 0x80;
+
+/// Errors of the parameter store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum StoreError {
+    /// There is no parameter with this ID.
+    UnknownParameter(u16),
+    /// The value has a different type than the default of the parameter.
+    TypeMismatch(u16),
+    /// The value is outside of the range of the parameter.
+    OutOfRange(u16),
+    /// The store has not been mounted.
+    NotMounted,
+    /// A sector is too small for the values of every parameter.
+    SectorTooSmall,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for StoreError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+/// A parameter and its default, which also fixes its type.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct ParameterDef {
+    pub id: u16,
+    pub default: Value,
+    /// Smallest and largest valid value, both included. `None` accepts every value of the type.
+    pub range: Option<(Value, Value)>,
+}
+
+impl ParameterDef {
+    pub const fn new(id: u16, default: Value) -> Self {
+        Self {
+            id,
+            default,
+            range: None,
+        }
+    }
+
+    /// Only accepts values from `min` to `max`, which have the type of the default.
+    pub const fn range(mut self, min: Value, max: Value) -> Self {
+        self.range = Some((min, max));
+        self
+    }
+
+    /// Checks that `value` has the type of the default and is in the range.
+    pub fn check(&self, value: Value) -> Result<(), StoreError> {
+        if value.type_code() != self.default.type_code() {
+            return Err(StoreError::TypeMismatch(self.id));
+        }
+        match self.range {
+            // A NaN compares false and is out of every range
+            Some((min, max)) if !(min <= value && value <= max) => {
+                Err(StoreError::OutOfRange(self.id))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A parameter and its current value.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Entry {
+    pub id: u16,
+    pub value: Value,
+    /// The value is stored, rather than the default.
+    pub stored: bool,
+}
+
+/// Counters of a parameter store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct StoreStats {
+    /// Records appended.
+    pub writes: u32,
+    /// Copies to the other sector.
+    pub compactions: u32,
+    /// Records skipped on mount because their CRC failed.
+    pub corrupted: u32,
+}
+
+/// State of a sector from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SectorState {
+    Erased,
+    /// A copy into the sector started and did not finish.
+    Receiving,
+    Active {
+        generation: u32,
+    },
+    Invalid,
+}
+
+/// Keeps up to `N` parameters in two flash sectors of equal length.
+pub struct ParamStore<const N: usize> {
+    sectors: [u8; 2],
+    definitions: &'static [ParameterDef],
+    values: Vec<(u16, Value), N>,
+    /// Index of the active sector in `sectors`, `None` until mounted.
+    active: Option<usize>,
+    generation: u32,
+    /// Where the next record goes in the active sector.
+    offset: usize,
+    stats: StoreStats,
+}
+
+impl<const N: usize> ParamStore<N> {
+    /// A store in the flash sectors `sectors` for the parameters `definitions`, at most `N` of
+    /// them. It has to be mounted before use.
+    pub const fn new(sectors: [u8; 2], definitions: &'static [ParameterDef]) -> Self {
+        Self {
+            sectors,
+            definitions,
+            values: Vec::new(),
+            active: None,
+            generation: 0,
+            offset: 0,
+            stats: StoreStats {
+                writes: 0,
+                compactions: 0,
+                corrupted: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.stats
+    }
+
+    /// Number of copies between the sectors since they were first formatted.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn definitions(&self) -> &'static [ParameterDef] {
+        self.definitions
+    }
+
+    /// Reads the stored values, finishing or undoing whatever a power loss interrupted. Sectors
+    /// without a store are formatted, leaving every parameter at its default.
+    pub fn mount(&mut self, flash: &mut (impl Flash + ?Sized)) -> Result<(), StoreError> {
+        debug_assert!(self.definitions.len() <= N);
+
+        self.active = None;
+        self.values.clear();
+        if self
+            .sectors
+            .iter()
+            .any(|&sector| flash.sector_len(sector) < HEADER_LEN + N * MAX_RECORD_LEN)
+        {
+            return Err(StoreError::SectorTooSmall);
+        }
+
+        let states = [
+            sector_state(flash, self.sectors[0])?,
+            sector_state(flash, self.sectors[1])?,
+        ];
+        let active = match states {
+            [SectorState::Active { generation: first }, SectorState::Active { generation: second }] =>
+            {
+                // The copy finished, but the old sector was not erased yet
+                if second.wrapping_sub(first) as i32 > 0 {
+                    1
+                } else {
+                    0
+                }
+            }
+            [SectorState::Active { .. }, _] => 0,
+            [_, SectorState::Active { .. }] => 1,
+            _ => {
+                warn!("No parameter store found, formatting");
+                self.format(flash, 0, 0, &[])?;
+                self.active = Some(0);
+                return Ok(());
+            }
+        };
+
+        let other = 1 - active;
+        if states[other] != SectorState::Erased {
+            flash.erase(self.sectors[other])?;
+        }
+        if let SectorState::Active { generation } = states[active] {
+            self.generation = generation;
+        }
+        self.load(flash, active)?;
+        self.active = Some(active);
+        Ok(())
+    }
+
+    /// The value of parameter `id`, stored or default. `None` if there is no such parameter.
+    pub fn get(&self, id: u16) -> Option<Value> {
+        self.stored(id)
+            .or_else(|| self.definition(id).map(|definition| definition.default))
+    }
+
+    /// Returns `true` if parameter `id` has a stored value.
+    pub fn is_stored(&self, id: u16) -> bool {
+        self.stored(id).is_some()
+    }
+
+    /// Every parameter with its current value, in the order of the definitions.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.definitions.iter().map(|definition| {
+            let stored = self.stored(definition.id);
+            Entry {
+                id: definition.id,
+                value: stored.unwrap_or(definition.default),
+                stored: stored.is_some(),
+            }
+        })
+    }
+
+    /// Stores `value` for parameter `id`. Setting the value it already has writes nothing.
+    pub fn set(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        id: u16,
+        value: Value,
+    ) -> Result<(), StoreError> {
+        self.definition(id)
+            .ok_or(StoreError::UnknownParameter(id))?
+            .check(value)?;
+        if self.stored(id) == Some(value) {
+            return Ok(());
+        }
+
+        let mut values = self.values.clone();
+        upsert(&mut values, id, value);
+        self.append(flash, id, Some(value), values)
+    }
+
+    /// Returns parameter `id` to its default.
+    pub fn reset(&mut self, flash: &mut (impl Flash + ?Sized), id: u16) -> Result<(), StoreError> {
+        self.definition(id)
+            .ok_or(StoreError::UnknownParameter(id))?;
+        if !self.is_stored(id) {
+            return Ok(());
+        }
+
+        let mut values = self.values.clone();
+        values.retain(|&(entry, _)| entry != id);
+        self.append(flash, id, None, values)
+    }
+
+    /// Returns every parameter to its default.
+    pub fn reset_all(&mut self, flash: &mut (impl Flash + ?Sized)) -> Result<(), StoreError> {
+        self.active.ok_or(StoreError::NotMounted)?;
+        self.compact(flash, Vec::new())
+    }
+
+    fn definition(&self, id: u16) -> Option<&ParameterDef> {
+        self.definitions
+            .iter()
+            .find(|definition| definition.id == id)
+    }
+
+    fn stored(&self, id: u16) -> Option<Value> {
+        self.values
+            .iter()
+            .find(|(entry, _)| *entry == id)
+            .map(|&(_, value)| value)
+    }
+
+    /// Appends the record of `id`, or copies `values` to the other sector if it does not fit.
+    /// `values` are the values with the change applied.
+    fn append(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        id: u16,
+        value: Option<Value>,
+        values: Vec<(u16, Value), N>,
+    ) -> Result<(), StoreError> {
+        let active = self.active.ok_or(StoreError::NotMounted)?;
+        let sector = self.sectors[active];
+        let (record, len) = encode_record(id, value);
+
+        if self.offset + len > flash.sector_len(sector) {
+            return self.compact(flash, values);
+        }
+        if let Err(error) = flash.program(sector, self.offset, &record[..len]) {
+            // What was programmed is unknown, the next change starts a fresh sector
+            self.offset = flash.sector_len(sector);
+            return Err(error.into());
+        }
+
+        self.offset += len;
+        self.stats.writes += 1;
+        self.values = values;
+        Ok(())
+    }
+
+    /// Copies `values` to the other sector, makes it the active one and erases the old one.
+    fn compact(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        values: Vec<(u16, Value), N>,
+    ) -> Result<(), StoreError> {
+        let from = self.active.ok_or(StoreError::NotMounted)?;
+        let to = 1 - from;
+        let generation = self.generation.wrapping_add(1);
+
+        self.format(flash, to, generation, &values)?;
+        self.active = Some(to);
+        self.values = values;
+        self.stats.compactions += 1;
+
+        flash.erase(self.sectors[from])?;
+        Ok(())
+    }
+
+    /// Writes a sector with `values` and marks it active.
+    fn format(
+        &mut self,
+        flash: &mut (impl Flash + ?Sized),
+        index: usize,
+        generation: u32,
+        values: &[(u16, Value)],
+    ) -> Result<(), StoreError> {
+        let sector = self.sectors[index];
+        if !flash.is_erased(sector)? {
+            flash.erase(sector)?;
+        }
+
+        let mut header = [0xff; ACTIVE_OFFSET];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..].copy_from_slice(&generation.to_be_bytes());
+        flash.program(sector, 0, &header)?;
+
+        let mut offset = HEADER_LEN;
+        for &(id, value) in values {
+            let (record, len) = encode_record(id, Some(value));
+            flash.program(sector, offset, &record[..len])?;
+            offset += len;
+            self.stats.writes += 1;
+        }
+
+        flash.program(sector, ACTIVE_OFFSET, &[0x00])?;
+        self.generation = generation;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Reads the records of the active sector `index`.
+    fn load(&mut self, flash: &mut (impl Flash + ?Sized), index: usize) -> Result<(), StoreError> {
+        let sector = self.sectors[index];
+        let len = flash.sector_len(sector);
+        let mut offset = HEADER_LEN;
+
+        while offset + RECORD_HEADER_LEN <= len {
+            let mut record = [0; MAX_RECORD_LEN];
+            flash.read(sector, offset, &mut record[..RECORD_HEADER_LEN])?;
+            let header = &record[..RECORD_HEADER_LEN];
+            if header.iter().all(|&byte| byte == 0xff) {
+                break;
+            }
+
+            let value_len = usize::from(header[3]);
+            let record_len = RECORD_HEADER_LEN + value_len + CRC_LEN;
+            if value_len > MAX_VALUE_LEN || offset + record_len > len {
+                // A torn header, nothing after it can be found
+                warn!("Parameter store damaged at {}", offset);
+                self.stats.corrupted += 1;
+                offset = len;
+                break;
+            }
+            flash.read(
+                sector,
+                offset + RECORD_HEADER_LEN,
+                &mut record[RECORD_HEADER_LEN..record_len],
+            )?;
+            offset += record_len;
+
+            let (data, crc) = record[..record_len].split_at(record_len - CRC_LEN);
+            if crc16(data) != u16::from_be_bytes([crc[0], crc[1]]) {
+                self.stats.corrupted += 1;
+                continue;
+            }
+            self.apply(data);
+        }
+
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Applies a record with a valid CRC. Records of unknown parameters, with the wrong type or
+    /// out of range are ignored and dropped with the next copy.
+    fn apply(&mut self, record: &[u8]) {
+        let (header, value) = record.split_at(RECORD_HEADER_LEN);
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        let Some(&definition) = self.definition(id) else {
+            return;
+        };
+
+        if header[2] == TOMBSTONE {
+            self.values.retain(|&(entry, _)| entry != id);
+            return;
+        }
+        let default = definition.default;
+        if header[2] != default.type_code() || value.len() != default.encoded_len() {
+            return;
+        }
+        match default.decode_like(value) {
+            Some(value) if definition.check(value).is_ok() => upsert(&mut self.values, id, value),
+            Some(_) => warn!("Parameter {=u16:#06x} out of range, using the default", id),
+            None => {}
+        }
+    }
+}
+
+impl<const N: usize> ParameterSource for ParamStore<N> {
+    fn read(&self, id: u16) -> Option<Value> {
+        self.get(id)
+    }
+}
+
+fn upsert<const N: usize>(values: &mut Vec<(u16, Value), N>, id: u16, value: Value) {
+    match values.iter_mut().find(|(entry, _)| *entry == id) {
+        Some((_, entry)) => *entry = value,
+        // There are at most as many values as definitions
+        None => {
+            values.push((id, value)).ok();
+        }
+    }
+}
+
+/// The record setting `id` to `value`, or returning it to the default for `None`.
+fn encode_record(id: u16, value: Option<Value>) -> ([u8; MAX_RECORD_LEN], usize) {
+    let mut record = [0xff; MAX_RECORD_LEN];
+    record[..2].copy_from_slice(&id.to_be_bytes());
+    let value_len = match value {
+        Some(value) => {
+            record[2] = value.type_code();
+            value
+                .encode(&mut record[RECORD_HEADER_LEN..])
+                .expect("room for a value")
+        }
+        None => {
+            record[2] = TOMBSTONE;
+            0
+        }
+    };
+    record[3] = value_len as u8;
+
+    let len = RECORD_HEADER_LEN + value_len;
+    let crc = crc16(&record[..len]);
+    record[len..len + CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+    (record, len + CRC_LEN)
+}
+
+fn sector_state(flash: &(impl Flash + ?Sized), sector: u8) -> Result<SectorState, FlashError> {
+    let mut header = [0; HEADER_LEN];
+    flash.read(sector, 0, &mut header)?;
+
+    if header.iter().all(|&byte| byte == 0xff) {
+        return Ok(SectorState::Erased);
+    }
+    if header[..4] != MAGIC {
+        return Ok(SectorState::Invalid);
+    }
+    Ok(match header[ACTIVE_OFFSET] {
+        0x00 => SectorState::Active {
+            generation: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        },
+        0xff => SectorState::Receiving,
+        _ => SectorState::Invalid,
+    })
+}
@@ -0,0 +1,613 @@
+//! A small service layer in the style of the ECSS Packet Utilisation Standard (ECSS-E-ST-70-41C).
+//!
+//! Telecommands are CCSDS telecommand packets whose data field starts with a PUS header naming
+//! the service type and subtype of the request. Telemetry reports are CCSDS telemetry packets
+//! with a [`Cuc`] secondary header, followed by a PUS header and the source data:
+//!
+//! ```text
+//! TC: | primary header | version, ack | service | subtype | source ID | application data |
+//! TM: | primary header | CUC time | version | service | subtype | msg count | destination | data |
+//! ```
+//!
+//! Each subsystem implements [`Service`] for the service types it handles and registers it with a
+//! [`Dispatcher`]. The dispatcher checks every telecommand, hands it to its service and sends the
+//! [`verification`] reports the request asks for. Reports go to a [`TmSink`], for example a
+//! [`TmQueue`] that a transport such as ISO-TP drains.
+//!
+//! Nothing allocates: the dispatcher only borrows its services, so it is cheap to set up right
+//! where a telecommand comes in, with the services locked from the shared resources:
+//!
+//! ```ignore
+//! let mut tm = channel.reporter(&mut queue, now);
+//! let mut dispatcher = Dispatcher::<4>::new(APID);
+//! dispatcher.register(&mut ping)?;
+//! dispatcher.register(&mut housekeeping)?;
+//! dispatcher.register(&mut events)?;
+//! dispatcher.dispatch(&tc, &mut tm)?;
+//! ```
+
+use defmt::Format;
+use heapless::{Deque, Vec};
+
+use crate::ccsds::{
+    CcsdsError, Cuc, CucFormat, PacketBuilder, PacketType, SequenceCounter, SpacePacket,
+    PRIMARY_HEADER_LEN,
+};
+
+pub mod event;
+pub mod housekeeping;
+pub mod mode;
+pub mod parameter;
+pub mod ping;
+pub mod scheduler;
+pub mod storage;
+pub mod time;
+pub mod verification;
+
+use verification::{AckFlags, RequestId};
+
+/// PUS version number of the telecommand and telemetry headers.
+pub const PUS_VERSION: u8 = 2;
+
+/// Length of the PUS header of a telecommand.
+pub const TC_HEADER_LEN: usize = 5;
+
+/// Length of the PUS header of a telemetry report, after the timestamp.
+pub const TM_HEADER_LEN: usize = 7;
+
+/// Longest telemetry packet a [`Reporter`] builds.
+pub const MAX_TM_LEN: usize = 256;
+
+/// Reasons a telecommand or report is not handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PusError {
+    /// The packet itself is broken.
+    Ccsds(CcsdsError),
+    /// The PUS header is missing or has another version.
+    Malformed,
+    /// A telemetry packet where a telecommand was expected, or the other way around.
+    WrongPacketType,
+    /// The telecommand is addressed to another application.
+    WrongApid(u16),
+    /// The request failed its acceptance checks with this code. The failure has been reported.
+    Rejected(u16),
+    /// The request failed during execution. The failure has been reported.
+    Failed(Failure),
+    /// The service type is already registered.
+    DuplicateService(u8),
+    /// A fixed size table is full.
+    TableFull,
+    /// The report does not fit into [`MAX_TM_LEN`].
+    TooLong,
+    /// The sink has no room for another report.
+    SinkFull,
+}
+
+impl From<CcsdsError> for PusError {
+    fn from(error: CcsdsError) -> Self {
+        PusError::Ccsds(error)
+    }
+}
+
+/// How the execution of an accepted request failed, with a failure code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Failure {
+    /// The request could not be started, e.g. because the subsystem is busy.
+    Start(u16),
+    /// The request was started but could not be completed.
+    Completion(u16),
+}
+
+impl From<PusError> for Failure {
+    /// A report of the request could not be sent.
+    fn from(_: PusError) -> Self {
+        Failure::Completion(verification::REPORT_FAILED)
+    }
+}
+
+/// The PUS header of a telecommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TcHeader {
+    /// The verification reports the sender wants on success.
+    pub ack: AckFlags,
+    pub service: u8,
+    pub subtype: u8,
+    /// The sender, the destination of the reports.
+    pub source: u16,
+}
+
+impl TcHeader {
+    pub const fn to_bytes(&self) -> [u8; TC_HEADER_LEN] {
+        let [source_hi, source_lo] = self.source.to_be_bytes();
+        [
+            PUS_VERSION << 4 | self.ack.bits(),
+            self.service,
+            self.subtype,
+            source_hi,
+            source_lo,
+        ]
+    }
+
+    pub const fn from_bytes(bytes: &[u8; TC_HEADER_LEN]) -> Result<Self, PusError> {
+        if bytes[0] >> 4 != PUS_VERSION {
+            return Err(PusError::Malformed);
+        }
+        Ok(Self {
+            ack: AckFlags::from_bits(bytes[0]),
+            service: bytes[1],
+            subtype: bytes[2],
+            source: u16::from_be_bytes([bytes[3], bytes[4]]),
+        })
+    }
+}
+
+/// The PUS header of a telemetry report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TmHeader {
+    pub service: u8,
+    pub subtype: u8,
+    /// Counts the reports of the application, wrapping.
+    pub message_count: u16,
+    /// The receiver, the source of the telecommand for responses.
+    pub destination: u16,
+}
+
+impl TmHeader {
+    pub const fn to_bytes(&self) -> [u8; TM_HEADER_LEN] {
+        let [count_hi, count_lo] = self.message_count.to_be_bytes();
+        let [destination_hi, destination_lo] = self.destination.to_be_bytes();
+        [
+            PUS_VERSION << 4,
+            self.service,
+            self.subtype,
+            count_hi,
+            count_lo,
+            destination_hi,
+            destination_lo,
+        ]
+    }
+
+    pub const fn from_bytes(bytes: &[u8; TM_HEADER_LEN]) -> Result<Self, PusError> {
+        if bytes[0] >> 4 != PUS_VERSION {
+            return Err(PusError::Malformed);
+        }
+        Ok(Self {
+            service: bytes[1],
+            subtype: bytes[2],
+            message_count: u16::from_be_bytes([bytes[3], bytes[4]]),
+            destination: u16::from_be_bytes([bytes[5], bytes[6]]),
+        })
+    }
+}
+
+/// A telecommand in a byte buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Telecommand<'a> {
+    packet: SpacePacket<'a>,
+    header: TcHeader,
+}
+
+impl<'a> Telecommand<'a> {
+    /// Reads the PUS header of a telecommand packet.
+    pub fn parse(packet: SpacePacket<'a>) -> Result<Self, PusError> {
+        if packet.packet_type() != PacketType::Telecommand {
+            return Err(PusError::WrongPacketType);
+        }
+        let header = packet
+            .data_field()
+            .first_chunk::<TC_HEADER_LEN>()
+            .ok_or(PusError::Malformed)?;
+        let header = TcHeader::from_bytes(header)?;
+
+        Ok(Self { packet, header })
+    }
+
+    /// Writes a telecommand with `header` and `app_data` to the start of `buf`.
+    pub fn build<'b>(
+        packet: PacketBuilder,
+        header: &TcHeader,
+        app_data: &[u8],
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], CcsdsError> {
+        let len = packet.build_with(TC_HEADER_LEN + app_data.len(), buf, |data| {
+            let (pus, rest) = data.split_at_mut(TC_HEADER_LEN);
+            pus.copy_from_slice(&header.to_bytes());
+            rest.copy_from_slice(app_data);
+        })?;
+        Ok(&buf[..len])
+    }
+
+    pub fn packet(&self) -> SpacePacket<'a> {
+        self.packet
+    }
+
+    pub fn header(&self) -> TcHeader {
+        self.header
+    }
+
+    pub fn request_id(&self) -> RequestId {
+        RequestId::of(&self.packet)
+    }
+
+    pub fn service(&self) -> u8 {
+        self.header.service
+    }
+
+    pub fn subtype(&self) -> u8 {
+        self.header.subtype
+    }
+
+    pub fn app_data(&self) -> &'a [u8] {
+        &self.packet.data_field()[TC_HEADER_LEN..]
+    }
+}
+
+/// A telemetry report in a byte buffer, as seen by the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report<'a> {
+    pub time: Cuc,
+    pub header: TmHeader,
+    pub source_data: &'a [u8],
+}
+
+impl<'a> Report<'a> {
+    /// Reads a report timestamped in `format`.
+    pub fn parse(packet: SpacePacket<'a>, format: CucFormat) -> Result<Self, PusError> {
+        if packet.packet_type() != PacketType::Telemetry {
+            return Err(PusError::WrongPacketType);
+        }
+        let (time, data) = packet.secondary_header(format)?;
+        let header = data
+            .first_chunk::<TM_HEADER_LEN>()
+            .ok_or(PusError::Malformed)?;
+
+        Ok(Self {
+            time,
+            header: TmHeader::from_bytes(header)?,
+            source_data: &data[TM_HEADER_LEN..],
+        })
+    }
+}
+
+/// Where the [`Reporter`] sends finished telemetry packets.
+pub trait TmSink {
+    /// Takes a copy of `packet`, or fails with [`PusError::SinkFull`].
+    fn send(&mut self, packet: &[u8]) -> Result<(), PusError>;
+}
+
+/// A FIFO of telemetry packets waiting for the transport.
+pub struct TmQueue<const N: usize> {
+    packets: Deque<Vec<u8, MAX_TM_LEN>, N>,
+}
+
+impl<const N: usize> Default for TmQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TmQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            packets: Deque::new(),
+        }
+    }
+
+    /// The oldest packet, still in the queue.
+    pub fn front(&self) -> Option<&[u8]> {
+        self.packets.front().map(|packet| packet.as_slice())
+    }
+
+    /// Removes the oldest packet, once the transport has taken it.
+    pub fn pop(&mut self) -> Option<Vec<u8, MAX_TM_LEN>> {
+        self.packets.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+impl<const N: usize> TmSink for TmQueue<N> {
+    fn send(&mut self, packet: &[u8]) -> Result<(), PusError> {
+        let packet = Vec::from_slice(packet).map_err(|_| PusError::TooLong)?;
+        self.packets
+            .push_back(packet)
+            .map_err(|_| PusError::SinkFull)
+    }
+}
+
+/// The telemetry state of one application: its APID, time format and counters.
+#[derive(Debug, Clone, Copy)]
+pub struct TmChannel {
+    apid: u16,
+    format: CucFormat,
+    sequence: SequenceCounter,
+    message_count: u16,
+}
+
+impl TmChannel {
+    pub const fn new(apid: u16, format: CucFormat) -> Self {
+        Self {
+            apid,
+            format,
+            sequence: SequenceCounter::new(),
+            message_count: 0,
+        }
+    }
+
+    pub fn apid(&self) -> u16 {
+        self.apid
+    }
+
+    pub fn format(&self) -> CucFormat {
+        self.format
+    }
+
+    /// Reports go to `sink` and are timestamped with `time`.
+    pub fn reporter<'a>(&'a mut self, sink: &'a mut dyn TmSink, time: Cuc) -> Reporter<'a> {
+        Reporter {
+            channel: self,
+            sink,
+            time,
+            destination: 0,
+            request: None,
+        }
+    }
+}
+
+/// The request in execution.
+#[derive(Clone, Copy)]
+struct Execution {
+    id: RequestId,
+    ack: AckFlags,
+    started: bool,
+}
+
+/// Builds and sends reports for a [`TmChannel`].
+pub struct Reporter<'a> {
+    channel: &'a mut TmChannel,
+    sink: &'a mut dyn TmSink,
+    time: Cuc,
+    destination: u16,
+    request: Option<Execution>,
+}
+
+impl Reporter<'_> {
+    pub fn time(&self) -> Cuc {
+        self.time
+    }
+
+    /// The format of the timestamps, also used for times in telecommands.
+    pub fn format(&self) -> CucFormat {
+        self.channel.format
+    }
+
+    /// Sends a report with `data` as the source data.
+    pub fn report(&mut self, service: u8, subtype: u8, data: &[u8]) -> Result<(), PusError> {
+        self.report_with(service, subtype, |buf| {
+            if let Some(report) = buf.get_mut(..data.len()) {
+                report.copy_from_slice(data);
+            }
+            data.len()
+        })
+    }
+
+    /// Sends a report whose source data `fill` writes in place, returning its length. `fill`
+    /// gets all the room left in the packet; a longer length fails with [`PusError::TooLong`].
+    ///
+    /// While a telecommand is executing, the start of execution is reported first.
+    pub fn report_with(
+        &mut self,
+        service: u8,
+        subtype: u8,
+        fill: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), PusError> {
+        self.report_start()?;
+        self.send(service, subtype, fill)
+    }
+
+    fn send(
+        &mut self,
+        service: u8,
+        subtype: u8,
+        fill: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), PusError> {
+        let format = self.channel.format;
+        let offset = PRIMARY_HEADER_LEN + format.encoded_len() + TM_HEADER_LEN;
+        let mut buf = [0; MAX_TM_LEN];
+        let room = buf.len() - offset;
+        let len = fill(&mut buf[offset..]);
+        if len > room {
+            return Err(PusError::TooLong);
+        }
+
+        let header = TmHeader {
+            service,
+            subtype,
+            message_count: self.channel.message_count,
+            destination: self.destination,
+        };
+        // The source data is already in place, only the headers go in front of it.
+        let len = PacketBuilder::telemetry(self.channel.apid)
+            .sequence_count(self.channel.sequence.next_count())
+            .timestamp(format, self.time)
+            .build_with(TM_HEADER_LEN + len, &mut buf, |data| {
+                data[..TM_HEADER_LEN].copy_from_slice(&header.to_bytes());
+            })?;
+        self.channel.message_count = self.channel.message_count.wrapping_add(1);
+
+        self.sink.send(&buf[..len])
+    }
+
+    /// Sends a verification report of `id`, with a failure code for the failure subtypes.
+    fn verify(&mut self, subtype: u8, id: RequestId, code: Option<u16>) -> Result<(), PusError> {
+        self.send(verification::SERVICE, subtype, |buf| {
+            buf[..4].copy_from_slice(&id.to_bytes());
+            match code {
+                Some(code) => {
+                    buf[4..6].copy_from_slice(&code.to_be_bytes());
+                    6
+                }
+                None => 4,
+            }
+        })
+    }
+
+    /// Reports the start of the executing request, once.
+    fn report_start(&mut self) -> Result<(), PusError> {
+        let Some(request) = self.request.as_mut().filter(|request| !request.started) else {
+            return Ok(());
+        };
+        request.started = true;
+        let (id, ack) = (request.id, request.ack);
+        if ack.contains(AckFlags::START) {
+            self.verify(verification::START_SUCCESS, id, None)?;
+        }
+        Ok(())
+    }
+
+    /// Reports the outcome of the executing request. A start failure after the start has been
+    /// reported is reported as a completion failure.
+    fn finish(&mut self, result: Result<(), Failure>) -> Result<(), PusError> {
+        let Some(request) = self.request else {
+            return Ok(());
+        };
+        let id = request.id;
+
+        let reported = match result {
+            Err(Failure::Start(code)) if !request.started => {
+                self.verify(verification::START_FAILURE, id, Some(code))
+            }
+            _ => self.report_start().and_then(|()| match result {
+                Ok(()) if request.ack.contains(AckFlags::COMPLETION) => {
+                    self.verify(verification::COMPLETION_SUCCESS, id, None)
+                }
+                Ok(()) => Ok(()),
+                Err(Failure::Start(code) | Failure::Completion(code)) => {
+                    self.verify(verification::COMPLETION_FAILURE, id, Some(code))
+                }
+            }),
+        };
+        self.request = None;
+
+        reported?;
+        result.map_err(PusError::Failed)
+    }
+}
+
+/// The handler of one service type.
+pub trait Service {
+    /// The service type number the handler takes telecommands for.
+    fn service_type(&self) -> u8;
+
+    /// Checks the request before it is accepted, typically the subtype and the application data.
+    /// Fails with the code for the acceptance failure report.
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16>;
+
+    /// Executes an accepted request. Reports go through `tm`, the start of execution is reported
+    /// before the first of them.
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure>;
+}
+
+/// Routes the telecommands of one APID to up to `N` registered services.
+pub struct Dispatcher<'a, const N: usize> {
+    apid: u16,
+    services: Vec<&'a mut dyn Service, N>,
+}
+
+impl<'a, const N: usize> Dispatcher<'a, N> {
+    pub const fn new(apid: u16) -> Self {
+        Self {
+            apid,
+            services: Vec::new(),
+        }
+    }
+
+    pub fn apid(&self) -> u16 {
+        self.apid
+    }
+
+    /// Routes the telecommands of the service type of `service` to it.
+    pub fn register(&mut self, service: &'a mut dyn Service) -> Result<(), PusError> {
+        let service_type = service.service_type();
+        if self.is_registered(service_type) {
+            return Err(PusError::DuplicateService(service_type));
+        }
+        self.services.push(service).map_err(|_| PusError::TableFull)
+    }
+
+    pub fn is_registered(&self, service_type: u8) -> bool {
+        self.services
+            .iter()
+            .any(|service| service.service_type() == service_type)
+    }
+
+    /// Verifies and executes the telecommand in `bytes`.
+    ///
+    /// Packets that are not telecommands for this APID are left alone. Everything else gets an
+    /// acceptance report, and accepted requests the start and completion reports their
+    /// [`AckFlags`] ask for. Failures are always reported, and returned as
+    /// [`PusError::Rejected`] or [`PusError::Failed`].
+    pub fn dispatch(&mut self, bytes: &[u8], tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let packet = SpacePacket::parse(bytes)?;
+        if packet.packet_type() != PacketType::Telecommand {
+            return Err(PusError::WrongPacketType);
+        }
+        if packet.apid() != self.apid {
+            return Err(PusError::WrongApid(packet.apid()));
+        }
+
+        let id = RequestId::of(&packet);
+        tm.destination = 0;
+        let tc = match Telecommand::parse(packet) {
+            Ok(tc) => tc,
+            Err(_) => return reject(tm, id, verification::MALFORMED),
+        };
+        tm.destination = tc.header.source;
+
+        let Some(service) = self
+            .services
+            .iter_mut()
+            .find(|service| service.service_type() == tc.service())
+        else {
+            return reject(tm, id, verification::UNKNOWN_SERVICE);
+        };
+        if let Err(code) = service.accept(&tc) {
+            return reject(tm, id, code);
+        }
+        if tc.header.ack.contains(AckFlags::ACCEPTANCE) {
+            tm.verify(verification::ACCEPTANCE_SUCCESS, id, None)?;
+        }
+
+        tm.request = Some(Execution {
+            id,
+            ack: tc.header.ack,
+            started: false,
+        });
+        let result = service.handle(&tc, tm);
+        tm.finish(result)
+    }
+}
+
+fn reject(tm: &mut Reporter<'_>, id: RequestId, code: u16) -> Result<(), PusError> {
+    tm.verify(verification::ACCEPTANCE_FAILURE, id, Some(code))?;
+    Err(PusError::Rejected(code))
+}
+
+/// Reads application data made of a count octet and that many 16-bit IDs, as used to enable and
+/// disable reports. `None` if the length does not match the count.
+pub(crate) fn id_list(data: &[u8]) -> Option<impl ExactSizeIterator<Item = u16> + '_> {
+    let (&count, ids) = data.split_first()?;
+    if ids.len() != 2 * usize::from(count) {
+        return None;
+    }
+    Some(
+        ids.chunks_exact(2)
+            .map(|id| u16::from_be_bytes([id[0], id[1]])),
+    )
+}
@@ -0,0 +1,149 @@
+//! Event reporting service (5).
+//!
+//! Subsystems report events with a 16-bit event ID, a severity and optional auxiliary data.
+//! Ground can disable the reports of noisy event IDs and enable them again.
+
+use defmt::Format;
+use heapless::Vec;
+
+use super::{id_list, verification, Failure, PusError, Reporter, Service, Telecommand};
+
+/// Service type of the event reporting service.
+pub const SERVICE: u8 = 5;
+
+/// Enable the reports of the listed event IDs.
+pub const ENABLE: u8 = 5;
+/// Disable the reports of the listed event IDs.
+pub const DISABLE: u8 = 6;
+
+/// More event IDs are disabled than the service keeps track of.
+pub const TOO_MANY_DISABLED: u16 = 0x0501;
+
+/// Severity of an event, also the subtype of its report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum Severity {
+    Informative = 1,
+    Low = 2,
+    Medium = 3,
+    High = 4,
+}
+
+impl Severity {
+    /// The severity of a report with `subtype`.
+    pub const fn from_subtype(subtype: u8) -> Option<Self> {
+        match subtype {
+            1 => Some(Self::Informative),
+            2 => Some(Self::Low),
+            3 => Some(Self::Medium),
+            4 => Some(Self::High),
+            _ => None,
+        }
+    }
+}
+
+/// Event counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct EventStats {
+    /// Events reported.
+    pub reported: u32,
+    /// Events not reported because their ID is disabled.
+    pub suppressed: u32,
+}
+
+/// The event reporting service, keeping track of up to `N` disabled event IDs.
+pub struct Events<const N: usize> {
+    disabled: Vec<u16, N>,
+    stats: EventStats,
+}
+
+impl<const N: usize> Default for Events<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Events<N> {
+    pub const fn new() -> Self {
+        Self {
+            disabled: Vec::new(),
+            stats: EventStats {
+                reported: 0,
+                suppressed: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> EventStats {
+        self.stats
+    }
+
+    pub fn is_enabled(&self, id: u16) -> bool {
+        !self.disabled.contains(&id)
+    }
+
+    pub fn enable(&mut self, id: u16) {
+        self.disabled.retain(|&disabled| disabled != id);
+    }
+
+    pub fn disable(&mut self, id: u16) -> Result<(), PusError> {
+        if self.is_enabled(id) {
+            self.disabled.push(id).map_err(|_| PusError::TableFull)?;
+        }
+        Ok(())
+    }
+
+    /// Reports event `id` with `data` as auxiliary data, unless the ID is disabled.
+    pub fn report(
+        &mut self,
+        tm: &mut Reporter<'_>,
+        severity: Severity,
+        id: u16,
+        data: &[u8],
+    ) -> Result<(), PusError> {
+        if !self.is_enabled(id) {
+            self.stats.suppressed += 1;
+            return Ok(());
+        }
+
+        tm.report_with(SERVICE, severity as u8, |buf| {
+            let len = 2 + data.len();
+            if let Some(report) = buf.get_mut(..len) {
+                report[..2].copy_from_slice(&id.to_be_bytes());
+                report[2..].copy_from_slice(data);
+            }
+            len
+        })?;
+        self.stats.reported += 1;
+        Ok(())
+    }
+}
+
+impl<const N: usize> Service for Events<N> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        if !matches!(tc.subtype(), ENABLE | DISABLE) {
+            return Err(verification::UNKNOWN_SUBTYPE);
+        }
+        match id_list(tc.app_data()) {
+            Some(_) => Ok(()),
+            None => Err(verification::INVALID_DATA),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, _: &mut Reporter<'_>) -> Result<(), Failure> {
+        let ids = id_list(tc.app_data()).ok_or(Failure::Start(verification::INVALID_DATA))?;
+
+        for id in ids {
+            if tc.subtype() == ENABLE {
+                self.enable(id);
+            } else {
+                self.disable(id)
+                    .map_err(|_| Failure::Completion(TOO_MANY_DISABLED))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! Housekeeping service (3).
+//!
+//! The application registers its parameters in a [`ParameterSource`], typically a
+//! [`Parameters`] pool its tasks keep up to date, and groups them into report structures, each
+//! identified by a structure ID (SID). A report carries the SID followed by the values of the
+//! parameters of the structure.
+//!
+//! Periodic reports are timed in collection intervals: a monotonic task calls
+//! [`Housekeeping::tick`] once per interval of the application's choosing, and a structure with a
+//! collection interval of `n` is reported every `n` ticks. Ground can create, delete, enable,
+//! disable and retime structures, and ask for reports right away.
+//!
+//! ```ignore
+//! parameters.register(UPTIME, Value::U32(0))?;
+//! parameters.register(CAN1_TEC, Value::U8(0))?;
+//! let mut housekeeping = Housekeeping::<_, 4>::new(parameters);
+//! housekeeping.define(SID_STATUS, &[UPTIME, CAN1_TEC], 10)?;
+//! housekeeping.enable(SID_STATUS)?;
+//! ```
+
+use defmt::Format;
+use heapless::Vec;
+
+use super::{id_list, verification, Failure, PusError, Reporter, Service, Telecommand};
+
+pub mod parameters;
+
+pub use parameters::{ParameterSource, Parameters, Value};
+
+/// Service type of the housekeeping service.
+pub const SERVICE: u8 = 3;
+
+/// Create a structure: SID, collection interval, count and parameter IDs.
+pub const CREATE: u8 = 1;
+/// Delete the listed structures.
+pub const DELETE: u8 = 3;
+/// Enable the periodic generation of the listed structures.
+pub const ENABLE_PERIODIC: u8 = 5;
+/// Disable the periodic generation of the listed structures.
+pub const DISABLE_PERIODIC: u8 = 6;
+/// Report the definitions of the listed structures.
+pub const REPORT_STRUCTURES: u8 = 9;
+/// Structure definition report: SID, periodic, collection interval, count and parameter IDs.
+pub const STRUCTURE_REPORT: u8 = 10;
+/// Housekeeping parameter report.
+pub const REPORT: u8 = 25;
+/// Generate one report of each listed structure now.
+pub const GENERATE_ONE_SHOT: u8 = 27;
+/// Change collection intervals: count, then SID and collection interval pairs.
+pub const MODIFY_INTERVAL: u8 = 31;
+
+/// A listed structure is not defined.
+pub const UNKNOWN_STRUCTURE: u16 = 0x0301;
+/// The structure to create is already defined.
+pub const STRUCTURE_EXISTS: u16 = 0x0302;
+/// A parameter of the structure to create is not known.
+pub const UNKNOWN_PARAMETER: u16 = 0x0303;
+/// The structure to create has more than [`MAX_STRUCTURE_PARAMETERS`] parameters.
+pub const TOO_MANY_PARAMETERS: u16 = 0x0304;
+/// There is no room for another structure.
+pub const TOO_MANY_STRUCTURES: u16 = 0x0305;
+/// A collection interval is 0.
+pub const INVALID_INTERVAL: u16 = 0x0306;
+
+/// Most parameters in one report structure.
+pub const MAX_STRUCTURE_PARAMETERS: usize = 16;
+
+/// Reasons a structure or parameter cannot be defined or changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HousekeepingError {
+    UnknownStructure(u16),
+    StructureExists(u16),
+    UnknownParameter(u16),
+    ParameterExists(u16),
+    /// The value has a different type than the parameter was registered with.
+    TypeMismatch(u16),
+    /// More than [`MAX_STRUCTURE_PARAMETERS`] parameters.
+    TooManyParameters,
+    /// The structure or parameter table is full.
+    TableFull,
+    /// Collection intervals start at 1.
+    InvalidInterval,
+}
+
+impl HousekeepingError {
+    /// The failure code of the verification report.
+    pub const fn code(self) -> u16 {
+        match self {
+            HousekeepingError::UnknownStructure(_) => UNKNOWN_STRUCTURE,
+            HousekeepingError::StructureExists(_) => STRUCTURE_EXISTS,
+            HousekeepingError::UnknownParameter(_) | HousekeepingError::ParameterExists(_) => {
+                UNKNOWN_PARAMETER
+            }
+            HousekeepingError::TypeMismatch(_) => verification::INVALID_DATA,
+            HousekeepingError::TooManyParameters => TOO_MANY_PARAMETERS,
+            HousekeepingError::TableFull => TOO_MANY_STRUCTURES,
+            HousekeepingError::InvalidInterval => INVALID_INTERVAL,
+        }
+    }
+}
+
+/// A defined report structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Structure {
+    sid: u16,
+    parameters: Vec<u16, MAX_STRUCTURE_PARAMETERS>,
+    /// Collection interval in ticks.
+    interval: u16,
+    /// Ticks until the next periodic report.
+    countdown: u16,
+    periodic: bool,
+}
+
+/// The housekeeping service with up to `N` report structures of the parameters in `S`.
+pub struct Housekeeping<S, const N: usize> {
+    source: S,
+    structures: Vec<Structure, N>,
+}
+
+impl<S: ParameterSource, const N: usize> Housekeeping<S, N> {
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            structures: Vec::new(),
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Defines structure `sid` with `parameters`, reported every `interval` ticks once enabled.
+    pub fn define(
+        &mut self,
+        sid: u16,
+        parameters: &[u16],
+        interval: u16,
+    ) -> Result<(), HousekeepingError> {
+        self.check_definition(sid, parameters.iter().copied(), interval)?;
+
+        let structure = Structure {
+            sid,
+            parameters: Vec::from_slice(parameters)
+                .map_err(|_| HousekeepingError::TooManyParameters)?,
+            interval,
+            countdown: interval,
+            periodic: false,
+        };
+        self.structures
+            .push(structure)
+            .map_err(|_| HousekeepingError::TableFull)
+    }
+
+    /// Removes structure `sid`.
+    pub fn delete(&mut self, sid: u16) -> Result<(), HousekeepingError> {
+        let index = self
+            .structures
+            .iter()
+            .position(|structure| structure.sid == sid)
+            .ok_or(HousekeepingError::UnknownStructure(sid))?;
+        self.structures.swap_remove(index);
+        Ok(())
+    }
+
+    /// Starts the periodic reports of structure `sid`, the first one after a full interval.
+    pub fn enable(&mut self, sid: u16) -> Result<(), HousekeepingError> {
+        let structure = self.structure_mut(sid)?;
+        if !structure.periodic {
+            structure.periodic = true;
+            structure.countdown = structure.interval;
+        }
+        Ok(())
+    }
+
+    /// Stops the periodic reports of structure `sid`.
+    pub fn disable(&mut self, sid: u16) -> Result<(), HousekeepingError> {
+        self.structure_mut(sid)?.periodic = false;
+        Ok(())
+    }
+
+    /// Changes the collection interval of structure `sid`. The next report is due a full new
+    /// interval from now.
+    pub fn set_interval(&mut self, sid: u16, interval: u16) -> Result<(), HousekeepingError> {
+        if interval == 0 {
+            return Err(HousekeepingError::InvalidInterval);
+        }
+        let structure = self.structure_mut(sid)?;
+        structure.interval = interval;
+        structure.countdown = interval;
+        Ok(())
+    }
+
+    pub fn is_defined(&self, sid: u16) -> bool {
+        self.structure(sid).is_some()
+    }
+
+    /// Whether structure `sid` is reported periodically, `None` if it is not defined.
+    pub fn is_periodic(&self, sid: u16) -> Option<bool> {
+        self.structure(sid).map(|structure| structure.periodic)
+    }
+
+    /// The collection interval of structure `sid`, `None` if it is not defined.
+    pub fn interval(&self, sid: u16) -> Option<u16> {
+        self.structure(sid).map(|structure| structure.interval)
+    }
+
+    /// The parameters of structure `sid`, `None` if it is not defined.
+    pub fn parameters(&self, sid: u16) -> Option<&[u16]> {
+        self.structure(sid)
+            .map(|structure| structure.parameters.as_slice())
+    }
+
+    /// Sends a report of structure `sid` with the current parameter values. Returns `false` if
+    /// the structure is not defined.
+    pub fn generate(&mut self, sid: u16, tm: &mut Reporter<'_>) -> Result<bool, PusError> {
+        match self.structure(sid) {
+            Some(structure) => report(&self.source, structure, tm).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Advances the periodic reports by one collection interval and sends the reports that are
+    /// due. Returns the number of reports sent; after a failed report the others still go out and
+    /// the first error is returned.
+    pub fn tick(&mut self, tm: &mut Reporter<'_>) -> Result<usize, PusError> {
+        let (mut sent, mut result) = (0, Ok(()));
+
+        for structure in self.structures.iter_mut().filter(|s| s.periodic) {
+            structure.countdown = structure.countdown.saturating_sub(1);
+            if structure.countdown > 0 {
+                continue;
+            }
+            structure.countdown = structure.interval;
+
+            match report(&self.source, structure, tm) {
+                Ok(()) => sent += 1,
+                Err(error) => result = result.and(Err(error)),
+            }
+        }
+        result.map(|()| sent)
+    }
+
+    fn check_definition(
+        &self,
+        sid: u16,
+        mut parameters: impl ExactSizeIterator<Item = u16>,
+        interval: u16,
+    ) -> Result<(), HousekeepingError> {
+        if self.is_defined(sid) {
+            return Err(HousekeepingError::StructureExists(sid));
+        }
+        if interval == 0 {
+            return Err(HousekeepingError::InvalidInterval);
+        }
+        if parameters.len() > MAX_STRUCTURE_PARAMETERS {
+            return Err(HousekeepingError::TooManyParameters);
+        }
+        if let Some(id) = parameters.find(|&id| self.source.read(id).is_none()) {
+            return Err(HousekeepingError::UnknownParameter(id));
+        }
+        if self.structures.is_full() {
+            return Err(HousekeepingError::TableFull);
+        }
+        Ok(())
+    }
+
+    fn structure(&self, sid: u16) -> Option<&Structure> {
+        self.structures
+            .iter()
+            .find(|structure| structure.sid == sid)
+    }
+
+    fn structure_mut(&mut self, sid: u16) -> Result<&mut Structure, HousekeepingError> {
+        self.structures
+            .iter_mut()
+            .find(|structure| structure.sid == sid)
+            .ok_or(HousekeepingError::UnknownStructure(sid))
+    }
+
+    fn report_definition(&self, sid: u16, tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let Some(structure) = self.structure(sid) else {
+            return Ok(());
+        };
+        tm.report_with(SERVICE, STRUCTURE_REPORT, |buf| {
+            buf[..2].copy_from_slice(&structure.sid.to_be_bytes());
+            buf[2] = structure.periodic as u8;
+            buf[3..5].copy_from_slice(&structure.interval.to_be_bytes());
+            buf[5] = structure.parameters.len() as u8;
+            for (id, out) in structure
+                .parameters
+                .iter()
+                .zip(buf[6..].chunks_exact_mut(2))
+            {
+                out.copy_from_slice(&id.to_be_bytes());
+            }
+            6 + 2 * structure.parameters.len()
+        })
+    }
+}
+
+/// Sends a report of `structure`. Parameters the source no longer knows are left out.
+fn report(
+    source: &impl ParameterSource,
+    structure: &Structure,
+    tm: &mut Reporter<'_>,
+) -> Result<(), PusError> {
+    tm.report_with(SERVICE, REPORT, |buf| {
+        buf[..2].copy_from_slice(&structure.sid.to_be_bytes());
+        let mut len = 2;
+        for value in structure
+            .parameters
+            .iter()
+            .filter_map(|&id| source.read(id))
+        {
+            match value.encode(&mut buf[len..]) {
+                Some(written) => len += written,
+                None => return usize::MAX,
+            }
+        }
+        len
+    })
+}
+
+/// Reads the application data of [`CREATE`]: SID, interval and the parameter IDs.
+fn creation(data: &[u8]) -> Option<(u16, u16, impl ExactSizeIterator<Item = u16> + '_)> {
+    let (header, ids) = data.split_first_chunk::<4>()?;
+    let sid = u16::from_be_bytes([header[0], header[1]]);
+    let interval = u16::from_be_bytes([header[2], header[3]]);
+    Some((sid, interval, id_list(ids)?))
+}
+
+/// Reads the application data of [`MODIFY_INTERVAL`]: SID and interval pairs.
+fn intervals(data: &[u8]) -> Option<impl Iterator<Item = (u16, u16)> + '_> {
+    let (&count, pairs) = data.split_first()?;
+    if pairs.len() != 4 * usize::from(count) {
+        return None;
+    }
+    Some(pairs.chunks_exact(4).map(|pair| {
+        (
+            u16::from_be_bytes([pair[0], pair[1]]),
+            u16::from_be_bytes([pair[2], pair[3]]),
+        )
+    }))
+}
+
+impl<S: ParameterSource, const N: usize> Service for Housekeeping<S, N> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+
+        match tc.subtype() {
+            CREATE => {
+                let (sid, interval, ids) = creation(data).ok_or(verification::INVALID_DATA)?;
+                self.check_definition(sid, ids, interval)
+                    .map_err(HousekeepingError::code)
+            }
+            MODIFY_INTERVAL => {
+                let mut pairs = intervals(data).ok_or(verification::INVALID_DATA)?;
+                pairs.try_for_each(|(sid, interval)| match self.is_defined(sid) {
+                    false => Err(UNKNOWN_STRUCTURE),
+                    true if interval == 0 => Err(INVALID_INTERVAL),
+                    true => Ok(()),
+                })
+            }
+            DELETE | ENABLE_PERIODIC | DISABLE_PERIODIC | REPORT_STRUCTURES | GENERATE_ONE_SHOT => {
+                let mut sids = id_list(data).ok_or(verification::INVALID_DATA)?;
+                match sids.all(|sid| self.is_defined(sid)) {
+                    true => Ok(()),
+                    false => Err(UNKNOWN_STRUCTURE),
+                }
+            }
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        let data = tc.app_data();
+        let invalid = Failure::Start(verification::INVALID_DATA);
+        let failed = |error: HousekeepingError| Failure::Completion(error.code());
+
+        match tc.subtype() {
+            CREATE => {
+                let (sid, interval, ids) = creation(data).ok_or(invalid)?;
+                let mut parameters = Vec::<u16, MAX_STRUCTURE_PARAMETERS>::new();
+                for id in ids {
+                    parameters
+                        .push(id)
+                        .map_err(|_| failed(HousekeepingError::TooManyParameters))?;
+                }
+                self.define(sid, &parameters, interval).map_err(failed)?;
+            }
+            MODIFY_INTERVAL => {
+                for (sid, interval) in intervals(data).ok_or(invalid)? {
+                    self.set_interval(sid, interval).map_err(failed)?;
+                }
+            }
+            subtype => {
+                for sid in id_list(data).ok_or(invalid)? {
+                    match subtype {
+                        DELETE => self.delete(sid).map_err(failed)?,
+                        ENABLE_PERIODIC => self.enable(sid).map_err(failed)?,
+                        DISABLE_PERIODIC => self.disable(sid).map_err(failed)?,
+                        REPORT_STRUCTURES => self.report_definition(sid, tm)?,
+                        _ => {
+                            self.generate(sid, tm)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
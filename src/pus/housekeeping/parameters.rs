@@ -0,0 +1,155 @@
+//! Housekeeping parameters: typed values identified by a 16-bit parameter ID.
+
+use defmt::Format;
+use heapless::Vec;
+
+use super::HousekeepingError;
+
+/// The value of a parameter. Reports carry it big-endian in the width of its type, without the
+/// type, so a parameter keeps its type once its reports are defined.
+///
+/// Values of the same type compare by their content, values of different types by their type.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Format)]
+pub enum Value {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    F32(f32),
+}
+
+impl Value {
+    /// Length of the value in a report.
+    pub const fn encoded_len(&self) -> usize {
+        match self {
+            Value::Bool(_) | Value::U8(_) | Value::I8(_) => 1,
+            Value::U16(_) | Value::I16(_) => 2,
+            Value::U32(_) | Value::I32(_) | Value::F32(_) => 4,
+        }
+    }
+
+    /// Writes the value to the start of `buf` and returns its length, or `None` if it does not
+    /// fit.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.encoded_len();
+        let out = buf.get_mut(..len)?;
+        match *self {
+            Value::Bool(value) => out[0] = value as u8,
+            Value::U8(value) => out[0] = value,
+            Value::I8(value) => out.copy_from_slice(&value.to_be_bytes()),
+            Value::U16(value) => out.copy_from_slice(&value.to_be_bytes()),
+            Value::I16(value) => out.copy_from_slice(&value.to_be_bytes()),
+            Value::U32(value) => out.copy_from_slice(&value.to_be_bytes()),
+            Value::I32(value) => out.copy_from_slice(&value.to_be_bytes()),
+            Value::F32(value) => out.copy_from_slice(&value.to_be_bytes()),
+        }
+        Some(len)
+    }
+
+    /// Code of the type, in the order of the variants from 0.
+    pub const fn type_code(&self) -> u8 {
+        match self {
+            Value::Bool(_) => 0,
+            Value::U8(_) => 1,
+            Value::U16(_) => 2,
+            Value::U32(_) => 3,
+            Value::I8(_) => 4,
+            Value::I16(_) => 5,
+            Value::I32(_) => 6,
+            Value::F32(_) => 7,
+        }
+    }
+
+    /// Reads a value of the same type as this one from the start of `bytes`, `None` if they are
+    /// too short.
+    pub fn decode_like(&self, bytes: &[u8]) -> Option<Self> {
+        let field = bytes.get(..self.encoded_len())?;
+        let mut raw = [0; 4];
+        raw[..field.len()].copy_from_slice(field);
+        let [b0, b1, b2, b3] = raw;
+
+        Some(match self {
+            Value::Bool(_) => Value::Bool(b0 != 0),
+            Value::U8(_) => Value::U8(b0),
+            Value::I8(_) => Value::I8(b0 as i8),
+            Value::U16(_) => Value::U16(u16::from_be_bytes([b0, b1])),
+            Value::I16(_) => Value::I16(i16::from_be_bytes([b0, b1])),
+            Value::U32(_) => Value::U32(u32::from_be_bytes(raw)),
+            Value::I32(_) => Value::I32(i32::from_be_bytes(raw)),
+            Value::F32(_) => Value::F32(f32::from_be_bytes([b0, b1, b2, b3])),
+        })
+    }
+}
+
+/// Where the housekeeping service reads parameters from.
+pub trait ParameterSource {
+    /// The current value of parameter `id`, `None` if there is no such parameter.
+    fn read(&self, id: u16) -> Option<Value>;
+}
+
+/// A pool of up to `N` parameters that tasks keep up to date.
+pub struct Parameters<const N: usize> {
+    entries: Vec<(u16, Value), N>,
+}
+
+impl<const N: usize> Default for Parameters<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Parameters<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds parameter `id` with its initial value, which also fixes its type.
+    pub fn register(&mut self, id: u16, initial: Value) -> Result<(), HousekeepingError> {
+        if self.get(id).is_some() {
+            return Err(HousekeepingError::ParameterExists(id));
+        }
+        self.entries
+            .push((id, initial))
+            .map_err(|_| HousekeepingError::TableFull)
+    }
+
+    /// Updates parameter `id` with a value of the type it was registered with.
+    pub fn set(&mut self, id: u16, value: Value) -> Result<(), HousekeepingError> {
+        let (_, entry) = self
+            .entries
+            .iter_mut()
+            .find(|(entry, _)| *entry == id)
+            .ok_or(HousekeepingError::UnknownParameter(id))?;
+        if value.type_code() != entry.type_code() {
+            return Err(HousekeepingError::TypeMismatch(id));
+        }
+        *entry = value;
+        Ok(())
+    }
+
+    pub fn get(&self, id: u16) -> Option<Value> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| *entry == id)
+            .map(|&(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<const N: usize> ParameterSource for Parameters<N> {
+    fn read(&self, id: u16) -> Option<Value> {
+        self.get(id)
+    }
+}
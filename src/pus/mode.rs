@@ -0,0 +1,89 @@
+//! Mode management service (200), a mission specific service for the [`ModeManager`].
+//!
+//! Ground requests the transitions of the table of the manager and asks for the current mode.
+//! The mode changes stay with the manager, to be reported as events like the automatic ones. The
+//! service borrows the manager and the actions for one telecommand:
+//!
+//! ```ignore
+//! let mut modes = ModeService::new(&mut manager, &mut actions);
+//! dispatcher.register(&mut modes)?;
+//! ```
+
+use super::{verification, Failure, Reporter, Service, Telecommand};
+use crate::mode::{Mode, ModeActions, ModeError, ModeManager};
+
+/// Service type of the mode management service.
+pub const SERVICE: u8 = 200;
+
+/// Go to a mode: the mode (u8).
+pub const REQUEST: u8 = 1;
+/// Ask for a [`MODE_REPORT`].
+pub const REPORT_MODE: u8 = 2;
+/// Current mode: the mode (u8).
+pub const MODE_REPORT: u8 = 3;
+
+/// The table has no transition from the current mode to the requested one.
+pub const NOT_ALLOWED: u16 = 0xc801;
+
+impl ModeError {
+    /// The failure code of the verification report.
+    pub const fn code(self) -> u16 {
+        match self {
+            ModeError::NotAllowed(_) => NOT_ALLOWED,
+        }
+    }
+}
+
+/// The mode management service of `manager`, which leaves and enters modes with `actions`.
+pub struct ModeService<'a, 't, A: ModeActions + ?Sized> {
+    manager: &'a mut ModeManager<'t>,
+    actions: &'a mut A,
+}
+
+impl<'a, 't, A: ModeActions + ?Sized> ModeService<'a, 't, A> {
+    pub fn new(manager: &'a mut ModeManager<'t>, actions: &'a mut A) -> Self {
+        Self { manager, actions }
+    }
+}
+
+/// Reads the application data of [`REQUEST`].
+fn requested(data: &[u8]) -> Option<Mode> {
+    match data {
+        &[mode] => Mode::from_u8(mode),
+        _ => None,
+    }
+}
+
+impl<A: ModeActions + ?Sized> Service for ModeService<'_, '_, A> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+
+        match tc.subtype() {
+            REQUEST => match requested(data).ok_or(verification::INVALID_DATA)? {
+                mode if !self.manager.allows(mode) => Err(NOT_ALLOWED),
+                _ => Ok(()),
+            },
+            REPORT_MODE if data.is_empty() => Ok(()),
+            REPORT_MODE => Err(verification::INVALID_DATA),
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        match tc.subtype() {
+            REQUEST => {
+                let mode =
+                    requested(tc.app_data()).ok_or(Failure::Start(verification::INVALID_DATA))?;
+                self.manager
+                    .request(mode, self.actions)
+                    .map_err(|error| Failure::Start(error.code()))?;
+            }
+            _ => tm.report(SERVICE, MODE_REPORT, &[self.manager.mode() as u8])?,
+        }
+        Ok(())
+    }
+}
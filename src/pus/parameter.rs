@@ -0,0 +1,214 @@
+//! Parameter management service (20).
+//!
+//! Ground reads and changes the parameters of a [`ParamStore`], and the changes survive a reset.
+//! Values travel as the big-endian bytes of their type, as wide as the default of the parameter.
+//! The service borrows the store and the flash for one telecommand:
+//!
+//! ```ignore
+//! let mut parameters = ParameterService::new(&mut store, &mut flash);
+//! dispatcher.register(&mut parameters)?;
+//! ```
+
+use heapless::Vec;
+
+use super::{id_list, verification, Failure, PusError, Reporter, Service, Telecommand};
+use crate::{
+    flash::Flash,
+    nvstore::{Entry, ParamStore, ParameterDef, StoreError},
+    pus::housekeeping::Value,
+};
+
+/// Service type of the parameter management service.
+pub const SERVICE: u8 = 20;
+
+/// Report the values of the listed parameters, of all of them for a count of 0.
+pub const REPORT_VALUES: u8 = 1;
+/// Parameter value report: count, then ID and value for each parameter.
+pub const VALUE_REPORT: u8 = 2;
+/// Set and store parameter values: count, then ID and value for each parameter.
+pub const SET_VALUES: u8 = 3;
+/// Ask for a [`LIST_REPORT`] of every parameter.
+pub const LIST: u8 = 128;
+/// Parameter list report: count, then ID, type code, stored flag and value for each parameter.
+pub const LIST_REPORT: u8 = 129;
+/// Return the listed parameters to their defaults, all of them for a count of 0.
+pub const RESET: u8 = 130;
+
+/// A listed parameter is not defined.
+pub const UNKNOWN_PARAMETER: u16 = 0x1401;
+/// The flash could not be written.
+pub const FLASH_FAILED: u16 = 0x1402;
+/// A value is outside of the range of its parameter.
+pub const OUT_OF_RANGE: u16 = 0x1403;
+
+impl StoreError {
+    /// The failure code of the verification report.
+    pub const fn code(self) -> u16 {
+        match self {
+            StoreError::UnknownParameter(_) => UNKNOWN_PARAMETER,
+            StoreError::TypeMismatch(_) => verification::INVALID_DATA,
+            StoreError::OutOfRange(_) => OUT_OF_RANGE,
+            StoreError::NotMounted | StoreError::SectorTooSmall | StoreError::Flash(_) => {
+                FLASH_FAILED
+            }
+        }
+    }
+}
+
+/// The parameter management service of a store with up to `N` parameters in `flash`.
+pub struct ParameterService<'a, F: Flash + ?Sized, const N: usize> {
+    store: &'a mut ParamStore<N>,
+    flash: &'a mut F,
+}
+
+impl<'a, F: Flash + ?Sized, const N: usize> ParameterService<'a, F, N> {
+    pub fn new(store: &'a mut ParamStore<N>, flash: &'a mut F) -> Self {
+        Self { store, flash }
+    }
+
+    /// Sends a [`VALUE_REPORT`] of `ids`.
+    fn report_values(
+        &self,
+        ids: impl Iterator<Item = u16>,
+        tm: &mut Reporter<'_>,
+    ) -> Result<(), PusError> {
+        let store = &*self.store;
+        tm.report_with(SERVICE, VALUE_REPORT, |buf| {
+            let mut len = 1;
+            let mut count = 0u8;
+            for (id, value) in ids.filter_map(|id| Some((id, store.get(id)?))) {
+                let Some(out) = buf.get_mut(len..len + 2) else {
+                    return usize::MAX;
+                };
+                out.copy_from_slice(&id.to_be_bytes());
+                match value.encode(&mut buf[len + 2..]) {
+                    Some(written) => len += 2 + written,
+                    None => return usize::MAX,
+                }
+                count += 1;
+            }
+            buf[0] = count;
+            len
+        })
+    }
+
+    fn report_list(&self, tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let store = &*self.store;
+        tm.report_with(SERVICE, LIST_REPORT, |buf| {
+            buf[0] = store.definitions().len() as u8;
+            let mut len = 1;
+            for Entry { id, value, stored } in store.entries() {
+                let Some(out) = buf.get_mut(len..len + 4) else {
+                    return usize::MAX;
+                };
+                out[..2].copy_from_slice(&id.to_be_bytes());
+                out[2] = value.type_code();
+                out[3] = stored as u8;
+                match value.encode(&mut buf[len + 4..]) {
+                    Some(written) => len += 4 + written,
+                    None => return usize::MAX,
+                }
+            }
+            len
+        })
+    }
+}
+
+/// Reads the application data of [`SET_VALUES`]: a count, then ID and value pairs, each value as
+/// wide as the default of its parameter and within its range. Fails with the code of the
+/// acceptance failure.
+fn settings<const N: usize>(
+    definitions: &[ParameterDef],
+    data: &[u8],
+) -> Result<Vec<(u16, Value), N>, u16> {
+    let (&count, mut rest) = data.split_first().ok_or(verification::INVALID_DATA)?;
+    let mut settings = Vec::new();
+
+    for _ in 0..count {
+        let (id, tail) = rest
+            .split_first_chunk::<2>()
+            .ok_or(verification::INVALID_DATA)?;
+        let id = u16::from_be_bytes(*id);
+        let definition = definitions
+            .iter()
+            .find(|definition| definition.id == id)
+            .ok_or(UNKNOWN_PARAMETER)?;
+        let (value, tail) = tail
+            .split_at_checked(definition.default.encoded_len())
+            .ok_or(verification::INVALID_DATA)?;
+        let value = definition
+            .default
+            .decode_like(value)
+            .ok_or(verification::INVALID_DATA)?;
+        definition.check(value).map_err(StoreError::code)?;
+        settings
+            .push((id, value))
+            .map_err(|_| verification::INVALID_DATA)?;
+        rest = tail;
+    }
+
+    match rest.is_empty() {
+        true => Ok(settings),
+        false => Err(verification::INVALID_DATA),
+    }
+}
+
+impl<F: Flash + ?Sized, const N: usize> Service for ParameterService<'_, F, N> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+
+        match tc.subtype() {
+            SET_VALUES => settings::<N>(self.store.definitions(), data).map(|_| ()),
+            REPORT_VALUES | RESET => {
+                let mut ids = id_list(data).ok_or(verification::INVALID_DATA)?;
+                match ids.all(|id| self.store.get(id).is_some()) {
+                    true => Ok(()),
+                    false => Err(UNKNOWN_PARAMETER),
+                }
+            }
+            LIST if data.is_empty() => Ok(()),
+            LIST => Err(verification::INVALID_DATA),
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        let data = tc.app_data();
+        let invalid = Failure::Start(verification::INVALID_DATA);
+        let failed = |error: StoreError| Failure::Completion(error.code());
+        let definitions = self.store.definitions();
+
+        match tc.subtype() {
+            SET_VALUES => {
+                let settings = settings::<N>(definitions, data).map_err(Failure::Start)?;
+                for (id, value) in settings {
+                    self.store.set(self.flash, id, value).map_err(failed)?;
+                }
+            }
+            REPORT_VALUES => {
+                let ids = id_list(data).ok_or(invalid)?;
+                match ids.len() {
+                    0 => {
+                        self.report_values(definitions.iter().map(|definition| definition.id), tm)?
+                    }
+                    _ => self.report_values(ids, tm)?,
+                }
+            }
+            RESET => {
+                let ids = id_list(data).ok_or(invalid)?;
+                if ids.len() == 0 {
+                    self.store.reset_all(self.flash).map_err(failed)?;
+                }
+                for id in ids {
+                    self.store.reset(self.flash, id).map_err(failed)?;
+                }
+            }
+            _ => self.report_list(tm)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+//! Test service (17): answers a ping with a pong, to check a node is alive end to end.
+
+use super::{verification, Failure, Reporter, Service, Telecommand};
+
+/// Service type of the test service.
+pub const SERVICE: u8 = 17;
+
+/// Are-you-alive request, without application data.
+pub const PING: u8 = 1;
+/// Are-you-alive report, without source data.
+pub const PONG: u8 = 2;
+
+/// The test service.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ping;
+
+impl Service for Ping {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        if tc.subtype() != PING {
+            return Err(verification::UNKNOWN_SUBTYPE);
+        }
+        if !tc.app_data().is_empty() {
+            return Err(verification::INVALID_DATA);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, _: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        tm.report(SERVICE, PONG, &[])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+//! Time-based scheduling service (11).
+//!
+//! Ground uploads telecommands tagged with an absolute mission time, and the scheduler releases
+//! each one when its time has come, even without contact. Released telecommands go through the
+//! [`Dispatcher`](super::Dispatcher) like any other.
+//!
+//! The scheduler has no clock of its own: every call that depends on the time takes the current
+//! [`Met`], so the host tests drive it with a simulated clock. On the target a monotonic
+//! task sleeps until [`Scheduler::next_release`] and then takes the due telecommands:
+//!
+//! ```ignore
+//! while let Some(tc) = scheduler.pop_due(met) {
+//!     dispatcher.dispatch(&tc, &mut reporter)?;
+//! }
+//! ```
+
+use core::cmp::Ordering;
+
+use defmt::Format;
+use heapless::{
+    binary_heap::{BinaryHeap, Min},
+    Vec,
+};
+
+use super::{verification, Failure, PusError, Reporter, Service, Telecommand};
+use crate::{
+    ccsds::{Cuc, CucFormat, PacketType, SpacePacket},
+    time::{self, Met},
+};
+
+use verification::RequestId;
+
+/// Service type of the time-based scheduling service.
+pub const SERVICE: u8 = 11;
+
+/// Enable the release of scheduled telecommands.
+pub const ENABLE: u8 = 1;
+/// Disable the release of scheduled telecommands, they stay in the schedule.
+pub const DISABLE: u8 = 2;
+/// Delete every scheduled telecommand.
+pub const RESET: u8 = 3;
+/// Insert telecommands: count, then release time and telecommand packet for each.
+pub const INSERT: u8 = 4;
+/// Delete the telecommands with the listed request IDs: count, then the request IDs.
+pub const DELETE: u8 = 5;
+/// Shift the telecommands with the listed request IDs: offset in milliseconds as a signed 32-bit
+/// integer, count, then the request IDs.
+pub const TIME_SHIFT: u8 = 7;
+/// Summary report: count, then release time and request ID for each telecommand.
+pub const SUMMARY_REPORT: u8 = 13;
+/// Shift every scheduled telecommand: offset in milliseconds as a signed 32-bit integer.
+pub const TIME_SHIFT_ALL: u8 = 15;
+/// Ask for a [`SUMMARY_REPORT`] of the whole schedule.
+pub const REPORT_SUMMARY: u8 = 17;
+
+/// There is no room for the telecommands in the schedule.
+pub const SCHEDULE_FULL: u16 = 0x0b01;
+/// A telecommand is longer than [`MAX_TC_LEN`].
+pub const TC_TOO_LONG: u16 = 0x0b02;
+/// A scheduled packet is not a telecommand.
+pub const NOT_TELECOMMAND: u16 = 0x0b03;
+/// A release time is not in the future.
+pub const TIME_PASSED: u16 = 0x0b04;
+/// No telecommand with a listed request ID is scheduled.
+pub const UNKNOWN_REQUEST: u16 = 0x0b05;
+
+/// Longest telecommand the scheduler stores.
+pub const MAX_TC_LEN: usize = 64;
+
+/// Reasons a telecommand cannot be scheduled, or the schedule not changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SchedulerError {
+    Full,
+    TooLong,
+    NotTelecommand,
+    /// The release time is not after the current time.
+    TimePassed,
+    UnknownRequest(RequestId),
+}
+
+impl SchedulerError {
+    /// The failure code of the verification report.
+    pub const fn code(self) -> u16 {
+        match self {
+            SchedulerError::Full => SCHEDULE_FULL,
+            SchedulerError::TooLong => TC_TOO_LONG,
+            SchedulerError::NotTelecommand => NOT_TELECOMMAND,
+            SchedulerError::TimePassed => TIME_PASSED,
+            SchedulerError::UnknownRequest(_) => UNKNOWN_REQUEST,
+        }
+    }
+}
+
+/// A scheduled telecommand.
+struct Entry {
+    release: Met,
+    /// Insertion order, so telecommands with the same release time keep their order.
+    sequence: u32,
+    request: RequestId,
+    tc: Vec<u8, MAX_TC_LEN>,
+}
+
+impl Entry {
+    fn key(&self) -> (Met, u32) {
+        (self.release, self.sequence)
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    /// Earlier release first, then older entries first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The time-based schedule of up to `N` telecommands, with release times in `format`.
+///
+/// `N` is at most 255, the count a summary report can hold.
+pub struct Scheduler<const N: usize> {
+    entries: BinaryHeap<Entry, Min, N>,
+    format: CucFormat,
+    enabled: bool,
+    next_sequence: u32,
+}
+
+impl<const N: usize> Scheduler<N> {
+    /// An empty schedule, with the release enabled.
+    pub const fn new(format: CucFormat) -> Self {
+        const { assert!(N <= 255, "summary reports count the entries in a byte") };
+        Self {
+            entries: BinaryHeap::new(),
+            format,
+            enabled: true,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Resumes the release. Telecommands that fell due meanwhile are released right away.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Holds back every telecommand until the release is enabled again.
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Deletes every scheduled telecommand.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Schedules `tc` for release at `release`, which has to be after `now`.
+    pub fn insert(
+        &mut self,
+        release: Met,
+        tc: &[u8],
+        now: Met,
+    ) -> Result<RequestId, SchedulerError> {
+        let request = check(tc)?;
+        if release <= now {
+            return Err(SchedulerError::TimePassed);
+        }
+
+        let entry = Entry {
+            release,
+            sequence: self.next_sequence,
+            request,
+            tc: Vec::from_slice(tc).map_err(|_| SchedulerError::TooLong)?,
+        };
+        self.entries.push(entry).map_err(|_| SchedulerError::Full)?;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        Ok(request)
+    }
+
+    /// Deletes the telecommands with request ID `request`.
+    pub fn delete(&mut self, request: RequestId) -> Result<(), SchedulerError> {
+        self.find(request)?;
+        self.rebuild(|entry| entry.request != request);
+        Ok(())
+    }
+
+    /// Moves the telecommands with request ID `request` by `offset` microseconds. Fails without
+    /// changes if one would end up at or before `now`.
+    pub fn shift(
+        &mut self,
+        request: RequestId,
+        offset: i64,
+        now: Met,
+    ) -> Result<(), SchedulerError> {
+        self.shift_requests(&[request], offset, now)
+    }
+
+    /// Moves the telecommands with the request IDs in `requests` by `offset` microseconds, all
+    /// of them or none.
+    pub fn shift_requests(
+        &mut self,
+        requests: &[RequestId],
+        offset: i64,
+        now: Met,
+    ) -> Result<(), SchedulerError> {
+        requests
+            .iter()
+            .try_for_each(|&request| self.find(request))?;
+        self.shift_where(|entry| requests.contains(&entry.request), offset, now)
+    }
+
+    /// Moves every scheduled telecommand by `offset` microseconds. Fails without changes if one
+    /// would end up at or before `now`.
+    pub fn shift_all(&mut self, offset: i64, now: Met) -> Result<(), SchedulerError> {
+        self.shift_where(|_| true, offset, now)
+    }
+
+    /// When the next telecommand is due, `None` if the schedule is empty or the release is
+    /// disabled.
+    pub fn next_release(&self) -> Option<Met> {
+        match self.enabled {
+            true => self.entries.peek().map(|entry| entry.release),
+            false => None,
+        }
+    }
+
+    /// Takes the next telecommand that is due at `now`, in order of release time.
+    pub fn pop_due(&mut self, now: Met) -> Option<Vec<u8, MAX_TC_LEN>> {
+        if self.next_release()? > now {
+            return None;
+        }
+        self.entries.pop().map(|entry| entry.tc)
+    }
+
+    /// The release times and request IDs of the schedule, in order of release.
+    pub fn summary(&self) -> Vec<(Met, RequestId), N> {
+        let mut summary: Vec<_, N> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.key(), entry.request))
+            .collect();
+        summary.sort_unstable_by_key(|&(key, _)| key);
+        summary
+            .into_iter()
+            .map(|((release, _), request)| (release, request))
+            .collect()
+    }
+
+    fn find(&self, request: RequestId) -> Result<(), SchedulerError> {
+        match self.entries.iter().any(|entry| entry.request == request) {
+            true => Ok(()),
+            false => Err(SchedulerError::UnknownRequest(request)),
+        }
+    }
+
+    fn shift_where(
+        &mut self,
+        selected: impl Fn(&Entry) -> bool,
+        offset: i64,
+        now: Met,
+    ) -> Result<(), SchedulerError> {
+        let shifted = |release: Met| {
+            release
+                .ticks()
+                .checked_add_signed(offset)
+                .map(Met::from_ticks)
+                .filter(|&release| release > now)
+        };
+        if self
+            .entries
+            .iter()
+            .filter(|entry| selected(entry))
+            .any(|entry| shifted(entry.release).is_none())
+        {
+            return Err(SchedulerError::TimePassed);
+        }
+
+        self.rebuild(|entry| {
+            if selected(entry) {
+                entry.release = shifted(entry.release).unwrap_or(entry.release);
+            }
+            true
+        });
+        Ok(())
+    }
+
+    /// Puts the entries `keep` returns `true` for back into a new heap, after `keep` had a
+    /// chance to change them.
+    fn rebuild(&mut self, mut keep: impl FnMut(&mut Entry) -> bool) {
+        let entries = core::mem::replace(&mut self.entries, BinaryHeap::new()).into_vec();
+        for mut entry in entries {
+            if keep(&mut entry) {
+                // Never more entries than before
+                self.entries.push(entry).ok();
+            }
+        }
+    }
+
+    fn report_summary(&self, tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let format = self.format;
+        let summary = self.summary();
+        tm.report_with(SERVICE, SUMMARY_REPORT, |buf| {
+            let time_len = format.encoded_len();
+            let entry_len = time_len + 4;
+            let len = 1 + summary.len() * entry_len;
+            let Some(report) = buf.get_mut(..len) else {
+                return len;
+            };
+            report[0] = summary.len() as u8;
+            for ((release, request), out) in
+                summary.iter().zip(report[1..].chunks_exact_mut(entry_len))
+            {
+                let (time, id) = out.split_at_mut(time_len);
+                // The chunk has room for the time
+                time::to_cuc(*release, format).encode(format, time).ok();
+                id.copy_from_slice(&request.to_bytes());
+            }
+            len
+        })
+    }
+}
+
+/// Checks that `tc` can be scheduled and returns its request ID.
+fn check(tc: &[u8]) -> Result<RequestId, SchedulerError> {
+    let packet = SpacePacket::parse(tc).map_err(|_| SchedulerError::NotTelecommand)?;
+    if packet.packet_type() != PacketType::Telecommand || packet.packet_len() != tc.len() {
+        return Err(SchedulerError::NotTelecommand);
+    }
+    if tc.len() > MAX_TC_LEN {
+        return Err(SchedulerError::TooLong);
+    }
+    Ok(RequestId::of(&packet))
+}
+
+/// The release times and telecommands in the application data of [`INSERT`].
+#[derive(Clone)]
+struct Insertions<'a> {
+    rest: &'a [u8],
+    remaining: u8,
+    format: CucFormat,
+}
+
+impl<'a> Insertions<'a> {
+    fn split_next(&mut self) -> Option<(Cuc, &'a [u8])> {
+        let time = Cuc::decode(self.format, self.rest).ok()?;
+        let packet = &self.rest[self.format.encoded_len()..];
+        let tc = SpacePacket::parse(packet).ok()?.as_bytes();
+        self.rest = &packet[tc.len()..];
+        Some((time, tc))
+    }
+}
+
+impl<'a> Iterator for Insertions<'a> {
+    type Item = (Cuc, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.split_next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = usize::from(self.remaining);
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for Insertions<'_> {}
+
+/// Splits the application data of [`INSERT`], `None` unless it holds exactly the announced
+/// number of packets.
+fn insertions(data: &[u8], format: CucFormat) -> Option<Insertions<'_>> {
+    let (&count, rest) = data.split_first()?;
+    let insertions = Insertions {
+        rest,
+        remaining: count,
+        format,
+    };
+
+    let mut check = insertions.clone();
+    for _ in 0..count {
+        check.split_next()?;
+    }
+    match check.rest.is_empty() {
+        true => Some(insertions),
+        false => None,
+    }
+}
+
+/// Reads a count octet followed by that many request IDs.
+fn requests(data: &[u8]) -> Option<impl ExactSizeIterator<Item = RequestId> + Clone + '_> {
+    let (&count, ids) = data.split_first()?;
+    if ids.len() != 4 * usize::from(count) {
+        return None;
+    }
+    Some(
+        ids.chunks_exact(4)
+            .map(|id| RequestId::from_bytes(&[id[0], id[1], id[2], id[3]])),
+    )
+}
+
+/// Reads the millisecond offset of a time-shift, in microseconds, and the data after it.
+fn offset(data: &[u8]) -> Option<(i64, &[u8])> {
+    let (offset, rest) = data.split_first_chunk::<4>()?;
+    Some((i64::from(i32::from_be_bytes(*offset)) * 1000, rest))
+}
+
+impl<const N: usize> Service for Scheduler<N> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+        let unknown = |request| match self.find(request) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(error.code()),
+        };
+
+        match tc.subtype() {
+            ENABLE | DISABLE | RESET | REPORT_SUMMARY if data.is_empty() => Ok(()),
+            INSERT => {
+                let mut entries =
+                    insertions(data, self.format).ok_or(verification::INVALID_DATA)?;
+                if entries.len() > N - self.len() {
+                    return Err(SCHEDULE_FULL);
+                }
+                entries.try_for_each(|(_, tc)| match check(tc) {
+                    Ok(_) => Ok(()),
+                    Err(error) => Err(error.code()),
+                })
+            }
+            DELETE => requests(data)
+                .ok_or(verification::INVALID_DATA)?
+                .try_for_each(unknown),
+            TIME_SHIFT => {
+                let (_, ids) = offset(data).ok_or(verification::INVALID_DATA)?;
+                let mut ids = requests(ids).ok_or(verification::INVALID_DATA)?;
+                // More than can be scheduled, so some are repeated
+                if ids.len() > N {
+                    return Err(verification::INVALID_DATA);
+                }
+                ids.try_for_each(unknown)
+            }
+            TIME_SHIFT_ALL if data.len() == 4 => Ok(()),
+            ENABLE | DISABLE | RESET | REPORT_SUMMARY | TIME_SHIFT_ALL => {
+                Err(verification::INVALID_DATA)
+            }
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        let data = tc.app_data();
+        let now = time::from_cuc(tm.time(), tm.format());
+        let invalid = Failure::Start(verification::INVALID_DATA);
+        let failed = |error: SchedulerError| Failure::Completion(error.code());
+
+        match tc.subtype() {
+            ENABLE => self.enable(),
+            DISABLE => self.disable(),
+            RESET => self.clear(),
+            INSERT => {
+                let entries = insertions(data, self.format).ok_or(invalid)?;
+                // All of them or none
+                let format = self.format;
+                if entries
+                    .clone()
+                    .any(|(release, _)| time::from_cuc(release, format) <= now)
+                {
+                    return Err(Failure::Start(TIME_PASSED));
+                }
+                for (release, tc) in entries {
+                    self.insert(time::from_cuc(release, format), tc, now)
+                        .map_err(failed)?;
+                }
+            }
+            DELETE => {
+                for request in requests(data).ok_or(invalid)? {
+                    self.delete(request).map_err(failed)?;
+                }
+            }
+            TIME_SHIFT => {
+                let (offset, ids) = offset(data).ok_or(invalid)?;
+                // Accepted lists have at most N IDs
+                let ids: Vec<RequestId, N> = requests(ids).ok_or(invalid)?.take(N).collect();
+                self.shift_requests(&ids, offset, now).map_err(failed)?;
+            }
+            TIME_SHIFT_ALL => {
+                let (offset, _) = offset(data).ok_or(invalid)?;
+                self.shift_all(offset, now).map_err(failed)?;
+            }
+            _ => self.report_summary(tm)?,
+        }
+        Ok(())
+    }
+}
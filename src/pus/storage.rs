@@ -0,0 +1,170 @@
+//! Storage and retrieval service (15), for the onboard [`EventLog`].
+//!
+//! Ground asks for the state of the log, then dumps the records it has not seen yet by their
+//! sequence numbers. Records that are no longer kept or fail their CRC are left out of the
+//! reports. The service borrows the log and the flash for one telecommand:
+//!
+//! ```ignore
+//! let mut storage = EventLogService::new(&log, &flash);
+//! dispatcher.register(&mut storage)?;
+//! ```
+
+use heapless::Vec;
+
+use super::{verification, Failure, PusError, Reporter, Service, Telecommand};
+use crate::{
+    eventlog::{EventLog, LogEntry, LogError},
+    flash::Flash,
+    time,
+};
+
+/// Service type of the storage and retrieval service.
+pub const SERVICE: u8 = 15;
+
+/// Dump a range of records: first sequence number (u32) and count (u16), at most
+/// [`MAX_DUMP_LEN`].
+pub const DUMP: u8 = 128;
+/// Records: count, then sequence number, CUC time, severity, event ID, parameter length and
+/// parameters of each.
+pub const ENTRIES_REPORT: u8 = 129;
+/// Ask for a [`STATUS_REPORT`].
+pub const REPORT_STATUS: u8 = 130;
+/// Log status: sequence numbers of the oldest and the next record and the capacity in records,
+/// each a u32.
+pub const STATUS_REPORT: u8 = 131;
+
+/// The range to dump is longer than [`MAX_DUMP_LEN`].
+pub const RANGE_TOO_LONG: u16 = 0x0f01;
+/// The flash could not be read.
+pub const FLASH_FAILED: u16 = 0x0f02;
+
+/// Most records dumped by one request.
+pub const MAX_DUMP_LEN: u16 = 48;
+
+/// Most records in one [`ENTRIES_REPORT`].
+const ENTRIES_PER_REPORT: usize = 6;
+
+impl LogError {
+    /// The failure code of the verification report.
+    pub const fn code(self) -> u16 {
+        match self {
+            LogError::TooLong => verification::INVALID_DATA,
+            LogError::NotMounted
+            | LogError::NotFound(_)
+            | LogError::Corrupted(_)
+            | LogError::Flash(_) => FLASH_FAILED,
+        }
+    }
+}
+
+/// The storage and retrieval service of an event log of `S` sectors in `flash`.
+pub struct EventLogService<'a, F: Flash + ?Sized, const S: usize> {
+    log: &'a EventLog<S>,
+    flash: &'a F,
+}
+
+impl<'a, F: Flash + ?Sized, const S: usize> EventLogService<'a, F, S> {
+    pub fn new(log: &'a EventLog<S>, flash: &'a F) -> Self {
+        Self { log, flash }
+    }
+
+    /// Sends [`ENTRIES_REPORT`]s of the `count` records from `first`, at least one.
+    fn dump(&self, first: u32, count: u16, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        let mut entries = Vec::<LogEntry, ENTRIES_PER_REPORT>::new();
+        let mut reported = false;
+
+        for seq in (0..u32::from(count)).map(|offset| first.wrapping_add(offset)) {
+            match self.log.read(self.flash, seq) {
+                Ok(entry) => {
+                    // There is room, the full ones are reported right away
+                    entries.push(entry).ok();
+                }
+                Err(LogError::NotFound(_) | LogError::Corrupted(_)) => continue,
+                Err(error) => return Err(Failure::Completion(error.code())),
+            }
+            if entries.is_full() {
+                report_entries(&entries, tm)?;
+                entries.clear();
+                reported = true;
+            }
+        }
+
+        if !entries.is_empty() || !reported {
+            report_entries(&entries, tm)?;
+        }
+        Ok(())
+    }
+
+    fn report_status(&self, tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let mut status = [0; 12];
+        status[..4].copy_from_slice(&self.log.oldest_seq().to_be_bytes());
+        status[4..8].copy_from_slice(&self.log.next_seq().to_be_bytes());
+        status[8..].copy_from_slice(&(self.log.capacity(self.flash) as u32).to_be_bytes());
+        tm.report(SERVICE, STATUS_REPORT, &status)
+    }
+}
+
+fn report_entries(entries: &[LogEntry], tm: &mut Reporter<'_>) -> Result<(), PusError> {
+    let format = tm.format();
+    tm.report_with(SERVICE, ENTRIES_REPORT, |buf| {
+        buf[0] = entries.len() as u8;
+        let mut len = 1;
+        for entry in entries {
+            let entry_len = 4 + format.encoded_len() + 4 + entry.params().len();
+            let Some(out) = buf.get_mut(len..len + entry_len) else {
+                return usize::MAX;
+            };
+            out[..4].copy_from_slice(&entry.seq.to_be_bytes());
+            let (time, rest) = out[4..].split_at_mut(format.encoded_len());
+            // The slice has the length of the time
+            time::to_cuc(entry.time, format).encode(format, time).ok();
+            rest[0] = entry.severity as u8;
+            rest[1..3].copy_from_slice(&entry.id.to_be_bytes());
+            rest[3] = entry.params().len() as u8;
+            rest[4..].copy_from_slice(entry.params());
+            len += entry_len;
+        }
+        len
+    })
+}
+
+/// Reads the application data of [`DUMP`]: first sequence number and count.
+fn range(data: &[u8]) -> Option<(u32, u16)> {
+    let data: &[u8; 6] = data.try_into().ok()?;
+    Some((
+        u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        u16::from_be_bytes([data[4], data[5]]),
+    ))
+}
+
+impl<F: Flash + ?Sized, const S: usize> Service for EventLogService<'_, F, S> {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+
+        match tc.subtype() {
+            DUMP => match range(data).ok_or(verification::INVALID_DATA)? {
+                (_, count) if count > MAX_DUMP_LEN => Err(RANGE_TOO_LONG),
+                _ => Ok(()),
+            },
+            REPORT_STATUS if data.is_empty() => Ok(()),
+            REPORT_STATUS => Err(verification::INVALID_DATA),
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        match tc.subtype() {
+            DUMP => {
+                let (first, count) =
+                    range(tc.app_data()).ok_or(Failure::Start(verification::INVALID_DATA))?;
+                self.dump(first, count, tm)?;
+            }
+            _ => self.report_status(tm)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Time management service (9).
+//!
+//! The onboard time is the MET of the report timestamps. The service reports it as a CUC time
+//! and, once ground has correlated it with UTC, the UTC as a CDS time. Ground takes the
+//! correlation from the time reports and uploads it with a [`CORRELATE`] request.
+
+use defmt::Format;
+
+use super::{verification, Failure, PusError, Reporter, Service, Telecommand};
+use crate::{
+    ccsds::{Cds, CdsFormat, Cuc, CucFormat},
+    time::{self, Correlation, Met, Utc},
+};
+
+/// Service type of the time management service.
+pub const SERVICE: u8 = 9;
+
+/// CUC time report: P-field and T-field of the MET.
+pub const CUC_TIME_REPORT: u8 = 2;
+/// CDS time report: P-field and T-field of the UTC.
+pub const CDS_TIME_REPORT: u8 = 3;
+/// Ask for a [`CUC_TIME_REPORT`], followed by a [`CDS_TIME_REPORT`] if the MET is correlated.
+pub const REPORT_TIME: u8 = 128;
+/// Correlate MET with UTC: the MET as a CUC T-field, the UTC at that MET as a CDS T-field and the
+/// drift of the onboard clock in parts per billion as a signed 32-bit integer.
+pub const CORRELATE: u8 = 129;
+
+/// A time field holds a value out of its range.
+pub const INVALID_TIME: u16 = 0x0901;
+
+/// The time management service, with times in `cuc` and `cds` formats.
+#[derive(Debug, Format)]
+pub struct TimeManagement {
+    cuc: CucFormat,
+    cds: CdsFormat,
+    correlation: Option<Correlation>,
+}
+
+impl TimeManagement {
+    /// A service without correlation.
+    pub const fn new(cuc: CucFormat, cds: CdsFormat) -> Self {
+        Self {
+            cuc,
+            cds,
+            correlation: None,
+        }
+    }
+
+    pub fn correlation(&self) -> Option<Correlation> {
+        self.correlation
+    }
+
+    pub fn correlate(&mut self, correlation: Correlation) {
+        self.correlation = Some(correlation);
+    }
+
+    /// The UTC at `met`, `None` until the MET is correlated.
+    pub fn to_utc(&self, met: Met) -> Option<Utc> {
+        self.correlation.map(|correlation| correlation.to_utc(met))
+    }
+
+    /// Sends the time reports for the time of `tm`.
+    pub fn report(&self, tm: &mut Reporter<'_>) -> Result<(), PusError> {
+        let met = time::from_cuc(tm.time(), tm.format());
+
+        let cuc = self.cuc;
+        tm.report_with(SERVICE, CUC_TIME_REPORT, |buf| {
+            let len = 1 + cuc.encoded_len();
+            let Some(report) = buf.get_mut(..len) else {
+                return len;
+            };
+            report[0] = cuc.pfield();
+            // The report has room for the time
+            time::to_cuc(met, cuc).encode(cuc, &mut report[1..]).ok();
+            len
+        })?;
+
+        let Some(utc) = self.to_utc(met) else {
+            return Ok(());
+        };
+        let cds = self.cds;
+        tm.report_with(SERVICE, CDS_TIME_REPORT, |buf| {
+            let len = 1 + cds.encoded_len();
+            let Some(report) = buf.get_mut(..len) else {
+                return len;
+            };
+            report[0] = cds.pfield();
+            utc.to_cds().encode(cds, &mut report[1..]).ok();
+            len
+        })
+    }
+
+    /// Reads the application data of [`CORRELATE`].
+    fn correlation_data(&self, data: &[u8]) -> Result<Correlation, u16> {
+        let (met, rest) = data
+            .split_at_checked(self.cuc.encoded_len())
+            .ok_or(verification::INVALID_DATA)?;
+        let (utc, drift) = rest
+            .split_at_checked(self.cds.encoded_len())
+            .ok_or(verification::INVALID_DATA)?;
+        let drift: [u8; 4] = drift.try_into().map_err(|_| verification::INVALID_DATA)?;
+
+        let met = Cuc::decode(self.cuc, met).map_err(|_| verification::INVALID_DATA)?;
+        let utc = Cds::decode(self.cds, utc).map_err(|_| INVALID_TIME)?;
+        Ok(
+            Correlation::new(time::from_cuc(met, self.cuc), Utc::from_cds(utc))
+                .drift(i32::from_be_bytes(drift)),
+        )
+    }
+}
+
+impl Service for TimeManagement {
+    fn service_type(&self) -> u8 {
+        SERVICE
+    }
+
+    fn accept(&self, tc: &Telecommand<'_>) -> Result<(), u16> {
+        let data = tc.app_data();
+
+        match tc.subtype() {
+            REPORT_TIME if data.is_empty() => Ok(()),
+            REPORT_TIME => Err(verification::INVALID_DATA),
+            CORRELATE => self.correlation_data(data).map(|_| ()),
+            _ => Err(verification::UNKNOWN_SUBTYPE),
+        }
+    }
+
+    fn handle(&mut self, tc: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        match tc.subtype() {
+            CORRELATE => {
+                let correlation = self
+                    .correlation_data(tc.app_data())
+                    .map_err(Failure::Start)?;
+                self.correlate(correlation);
+            }
+            _ => self.report(tm)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Request verification service (1).
+//!
+//! The [`Dispatcher`](super::Dispatcher) sends these reports itself. Each carries the
+//! [`RequestId`] of the telecommand, failure reports add a 16-bit failure code. Codes below 0x100
+//! are the generic ones here; services number their own from their service type times 0x100.
+
+use defmt::Format;
+
+use crate::ccsds::SpacePacket;
+
+/// Service type of the verification reports.
+pub const SERVICE: u8 = 1;
+
+pub const ACCEPTANCE_SUCCESS: u8 = 1;
+pub const ACCEPTANCE_FAILURE: u8 = 2;
+pub const START_SUCCESS: u8 = 3;
+pub const START_FAILURE: u8 = 4;
+pub const COMPLETION_SUCCESS: u8 = 7;
+pub const COMPLETION_FAILURE: u8 = 8;
+
+/// The PUS header is missing or has another version.
+pub const MALFORMED: u16 = 1;
+/// No service with this type is registered.
+pub const UNKNOWN_SERVICE: u16 = 2;
+/// The service has no request with this subtype.
+pub const UNKNOWN_SUBTYPE: u16 = 3;
+/// The application data does not fit the request.
+pub const INVALID_DATA: u16 = 4;
+/// A report of the request could not be sent.
+pub const REPORT_FAILED: u16 = 5;
+
+/// Which successful stages of a request are reported. Failures are always reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct AckFlags(u8);
+
+impl AckFlags {
+    pub const NONE: Self = Self(0);
+    pub const ACCEPTANCE: Self = Self(0b0001);
+    pub const START: Self = Self(0b0010);
+    pub const PROGRESS: Self = Self(0b0100);
+    pub const COMPLETION: Self = Self(0b1000);
+    pub const ALL: Self = Self(0b1111);
+
+    /// Takes the low 4 bits of `bits`.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits & 0b1111)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Identifies a telecommand in its verification reports: the packet ID and the packet sequence
+/// control, the first 4 octets of its primary header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RequestId {
+    pub packet_id: u16,
+    pub sequence_control: u16,
+}
+
+impl RequestId {
+    pub fn of(packet: &SpacePacket<'_>) -> Self {
+        let bytes = packet.as_bytes();
+        Self {
+            packet_id: u16::from_be_bytes([bytes[0], bytes[1]]),
+            sequence_control: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
+    }
+
+    pub const fn to_bytes(&self) -> [u8; 4] {
+        let [id_hi, id_lo] = self.packet_id.to_be_bytes();
+        let [seq_hi, seq_lo] = self.sequence_control.to_be_bytes();
+        [id_hi, id_lo, seq_hi, seq_lo]
+    }
+
+    /// Reads the request ID at the start of the source data of a verification report.
+    pub fn from_bytes(bytes: &[u8; 4]) -> Self {
+        Self {
+            packet_id: u16::from_be_bytes([bytes[0], bytes[1]]),
+            sequence_control: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
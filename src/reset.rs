@@ -0,0 +1,561 @@
+//! Reset cause, boot counter and crash record, kept over resets in retained RAM.
+//!
+//! A panic or a HardFault used to halt the node with nothing left to tell what happened once a
+//! watchdog reset it. `memory.x` keeps the last kilobyte of RAM out of the stack and the statics,
+//! and the startup code leaves it alone, so it holds a [`Retained`] record through resets. The
+//! panic and HardFault handlers of this crate leave a [`Crash`] there and reset the node, and so
+//! does the [watchdog supervisor](crate::watchdog) for a task that starved. The next boot takes
+//! it, together with the boot counter and the reset cause from RCC:
+//!
+//! ```ignore
+//! let last = reset::take(&device.RCC).unwrap();
+//! info!("Boot {} after {}", last.boots, last.cause);
+//! events.report(&mut tm, Severity::Informative, EVENT_BOOT, &last.summary())?;
+//! ```
+//!
+//! ```text
+//! record: | "RST1" | boots (u32) | crash | 0 ... | CRC-16 |
+//! crash:  | 0 | none
+//!         | 1 | line (u32) | file length | message length | file | message |  a panic
+//!         | 2 | r0 | r1 | r2 | r3 | r12 | lr | pc | xpsr | CFSR | HFSR |      a HardFault
+//!         | 3 | task | name length | name |                                 a starved task
+//! ```
+//!
+//! The RAM holds noise after a power loss, which fails the CRC, so the count starts over.
+
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use heapless::Vec;
+
+use crate::flash::crc16;
+
+/// Longest panic message kept, longer ones are cut.
+pub const MAX_MESSAGE_LEN: usize = 64;
+
+/// Longest file name of a panic location kept, longer ones keep their end.
+pub const MAX_FILE_LEN: usize = 32;
+
+/// Longest task name kept, longer ones are cut.
+pub const MAX_TASK_NAME_LEN: usize = 16;
+
+/// Longest encoded [`Crash`].
+pub const MAX_CRASH_LEN: usize = 7 + MAX_FILE_LEN + MAX_MESSAGE_LEN;
+
+/// Length of [`BootRecord::summary`].
+pub const SUMMARY_LEN: usize = 10;
+
+/// Length of the record in retained RAM.
+pub const RETAINED_LEN: usize = 128;
+
+const MAGIC: [u8; 4] = *b"RST1";
+
+const PANIC: u8 = 1;
+const HARD_FAULT: u8 = 2;
+const STARVED: u8 = 3;
+
+/// Cause of the last reset, from the flags of RCC_CSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ResetCause {
+    /// No flag was set.
+    Unknown = 0,
+    /// Power on or power down.
+    PowerOn = 1,
+    BrownOut = 2,
+    /// The NRST pin.
+    Pin = 3,
+    /// A reset request of the software, also after a crash.
+    Software = 4,
+    IndependentWatchdog = 5,
+    WindowWatchdog = 6,
+    /// Standby or stop mode with the option bytes set to reset.
+    LowPower = 7,
+}
+
+impl ResetCause {
+    /// The cause flagged in `csr`. The pin flag is set by every reset and the brown-out flag by
+    /// every power on, so they only count when nothing else is flagged.
+    pub const fn from_csr(csr: u32) -> Self {
+        const FLAGS: [(u32, ResetCause); 7] = [
+            (31, ResetCause::LowPower),
+            (30, ResetCause::WindowWatchdog),
+            (29, ResetCause::IndependentWatchdog),
+            (28, ResetCause::Software),
+            (27, ResetCause::PowerOn),
+            (25, ResetCause::BrownOut),
+            (26, ResetCause::Pin),
+        ];
+        let mut i = 0;
+        while i < FLAGS.len() {
+            if csr & 1 << FLAGS[i].0 != 0 {
+                return FLAGS[i].1;
+            }
+            i += 1;
+        }
+        ResetCause::Unknown
+    }
+}
+
+/// Where a panic happened and what it said, cut to fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicRecord {
+    pub line: u32,
+    file: Text<MAX_FILE_LEN>,
+    message: Text<MAX_MESSAGE_LEN>,
+}
+
+impl PanicRecord {
+    pub fn new(file: &str, line: u32, message: impl fmt::Display) -> Self {
+        // The end of a path names the file
+        let mut start = file.len().saturating_sub(MAX_FILE_LEN);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let mut record = Self {
+            line,
+            file: Text::new(),
+            message: Text::new(),
+        };
+        record.file.write_str(&file[start..]).ok();
+        // The text cuts what does not fit
+        write!(record.message, "{}", message).ok();
+        record
+    }
+
+    pub fn file(&self) -> &str {
+        self.file.as_str()
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+impl Format for PanicRecord {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{=str}:{=u32}: {=str}",
+            self.file(),
+            self.line,
+            self.message()
+        )
+    }
+}
+
+/// The registers a HardFault stacked and the fault status registers of the SCB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FaultRecord {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+}
+
+impl FaultRecord {
+    fn words(&self) -> [u32; 10] {
+        [
+            self.r0, self.r1, self.r2, self.r3, self.r12, self.lr, self.pc, self.xpsr, self.cfsr,
+            self.hfsr,
+        ]
+    }
+}
+
+/// A task that did not check in with the watchdog supervisor in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StarvedRecord {
+    /// Index of the task in the supervisor.
+    pub task: u8,
+    name: Text<MAX_TASK_NAME_LEN>,
+}
+
+impl StarvedRecord {
+    pub fn new(task: u8, name: &str) -> Self {
+        let mut record = Self {
+            task,
+            name: Text::new(),
+        };
+        record.name.write_str(name).ok();
+        record
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+impl Format for StarvedRecord {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "task {=u8} ({=str})", self.task, self.name())
+    }
+}
+
+/// How the last run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Crash {
+    Panic(PanicRecord),
+    HardFault(FaultRecord),
+    /// The watchdog reset the node because a task starved.
+    Starved(StarvedRecord),
+}
+
+impl Crash {
+    /// The kind of crash in its encoding, 1 for a panic, 2 for a HardFault and 3 for a starved
+    /// task.
+    pub const fn kind(&self) -> u8 {
+        match self {
+            Crash::Panic(_) => PANIC,
+            Crash::HardFault(_) => HARD_FAULT,
+            Crash::Starved(_) => STARVED,
+        }
+    }
+
+    /// The crash as laid out in the module documentation.
+    pub fn to_bytes(&self) -> Vec<u8, MAX_CRASH_LEN> {
+        let mut bytes = Vec::new();
+        // The encoding fits by the definition of MAX_CRASH_LEN
+        bytes.push(self.kind()).ok();
+        match self {
+            Crash::Panic(panic) => {
+                bytes.extend_from_slice(&panic.line.to_be_bytes()).ok();
+                bytes.push(panic.file().len() as u8).ok();
+                bytes.push(panic.message().len() as u8).ok();
+                bytes.extend_from_slice(panic.file().as_bytes()).ok();
+                bytes.extend_from_slice(panic.message().as_bytes()).ok();
+            }
+            Crash::HardFault(fault) => {
+                for word in fault.words() {
+                    bytes.extend_from_slice(&word.to_be_bytes()).ok();
+                }
+            }
+            Crash::Starved(starved) => {
+                bytes.push(starved.task).ok();
+                bytes.push(starved.name().len() as u8).ok();
+                bytes.extend_from_slice(starved.name().as_bytes()).ok();
+            }
+        }
+        bytes
+    }
+
+    /// Reads a crash from the start of `data`, `Ok(None)` if there is none.
+    fn decode(data: &[u8]) -> Result<Option<Self>, ()> {
+        let word = |i: usize| -> Result<u32, ()> {
+            let bytes = data.get(1 + 4 * i..5 + 4 * i).ok_or(())?;
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+
+        match data.first() {
+            Some(0) => Ok(None),
+            Some(&PANIC) => {
+                let line = word(0)?;
+                let (file_len, message_len) = match data.get(5..7) {
+                    Some(&[file, message]) => (usize::from(file), usize::from(message)),
+                    _ => return Err(()),
+                };
+                let file = data.get(7..7 + file_len).ok_or(())?;
+                let message = data
+                    .get(7 + file_len..7 + file_len + message_len)
+                    .ok_or(())?;
+                let file = core::str::from_utf8(file).map_err(|_| ())?;
+                let message = core::str::from_utf8(message).map_err(|_| ())?;
+                if file.len() > MAX_FILE_LEN || message.len() > MAX_MESSAGE_LEN {
+                    return Err(());
+                }
+                Ok(Some(Crash::Panic(PanicRecord::new(file, line, message))))
+            }
+            Some(&HARD_FAULT) => Ok(Some(Crash::HardFault(FaultRecord {
+                r0: word(0)?,
+                r1: word(1)?,
+                r2: word(2)?,
+                r3: word(3)?,
+                r12: word(4)?,
+                lr: word(5)?,
+                pc: word(6)?,
+                xpsr: word(7)?,
+                cfsr: word(8)?,
+                hfsr: word(9)?,
+            }))),
+            Some(&STARVED) => {
+                let (task, len) = match data.get(1..3) {
+                    Some(&[task, len]) => (task, usize::from(len)),
+                    _ => return Err(()),
+                };
+                let name = data.get(3..3 + len).ok_or(())?;
+                let name = core::str::from_utf8(name).map_err(|_| ())?;
+                if name.len() > MAX_TASK_NAME_LEN {
+                    return Err(());
+                }
+                Ok(Some(Crash::Starved(StarvedRecord::new(task, name))))
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the last run left behind, taken once at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct BootRecord {
+    /// Boots since the retained RAM lost power or the last run settled, this one included.
+    pub boots: u32,
+    pub cause: ResetCause,
+    pub crash: Option<Crash>,
+}
+
+impl BootRecord {
+    /// The boots (u32), the reset cause, the kind of crash, 0 for none, and the line of a panic,
+    /// the PC of a HardFault or the index of a starved task (u32). It fits into an
+    /// [`EventLog`](crate::eventlog::EventLog) record.
+    pub fn summary(&self) -> [u8; SUMMARY_LEN] {
+        let (kind, at) = match &self.crash {
+            None => (0, 0),
+            Some(crash @ Crash::Panic(panic)) => (crash.kind(), panic.line),
+            Some(crash @ Crash::HardFault(fault)) => (crash.kind(), fault.pc),
+            Some(crash @ Crash::Starved(starved)) => (crash.kind(), u32::from(starved.task)),
+        };
+        let mut summary = [0; SUMMARY_LEN];
+        summary[..4].copy_from_slice(&self.boots.to_be_bytes());
+        summary[4] = self.cause as u8;
+        summary[5] = kind;
+        summary[6..].copy_from_slice(&at.to_be_bytes());
+        summary
+    }
+}
+
+/// The record in retained RAM. Any bytes are a `Retained`, the CRC tells whether they hold a
+/// record.
+#[repr(C, align(4))]
+pub struct Retained([u8; RETAINED_LEN]);
+
+impl Default for Retained {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Retained {
+    /// Bytes that hold no record, like the RAM after a power loss.
+    pub const fn new() -> Self {
+        Self([0; RETAINED_LEN])
+    }
+
+    /// Counts a boot with the reset flags `csr` and takes the crash of the last run.
+    pub fn boot(&mut self, csr: u32) -> BootRecord {
+        let (boots, crash) = self.decode().unwrap_or((0, None));
+        let boots = boots.wrapping_add(1);
+        self.encode(boots, None);
+        BootRecord {
+            boots,
+            cause: ResetCause::from_csr(csr),
+            crash,
+        }
+    }
+
+    /// Keeps `crash` for the next boot. The first crash of a run is kept, it is the cause of the
+    /// others.
+    pub fn record(&mut self, crash: &Crash) {
+        match self.decode() {
+            Some((_, Some(_))) => {}
+            Some((boots, None)) => self.encode(boots, Some(crash)),
+            None => self.encode(0, Some(crash)),
+        }
+    }
+
+    /// Starts the count of boots over, once the run went well for long enough. A crash of this run
+    /// is kept.
+    pub fn settle(&mut self) {
+        let crash = self.decode().and_then(|(_, crash)| crash);
+        self.encode(0, crash.as_ref());
+    }
+
+    /// Flips the bits of `mask` in byte `offset`, like noise in the RAM.
+    #[cfg(feature = "mock")]
+    pub fn corrupt(&mut self, offset: usize, mask: u8) {
+        self.0[offset] ^= mask;
+    }
+
+    fn decode(&self) -> Option<(u32, Option<Crash>)> {
+        let (data, crc) = self.0.split_at(RETAINED_LEN - 2);
+        if data[..4] != MAGIC || crc16(data) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+        let boots = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        Some((boots, Crash::decode(&data[8..]).ok()?))
+    }
+
+    fn encode(&mut self, boots: u32, crash: Option<&Crash>) {
+        self.0 = [0; RETAINED_LEN];
+        self.0[..4].copy_from_slice(&MAGIC);
+        self.0[4..8].copy_from_slice(&boots.to_be_bytes());
+        if let Some(crash) = crash {
+            let bytes = crash.to_bytes();
+            self.0[8..8 + bytes.len()].copy_from_slice(&bytes);
+        }
+        let crc = crc16(&self.0[..RETAINED_LEN - 2]);
+        self.0[RETAINED_LEN - 2..].copy_from_slice(&crc.to_be_bytes());
+    }
+}
+
+/// UTF-8 text of up to `N` bytes, which cuts what does not fit at a character boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Text<const N: usize> {
+    bytes: [u8; N],
+    len: u8,
+}
+
+impl<const N: usize> Text<N> {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole characters are written
+        core::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl<const N: usize> Write for Text<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let len = usize::from(self.len);
+            if len + c.len_utf8() > N {
+                return Err(fmt::Error);
+            }
+            c.encode_utf8(&mut self.bytes[len..]);
+            self.len += c.len_utf8() as u8;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "none")]
+pub use device::{record, settle, take};
+
+#[cfg(target_os = "none")]
+mod device {
+    use core::{
+        mem::MaybeUninit,
+        panic::PanicInfo,
+        ptr::addr_of_mut,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use cortex_m::peripheral::SCB;
+    use cortex_m_rt::{exception, ExceptionFrame};
+    use stm32f4xx_hal::pac::RCC;
+
+    use super::{BootRecord, Crash, FaultRecord, PanicRecord, Retained};
+
+    /// The record, in the RAM that memory.x keeps for it.
+    #[link_section = ".uninit.retained"]
+    static mut RETAINED: MaybeUninit<Retained> = MaybeUninit::uninit();
+
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    /// Runs `f` on a copy of the record and writes the copy back.
+    ///
+    /// The RAM is never initialised, so there is no reference to it. The volatile read takes the
+    /// bytes as they are, and any bytes are a `Retained`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the record at the same time. [`take`] runs once, and the crash
+    /// handlers that interrupt it do not return.
+    unsafe fn update<R>(f: impl FnOnce(&mut Retained) -> R) -> R {
+        let record = addr_of_mut!(RETAINED).cast::<Retained>();
+        let mut retained = record.read_volatile();
+        let result = f(&mut retained);
+        record.write_volatile(retained);
+        result
+    }
+
+    /// Counts this boot and takes what the last run left behind, `None` after the first call.
+    ///
+    /// The reset flags of RCC are cleared, call it before the clocks are set up with
+    /// `RCC::constrain`.
+    pub fn take(rcc: &RCC) -> Option<BootRecord> {
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        let csr = rcc.csr.read().bits();
+        // The flags add up over resets until they are removed
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        // SAFETY: this is the first and only call
+        Some(unsafe { update(|retained| retained.boot(csr)) })
+    }
+
+    /// Keeps `crash` for the next boot, unless a crash of this run is kept already.
+    pub fn record(crash: &Crash) {
+        cortex_m::interrupt::free(|_| {
+            // SAFETY: `take` is done before the tasks run, and the crash handlers that could
+            // interrupt this do not return
+            unsafe { update(|retained| retained.record(crash)) }
+        });
+    }
+
+    /// Starts the count of boots over, see [`Retained::settle`].
+    pub fn settle() {
+        cortex_m::interrupt::free(|_| {
+            // SAFETY: as in `record`
+            unsafe { update(Retained::settle) }
+        });
+    }
+
+    // Records the panic, prints it like panic-probe and ends in the HardFault handler, where
+    // probe-run prints the backtrace
+    #[panic_handler]
+    fn panic(info: &PanicInfo) -> ! {
+        static PANICKED: AtomicBool = AtomicBool::new(false);
+
+        cortex_m::interrupt::disable();
+
+        // Guard against a panic while recording the panic
+        if !PANICKED.swap(true, Ordering::Relaxed) {
+            let (file, line) = info
+                .location()
+                .map_or(("", 0), |location| (location.file(), location.line()));
+            let crash = Crash::Panic(PanicRecord::new(file, line, info.message()));
+            // SAFETY: interrupts are disabled and this does not return
+            unsafe { update(|retained| retained.record(&crash)) };
+
+            defmt::error!("{}", defmt::Display2Format(info));
+        }
+
+        // `udf` raises a UsageFault instead of a HardFault while those are enabled
+        const SHCSR: *mut u32 = 0xE000_ED24usize as _;
+        const USGFAULTENA: u32 = 18;
+        // SAFETY: SHCSR is a register of the SCB
+        unsafe { SHCSR.write_volatile(SHCSR.read_volatile() & !(1 << USGFAULTENA)) };
+
+        cortex_m::asm::udf()
+    }
+
+    // Records the fault, unless it follows a panic, and resets
+    #[exception]
+    unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+        let scb = &*SCB::PTR;
+        let crash = Crash::HardFault(FaultRecord {
+            r0: frame.r0(),
+            r1: frame.r1(),
+            r2: frame.r2(),
+            r3: frame.r3(),
+            r12: frame.r12(),
+            lr: frame.lr(),
+            pc: frame.pc(),
+            xpsr: frame.xpsr(),
+            cfsr: scb.cfsr.read(),
+            hfsr: scb.hfsr.read(),
+        });
+        update(|retained| retained.record(&crash));
+
+        SCB::sys_reset()
+    }
+}
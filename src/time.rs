@@ -0,0 +1,152 @@
+//! Onboard timekeeping: mission elapsed time (MET) and its correlation to UTC.
+//!
+//! The monotonic timer only counts cycles since boot in 32 bits and wraps after seconds.
+//! [`MissionClock`] extends it to a 64-bit [`Met`] in microseconds that starts where the MET
+//! stood at boot, which the [`RtcBackup`] keeps across resets. Every telemetry timestamp is the
+//! MET as a [`Cuc`] time, see [`to_cuc`].
+//!
+//! Ground relates MET to UTC with a [`Correlation`] from the time reports of the
+//! [time management service](crate::pus::time):
+//!
+//! Nodes on a CAN bus share the MET of one of them through [`sync`].
+//!
+//! ```ignore
+//! static CLOCK: Mutex<RefCell<MissionClock<180_000_000>>> =
+//!     Mutex::new(RefCell::new(MissionClock::new()));
+//!
+//! // in init
+//! let mut backup = RtcBackup::new(Rtc::new(device.RTC, &mut device.PWR), clocks.hclk());
+//! let boot = backup.restore().unwrap_or(Met::from_ticks(0));
+//! interrupt::free(|cs| CLOCK.borrow(cs).borrow_mut().set_boot_time(boot));
+//!
+//! // anywhere, at least once per wrap of the monotonic timer
+//! let met = interrupt::free(|cs| CLOCK.borrow(cs).borrow_mut().update(monotonics::now()));
+//! ```
+
+use defmt::Format;
+use fugit::{TimerInstantU32, TimerInstantU64};
+
+use crate::ccsds::{Cds, Cuc, CucFormat};
+
+pub mod rtc;
+pub mod sync;
+
+pub use rtc::{RtcBackup, RtcError};
+
+/// Mission elapsed time, microseconds since the epoch of the mission's CUC times.
+pub type Met = TimerInstantU64<1_000_000>;
+
+/// The MET as a CUC time in `format`.
+pub const fn to_cuc(met: Met, format: CucFormat) -> Cuc {
+    Cuc::from_micros(met.ticks(), format)
+}
+
+/// The MET of a CUC time in `format`.
+pub const fn from_cuc(time: Cuc, format: CucFormat) -> Met {
+    Met::from_ticks(time.to_micros(format))
+}
+
+/// Extends the 32-bit monotonic timer counting at `HZ` to the 64-bit MET.
+///
+/// The clock notices a wrap of the timer when it goes backwards, so it has to be updated at
+/// least once per wrap period.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct MissionClock<const HZ: u32> {
+    boot: Met,
+    last: u32,
+    wraps: u32,
+}
+
+impl<const HZ: u32> Default for MissionClock<HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const HZ: u32> MissionClock<HZ> {
+    /// A clock with MET 0 at boot.
+    pub const fn new() -> Self {
+        Self {
+            boot: Met::from_ticks(0),
+            last: 0,
+            wraps: 0,
+        }
+    }
+
+    /// Sets the MET of the start of the monotonic timer.
+    pub fn set_boot_time(&mut self, boot: Met) {
+        self.boot = boot;
+    }
+
+    /// The MET at `now` of the monotonic timer.
+    pub fn update(&mut self, now: TimerInstantU32<HZ>) -> Met {
+        let ticks = now.ticks();
+        if ticks < self.last {
+            self.wraps += 1;
+        }
+        self.last = ticks;
+
+        let ticks = u64::from(self.wraps) << 32 | u64::from(ticks);
+        let micros = u128::from(ticks) * 1_000_000 / u128::from(HZ);
+        self.boot + fugit::TimerDurationU64::<1_000_000>::from_ticks(micros as u64)
+    }
+}
+
+/// A UTC time, microseconds since the CCSDS epoch of 1958-01-01. Every day has 86400 seconds,
+/// like in a [`Cds`] time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Format)]
+pub struct Utc(pub u64);
+
+impl Utc {
+    pub const fn from_cds(time: Cds) -> Self {
+        Self(time.to_micros())
+    }
+
+    pub const fn to_cds(self) -> Cds {
+        Cds::from_micros(self.0)
+    }
+}
+
+/// Maps MET to UTC: UTC `utc` at MET `met`, and a drift of the onboard clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Correlation {
+    met: Met,
+    utc: Utc,
+    drift: i32,
+}
+
+impl Correlation {
+    /// UTC `utc` was at MET `met`, and both advance at the same rate.
+    pub const fn new(met: Met, utc: Utc) -> Self {
+        Self { met, utc, drift: 0 }
+    }
+
+    /// UTC advances by 1 + `ppb` · 10⁻⁹ seconds per second of MET.
+    pub const fn drift(mut self, ppb: i32) -> Self {
+        self.drift = ppb;
+        self
+    }
+
+    pub const fn drift_ppb(&self) -> i32 {
+        self.drift
+    }
+
+    /// The MET of the correlation point.
+    pub const fn met(&self) -> Met {
+        self.met
+    }
+
+    /// The UTC of the correlation point.
+    pub const fn utc(&self) -> Utc {
+        self.utc
+    }
+
+    /// The UTC at `met`, before or after the correlation point. Times before the epoch are
+    /// clamped to it.
+    pub fn to_utc(&self, met: Met) -> Utc {
+        let elapsed = i128::from(met.ticks()) - i128::from(self.met.ticks());
+        let corrected = elapsed + elapsed * i128::from(self.drift) / 1_000_000_000;
+        let micros = i128::from(self.utc.0) + corrected;
+        Utc(micros.clamp(0, i128::from(u64::MAX)) as u64)
+    }
+}
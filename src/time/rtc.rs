@@ -0,0 +1,267 @@
+//! The MET in the RTC calendar, which keeps counting through resets as long as the backup domain
+//! is powered.
+//!
+//! The calendar holds the MET, not a date: MET 0 is day 1 of month 1 of year 1. Year 0 is what
+//! the RTC holds after a reset of the backup domain, so a calendar in year 0 holds no MET.
+//! [`Calendar`] follows the rules of the RTC hardware rather than the Gregorian ones, every year
+//! divisible by 4 is a leap year, which is why the HAL's date conversions are not used.
+
+use cortex_m::peripheral::DWT;
+use defmt::Format;
+use stm32f4xx_hal::{
+    rtc::{Lse, Rtc},
+    time::Hertz,
+};
+
+use super::Met;
+
+/// Year of MET 0.
+const EPOCH_YEAR: u8 = 1;
+
+/// The last year of the RTC calendar.
+const LAST_YEAR: u8 = 99;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// How long to wait for the RTC to sync its shadow registers or enter initialization mode. Both
+/// take a few cycles of the 32 kHz RTC clock, so this only expires if that clock is not running.
+const TIMEOUT_MS: u64 = 10;
+
+/// Errors of the [`RtcBackup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RtcError {
+    /// The RTC did not respond within the timeout, its clock is not running.
+    Timeout,
+    /// The MET is past the last year of the calendar.
+    OutOfRange,
+    /// The timeout is measured with the DWT cycle counter, which is not running.
+    CycleCounterDisabled,
+}
+
+const fn is_leap_year(year: u8) -> bool {
+    year.is_multiple_of(4)
+}
+
+const fn days_in_year(year: u8) -> u64 {
+    if is_leap_year(year) {
+        366
+    } else {
+        365
+    }
+}
+
+const fn days_in_month(year: u8, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The fields of the RTC calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Calendar {
+    /// Year of the century, 0 to 99.
+    pub year: u8,
+    /// Month, 1 to 12.
+    pub month: u8,
+    /// Day of the month, from 1.
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl Calendar {
+    /// The calendar at `seconds` of MET, `None` after the last year of the calendar.
+    pub fn from_met_seconds(seconds: u64) -> Option<Self> {
+        let mut days = seconds / SECONDS_PER_DAY;
+        let time = seconds % SECONDS_PER_DAY;
+
+        let mut year = EPOCH_YEAR;
+        while days >= days_in_year(year) {
+            days -= days_in_year(year);
+            year += 1;
+            if year > LAST_YEAR {
+                return None;
+            }
+        }
+        let mut month = 1;
+        while days >= u64::from(days_in_month(year, month)) {
+            days -= u64::from(days_in_month(year, month));
+            month += 1;
+        }
+
+        Some(Self {
+            year,
+            month,
+            day: days as u8 + 1,
+            hours: (time / 3600) as u8,
+            minutes: (time / 60 % 60) as u8,
+            seconds: (time % 60) as u8,
+        })
+    }
+
+    /// The seconds of MET, `None` if the fields are out of range or the calendar is in year 0.
+    pub fn met_seconds(&self) -> Option<u64> {
+        if !(EPOCH_YEAR..=LAST_YEAR).contains(&self.year)
+            || !(1..=12).contains(&self.month)
+            || !(1..=days_in_month(self.year, self.month)).contains(&self.day)
+            || self.hours > 23
+            || self.minutes > 59
+            || self.seconds > 59
+        {
+            return None;
+        }
+
+        let days = (EPOCH_YEAR..self.year).map(days_in_year).sum::<u64>()
+            + (1..self.month)
+                .map(|month| u64::from(days_in_month(self.year, month)))
+                .sum::<u64>()
+            + u64::from(self.day - 1);
+        let time =
+            u64::from(self.hours) * 3600 + u64::from(self.minutes) * 60 + u64::from(self.seconds);
+        Some(days * SECONDS_PER_DAY + time)
+    }
+}
+
+const fn bcd_decode(tens: u8, units: u8) -> u8 {
+    tens * 10 + units
+}
+
+const fn bcd_encode(value: u8) -> (u8, u8) {
+    (value / 10, value % 10)
+}
+
+/// Keeps the MET in the RTC.
+pub struct RtcBackup<CS = Lse> {
+    rtc: Rtc<CS>,
+    timeout_cycles: u32,
+}
+
+impl<CS> RtcBackup<CS> {
+    /// Takes an RTC that is set up with a 1 Hz calendar clock, which the HAL constructors do.
+    ///
+    /// Waits for the RTC are bounded with the DWT cycle counter, which has to run at `hclk`. Note
+    /// that the HAL constructors themselves wait for the LSE to start without a bound.
+    pub fn new(rtc: Rtc<CS>, hclk: Hertz) -> Self {
+        let cycles = u64::from(hclk.raw()) * TIMEOUT_MS / 1000;
+        Self {
+            rtc,
+            timeout_cycles: cycles.min(u64::from(u32::MAX)) as u32,
+        }
+    }
+
+    pub fn free(self) -> Rtc<CS> {
+        self.rtc
+    }
+
+    /// Waits until `done` returns `true`, at most for the timeout.
+    fn wait(&self, mut done: impl FnMut() -> bool) -> Result<(), RtcError> {
+        if !DWT::cycle_counter_enabled() {
+            return Err(RtcError::CycleCounterDisabled);
+        }
+        let start = DWT::cycle_count();
+        while !done() {
+            if DWT::cycle_count().wrapping_sub(start) >= self.timeout_cycles {
+                return Err(RtcError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// The MET the RTC counted, with the subseconds of the RTC, `None` if the calendar holds no
+    /// MET.
+    pub fn read(&mut self) -> Result<Option<Met>, RtcError> {
+        let regs = &self.rtc.regs;
+        if regs.isr.read().inits().bit_is_clear() {
+            return Ok(None);
+        }
+
+        // The subseconds lock the calendar until the date is read
+        self.wait(|| regs.isr.read().rsf().bit_is_set())?;
+        let ssr = regs.ssr.read().ss().bits();
+        let tr = regs.tr.read();
+        let dr = regs.dr.read();
+        regs.isr.modify(|_, w| w.rsf().clear_bit());
+        let prediv_s = regs.prer.read().prediv_s().bits();
+
+        let calendar = Calendar {
+            year: bcd_decode(dr.yt().bits(), dr.yu().bits()),
+            month: bcd_decode(dr.mt().bit() as u8, dr.mu().bits()),
+            day: bcd_decode(dr.dt().bits(), dr.du().bits()),
+            hours: bcd_decode(tr.ht().bits(), tr.hu().bits()),
+            minutes: bcd_decode(tr.mnt().bits(), tr.mnu().bits()),
+            seconds: bcd_decode(tr.st().bits(), tr.su().bits()),
+        };
+        let Some(seconds) = calendar.met_seconds() else {
+            return Ok(None);
+        };
+
+        // The subsecond counter counts down from the synchronous prescaler
+        let elapsed = u64::from(prediv_s.saturating_sub(ssr));
+        let micros = elapsed * 1_000_000 / (u64::from(prediv_s) + 1);
+        Ok(Some(Met::from_ticks(seconds * 1_000_000 + micros)))
+    }
+
+    /// Sets the calendar to `met`, rounded down to whole seconds.
+    pub fn write(&mut self, met: Met) -> Result<(), RtcError> {
+        let calendar =
+            Calendar::from_met_seconds(met.ticks() / 1_000_000).ok_or(RtcError::OutOfRange)?;
+        let (yt, yu) = bcd_encode(calendar.year);
+        let (mt, mu) = bcd_encode(calendar.month);
+        let (dt, du) = bcd_encode(calendar.day);
+        let (ht, hu) = bcd_encode(calendar.hours);
+        let (mnt, mnu) = bcd_encode(calendar.minutes);
+        let (st, su) = bcd_encode(calendar.seconds);
+
+        let regs = &self.rtc.regs;
+        // Unlock the write protection and stop the calendar
+        regs.wpr.write(|w| unsafe { w.bits(0xca) });
+        regs.wpr.write(|w| unsafe { w.bits(0x53) });
+        regs.isr.modify(|_, w| w.init().set_bit());
+        if let Err(error) = self.wait(|| regs.isr.read().initf().bit_is_set()) {
+            regs.isr.modify(|_, w| w.init().clear_bit());
+            regs.wpr.write(|w| unsafe { w.bits(0xff) });
+            return Err(error);
+        }
+
+        regs.tr.write(|w| {
+            w.ht().bits(ht);
+            w.hu().bits(hu);
+            w.mnt().bits(mnt);
+            w.mnu().bits(mnu);
+            w.st().bits(st);
+            w.su().bits(su);
+            w.pm().clear_bit()
+        });
+        regs.dr.write(|w| {
+            w.yt().bits(yt);
+            w.yu().bits(yu);
+            w.mt().bit(mt > 0);
+            w.mu().bits(mu);
+            w.dt().bits(dt);
+            w.du().bits(du)
+        });
+
+        regs.isr.modify(|_, w| w.init().clear_bit());
+        regs.wpr.write(|w| unsafe { w.bits(0xff) });
+        Ok(())
+    }
+
+    /// The MET at boot: what the RTC counted, or 0 after a reset of the backup domain, in which
+    /// case the RTC starts counting from 0.
+    ///
+    /// Fails if the RTC does not respond. The MET then starts from 0 without a backup.
+    pub fn restore(&mut self) -> Result<Met, RtcError> {
+        match self.read()? {
+            Some(met) => Ok(met),
+            None => {
+                let start = Met::from_ticks(0);
+                self.write(start)?;
+                Ok(start)
+            }
+        }
+    }
+}
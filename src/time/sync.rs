@@ -0,0 +1,341 @@
+//! Time synchronization between nodes on a CAN bus.
+//!
+//! A [`SyncMaster`] distributes its MET in two steps. It sends a SYNC frame and notes the
+//! instant the frame left its mailbox, then sends that instant in a FOLLOW_UP frame. A
+//! [`SyncSlave`] notes the instant it received the SYNC frame, so each pair gives it one instant
+//! on both clocks. From successive pairs it learns the offset and the drift of its own MET and
+//! maps its MET to the master's with [`SyncSlave::to_master`]:
+//!
+//! ```text
+//! master                       slave
+//!   | -- SYNC n ---------------> |  received at local MET l
+//!   |  left the mailbox at m     |
+//!   | -- FOLLOW_UP n (m) ------> |  master MET m was at local MET l
+//! ```
+//!
+//! The first byte of both frames holds the frame type in the high nibble and a sequence number in
+//! the low nibble. A FOLLOW_UP carries the master's MET in microseconds in the other 7 bytes.
+//!
+//! bxcan 0.7 gives no access to the timestamps the controller captures in time triggered
+//! communication mode, and the controller's timer cannot be read to relate them to the MET, so
+//! both instants are taken in software: the master's in the TX interrupt, the slave's in the RX
+//! interrupt. Their latencies are much alike and mostly cancel out. Keep the CAN interrupts at the
+//! highest priority and give the SYNC ID priority over every other frame of the master:
+//!
+//! ```ignore
+//! // slave, in the RX interrupt
+//! let now = mission_now();
+//! producer.drain_with(shield.channel(Channel::Can1), |frame| slave.on_frame(frame, now));
+//!
+//! // master, in a periodic task of lower priority than the CAN interrupts
+//! master.send_sync(shield)?;
+//! // master, in the TX interrupt, before refilling the mailboxes
+//! master.on_tx_interrupt(shield, mission_now())?;
+//! ```
+
+use bxcan::{Data, Frame, Id};
+use defmt::{debug, warn, Format};
+use fugit::MillisDurationU32;
+
+use super::Met;
+use crate::can_shield::{Channel, Shield};
+
+/// Frame type of a SYNC frame.
+const SYNC: u8 = 0x1;
+/// Frame type of a FOLLOW_UP frame.
+const FOLLOW_UP: u8 = 0x2;
+
+/// Bytes of the master's MET in a FOLLOW_UP frame.
+const TIME_LEN: usize = 7;
+
+/// Weight of a new drift measurement is 1 / `DRIFT_GAIN`.
+const DRIFT_GAIN: i64 = 4;
+
+const PPB: i128 = 1_000_000_000;
+
+/// Reasons the master could not send a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SyncError {
+    /// Frames are waiting to be sent, so the instant the SYNC frame leaves would be unknown. Try
+    /// again later.
+    Busy,
+    /// The transmit queue of the channel is full.
+    TxQueueFull,
+}
+
+/// Counters and the quality of the synchronization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct SyncStats {
+    /// SYNC and FOLLOW_UP pairs sent by the master or used by the slave.
+    pub syncs: u32,
+    /// Pairs the master could not complete, or FOLLOW_UP frames the slave had no SYNC frame for.
+    pub missed: u32,
+    /// Master MET minus local MET at the last pair, in microseconds.
+    pub offset_us: i64,
+    /// Drift of the master's clock against the local one, in parts per billion.
+    pub drift_ppb: i32,
+    /// How far the master's MET at the last pair was from the one predicted from the pairs
+    /// before, in microseconds.
+    pub error_us: i32,
+    /// Largest magnitude of `error_us` so far.
+    pub max_error_us: u32,
+}
+
+/// Configuration shared by the master and the slaves of a bus.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncConfig {
+    channel: Channel,
+    id: Id,
+    timeout: MillisDurationU32,
+}
+
+impl SyncConfig {
+    /// Creates a configuration with SYNC and FOLLOW_UP frames on `id` on `channel`.
+    ///
+    /// Defaults to a timeout of 5000 ms.
+    pub fn new(channel: Channel, id: impl Into<Id>) -> Self {
+        Self {
+            channel,
+            id: id.into(),
+            timeout: MillisDurationU32::millis(5000),
+        }
+    }
+
+    /// Time after the last pair until a slave no longer counts as synchronized.
+    pub fn timeout(mut self, timeout: MillisDurationU32) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+fn header(kind: u8, sequence: u8) -> u8 {
+    kind << 4 | sequence & 0x0f
+}
+
+/// Sends SYNC and FOLLOW_UP frames with the local MET.
+pub struct SyncMaster {
+    config: SyncConfig,
+    stats: SyncStats,
+    sequence: u8,
+    /// A SYNC frame is in a mailbox, waiting for its TX interrupt.
+    confirming: bool,
+}
+
+impl SyncMaster {
+    pub const fn new(config: SyncConfig) -> Self {
+        Self {
+            config,
+            stats: SyncStats {
+                syncs: 0,
+                missed: 0,
+                offset_us: 0,
+                drift_ppb: 0,
+                error_us: 0,
+                max_error_us: 0,
+            },
+            sequence: 0,
+            confirming: false,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.config.channel
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        self.stats
+    }
+
+    /// Sends the next SYNC frame.
+    ///
+    /// The frame only goes out while no other frame of the channel is waiting, so the next TX
+    /// interrupt is the one of the SYNC frame. Call this from a task of lower priority than the TX
+    /// interrupt, which then has handled every earlier frame. A SYNC frame still waiting for its
+    /// TX interrupt is counted as missed.
+    pub fn send_sync(&mut self, shield: &mut (impl Shield + ?Sized)) -> Result<(), SyncError> {
+        let channel = self.config.channel;
+        if shield.tx_pending(channel) != 0 || !shield.channel(channel).status().transmitter_idle {
+            self.stats.missed += 1;
+            return Err(SyncError::Busy);
+        }
+        if self.confirming {
+            warn!("{}, SYNC {} was not confirmed", channel, self.sequence);
+            self.stats.missed += 1;
+        }
+
+        self.sequence = (self.sequence + 1) & 0x0f;
+        let data = Data::new(&[header(SYNC, self.sequence)]).expect("1 byte");
+        self.confirming = false;
+        self.transmit(shield, data)?;
+        self.confirming = true;
+        Ok(())
+    }
+
+    /// Sends the FOLLOW_UP frame once the SYNC frame has left, with `now` as the instant it
+    /// did.
+    ///
+    /// Call this from the TX interrupt of the channel, with the MET taken on entry.
+    pub fn on_tx_interrupt(
+        &mut self,
+        shield: &mut (impl Shield + ?Sized),
+        now: Met,
+    ) -> Result<(), SyncError> {
+        if !self.confirming {
+            return Ok(());
+        }
+        self.confirming = false;
+
+        let mut buf = [0; 1 + TIME_LEN];
+        buf[0] = header(FOLLOW_UP, self.sequence);
+        buf[1..].copy_from_slice(&now.ticks().to_be_bytes()[8 - TIME_LEN..]);
+        let data = Data::new(&buf).expect("8 bytes");
+
+        if let Err(error) = self.transmit(shield, data) {
+            self.stats.missed += 1;
+            return Err(error);
+        }
+        self.stats.syncs += 1;
+        Ok(())
+    }
+
+    fn transmit(&self, shield: &mut (impl Shield + ?Sized), data: Data) -> Result<(), SyncError> {
+        shield
+            .transmit(self.config.channel, &Frame::new_data(self.config.id, data))
+            .map_err(|_| SyncError::TxQueueFull)
+    }
+}
+
+/// A SYNC frame and the FOLLOW_UP that belongs to it, as an instant on both clocks.
+#[derive(Debug, Clone, Copy)]
+struct Pair {
+    local: Met,
+    master: Met,
+}
+
+/// Follows the MET of a [`SyncMaster`].
+pub struct SyncSlave {
+    config: SyncConfig,
+    stats: SyncStats,
+    /// Sequence number and local receive instant of the last SYNC frame.
+    sync: Option<(u8, Met)>,
+    last: Option<Pair>,
+    drift: i64,
+}
+
+impl SyncSlave {
+    pub const fn new(config: SyncConfig) -> Self {
+        Self {
+            config,
+            stats: SyncStats {
+                syncs: 0,
+                missed: 0,
+                offset_us: 0,
+                drift_ppb: 0,
+                error_us: 0,
+                max_error_us: 0,
+            },
+            sync: None,
+            last: None,
+            drift: 0,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.config.channel
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        self.stats
+    }
+
+    /// Handles a frame received at local MET `rx`. Returns `true` if it was a SYNC or FOLLOW_UP
+    /// frame, which need no further handling.
+    ///
+    /// Call this from the RX interrupt of the channel, with the MET taken on entry, see
+    /// [`RxProducer::drain_with`](crate::can_shield::rx::RxProducer::drain_with).
+    pub fn on_frame(&mut self, frame: &Frame, rx: Met) -> bool {
+        if frame.id() != self.config.id {
+            return false;
+        }
+        let Some((&first, time)) = frame.data().and_then(|data| data.split_first()) else {
+            return false;
+        };
+        let sequence = first & 0x0f;
+
+        match (first >> 4, time) {
+            (SYNC, []) => self.sync = Some((sequence, rx)),
+            (FOLLOW_UP, time) if time.len() == TIME_LEN => match self.sync.take() {
+                Some((synced, local)) if synced == sequence => {
+                    let mut bytes = [0; 8];
+                    bytes[8 - TIME_LEN..].copy_from_slice(time);
+                    let master = Met::from_ticks(u64::from_be_bytes(bytes));
+                    self.sample(Pair { local, master });
+                }
+                _ => {
+                    debug!(
+                        "{}, FOLLOW_UP {} without SYNC",
+                        self.config.channel, sequence
+                    );
+                    self.stats.missed += 1;
+                }
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns `true` if the last pair is no older than the timeout at local MET `local`.
+    pub fn is_synchronized(&self, local: Met) -> bool {
+        self.last.is_some_and(|last| {
+            local.ticks().saturating_sub(last.local.ticks())
+                <= u64::from(self.config.timeout.to_millis()) * 1000
+        })
+    }
+
+    /// The master's MET at local MET `local`, `None` unless synchronized.
+    pub fn to_master(&self, local: Met) -> Option<Met> {
+        if !self.is_synchronized(local) {
+            return None;
+        }
+        self.last.map(|last| {
+            Met::from_ticks(self.predict(last, local).clamp(0, u64::MAX as i128) as u64)
+        })
+    }
+
+    /// The master's MET at `local`, from the pair `last` and the drift.
+    fn predict(&self, last: Pair, local: Met) -> i128 {
+        let elapsed = i128::from(local.ticks()) - i128::from(last.local.ticks());
+        i128::from(last.master.ticks()) + elapsed + elapsed * i128::from(self.drift) / PPB
+    }
+
+    fn sample(&mut self, pair: Pair) {
+        if let Some(last) = self.last {
+            let elapsed = i128::from(pair.local.ticks()) - i128::from(last.local.ticks());
+            if elapsed <= 0 {
+                self.stats.missed += 1;
+                return;
+            }
+
+            let error = i128::from(pair.master.ticks()) - self.predict(last, pair.local);
+            let error = error.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+            self.stats.error_us = error;
+            self.stats.max_error_us = self.stats.max_error_us.max(error.unsigned_abs());
+
+            let advanced = i128::from(pair.master.ticks()) - i128::from(last.master.ticks());
+            let measured = ((advanced - elapsed) * PPB / elapsed)
+                .clamp(i32::MIN.into(), i32::MAX.into()) as i64;
+            // The first measurement is taken as it is, later ones are smoothed
+            self.drift = if self.stats.syncs == 1 {
+                measured
+            } else {
+                self.drift + (measured - self.drift) / DRIFT_GAIN
+            };
+        }
+
+        self.last = Some(pair);
+        self.stats.syncs += 1;
+        self.stats.offset_us = (i128::from(pair.master.ticks()) - i128::from(pair.local.ticks()))
+            .clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        self.stats.drift_ppb = self.drift as i32;
+    }
+}
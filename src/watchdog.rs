@@ -0,0 +1,291 @@
+//! A supervisor that kicks the hardware watchdog only while every task checks in.
+//!
+//! Kicking the watchdog from one periodic task only proves that this task runs. Tasks register
+//! with the [`Supervisor`] with a deadline each and check in whenever they did their work. The
+//! supervisor kicks the watchdog only while every task checked in within its deadline. Once a
+//! task starves it stops kicking for good, and the watchdog resets the node. Keep the
+//! [`Starved`] task in the [crash record](crate::reset) first, so the next boot names it:
+//!
+//! ```ignore
+//! let mut iwdg = IndependentWatchdog::new(device.IWDG);
+//! let mut supervisor = Supervisor::<4>::new();
+//! let collect = supervisor.register("collect", 500.millis(), now)?;
+//! iwdg.start(2000.millis());
+//!
+//! // in the collect task
+//! supervisor.check_in(collect, now);
+//! // periodically, in a task of the lowest priority
+//! if let Err(starved) = supervisor.poll(now, &mut iwdg) {
+//!     reset::record(&starved.crash());
+//! }
+//! ```
+//!
+//! The IWDG of the HAL runs on its own clock and resets the node once it is not kicked within its
+//! timeout, which has to be longer than the period of the polls. The [`WindowWatchdog`] also
+//! resets the node if it is kicked too early, so its polls need a steady period. [`Pair`] kicks
+//! both.
+
+use defmt::Format;
+use embedded_hal::watchdog::Watchdog;
+use fugit::{HertzU32, MicrosDurationU32, MillisDurationU32, TimerDurationU64};
+use heapless::Vec;
+use stm32f4xx_hal::pac::{RCC, WWDG};
+
+use crate::{
+    reset::{Crash, StarvedRecord},
+    time::Met,
+};
+
+/// Errors of the supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum WatchdogError {
+    /// More tasks registered than the supervisor keeps track of.
+    TableFull,
+}
+
+/// A task registered with a [`Supervisor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TaskId(u8);
+
+impl TaskId {
+    /// The task registered as the `index`th, from 0, for tables like the
+    /// [FDIR monitors](crate::fdir::Check::Deadline).
+    pub const fn new(index: u8) -> Self {
+        Self(index)
+    }
+
+    /// Index of the task, in the order of registration.
+    pub const fn index(self) -> u8 {
+        self.0
+    }
+}
+
+/// A task that did not check in within its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Starved {
+    pub task: TaskId,
+    pub name: &'static str,
+    /// MET of the last check-in.
+    pub last: Met,
+}
+
+impl Starved {
+    /// The crash record naming the task.
+    pub fn crash(&self) -> Crash {
+        Crash::Starved(StarvedRecord::new(self.task.index(), self.name))
+    }
+}
+
+/// Supervisor counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub struct SupervisorStats {
+    /// Check-ins of all tasks.
+    pub check_ins: u32,
+    /// Kicks of the watchdog.
+    pub kicks: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Task {
+    name: &'static str,
+    deadline: MillisDurationU32,
+    last: Met,
+    paused: bool,
+}
+
+impl Task {
+    fn is_late(&self, now: Met) -> bool {
+        let deadline = TimerDurationU64::<1_000_000>::millis(u64::from(self.deadline.to_millis()));
+        self.since(now).is_some_and(|elapsed| elapsed > deadline)
+    }
+
+    fn since(&self, now: Met) -> Option<TimerDurationU64<1_000_000>> {
+        match self.paused {
+            true => None,
+            false => now.checked_duration_since(self.last),
+        }
+    }
+}
+
+/// Watches up to `N` tasks and kicks the watchdog while all of them check in.
+pub struct Supervisor<const N: usize> {
+    tasks: Vec<Task, N>,
+    starved: Option<Starved>,
+    stats: SupervisorStats,
+}
+
+impl<const N: usize> Default for Supervisor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Supervisor<N> {
+    pub const fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            starved: None,
+            stats: SupervisorStats {
+                check_ins: 0,
+                kicks: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> SupervisorStats {
+        self.stats
+    }
+
+    /// Watches task `name`, which has to check in at most `deadline` after the last time, counted
+    /// from `now`.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        deadline: MillisDurationU32,
+        now: Met,
+    ) -> Result<TaskId, WatchdogError> {
+        let task = TaskId(self.tasks.len() as u8);
+        self.tasks
+            .push(Task {
+                name,
+                deadline,
+                last: now,
+                paused: false,
+            })
+            .map_err(|_| WatchdogError::TableFull)?;
+        Ok(task)
+    }
+
+    /// Notes that `task` did its work at `now`.
+    pub fn check_in(&mut self, task: TaskId, now: Met) {
+        if let Some(task) = self.tasks.get_mut(usize::from(task.0)) {
+            task.last = now;
+            self.stats.check_ins += 1;
+        }
+    }
+
+    /// Stops watching `task`, while it does not run on purpose.
+    pub fn pause(&mut self, task: TaskId) {
+        if let Some(task) = self.tasks.get_mut(usize::from(task.0)) {
+            task.paused = true;
+        }
+    }
+
+    /// Watches `task` again, with its deadline counted from `now`.
+    pub fn resume(&mut self, task: TaskId, now: Met) {
+        if let Some(task) = self.tasks.get_mut(usize::from(task.0)) {
+            task.paused = false;
+            task.last = now;
+        }
+    }
+
+    /// Time since `task` last checked in, `None` while it is paused.
+    pub fn since_check_in(&self, task: TaskId, now: Met) -> Option<MillisDurationU32> {
+        let since = self.tasks.get(usize::from(task.0))?.since(now)?;
+        Some(MillisDurationU32::millis(
+            since.to_millis().try_into().unwrap_or(u32::MAX),
+        ))
+    }
+
+    /// The first task that starved, after which the watchdog is no longer kicked.
+    pub fn starved(&self) -> Option<Starved> {
+        self.starved
+    }
+
+    /// Kicks `watchdog` if every task checked in within its deadline. Otherwise returns the first
+    /// task that starved, from then on.
+    pub fn poll(&mut self, now: Met, watchdog: &mut impl Watchdog) -> Result<(), Starved> {
+        if self.starved.is_none() {
+            self.starved = self
+                .tasks
+                .iter()
+                .enumerate()
+                .find(|(_, task)| task.is_late(now))
+                .map(|(i, task)| Starved {
+                    task: TaskId(i as u8),
+                    name: task.name,
+                    last: task.last,
+                });
+        }
+        if let Some(starved) = self.starved {
+            return Err(starved);
+        }
+
+        watchdog.feed();
+        self.stats.kicks += 1;
+        Ok(())
+    }
+}
+
+/// Two watchdogs kicked together, like the IWDG and the [`WindowWatchdog`].
+pub struct Pair<A, B>(pub A, pub B);
+
+impl<A: Watchdog, B: Watchdog> Watchdog for Pair<A, B> {
+    fn feed(&mut self) {
+        self.0.feed();
+        self.1.feed();
+    }
+}
+
+/// Lowest value of the WWDG counter, it resets the node when it counts below.
+const WWDG_MIN: u8 = 0x40;
+/// Highest value of the WWDG counter.
+const WWDG_MAX: u8 = 0x7f;
+
+/// The window watchdog, which resets the node when it is kicked too late or too early.
+///
+/// It counts on the APB1 clock, so its timeout is at most about 46 ms at 45 MHz.
+pub struct WindowWatchdog {
+    wwdg: WWDG,
+    counter: u8,
+}
+
+impl WindowWatchdog {
+    /// Enables the clock of the WWDG. It starts with [`WindowWatchdog::start`].
+    pub fn new(wwdg: WWDG) -> Self {
+        // SAFETY: only the WWDG bit of the register is changed
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.wwdgen().set_bit());
+        Self {
+            wwdg,
+            counter: WWDG_MAX,
+        }
+    }
+
+    /// Starts the watchdog on an APB1 clock of `pclk1`. It has to be kicked between `window` and
+    /// `timeout` after the last kick, a `window` of 0 allows kicks at any time. Once started it
+    /// cannot be stopped.
+    pub fn start(
+        &mut self,
+        pclk1: HertzU32,
+        timeout: MicrosDurationU32,
+        window: MicrosDurationU32,
+    ) {
+        // The counter ticks every 4096 << prescaler cycles of the clock
+        let ticks = |duration: MicrosDurationU32, prescaler: u32| {
+            u64::from(duration.to_micros()) * u64::from(pclk1.raw())
+                / (1_000_000 * (4096 << prescaler))
+        };
+        let range = u64::from(WWDG_MAX - WWDG_MIN);
+        let prescaler = (0..=3)
+            .find(|&prescaler| ticks(timeout, prescaler) <= range)
+            .unwrap_or(3);
+        let counter = WWDG_MIN + ticks(timeout, prescaler).min(range) as u8;
+        // Kicks are allowed once the counter is down to the window
+        let window = counter
+            .saturating_sub(ticks(window, prescaler).min(range) as u8)
+            .max(WWDG_MIN + 1);
+
+        self.counter = counter;
+        self.wwdg
+            .cfr
+            .write(|w| w.wdgtb().bits(prescaler as u8).w().bits(window));
+        self.wwdg.cr.write(|w| w.wdga().set_bit().t().bits(counter));
+    }
+}
+
+impl Watchdog for WindowWatchdog {
+    fn feed(&mut self) {
+        self.wwdg.cr.write(|w| w.t().bits(self.counter));
+    }
+}
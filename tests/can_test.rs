@@ -6,9 +6,8 @@ use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
 #[cfg(test)]
 #[defmt_test::tests]
 mod can_tests {
-    use bxcan::filter::{self, Mask32};
-    use bxcan::{Fifo, Frame, StandardId};
-    use defmt::{assert, info};
+    use bxcan::{Frame, StandardId};
+    use defmt::info;
     use stm32f446_rtic::can_shield::CanShield;
     use stm32f4xx_hal::{pac, prelude::*};
 
@@ -16,11 +15,11 @@ mod can_tests {
     // Requres connecting the CanShield to a bus with another CAN device running 
     // canshield_echo example (examples/canshield_echo.rs)
     fn test_shield() {
-        let core = cortex_m::Peripherals::take().unwrap();
+        let _core = cortex_m::Peripherals::take().unwrap();
         let device = pac::Peripherals::take().unwrap();
 
         let rcc = device.RCC.constrain();
-        let _clocks = rcc.cfgr.sysclk(180.MHz()).freeze();
+        let clocks = rcc.cfgr.sysclk(180.MHz()).freeze();
 
         let gpioa = device.GPIOA.split();
         let gpiob = device.GPIOB.split();
@@ -32,6 +31,7 @@ mod can_tests {
             gpiob.pb5,
             device.CAN1,
             device.CAN2,
+            &clocks,
         )
         .unwrap();
 
@@ -41,24 +41,24 @@ mod can_tests {
         let mut can2 = shield.can2;
 
         let mut test_frame1: [u8; 8] = [0; 8];
-        test_frame1[0] = 'H' as u8;
-        test_frame1[1] = 'e' as u8;
-        test_frame1[2] = 'j' as u8;
-        test_frame1[3] = 's' as u8;
-        test_frame1[4] = 'a' as u8;
-        test_frame1[5] = '!' as u8;
-        test_frame1[6] = ' ' as u8;
-        test_frame1[7] = '1' as u8;
+        test_frame1[0] = b'H';
+        test_frame1[1] = b'e';
+        test_frame1[2] = b'j';
+        test_frame1[3] = b's';
+        test_frame1[4] = b'a';
+        test_frame1[5] = b'!';
+        test_frame1[6] = b' ';
+        test_frame1[7] = b'1';
 
         let mut test_frame2: [u8; 8] = [0; 8];
-        test_frame2[0] = 'H' as u8;
-        test_frame2[1] = 'e' as u8;
-        test_frame2[2] = 'l' as u8;
-        test_frame2[3] = 'l' as u8;
-        test_frame2[4] = 'o' as u8;
-        test_frame2[5] = '!' as u8;
-        test_frame2[6] = ' ' as u8;
-        test_frame2[7] = '2' as u8;
+        test_frame2[0] = b'H';
+        test_frame2[1] = b'e';
+        test_frame2[2] = b'l';
+        test_frame2[3] = b'l';
+        test_frame2[4] = b'o';
+        test_frame2[5] = b'!';
+        test_frame2[6] = b' ';
+        test_frame2[7] = b'2';
 
         let id_frame1 = StandardId::new(0x111).unwrap();
         let id_frame2 = StandardId::new(0x222).unwrap();
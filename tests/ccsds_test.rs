@@ -0,0 +1,339 @@
+//! Host tests of the CCSDS packets, with property tests. Run with `cargo test-host`.
+
+use bxcan::StandardId;
+use fugit::TimerInstantU32;
+use quickcheck::{quickcheck, TestResult};
+use stm32f446_rtic::{
+    can_shield::{
+        mock::{MockBus, MockShield},
+        CanChannel, Channel,
+    },
+    ccsds::{
+        CcsdsError, Cds, CdsFormat, Cuc, CucFormat, PacketBuilder, PacketType, PrimaryHeader,
+        SequenceCounter, SequenceFlags, SpacePacket, MAX_APID, MAX_SEQUENCE_COUNT,
+        PRIMARY_HEADER_LEN,
+    },
+    isotp::{IsoTpConfig, IsoTpLink},
+};
+
+fn flags(bits: u8) -> SequenceFlags {
+    [
+        SequenceFlags::Continuation,
+        SequenceFlags::First,
+        SequenceFlags::Last,
+        SequenceFlags::Unsegmented,
+    ][usize::from(bits % 4)]
+}
+
+/// A valid CUC format from arbitrary octet counts.
+fn format(coarse: u8, fine: u8) -> CucFormat {
+    CucFormat::new(coarse % 4 + 1, fine % 4).unwrap()
+}
+
+/// Keeps the octets of `value` that fit into `octets`.
+fn truncate(value: u32, octets: u8) -> u32 {
+    match octets {
+        0 => 0,
+        4 => value,
+        _ => value & ((1 << (8 * octets)) - 1),
+    }
+}
+
+#[test]
+fn primary_header_layout() {
+    let mut buf = [0; 16];
+    let packet = PacketBuilder::telemetry(0x123)
+        .sequence_count(5)
+        .build(&[1, 2, 3], &mut buf)
+        .unwrap();
+    assert_eq!(packet, [0x01, 0x23, 0xc0, 0x05, 0x00, 0x02, 1, 2, 3]);
+
+    let time = Cuc {
+        coarse: 0x0102_0304,
+        fine: 0x0506,
+    };
+    let packet = PacketBuilder::telecommand(0x7fe)
+        .sequence_flags(SequenceFlags::First)
+        .sequence_count(0x3fff)
+        .timestamp(CucFormat::DEFAULT, time)
+        .build(&[0xaa], &mut buf)
+        .unwrap();
+    assert_eq!(
+        packet,
+        [0x1f, 0xfe, 0x7f, 0xff, 0x00, 0x06, 1, 2, 3, 4, 5, 6, 0xaa]
+    );
+}
+
+#[test]
+fn build_errors() {
+    let mut buf = [0; 8];
+
+    assert_eq!(
+        PacketBuilder::telemetry(0x800).build(&[1], &mut buf),
+        Err(CcsdsError::InvalidApid(0x800))
+    );
+    assert_eq!(
+        PacketBuilder::telemetry(1).build(&[], &mut buf),
+        Err(CcsdsError::InvalidDataLength)
+    );
+    assert_eq!(
+        PacketBuilder::telemetry(1).build(&[0; 3], &mut buf),
+        Err(CcsdsError::BufferTooShort { required: 9 })
+    );
+}
+
+#[test]
+fn parse_errors() {
+    assert_eq!(
+        SpacePacket::parse(&[0x01, 0x23, 0xc0]),
+        Err(CcsdsError::BufferTooShort { required: 6 })
+    );
+    assert_eq!(
+        SpacePacket::parse(&[0x01, 0x23, 0xc0, 0x05, 0x00, 0x02, 1, 2]),
+        Err(CcsdsError::BufferTooShort { required: 9 })
+    );
+    assert_eq!(
+        SpacePacket::parse(&[0x21, 0x23, 0xc0, 0x05, 0x00, 0x00, 1]),
+        Err(CcsdsError::UnsupportedVersion(1))
+    );
+
+    let packet = SpacePacket::parse(&[0x01, 0x23, 0xc0, 0x05, 0x00, 0x00, 1]).unwrap();
+    assert_eq!(
+        packet.secondary_header(CucFormat::DEFAULT),
+        Err(CcsdsError::NoSecondaryHeader)
+    );
+}
+
+#[test]
+fn cuc_pfield() {
+    assert_eq!(CucFormat::DEFAULT.pfield(), 0b0010_1110);
+    assert_eq!(CucFormat::from_pfield(0b0001_0001), CucFormat::new(1, 1));
+    assert_eq!(CucFormat::from_pfield(0b1010_1110), None);
+    assert_eq!(CucFormat::from_pfield(0b0100_1110), None);
+}
+
+#[test]
+fn cds_layout() {
+    assert_eq!(CdsFormat::DEFAULT.pfield(), 0b0100_0001);
+    assert_eq!(CdsFormat::from_pfield(0b0100_0100), CdsFormat::new(3, 0));
+    assert_eq!(CdsFormat::from_pfield(0b0100_0010), None);
+    assert_eq!(CdsFormat::from_pfield(0b0100_1001), None);
+    assert_eq!(CdsFormat::new(4, 0), None);
+
+    // 1970-01-01 00:00:00.001002, the Unix epoch is day 4383 of the CCSDS epoch
+    let time = Cds::from_micros(4383 * 86_400_000_000 + 1002);
+    let mut buf = [0; 8];
+    assert_eq!(time.encode(CdsFormat::DEFAULT, &mut buf), Ok(8));
+    assert_eq!(buf, [0x11, 0x1f, 0, 0, 0, 1, 0, 2]);
+
+    // The millisecond after the end of a day with a leap second
+    let too_long = [0x11, 0x1f, 0x05, 0x26, 0x5f, 0xe8, 0, 0];
+    assert_eq!(
+        Cds::decode(CdsFormat::DEFAULT, &too_long),
+        Err(CcsdsError::InvalidTime)
+    );
+    assert_eq!(
+        Cds::decode(CdsFormat::DEFAULT, &buf[..7]),
+        Err(CcsdsError::BufferTooShort { required: 8 })
+    );
+}
+
+#[test]
+fn sequence_counter_wraps() {
+    let mut counter = SequenceCounter::new();
+    for expected in 0..=MAX_SEQUENCE_COUNT {
+        assert_eq!(counter.next_count(), expected);
+    }
+    assert_eq!(counter.next_count(), 0);
+}
+
+#[test]
+fn packet_over_isotp() {
+    let bus = MockBus::new();
+    let other = MockBus::new();
+    let (tx_id, rx_id) = (
+        StandardId::new(0x7e0).unwrap(),
+        StandardId::new(0x7e8).unwrap(),
+    );
+    let mut sender = MockShield::new(&bus, &other);
+    let mut receiver = MockShield::new(&bus, &other);
+    let mut sender_link = IsoTpLink::<1000>::new(IsoTpConfig::new(Channel::Can1, tx_id, rx_id));
+    let mut receiver_link = IsoTpLink::<1000>::new(IsoTpConfig::new(Channel::Can1, rx_id, tx_id));
+
+    let mut buf = [0; 128];
+    let report: Vec<u8> = (0..100).collect();
+    let time = Cuc::from_micros(12_345_678, CucFormat::DEFAULT);
+    let packet = PacketBuilder::telemetry(0x42)
+        .timestamp(CucFormat::DEFAULT, time)
+        .build(&report, &mut buf)
+        .unwrap();
+    sender_link
+        .send(&mut sender, packet, TimerInstantU32::from_ticks(0))
+        .unwrap();
+
+    let mut received = None;
+    for ms in 0..200 {
+        let now = TimerInstantU32::from_ticks(ms);
+        bus.step();
+        while let Ok(frame) = receiver.can1.receive() {
+            if let Some(message) = receiver_link.on_frame(&mut receiver, &frame, now).unwrap() {
+                received = Some(message.to_vec());
+            }
+        }
+        while let Ok(frame) = sender.can1.receive() {
+            sender_link.on_frame(&mut sender, &frame, now).unwrap();
+        }
+        sender.service_tx_interrupts();
+        receiver.service_tx_interrupts();
+        sender_link.poll(&mut sender, now).unwrap();
+        receiver_link.poll(&mut receiver, now).unwrap();
+    }
+
+    let received = received.expect("message received");
+    let parsed = SpacePacket::parse(&received).unwrap();
+    assert_eq!(parsed.apid(), 0x42);
+    assert_eq!(
+        parsed.secondary_header(CucFormat::DEFAULT).unwrap(),
+        (time, report.as_slice())
+    );
+}
+
+quickcheck! {
+    fn cds_round_trip(long_days: bool, micros: bool, days: u16, millis: u32, submillis: u16) -> bool {
+        let format = CdsFormat::new(2 + long_days as u8, 2 * micros as u8).unwrap();
+        let time = Cds {
+            days: u32::from(days),
+            millis: millis % 86_400_000,
+            micros: if micros { submillis % 1000 } else { 0 },
+        };
+        let mut buf = [0; 9];
+        let len = time.encode(format, &mut buf).unwrap();
+        len == format.encoded_len()
+            && Cds::decode(format, &buf) == Ok(time)
+            && Cds::from_micros(time.to_micros()) == time
+    }
+
+    fn header_round_trip(
+        telecommand: bool,
+        secondary_header: bool,
+        apid: u16,
+        sequence_flags: u8,
+        sequence_count: u16,
+        length: u16
+    ) -> bool {
+        let header = PrimaryHeader {
+            packet_type: if telecommand { PacketType::Telecommand } else { PacketType::Telemetry },
+            secondary_header,
+            apid: apid & MAX_APID,
+            sequence_flags: flags(sequence_flags),
+            sequence_count: sequence_count & MAX_SEQUENCE_COUNT,
+            data_len: usize::from(length) + 1,
+        };
+        PrimaryHeader::from_bytes(&header.to_bytes()) == Ok(header)
+    }
+
+    fn packet_round_trip(
+        apid: u16,
+        sequence_count: u16,
+        sequence_flags: u8,
+        time: Option<(u8, u8, u32, u32)>,
+        user_data: Vec<u8>
+    ) -> TestResult {
+        let apid = apid & MAX_APID;
+        let time = time.map(|(coarse, fine, seconds, fraction)| {
+            let format = format(coarse, fine);
+            let time = Cuc {
+                coarse: truncate(seconds, format.coarse_octets()),
+                fine: truncate(fraction, format.fine_octets()),
+            };
+            (format, time)
+        });
+        if user_data.is_empty() && time.is_none() {
+            return TestResult::discard();
+        }
+
+        let mut builder = PacketBuilder::telemetry(apid)
+            .sequence_count(sequence_count)
+            .sequence_flags(flags(sequence_flags));
+        if let Some((format, time)) = time {
+            builder = builder.timestamp(format, time);
+        }
+
+        let mut buf = vec![0; 512];
+        let len = builder.build(&user_data, &mut buf).unwrap().len();
+        let packet = SpacePacket::parse(&buf).unwrap();
+
+        let header = packet.header();
+        let user = match time {
+            Some((format, expected)) => {
+                let (time, user) = packet.secondary_header(format).unwrap();
+                assert_eq!(time, expected);
+                user
+            }
+            None => packet.data_field(),
+        };
+
+        TestResult::from_bool(
+            packet.packet_len() == len
+                && header.apid == apid
+                && header.sequence_count == sequence_count & MAX_SEQUENCE_COUNT
+                && header.sequence_flags == flags(sequence_flags)
+                && header.secondary_header == time.is_some()
+                && user == user_data.as_slice(),
+        )
+    }
+
+    fn packet_stream(payloads: Vec<Vec<u8>>) -> bool {
+        let payloads: Vec<Vec<u8>> = payloads.into_iter().filter(|p| !p.is_empty()).collect();
+        let mut stream = Vec::new();
+        for (count, payload) in payloads.iter().enumerate() {
+            let mut buf = vec![0; PRIMARY_HEADER_LEN + payload.len()];
+            let packet = PacketBuilder::telemetry(7)
+                .sequence_count(count as u16)
+                .build(payload, &mut buf)
+                .unwrap();
+            stream.extend_from_slice(packet);
+        }
+
+        let mut rest = stream.as_slice();
+        for (count, payload) in payloads.iter().enumerate() {
+            let packet = SpacePacket::parse(rest).unwrap();
+            if packet.sequence_count() != count as u16 || packet.data_field() != payload.as_slice() {
+                return false;
+            }
+            rest = &rest[packet.packet_len()..];
+        }
+        rest.is_empty()
+    }
+
+    fn parse_never_panics(bytes: Vec<u8>) -> bool {
+        match SpacePacket::parse(&bytes) {
+            Ok(packet) => packet.packet_len() <= bytes.len() && packet.as_bytes() == &bytes[..packet.packet_len()],
+            Err(_) => true,
+        }
+    }
+
+    fn cuc_round_trip(coarse: u8, fine: u8, seconds: u32, fraction: u32) -> bool {
+        let format = format(coarse, fine);
+        let time = Cuc { coarse: seconds, fine: fraction };
+
+        let mut buf = [0; 7];
+        let len = time.encode(format, &mut buf).unwrap();
+        let decoded = Cuc::decode(format, &buf[..len]).unwrap();
+
+        len == format.encoded_len()
+            && decoded.coarse == truncate(seconds, format.coarse_octets())
+            && decoded.fine == truncate(fraction, format.fine_octets())
+            && CucFormat::from_pfield(format.pfield()) == Some(format)
+    }
+
+    fn cuc_micros_within_resolution(fine: u8, micros: u64) -> bool {
+        let format = format(3, fine);
+        let micros = micros % (u64::from(u32::MAX) * 1_000_000);
+        let back = Cuc::from_micros(micros, format).to_micros(format);
+        // A fine tick, rounded up
+        let resolution = 1_000_000u64.div_ceil(1 << (8 * format.fine_octets()));
+
+        back <= micros && micros - back <= resolution
+    }
+}
@@ -0,0 +1,79 @@
+//! Telecommands and reports shared by the host tests of the PUS services.
+
+// Each test crate uses only some of it
+#![allow(dead_code)]
+
+use stm32f446_rtic::{
+    ccsds::{Cuc, CucFormat, PacketBuilder, SpacePacket},
+    pus::{
+        verification::AckFlags, Dispatcher, Report, Service, TcHeader, Telecommand, TmChannel,
+        TmQueue,
+    },
+};
+
+pub const APID: u16 = 0x10;
+pub const GROUND: u16 = 0x42;
+
+/// The time of every report.
+pub fn now() -> Cuc {
+    Cuc {
+        coarse: 1000,
+        fine: 0x8000,
+    }
+}
+
+/// A telecommand from the ground to `service`, without acknowledgements.
+pub fn tc(service: u8, subtype: u8, app_data: &[u8]) -> Vec<u8> {
+    tc_with(0, service, subtype, AckFlags::NONE, app_data)
+}
+
+/// A telecommand from the ground with the sequence count `count` and the acknowledgements `ack`.
+pub fn tc_with(count: u16, service: u8, subtype: u8, ack: AckFlags, app_data: &[u8]) -> Vec<u8> {
+    let header = TcHeader {
+        ack,
+        service,
+        subtype,
+        source: GROUND,
+    };
+    let mut buf = [0; 64];
+    Telecommand::build(
+        PacketBuilder::telecommand(APID).sequence_count(count),
+        &header,
+        app_data,
+        &mut buf,
+    )
+    .unwrap()
+    .to_vec()
+}
+
+/// The (service, subtype, source data) of every queued report.
+pub fn reports<const N: usize>(queue: &mut TmQueue<N>) -> Vec<(u8, u8, Vec<u8>)> {
+    let mut reports = Vec::new();
+    while let Some(packet) = queue.pop() {
+        let packet = SpacePacket::parse(&packet).unwrap();
+        assert_eq!(packet.apid(), APID);
+        let report = Report::parse(packet, CucFormat::DEFAULT).unwrap();
+        assert_eq!(report.time, now());
+        reports.push((
+            report.header.service,
+            report.header.subtype,
+            report.source_data.to_vec(),
+        ));
+    }
+    reports
+}
+
+/// Dispatches `request` to `service` alone and returns the (subtype, source data) of the reports.
+pub fn dispatch(service: &mut dyn Service, request: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<16>::new();
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(service).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    dispatcher.dispatch(request, &mut tm).ok();
+
+    reports(&mut queue)
+        .into_iter()
+        .map(|(_, subtype, data)| (subtype, data))
+        .collect()
+}
@@ -0,0 +1,395 @@
+//! Host tests of CSP over CAN against the mock backend. Run with `cargo test-host`.
+
+use bxcan::{Frame, Id, StandardId};
+use fugit::{ExtU32, TimerInstantU32};
+use stm32f446_rtic::{
+    can_shield::{
+        filters::FilterSet,
+        mock::{MockBus, MockShield, Round},
+        CanChannel, Channel,
+    },
+    csp::{
+        cfp::{self, CfpError, CfpId, Fragmenter, FrameType, Reassembler},
+        CspError, CspInterface, Header, Packet, Priority, Route, Router, CSP_MTU,
+    },
+};
+
+fn at(ms: u32) -> TimerInstantU32<1000> {
+    TimerInstantU32::from_ticks(ms)
+}
+
+fn header(src: u8, dst: u8) -> Header {
+    Header {
+        priority: Priority::Normal,
+        src,
+        dst,
+        dport: 10,
+        sport: 20,
+        flags: 0,
+    }
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+/// A node with its own shield on the two shared buses.
+struct Node {
+    shield: MockShield,
+    csp: CspInterface<1000>,
+    received: Vec<Packet>,
+}
+
+impl Node {
+    /// Drains both receive FIFOs like the RX interrupts, then services the TX interrupts.
+    fn service(&mut self) {
+        for channel in Channel::ALL {
+            let can: &mut dyn CanChannel = match channel {
+                Channel::Can1 => &mut self.shield.can1,
+                Channel::Can2 => &mut self.shield.can2,
+            };
+            while let Ok(frame) = can.receive() {
+                if let Some(packet) = self.csp.receive(channel, &frame, at(0)).unwrap() {
+                    self.received.push(packet);
+                }
+            }
+        }
+        self.shield.service_tx_interrupts();
+        self.csp.poll_tx(&mut self.shield);
+    }
+}
+
+/// Nodes sharing the two buses.
+struct Network {
+    bus1: MockBus,
+    bus2: MockBus,
+    nodes: Vec<Node>,
+}
+
+impl Network {
+    fn new() -> Self {
+        Self {
+            bus1: MockBus::new(),
+            bus2: MockBus::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Adds a node and returns its index.
+    fn add(&mut self, address: u8, router: Router) -> usize {
+        self.nodes.push(Node {
+            shield: MockShield::new(&self.bus1, &self.bus2),
+            csp: CspInterface::new(address, router),
+            received: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+
+    fn send(&mut self, node: usize, packet: &Packet) -> Result<(), CspError> {
+        let node = &mut self.nodes[node];
+        node.csp.send(&mut node.shield, packet)
+    }
+
+    /// Sends a frame at a time on each bus until both are idle.
+    fn run(&mut self) {
+        loop {
+            let rounds = [self.bus1.step(), self.bus2.step()];
+            for node in &mut self.nodes {
+                node.service();
+            }
+            let busy = self.nodes.iter().any(|node| {
+                Channel::ALL
+                    .into_iter()
+                    .any(|channel| node.csp.is_sending(channel))
+                    || node.shield.can1.pending() + node.shield.can2.pending() > 0
+            });
+            if rounds == [Round::Idle, Round::Idle] && !busy {
+                break;
+            }
+        }
+    }
+
+    fn received(&mut self, node: usize) -> Vec<Packet> {
+        core::mem::take(&mut self.nodes[node].received)
+    }
+}
+
+#[test]
+fn header_matches_libcsp_layout() {
+    let header = header(1, 2);
+
+    assert_eq!(header.to_bits(), 0x8222_9400);
+    assert_eq!(Header::from_bits(0x8222_9400), header);
+}
+
+#[test]
+fn fragments_match_libcsp_layout() {
+    let packet = Packet::new(header(1, 2), &payload(11)).unwrap();
+    let frames: Vec<Frame> = Fragmenter::new(packet, 2, 5).collect();
+
+    // 6 bytes of header and length plus 11 bytes of payload take 3 frames.
+    assert_eq!(frames.len(), cfp::frame_count(11));
+    assert_eq!(frames.len(), 3);
+
+    let raw = |frame: &Frame| match frame.id() {
+        Id::Extended(id) => id.as_raw(),
+        Id::Standard(_) => panic!("standard ID"),
+    };
+    assert_eq!(raw(&frames[0]), 1 << 24 | 2 << 19 | 2 << 10 | 5);
+    assert_eq!(raw(&frames[1]), 1 << 24 | 2 << 19 | 1 << 18 | 1 << 10 | 5);
+    assert_eq!(raw(&frames[2]), 1 << 24 | 2 << 19 | 1 << 18 | 5);
+
+    assert_eq!(
+        frames[0].data().unwrap().as_ref(),
+        [0x82, 0x22, 0x94, 0x00, 0x00, 11, 0, 1]
+    );
+    assert_eq!(frames[1].data().unwrap().as_ref(), [2, 3, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(frames[2].data().unwrap().as_ref(), [10]);
+
+    let id = CfpId::from_frame(&frames[1]).unwrap();
+    assert_eq!(id.frame_type, FrameType::More);
+    assert_eq!(id.remain, 1);
+}
+
+#[test]
+fn packets_of_every_length_survive_the_bus() {
+    let mut net = Network::new();
+    let a = net.add(1, Router::new(Channel::Can1));
+    let b = net.add(2, Router::new(Channel::Can1));
+
+    for len in [0, 1, 2, 3, 10, 100, CSP_MTU] {
+        let packet = Packet::new(header(1, 2), &payload(len)).unwrap();
+        net.send(a, &packet).unwrap();
+        net.run();
+
+        assert_eq!(net.received(b), [packet], "length {}", len);
+    }
+
+    // Every frame went out in order.
+    let log = net.bus1.take_log();
+    assert_eq!(
+        log.len(),
+        [0, 1, 2, 3, 10, 100, CSP_MTU]
+            .map(cfp::frame_count)
+            .iter()
+            .sum()
+    );
+    let stats = net.nodes[b].csp.reassembler().stats();
+    assert_eq!((stats.packets, stats.errors), (7, 0));
+}
+
+#[test]
+fn routes_pick_the_channel_and_next_hop() {
+    let mut router = Router::new(Channel::Can1);
+    router
+        .set_route(2, Route::direct(Channel::Can2))
+        .set_route(9, Route::via(Channel::Can2, 3));
+
+    let mut net = Network::new();
+    let a = net.add(1, router);
+    let b = net.add(2, Router::new(Channel::Can2));
+    let c = net.add(3, Router::new(Channel::Can2));
+
+    let direct = Packet::new(header(1, 2), b"direct").unwrap();
+    let forwarded = Packet::new(header(1, 9), b"forwarded").unwrap();
+    net.send(a, &direct).unwrap();
+    net.run();
+    net.send(a, &forwarded).unwrap();
+    net.run();
+
+    assert!(net.bus1.take_log().is_empty());
+    assert_eq!(net.received(b), [direct]);
+    // Node 3 is the next hop and gets the packet for node 9 to forward.
+    assert_eq!(net.received(c), [forwarded]);
+}
+
+#[test]
+fn both_channels_send_at_the_same_time() {
+    let mut router = Router::new(Channel::Can1);
+    router.set_route(3, Route::direct(Channel::Can2));
+
+    let mut net = Network::new();
+    let a = net.add(1, router);
+    let b = net.add(2, Router::new(Channel::Can1));
+    let c = net.add(3, Router::new(Channel::Can1));
+
+    let to_b = Packet::new(header(1, 2), &payload(40)).unwrap();
+    let to_c = Packet::new(header(1, 3), &payload(60)).unwrap();
+    net.send(a, &to_b).unwrap();
+    net.send(a, &to_c).unwrap();
+    assert!(net.nodes[a].csp.is_sending(Channel::Can1));
+    assert_eq!(net.send(a, &to_b), Err(CspError::Busy));
+
+    net.run();
+    assert!(!net.nodes[a].csp.is_sending(Channel::Can1));
+    assert!(!net.nodes[a].csp.is_sending(Channel::Can2));
+    assert_eq!(net.received(b), [to_b]);
+    assert_eq!(net.received(c), [to_c]);
+}
+
+#[test]
+fn broadcasts_reach_every_node() {
+    let mut net = Network::new();
+    let a = net.add(1, Router::new(Channel::Can1));
+    let b = net.add(2, Router::new(Channel::Can1));
+    let c = net.add(3, Router::new(Channel::Can1));
+
+    let packet = Packet::new(header(1, 31), b"hi").unwrap();
+    net.send(a, &packet).unwrap();
+    net.run();
+
+    assert_eq!(net.received(b), std::slice::from_ref(&packet));
+    assert_eq!(net.received(c), [packet]);
+}
+
+#[test]
+fn filter_only_lets_through_frames_for_the_node() {
+    let mut net = Network::new();
+    let a = net.add(1, Router::new(Channel::Can1));
+    let b = net.add(2, Router::new(Channel::Can1));
+    let filter = net.nodes[b].csp.filter();
+    net.nodes[b]
+        .shield
+        .can1
+        .set_filters(FilterSet::new().with(filter));
+
+    for dst in [2, 3] {
+        let packet = Packet::new(header(1, dst), &payload(20)).unwrap();
+        let frames: Vec<Frame> = Fragmenter::new(packet, dst, 0).collect();
+        assert_eq!(
+            frames
+                .iter()
+                .all(|frame| FilterSet::new().with(filter).accepts(frame)),
+            dst == 2
+        );
+    }
+
+    net.send(a, &Packet::new(header(1, 3), b"elsewhere").unwrap())
+        .unwrap();
+    net.run();
+    net.send(a, &Packet::new(header(1, 2), b"here").unwrap())
+        .unwrap();
+    net.run();
+
+    assert_eq!(net.received(b).len(), 1);
+    assert_eq!(net.nodes[b].csp.reassembler().stats().errors, 0);
+}
+
+#[test]
+fn packets_without_route_are_refused() {
+    let mut router = Router::new(Channel::Can1);
+    router
+        .set_default(None)
+        .set_route(2, Route::direct(Channel::Can1));
+
+    let mut net = Network::new();
+    let a = net.add(1, router);
+
+    let packet = Packet::new(header(1, 5), b"lost").unwrap();
+    assert_eq!(net.send(a, &packet), Err(CspError::NoRoute { dst: 5 }));
+    assert!(!net.nodes[a].csp.is_sending(Channel::Can1));
+}
+
+#[test]
+fn interleaved_packets_from_both_channels() {
+    let short = Packet::new(header(4, 2), &payload(30)).unwrap();
+    let long = Packet::new(header(5, 2), &payload(90)).unwrap();
+    let mut first: Vec<Frame> = Fragmenter::new(short.clone(), 2, 7).collect();
+    let mut second: Vec<Frame> = Fragmenter::new(long.clone(), 2, 7).collect();
+    let mut reassembler = Reassembler::<1000>::new();
+    let mut done = Vec::new();
+
+    while !first.is_empty() || !second.is_empty() {
+        if !first.is_empty() {
+            let frame = first.remove(0);
+            done.extend(reassembler.push(Channel::Can1, &frame, at(0)).unwrap());
+        }
+        if !second.is_empty() {
+            let frame = second.remove(0);
+            done.extend(reassembler.push(Channel::Can2, &frame, at(0)).unwrap());
+        }
+    }
+
+    assert_eq!(done, [short, long]);
+    assert_eq!(reassembler.in_progress(), 0);
+}
+
+#[test]
+fn partial_packets_time_out() {
+    let packet = Packet::new(header(1, 2), &payload(20)).unwrap();
+    let frames: Vec<Frame> = Fragmenter::new(packet.clone(), 2, 0).collect();
+    let mut reassembler = Reassembler::<1000>::new();
+    reassembler.set_timeout(500.millis());
+
+    assert_eq!(reassembler.push(Channel::Can1, &frames[0], at(0)), Ok(None));
+    assert_eq!(reassembler.expire(at(499)), 0);
+    assert_eq!(reassembler.expire(at(500)), 1);
+    assert_eq!(
+        reassembler.push(Channel::Can1, &frames[1], at(500)),
+        Err(CfpError::UnexpectedFragment)
+    );
+
+    let stats = reassembler.stats();
+    assert_eq!((stats.timeouts, stats.errors), (1, 1));
+}
+
+#[test]
+fn missing_frames_drop_the_packet() {
+    let packet = Packet::new(header(1, 2), &payload(30)).unwrap();
+    let frames: Vec<Frame> = Fragmenter::new(packet.clone(), 2, 0).collect();
+    let mut reassembler = Reassembler::<1000>::new();
+
+    reassembler.push(Channel::Can1, &frames[0], at(0)).unwrap();
+    reassembler.push(Channel::Can1, &frames[1], at(0)).unwrap();
+    assert_eq!(
+        reassembler.push(Channel::Can1, &frames[3], at(0)),
+        Err(CfpError::OutOfOrder)
+    );
+    assert_eq!(reassembler.in_progress(), 0);
+
+    // A resent packet with the same identifier starts over.
+    for frame in &frames[..frames.len() - 1] {
+        assert_eq!(reassembler.push(Channel::Can1, frame, at(0)), Ok(None));
+    }
+    assert_eq!(
+        reassembler.push(Channel::Can1, &frames[frames.len() - 1], at(0)),
+        Ok(Some(packet))
+    );
+}
+
+#[test]
+fn malformed_first_frames_are_rejected() {
+    let mut reassembler = Reassembler::<1000>::new();
+    let begin = |remain: u8| CfpId {
+        src: 1,
+        dst: 2,
+        frame_type: FrameType::Begin,
+        remain,
+        ident: 0,
+    };
+
+    let short = Frame::new_data(begin(0).id(), [0x82, 0x22, 0x94, 0x00]);
+    assert_eq!(
+        reassembler.push(Channel::Can1, &short, at(0)),
+        Err(CfpError::Malformed)
+    );
+
+    // Announces 300 bytes, more than the MTU.
+    let long = Frame::new_data(begin(38).id(), [0x82, 0x22, 0x94, 0x00, 0x01, 0x2c]);
+    assert_eq!(
+        reassembler.push(Channel::Can1, &long, at(0)),
+        Err(CfpError::TooLong)
+    );
+
+    // Announces 20 bytes, which take 3 frames, not 2.
+    let wrong = Frame::new_data(begin(1).id(), [0x82, 0x22, 0x94, 0x00, 0x00, 20, 0, 1]);
+    assert_eq!(
+        reassembler.push(Channel::Can1, &wrong, at(0)),
+        Err(CfpError::Malformed)
+    );
+
+    // Frames with standard IDs are not CFP frames and are ignored.
+    let other = Frame::new_data(StandardId::new(0x100).unwrap(), []);
+    assert_eq!(reassembler.push(Channel::Can1, &other, at(0)), Ok(None));
+    assert_eq!(reassembler.stats().errors, 3);
+}
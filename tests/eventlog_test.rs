@@ -0,0 +1,313 @@
+//! Host tests of the event log against the RAM flash. Run with `cargo test-host`.
+
+mod common;
+
+use core::cell::RefCell;
+
+use common::{dispatch, now, tc, APID};
+use stm32f446_rtic::{
+    ccsds::{CucFormat, SpacePacket},
+    eventlog::{EventLog, LogError},
+    flash::{mock::RamFlash, Flash},
+    nvstore::{ParamStore, ParameterDef},
+    pus::{
+        event::Severity,
+        housekeeping::parameters::Value,
+        parameter::{self, ParameterService},
+        storage::{self, EventLogService},
+        verification::{self, RequestId},
+        Dispatcher, TmChannel, TmQueue,
+    },
+    time::Met,
+};
+
+/// Sectors of 8 records.
+const SECTOR_LEN: usize = 16 + 8 * 30;
+
+fn flash() -> RamFlash {
+    RamFlash::new(&[SECTOR_LEN; 3])
+}
+
+fn mounted(flash: &mut RamFlash) -> EventLog<3> {
+    let mut log = EventLog::new([0, 1, 2]);
+    log.mount(flash).unwrap();
+    log
+}
+
+fn at(seq: u32) -> Met {
+    Met::from_ticks(1_000_000 + u64::from(seq) * 1000)
+}
+
+/// Logs event `seq` with its sequence number as parameter.
+fn log_event(log: &mut EventLog<3>, flash: &mut RamFlash, seq: u32) -> Result<u32, LogError> {
+    log.log(flash, at(seq), Severity::Low, 0x0100, &seq.to_be_bytes())
+}
+
+#[test]
+fn records_survive_a_reset() {
+    let mut flash = flash();
+    let mut log = mounted(&mut flash);
+    assert_eq!((log.oldest_seq(), log.next_seq()), (0, 0));
+    assert_eq!(log.capacity(&flash), 24);
+
+    let params = [0xa5; 16];
+    assert_eq!(
+        log.log(&mut flash, at(0), Severity::High, 0x0203, &params),
+        Ok(0)
+    );
+    assert_eq!(
+        log.log(&mut flash, at(1), Severity::Informative, 1, &[]),
+        Ok(1)
+    );
+    assert_eq!(
+        log.log(&mut flash, at(2), Severity::Low, 2, &[0; 17]),
+        Err(LogError::TooLong)
+    );
+
+    let log = mounted(&mut flash);
+    assert_eq!((log.oldest_seq(), log.next_seq()), (0, 2));
+    let entry = log.read(&flash, 0).unwrap();
+    assert_eq!(entry.seq, 0);
+    assert_eq!(entry.time, at(0));
+    assert_eq!(entry.severity, Severity::High);
+    assert_eq!(entry.id, 0x0203);
+    assert_eq!(entry.params(), params);
+    assert_eq!(log.read(&flash, 1).unwrap().params(), []);
+    assert_eq!(log.read(&flash, 2), Err(LogError::NotFound(2)));
+    assert_eq!(log.stats().corrupted, 0);
+
+    let mut unmounted = EventLog::new([0, 1, 2]);
+    assert_eq!(
+        log_event(&mut unmounted, &mut flash, 0),
+        Err(LogError::NotMounted)
+    );
+}
+
+#[test]
+fn ring_erases_ahead() {
+    let mut flash = flash();
+    let mut log = mounted(&mut flash);
+    // Formatting leaves the sector after the first one erased, which it already was
+    assert_eq!(log.stats().erased, 0);
+
+    for seq in 0..100 {
+        assert_eq!(log_event(&mut log, &mut flash, seq), Ok(seq));
+
+        // Between one and two full sectors are kept besides the current one
+        let kept = log.next_seq() - log.oldest_seq();
+        assert!(seq < 16 || (8..=16).contains(&kept), "{kept} kept");
+    }
+    assert_eq!(log.oldest_seq(), 88);
+    assert_eq!(log.stats().erased, 11);
+    assert_eq!(log.read(&flash, 87), Err(LogError::NotFound(87)));
+
+    let log = mounted(&mut flash);
+    assert_eq!((log.oldest_seq(), log.next_seq()), (88, 100));
+    for seq in 88..100 {
+        let entry = log.read(&flash, seq).unwrap();
+        assert_eq!(entry.params(), seq.to_be_bytes());
+        assert_eq!(entry.time, at(seq));
+    }
+    // The sector ahead of the newest one is erased
+    assert!(flash.is_erased(1).unwrap());
+}
+
+#[test]
+fn power_loss_loses_the_record_in_progress_only() {
+    // Up to a bit over a whole ring, so the power fails while moving on to every sector
+    for count in 0..30 {
+        for budget in 0.. {
+            let mut flash = flash();
+            let mut log = mounted(&mut flash);
+            for seq in 0..count {
+                log_event(&mut log, &mut flash, seq).unwrap();
+            }
+            let oldest = log.oldest_seq();
+            flash.cut_power_after(budget);
+            let done = log_event(&mut log, &mut flash, count).is_ok();
+            flash.restore_power();
+
+            let mut log = mounted(&mut flash);
+            assert!(log.oldest_seq() >= oldest);
+            for seq in log.oldest_seq()..count {
+                assert_eq!(
+                    log.read(&flash, seq).unwrap().params(),
+                    seq.to_be_bytes(),
+                    "{seq} after {count} records and {budget} bytes"
+                );
+            }
+            match done {
+                true => assert!(log.read(&flash, count).is_ok()),
+                false => assert!(log.next_seq() <= count + 1),
+            }
+
+            // The log goes on
+            let seq = log_event(&mut log, &mut flash, 1000).unwrap();
+            let log = mounted(&mut flash);
+            assert_eq!(
+                log.read(&flash, seq).unwrap().params(),
+                1000u32.to_be_bytes()
+            );
+            assert_eq!(log.next_seq(), seq + 1);
+
+            if done {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn damaged_records_are_skipped() {
+    let mut flash = flash();
+    let mut log = mounted(&mut flash);
+    for seq in 0..3 {
+        log_event(&mut log, &mut flash, seq).unwrap();
+    }
+
+    // A bit of the parameters of the second record flips
+    flash.corrupt(0, 16 + 30 + 12, 0x10);
+    let mut log = mounted(&mut flash);
+    assert_eq!(log.stats().corrupted, 1);
+    assert_eq!(log.read(&flash, 1), Err(LogError::Corrupted(1)));
+    assert!(log.read(&flash, 2).is_ok());
+    assert_eq!(log_event(&mut log, &mut flash, 3), Ok(3));
+
+    // A damaged header loses its sector, the log starts over
+    flash.corrupt(0, 5, 0x01);
+    let log = mounted(&mut flash);
+    assert_eq!((log.oldest_seq(), log.next_seq()), (0, 0));
+    assert_eq!(log.read(&flash, 2), Err(LogError::NotFound(2)));
+}
+
+fn dump(first: u32, count: u16) -> Vec<u8> {
+    let mut data = first.to_be_bytes().to_vec();
+    data.extend(count.to_be_bytes());
+    tc(storage::SERVICE, storage::DUMP, &data)
+}
+
+/// The sequence numbers in an entries report.
+fn sequence_numbers(data: &[u8]) -> Vec<u32> {
+    let mut seqs = Vec::new();
+    let mut rest = &data[1..];
+    for _ in 0..data[0] {
+        seqs.push(u32::from_be_bytes(rest[..4].try_into().unwrap()));
+        let time_len = CucFormat::DEFAULT.encoded_len();
+        let len = 4 + time_len + 4 + usize::from(rest[4 + time_len + 3]);
+        rest = &rest[len..];
+    }
+    assert!(rest.is_empty());
+    seqs
+}
+
+#[test]
+fn log_requests() {
+    let mut flash = flash();
+    let mut log = mounted(&mut flash);
+    for seq in 0..20 {
+        log_event(&mut log, &mut flash, seq).unwrap();
+    }
+
+    let status = tc(storage::SERVICE, storage::REPORT_STATUS, &[]);
+    assert_eq!(
+        dispatch(&mut EventLogService::new(&log, &flash), &status),
+        [(
+            storage::STATUS_REPORT,
+            vec![0, 0, 0, 8, 0, 0, 0, 20, 0, 0, 0, 24]
+        )]
+    );
+
+    // One record in full: sequence number, MET 1.009 s, severity, ID and parameters
+    let reports = dispatch(&mut EventLogService::new(&log, &flash), &dump(9, 1));
+    assert_eq!(
+        reports,
+        [(
+            storage::ENTRIES_REPORT,
+            vec![1, 0, 0, 0, 9, 0, 0, 0, 1, 2, 0x4d, 2, 0x01, 0x00, 4, 0, 0, 0, 9]
+        )]
+    );
+
+    // Records before the oldest are left out, at most 6 go into a report
+    let reports = dispatch(&mut EventLogService::new(&log, &flash), &dump(4, 20));
+    let seqs: Vec<Vec<u32>> = reports
+        .iter()
+        .map(|(subtype, data)| {
+            assert_eq!(*subtype, storage::ENTRIES_REPORT);
+            sequence_numbers(data)
+        })
+        .collect();
+    assert_eq!(seqs, [(8..14).collect::<Vec<_>>(), (14..20).collect(),]);
+
+    // An empty range still gets an answer
+    assert_eq!(
+        dispatch(&mut EventLogService::new(&log, &flash), &dump(100, 3)),
+        [(storage::ENTRIES_REPORT, vec![0])]
+    );
+}
+
+#[test]
+fn log_rejections() {
+    let mut flash = flash();
+    let log = mounted(&mut flash);
+
+    let rejection = |request: &[u8], code: u16| {
+        let mut data = RequestId::of(&SpacePacket::parse(request).unwrap())
+            .to_bytes()
+            .to_vec();
+        data.extend(code.to_be_bytes());
+        (verification::ACCEPTANCE_FAILURE, data)
+    };
+    let long = dump(0, storage::MAX_DUMP_LEN + 1);
+    assert_eq!(
+        dispatch(&mut EventLogService::new(&log, &flash), &long),
+        [rejection(&long, storage::RANGE_TOO_LONG)]
+    );
+    let short = tc(storage::SERVICE, storage::DUMP, &[0, 0, 0, 0, 1]);
+    assert_eq!(
+        dispatch(&mut EventLogService::new(&log, &flash), &short),
+        [rejection(&short, verification::INVALID_DATA)]
+    );
+    let status = tc(storage::SERVICE, storage::REPORT_STATUS, &[0]);
+    assert_eq!(
+        dispatch(&mut EventLogService::new(&log, &flash), &status),
+        [rejection(&status, verification::INVALID_DATA)]
+    );
+}
+
+const STORED: &[ParameterDef] = &[ParameterDef::new(0x0200, Value::U8(0x10))];
+
+#[test]
+fn stores_share_the_flash() {
+    // The event log in the first three sectors, the parameter store in the last two
+    let flash = RefCell::new(RamFlash::new(&[SECTOR_LEN; 5]));
+    let mut shared = &flash;
+    let mut log = EventLog::new([0, 1, 2]);
+    log.mount(&mut shared).unwrap();
+    log.log(&mut shared, at(0), Severity::Low, 0x0100, &[1])
+        .unwrap();
+    let mut store = ParamStore::<1>::new([3, 4], STORED);
+    store.mount(&mut shared).unwrap();
+
+    {
+        let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+        let mut queue = TmQueue::<16>::new();
+        let reader = &flash;
+        let mut parameters = ParameterService::new(&mut store, &mut shared);
+        let mut storage = EventLogService::new(&log, &reader);
+        let mut dispatcher = Dispatcher::<2>::new(APID);
+        dispatcher.register(&mut parameters).unwrap();
+        dispatcher.register(&mut storage).unwrap();
+        let mut tm = channel.reporter(&mut queue, now());
+        let set = tc(
+            parameter::SERVICE,
+            parameter::SET_VALUES,
+            &[1, 0x02, 0x00, 0x20],
+        );
+        dispatcher.dispatch(&set, &mut tm).unwrap();
+        dispatcher.dispatch(&dump(0, 1), &mut tm).unwrap();
+        assert_eq!(queue.len(), 1);
+    }
+
+    assert_eq!(store.get(0x0200), Some(Value::U8(0x20)));
+    assert_eq!(log.read(&shared, 0).unwrap().params(), [1]);
+}
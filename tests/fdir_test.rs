@@ -0,0 +1,283 @@
+//! Host tests of the FDIR rule engine with injected values. Run with `cargo test-host`.
+
+use fugit::{ExtU32, MillisDurationU32};
+use stm32f446_rtic::{
+    can_shield::{
+        health::{BusState, ErrorStatus, LastError},
+        Channel,
+    },
+    fdir::{Check, FaultEvent, Fdir, Monitor, Observations, Recover, Recovery, Rule},
+    mode::{Mode, ModeActions, ModeManager, Transition, Trigger},
+    pus::{event::Severity, housekeeping::Value},
+    watchdog::TaskId,
+};
+
+const TEMPERATURE: u16 = 0x0102;
+
+const HOT: u16 = 1;
+const ERRORS: u16 = 2;
+const BUS_OFF: u16 = 3;
+const LATE: u16 = 4;
+
+const MONITORS: [Monitor; 4] = [
+    Monitor::new(
+        HOT,
+        Check::Limit {
+            parameter: TEMPERATURE,
+            low: -20.0,
+            high: 85.0,
+        },
+    )
+    .persistence(3),
+    Monitor::new(
+        ERRORS,
+        Check::ErrorCounters {
+            channel: Channel::Can2,
+            limit: 127,
+        },
+    )
+    .severity(Severity::Low),
+    Monitor::new(BUS_OFF, Check::BusOff(Channel::Can1)).persistence(2),
+    Monitor::new(
+        LATE,
+        Check::Deadline {
+            task: TaskId::new(1),
+            deadline: MillisDurationU32::millis(500),
+        },
+    ),
+];
+
+const RULES: &[Rule] = &[
+    Rule::new(HOT, Recovery::Mode(Mode::Safe)),
+    Rule::new(BUS_OFF, Recovery::RestartCan(Channel::Can1)),
+    Rule::new(BUS_OFF, Recovery::SwitchBus(Channel::Can2)),
+    Rule::new(LATE, Recovery::Reboot),
+];
+
+fn status(tec: u8, rec: u8, state: BusState) -> ErrorStatus {
+    ErrorStatus {
+        tec,
+        rec,
+        last_error: LastError::None,
+        state,
+    }
+}
+
+/// Injected values, and the recovery actions taken.
+struct Node {
+    temperature: Option<Value>,
+    can: [ErrorStatus; 2],
+    since_check_in: Option<MillisDurationU32>,
+    actions: Vec<(u16, Recovery)>,
+}
+
+impl Node {
+    fn healthy() -> Self {
+        Self {
+            temperature: Some(Value::F32(25.0)),
+            can: [status(0, 0, BusState::ErrorActive); 2],
+            since_check_in: Some(100.millis()),
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl Observations for Node {
+    fn parameter(&self, id: u16) -> Option<Value> {
+        self.temperature.filter(|_| id == TEMPERATURE)
+    }
+
+    fn error_status(&self, channel: Channel) -> ErrorStatus {
+        self.can[channel as usize]
+    }
+
+    fn since_check_in(&self, task: TaskId) -> Option<MillisDurationU32> {
+        assert_eq!(task, TaskId::new(1));
+        self.since_check_in
+    }
+}
+
+impl Recover for Node {
+    fn recover(&mut self, fault: u16, action: Recovery) {
+        self.actions.push((fault, action));
+    }
+}
+
+fn events(fdir: &mut Fdir<4>) -> Vec<FaultEvent> {
+    std::iter::from_fn(|| fdir.pop_event()).collect()
+}
+
+#[test]
+fn faults_persist_before_they_are_raised() {
+    let mut node = Node::healthy();
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+    fdir.run(&mut node);
+    assert_eq!(events(&mut fdir), []);
+
+    // A single hot sample is no fault yet
+    node.temperature = Some(Value::F32(90.0));
+    fdir.run(&mut node);
+    node.temperature = Some(Value::F32(80.0));
+    fdir.run(&mut node);
+    node.temperature = Some(Value::F32(90.0));
+    fdir.run(&mut node);
+    fdir.run(&mut node);
+    assert!(!fdir.is_raised(HOT));
+    assert_eq!(node.actions, []);
+
+    fdir.run(&mut node);
+    assert!(fdir.is_raised(HOT));
+    assert_eq!(node.actions, [(HOT, Recovery::Mode(Mode::Safe))]);
+    let raised = FaultEvent {
+        fault: HOT,
+        raised: true,
+        severity: Severity::Medium,
+        recoveries: 1,
+    };
+    assert_eq!(events(&mut fdir), [raised]);
+    assert_eq!(raised.to_bytes(), [0, 1, 1]);
+
+    // The recovery is taken once while the fault stays
+    fdir.run(&mut node);
+    assert_eq!(node.actions.len(), 1);
+    assert_eq!(events(&mut fdir), []);
+
+    node.temperature = Some(Value::F32(60.0));
+    fdir.run(&mut node);
+    assert!(!fdir.is_raised(HOT));
+    assert_eq!(
+        events(&mut fdir),
+        [FaultEvent {
+            fault: HOT,
+            raised: false,
+            severity: Severity::Informative,
+            recoveries: 0,
+        }]
+    );
+
+    let stats = fdir.stats();
+    assert_eq!((stats.runs, stats.faults, stats.recoveries), (8, 1, 1));
+}
+
+#[test]
+fn missing_parameter_fails_the_limit() {
+    let mut node = Node::healthy();
+    node.temperature = Some(Value::Bool(false));
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+    for _ in 0..3 {
+        fdir.run(&mut node);
+    }
+    assert!(fdir.is_raised(HOT));
+
+    let mut node = Node::healthy();
+    node.temperature = None;
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+    for _ in 0..3 {
+        fdir.run(&mut node);
+    }
+    assert!(fdir.is_raised(HOT));
+
+    // Integers are compared as well
+    let mut node = Node::healthy();
+    node.temperature = Some(Value::I8(-21));
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+    for _ in 0..3 {
+        fdir.run(&mut node);
+    }
+    assert!(fdir.is_raised(HOT));
+}
+
+#[test]
+fn can_faults_take_every_rule() {
+    let mut node = Node::healthy();
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+
+    // Error passive is reported without a recovery
+    node.can[Channel::Can2 as usize] = status(0, 128, BusState::ErrorPassive);
+    fdir.run(&mut node);
+    assert_eq!(
+        events(&mut fdir),
+        [FaultEvent {
+            fault: ERRORS,
+            raised: true,
+            severity: Severity::Low,
+            recoveries: 0,
+        }]
+    );
+    assert_eq!(node.actions, []);
+
+    node.can[Channel::Can1 as usize] = status(255, 0, BusState::BusOff);
+    fdir.run(&mut node);
+    fdir.run(&mut node);
+    assert_eq!(
+        node.actions,
+        [
+            (BUS_OFF, Recovery::RestartCan(Channel::Can1)),
+            (BUS_OFF, Recovery::SwitchBus(Channel::Can2)),
+        ]
+    );
+    assert_eq!(events(&mut fdir)[0].recoveries, 2);
+}
+
+#[test]
+fn late_task_reboots_unless_paused() {
+    let mut node = Node::healthy();
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+
+    node.since_check_in = Some(500.millis());
+    fdir.run(&mut node);
+    // Paused tasks are not watched
+    node.since_check_in = None;
+    fdir.run(&mut node);
+    assert_eq!(node.actions, []);
+
+    node.since_check_in = Some(501.millis());
+    fdir.run(&mut node);
+    assert_eq!(node.actions, [(LATE, Recovery::Reboot)]);
+}
+
+#[test]
+fn events_wait_to_be_reported() {
+    let mut node = Node::healthy();
+    let mut fdir = Fdir::new(&MONITORS, RULES);
+
+    // Every run raises and clears the error fault
+    for i in 0..10 {
+        node.can[Channel::Can2 as usize] = match i % 2 {
+            0 => status(200, 0, BusState::ErrorPassive),
+            _ => status(0, 0, BusState::ErrorActive),
+        };
+        fdir.run(&mut node);
+    }
+    assert_eq!(fdir.stats().dropped, 2);
+    assert_eq!(events(&mut fdir).len(), stm32f446_rtic::fdir::MAX_PENDING);
+}
+
+/// Writes down nothing, the mode manager is what is tested.
+struct NoActions;
+
+impl ModeActions for NoActions {
+    fn exit(&mut self, _: Mode) {}
+
+    fn enter(&mut self, _: Mode) {}
+}
+
+#[test]
+fn recovery_changes_the_mode() {
+    const MODES: &[Transition] = &[
+        Transition::request(Mode::Nominal, Mode::Safe),
+        Transition::request(Mode::Safe, Mode::Nominal),
+    ];
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+    assert_eq!(modes.recover(Mode::Safe, &mut NoActions), Ok(()));
+    assert_eq!(modes.mode(), Mode::Safe);
+
+    let change = modes.pop_change().unwrap();
+    assert_eq!(change.trigger, Trigger::Recovery);
+    assert_eq!(change.severity(), Severity::Medium);
+    assert_eq!(change.to_bytes(), [1, 2, 0xff]);
+
+    // Only over transitions of the table
+    assert!(modes.recover(Mode::Maintenance, &mut NoActions).is_err());
+    assert_eq!(modes.stats().rejected, 1);
+}
@@ -0,0 +1,93 @@
+//! Host tests of the filter bank planner. Run with `cargo test-host`.
+
+use bxcan::{filter::BankConfig, ExtendedId, StandardId};
+use stm32f446_rtic::can_shield::filters::{plan, FilterError, FilterRule, FilterSet};
+
+fn std_id(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+fn ext_id(raw: u32) -> ExtendedId {
+    ExtendedId::new(raw).unwrap()
+}
+
+/// Counts banks per mode as (List16, List32, Mask16, Mask32).
+fn modes(banks: &[BankConfig]) -> (usize, usize, usize, usize) {
+    let mut count = (0, 0, 0, 0);
+    for bank in banks {
+        match bank {
+            BankConfig::List16(_) => count.0 += 1,
+            BankConfig::List32(_) => count.1 += 1,
+            BankConfig::Mask16(_) => count.2 += 1,
+            BankConfig::Mask32(_) => count.3 += 1,
+        }
+    }
+    count
+}
+
+#[test]
+fn accept_all_uses_one_bank_per_channel() {
+    let plan = plan(&FilterSet::accept_all(), &FilterSet::accept_all()).unwrap();
+    assert_eq!(plan.split(), 1);
+    assert_eq!(modes(plan.can1()), (0, 0, 0, 1));
+    assert_eq!(modes(plan.can2()), (0, 0, 0, 1));
+}
+
+#[test]
+fn leftover_ids_fill_free_slots() {
+    let mut can1 = FilterSet::new();
+    for raw in 0..5 {
+        can1.push(FilterRule::Standard(std_id(raw)));
+    }
+    can1.push(FilterRule::StandardMask {
+        id: std_id(0x100),
+        mask: std_id(0x700),
+    });
+
+    let mut can2 = FilterSet::new();
+    for raw in 0..6 {
+        can2.push(FilterRule::Standard(std_id(raw)));
+    }
+    can2.push(FilterRule::Extended(ext_id(0x1234)));
+    can2.push(FilterRule::StandardMask {
+        id: std_id(0x100),
+        mask: std_id(0x700),
+    });
+
+    let plan = plan(&can1, &can2).unwrap();
+    assert_eq!(plan.split(), 2);
+    assert_eq!(modes(plan.can1()), (1, 0, 1, 0));
+    assert_eq!(modes(plan.can2()), (1, 1, 1, 0));
+}
+
+#[test]
+fn duplicates_are_ignored() {
+    let set = FilterSet::new()
+        .with(FilterRule::Standard(std_id(0x500)))
+        .with(FilterRule::Standard(std_id(0x500)));
+    assert_eq!(set.rules().len(), 1);
+}
+
+#[test]
+fn reports_overflow() {
+    let mut masks = FilterSet::new();
+    for raw in 0..20 {
+        masks.push(FilterRule::ExtendedMask {
+            id: ext_id(raw),
+            mask: ExtendedId::MAX,
+        });
+    }
+    assert_eq!(
+        plan(&masks, &masks).unwrap_err(),
+        FilterError::TooManyBanks { required: 40 }
+    );
+
+    let mut ids = FilterSet::new();
+    for raw in 0..65 {
+        ids.push(FilterRule::Standard(std_id(raw)));
+    }
+    assert_eq!(
+        plan(&ids, &FilterSet::new()).unwrap_err(),
+        FilterError::TooManyRules
+    );
+}
@@ -0,0 +1,120 @@
+//! Host tests of the error state monitoring against the mock backend. Run with `cargo test-host`.
+
+use fugit::{ExtU32, TimerInstantU32};
+use stm32f446_rtic::can_shield::{
+    health::{BusState, ErrorStatus, HealthMonitor, LastError, RecoveryPolicy},
+    mock::{MockBus, MockChannel},
+    CanChannel, Channel,
+};
+
+/// Milliseconds since start.
+fn at(ms: u32) -> TimerInstantU32<1000> {
+    TimerInstantU32::from_ticks(ms)
+}
+
+/// Builds a `CAN_ESR` value.
+fn esr(tec: u8, rec: u8, flags: u32) -> u32 {
+    (u32::from(rec) << 24) | (u32::from(tec) << 16) | flags
+}
+
+/// A controller on a bus with a peer, so it can recover from bus-off.
+fn attach() -> (MockBus, MockChannel, MockChannel) {
+    let bus = MockBus::new();
+    let can = bus.attach(Channel::Can2);
+    let peer = bus.attach(Channel::Can1);
+    (bus, can, peer)
+}
+
+#[test]
+fn decodes_esr() {
+    let status = ErrorStatus::from_esr(esr(130, 5, 0b011 | (3 << 4)));
+    assert_eq!(status.tec, 130);
+    assert_eq!(status.rec, 5);
+    assert_eq!(status.last_error, LastError::Acknowledgement);
+    assert_eq!(status.state, BusState::ErrorPassive);
+
+    assert_eq!(ErrorStatus::from_esr(0).state, BusState::ErrorActive);
+    assert_eq!(ErrorStatus::from_esr(0b001).state, BusState::ErrorWarning);
+    assert_eq!(ErrorStatus::from_esr(0b111).state, BusState::BusOff);
+}
+
+#[test]
+fn counts_transitions() {
+    let (bus, mut can, _peer) = attach();
+    let mut monitor = HealthMonitor::<1000>::new(Channel::Can2, RecoveryPolicy::Automatic);
+
+    for tec in [96, 128, 256] {
+        can.set_error_counters(tec, 0);
+        monitor.update(&mut can, at(0));
+    }
+    assert_eq!(monitor.state(), BusState::BusOff);
+
+    // The hardware recovers on its own.
+    bus.step();
+    monitor.update(&mut can, at(0));
+
+    let stats = monitor.stats();
+    assert_eq!(stats.error_warning, 1);
+    assert_eq!(stats.error_passive, 1);
+    assert_eq!(stats.bus_off, 1);
+    assert_eq!(stats.restarts, 0);
+    assert_eq!(monitor.state(), BusState::ErrorActive);
+}
+
+#[test]
+fn manual_restart_after_backoff() {
+    let (bus, mut can, _peer) = attach();
+    can.set_automatic_bus_off(false);
+    can.set_error_counters(256, 0);
+    let policy = RecoveryPolicy::Manual {
+        backoff: 100.millis(),
+    };
+    let mut monitor = HealthMonitor::<1000>::new(Channel::Can2, policy);
+
+    monitor.update(&mut can, at(1000));
+    monitor.update(&mut can, at(1099));
+    assert_eq!(monitor.stats().restarts, 0);
+
+    monitor.update(&mut can, at(1100));
+    assert_eq!(monitor.stats().restarts, 1);
+
+    // Still bus-off, the next restart waits for another backoff.
+    monitor.update(&mut can, at(1150));
+    assert_eq!(monitor.stats().restarts, 1);
+    monitor.update(&mut can, at(1200));
+    assert_eq!(monitor.stats().restarts, 2);
+
+    bus.step();
+    monitor.update(&mut can, at(1210));
+    assert_eq!(monitor.state(), BusState::ErrorActive);
+    assert_eq!(monitor.stats().restarts, 2);
+    assert_eq!(monitor.stats().bus_off, 1);
+}
+
+#[test]
+fn failed_restart_is_retried_after_backoff() {
+    let (bus, mut can, _peer) = attach();
+    can.set_automatic_bus_off(false);
+    can.set_error_counters(256, 0);
+    bus.set_stuck(true);
+    let policy = RecoveryPolicy::Manual {
+        backoff: 100.millis(),
+    };
+    let mut monitor = HealthMonitor::<1000>::new(Channel::Can2, policy);
+
+    monitor.update(&mut can, at(0));
+    monitor.update(&mut can, at(100));
+    assert_eq!(monitor.stats().failed_restarts, 1);
+    assert_eq!(monitor.stats().restarts, 0);
+
+    bus.set_stuck(false);
+    monitor.update(&mut can, at(150));
+    assert_eq!(monitor.stats().restarts, 0);
+    monitor.update(&mut can, at(200));
+    assert_eq!(monitor.stats().restarts, 1);
+    assert_eq!(monitor.stats().failed_restarts, 1);
+
+    bus.step();
+    monitor.update(&mut can, at(210));
+    assert_eq!(monitor.state(), BusState::ErrorActive);
+}
@@ -0,0 +1,213 @@
+//! Host tests of the heartbeat service against the mock backend. Run with `cargo test-host`.
+
+use bxcan::{Data, Frame, StandardId};
+use fugit::ExtU32;
+use stm32f446_rtic::{
+    can_shield::{
+        filters::FilterSet,
+        mock::{MockBus, MockShield},
+        CanChannel, Channel,
+    },
+    heartbeat::{Heartbeat, HeartbeatConfig, HeartbeatService, PresenceEvent},
+    time::Met,
+};
+
+/// Most nodes of a test boot 10 s into the mission.
+const BOOT_MS: u32 = 10_000;
+
+fn at(ms: u32) -> Met {
+    Met::from_ticks(u64::from(BOOT_MS + ms) * 1000)
+}
+
+/// A node with its own shield.
+struct Node {
+    shield: MockShield,
+    heartbeat: HeartbeatService<2>,
+    events: Vec<PresenceEvent>,
+    /// Stops sending heartbeats while `false`.
+    alive: bool,
+}
+
+impl Node {
+    fn new(bus: &MockBus, channel: Channel, node: u8, boot: Met) -> Self {
+        let config = HeartbeatConfig::new(channel, node).period(100.millis());
+        Self {
+            shield: MockShield::new(bus, bus),
+            heartbeat: HeartbeatService::new(config.timeout(300.millis()), boot),
+            events: Vec::new(),
+            alive: true,
+        }
+    }
+
+    /// What the RX and TX interrupts and the heartbeat task do at `now`.
+    fn service(&mut self, now: Met) {
+        let channel = self.heartbeat.channel();
+        let can: &mut dyn CanChannel = match channel {
+            Channel::Can1 => &mut self.shield.can1,
+            Channel::Can2 => &mut self.shield.can2,
+        };
+        while let Ok(frame) = can.receive() {
+            self.events
+                .extend(self.heartbeat.on_frame(channel, &frame, now));
+        }
+        self.shield.service_tx_interrupts();
+        if self.alive {
+            self.heartbeat.poll(&mut self.shield, now);
+        }
+        while let Some(event) = self.heartbeat.expire(now) {
+            self.events.push(event);
+        }
+    }
+}
+
+fn run(bus: &MockBus, nodes: &mut [&mut Node], start: u32, end: u32) {
+    for ms in start..end {
+        bus.step();
+        for node in nodes.iter_mut() {
+            node.service(at(ms));
+        }
+    }
+}
+
+#[test]
+fn heartbeat_layout() {
+    let heartbeat = Heartbeat {
+        node: 5,
+        uptime_s: 0x0102_0304,
+        mode: 2,
+        health: 0x8001,
+    };
+    let encoded = heartbeat.encode();
+    assert_eq!(encoded, [5, 2, 0x80, 0x01, 1, 2, 3, 4]);
+    assert_eq!(Heartbeat::decode(&encoded), Some(heartbeat));
+
+    assert_eq!(Heartbeat::decode(&encoded[..7]), None);
+    assert_eq!(Heartbeat::decode(&[0, 2, 0x80, 0x01, 1, 2, 3, 4]), None);
+    assert_eq!(Heartbeat::decode(&[128, 2, 0x80, 0x01, 1, 2, 3, 4]), None);
+}
+
+#[test]
+fn nodes_join_and_time_out() {
+    let bus = MockBus::new();
+    let mut a = Node::new(&bus, Channel::Can1, 1, at(0));
+    let mut b = Node::new(&bus, Channel::Can1, 2, at(0));
+    let mut c = Node::new(&bus, Channel::Can1, 3, Met::from_ticks(0));
+    c.heartbeat.set_mode(3);
+    c.heartbeat.set_health(0x0004);
+
+    run(&bus, &mut [&mut a, &mut b, &mut c], 0, 50);
+    assert_eq!(a.events.len(), 2);
+    assert!(a.heartbeat.is_present(2) && a.heartbeat.is_present(3));
+    assert!(!a.heartbeat.is_present(1));
+
+    let entry = a.heartbeat.entry(3).unwrap();
+    assert_eq!(entry.heartbeat.mode, 3);
+    assert_eq!(entry.heartbeat.health, 0x0004);
+    assert_eq!(entry.heartbeat.uptime_s, 10);
+
+    run(&bus, &mut [&mut a, &mut b, &mut c], 50, 2050);
+    assert_eq!(a.events.len(), 2);
+    assert_eq!(a.heartbeat.entry(2).unwrap().heartbeat.uptime_s, 2);
+    let last_seen = a.heartbeat.entry(3).unwrap().last_seen;
+
+    // Node 3 falls silent and times out 300 ms after its last heartbeat
+    c.alive = false;
+    run(&bus, &mut [&mut a, &mut b, &mut c], 2050, 2500);
+    assert_eq!(a.events[2], PresenceEvent::TimedOut { node: 3 });
+    assert_eq!(b.events[2], PresenceEvent::TimedOut { node: 3 });
+    assert!(!a.heartbeat.is_present(3));
+    assert_eq!(a.heartbeat.entry(3).unwrap().last_seen, last_seen);
+    assert!(last_seen < at(2050));
+
+    // It is reported again when it comes back
+    c.alive = true;
+    run(&bus, &mut [&mut a, &mut b, &mut c], 2500, 2600);
+    assert!(matches!(
+        a.events[3],
+        PresenceEvent::Joined(Heartbeat { node: 3, .. })
+    ));
+    assert_eq!(a.events.len(), 4);
+
+    let stats = a.heartbeat.stats();
+    assert_eq!(stats.sent, 26);
+    assert_eq!(stats.tx_errors, 0);
+    assert_eq!(stats.untracked, 0);
+}
+
+#[test]
+fn presence_table_is_bounded() {
+    let bus = MockBus::new();
+    let mut a = Node::new(&bus, Channel::Can2, 1, at(0));
+    let mut others: Vec<Node> = (2..=4)
+        .map(|node| Node::new(&bus, Channel::Can2, node, at(0)))
+        .collect();
+
+    for ms in 0..250 {
+        bus.step();
+        a.service(at(ms));
+        for node in &mut others {
+            node.service(at(ms));
+        }
+    }
+
+    let nodes: Vec<u8> = a
+        .heartbeat
+        .nodes()
+        .iter()
+        .map(|entry| entry.heartbeat.node)
+        .collect();
+    assert_eq!(nodes, [2, 3]);
+    assert_eq!(a.heartbeat.stats().untracked, 3);
+    assert_eq!(a.heartbeat.stats().received, 9);
+}
+
+#[test]
+fn foreign_frames_are_ignored() {
+    let mut heartbeat = HeartbeatService::<4>::new(HeartbeatConfig::new(Channel::Can1, 1), at(0));
+    let frame = |id: u16, data: &[u8]| {
+        Frame::new_data(StandardId::new(id).unwrap(), Data::new(data).unwrap())
+    };
+    let beat = |node: u8| {
+        Heartbeat {
+            node,
+            uptime_s: 0,
+            mode: 0,
+            health: 0,
+        }
+        .encode()
+    };
+
+    // Wrong channel, own ID, mismatched node ID, other IDs and short payloads
+    assert_eq!(
+        heartbeat.on_frame(Channel::Can2, &frame(0x702, &beat(2)), at(0)),
+        None
+    );
+    assert_eq!(
+        heartbeat.on_frame(Channel::Can1, &frame(0x701, &beat(1)), at(0)),
+        None
+    );
+    assert_eq!(
+        heartbeat.on_frame(Channel::Can1, &frame(0x702, &beat(3)), at(0)),
+        None
+    );
+    assert_eq!(
+        heartbeat.on_frame(Channel::Can1, &frame(0x602, &beat(2)), at(0)),
+        None
+    );
+    assert_eq!(
+        heartbeat.on_frame(Channel::Can1, &frame(0x702, &[2]), at(0)),
+        None
+    );
+    assert!(heartbeat.nodes().is_empty());
+
+    assert!(heartbeat
+        .on_frame(Channel::Can1, &frame(0x702, &beat(2)), at(0))
+        .is_some());
+    assert_eq!(heartbeat.stats().received, 1);
+
+    // The filter lets through the heartbeat IDs only
+    let filters = FilterSet::new().with(heartbeat.filter());
+    assert!(filters.accepts(&frame(0x77f, &beat(2))));
+    assert!(filters.accepts(&frame(0x701, &beat(2))));
+    assert!(!filters.accepts(&frame(0x681, &beat(2))));
+}
@@ -0,0 +1,374 @@
+//! Host tests of ISO-TP against the mock backend. Run with `cargo test-host`.
+
+use bxcan::{Frame, StandardId};
+use fugit::{ExtU32, TimerInstantU32};
+use stm32f446_rtic::{
+    can_shield::{
+        mock::{MockBus, MockChannel, MockShield},
+        CanChannel, Channel,
+    },
+    isotp::{FlowStatus, IsoTpConfig, IsoTpError, IsoTpLink, Pci, StMin, Timeout, MAX_MESSAGE_LEN},
+};
+
+const TESTER: u16 = 0x7e0;
+const ECU: u16 = 0x7e8;
+
+fn id(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+fn at(ms: u32) -> TimerInstantU32<1000> {
+    TimerInstantU32::from_ticks(ms)
+}
+
+fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7) as u8).collect()
+}
+
+/// An ISO-TP node with its own shield.
+struct Node {
+    shield: MockShield,
+    link: IsoTpLink<1000>,
+    received: Vec<Vec<u8>>,
+    errors: Vec<IsoTpError>,
+}
+
+impl Node {
+    fn new(bus: &MockBus, config: IsoTpConfig) -> Self {
+        Self {
+            shield: MockShield::new(bus, &MockBus::new()),
+            link: IsoTpLink::new(config),
+            received: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// What the RX and TX interrupts and the poll task do at `now`.
+    fn service(&mut self, now: TimerInstantU32<1000>) {
+        while let Ok(frame) = self.shield.can1.receive() {
+            match self.link.on_frame(&mut self.shield, &frame, now) {
+                Ok(Some(message)) => self.received.push(message.to_vec()),
+                Ok(None) => {}
+                Err(error) => self.errors.push(error),
+            }
+        }
+        self.shield.service_tx_interrupts();
+        if let Err(error) = self.link.poll(&mut self.shield, now) {
+            self.errors.push(error);
+            self.link.poll(&mut self.shield, now).unwrap();
+        }
+    }
+}
+
+/// Runs the bus a frame per millisecond until `done` or the time limit. Returns the time.
+fn run(
+    bus: &MockBus,
+    nodes: &mut [&mut Node],
+    start: u32,
+    limit: u32,
+    done: impl Fn(&[&mut Node]) -> bool,
+) -> u32 {
+    for ms in start..start + limit {
+        bus.step();
+        for node in nodes.iter_mut() {
+            node.service(at(ms));
+        }
+        if done(nodes) {
+            return ms;
+        }
+    }
+    panic!("not done after {} ms", limit);
+}
+
+fn tester_config() -> IsoTpConfig {
+    IsoTpConfig::new(Channel::Can1, id(TESTER), id(ECU))
+}
+
+fn ecu_config() -> IsoTpConfig {
+    IsoTpConfig::new(Channel::Can1, id(ECU), id(TESTER))
+}
+
+/// A bare controller to inject and capture frames.
+fn probe(bus: &MockBus) -> MockChannel {
+    bus.attach(Channel::Can1)
+}
+
+fn frame(raw: u16, data: &[u8]) -> Frame {
+    Frame::new_data(id(raw), bxcan::Data::new(data).unwrap())
+}
+
+/// Sends a frame from `probe` and delivers it.
+fn inject(bus: &MockBus, probe: &mut MockChannel, frame: &Frame) {
+    probe.transmit(frame).unwrap();
+    bus.run();
+}
+
+#[test]
+fn pci_decoding() {
+    assert_eq!(Pci::decode(&[0x03, 1, 2, 3]), Some(Pci::Single { len: 3 }));
+    assert_eq!(Pci::decode(&[0x05, 1, 2]), None);
+    assert_eq!(
+        Pci::decode(&[0x1f, 0xff, 0, 0, 0, 0, 0, 0]),
+        Some(Pci::First { len: 4095 })
+    );
+    assert_eq!(Pci::decode(&[0x10, 0x07, 0, 0, 0, 0, 0, 0]), None);
+    assert_eq!(
+        Pci::decode(&[0x2a, 0]),
+        Some(Pci::Consecutive { sequence: 10 })
+    );
+    assert_eq!(
+        Pci::decode(&[0x31, 4, 0xf3]),
+        Some(Pci::FlowControl {
+            status: FlowStatus::Wait,
+            block_size: 4,
+            st_min: StMin::hundred_micros(3),
+        })
+    );
+    assert_eq!(Pci::decode(&[0x33, 0, 0]), None);
+
+    assert_eq!(StMin::millis(20).to_micros(), 20_000);
+    assert_eq!(StMin::hundred_micros(3).to_micros(), 300);
+    assert_eq!(StMin::from_raw(0x80).to_micros(), 127_000);
+}
+
+#[test]
+fn single_frame_message() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config());
+    let mut ecu = Node::new(&bus, ecu_config());
+
+    tester
+        .link
+        .send(&mut tester.shield, &[0x22, 0xf1, 0x90], at(0))
+        .unwrap();
+    run(&bus, &mut [&mut tester, &mut ecu], 0, 10, |nodes| {
+        !nodes[0].link.is_sending()
+    });
+
+    assert_eq!(ecu.received, [vec![0x22, 0xf1, 0x90]]);
+    assert_eq!(
+        bus.take_log()[0].data().unwrap().as_ref(),
+        [3, 0x22, 0xf1, 0x90, 0xcc, 0xcc, 0xcc, 0xcc]
+    );
+    assert_eq!(tester.link.stats().sent, 1);
+}
+
+#[test]
+fn longest_message_with_blocks_and_separation_time() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config());
+    let mut ecu = Node::new(&bus, ecu_config().block_size(8).st_min(StMin::millis(2)));
+
+    let data = message(MAX_MESSAGE_LEN);
+    tester.link.send(&mut tester.shield, &data, at(0)).unwrap();
+    let end = run(&bus, &mut [&mut tester, &mut ecu], 0, 5000, |nodes| {
+        !nodes[0].link.is_sending()
+    });
+
+    assert_eq!(ecu.received, [data]);
+    assert!(tester.errors.is_empty() && ecu.errors.is_empty());
+
+    // 6 bytes in the first frame, 7 in each of the 585 consecutive frames.
+    let log = bus.take_log();
+    let kinds = |kind: u8| {
+        log.iter()
+            .filter(|frame| frame.data().unwrap()[0] >> 4 == kind)
+            .count()
+    };
+    assert_eq!(kinds(1), 1);
+    assert_eq!(kinds(2), 585);
+    assert_eq!(kinds(3), 585_usize.div_ceil(8));
+
+    // The sender keeps at least 2 ms between consecutive frames.
+    assert!(end >= 584 * 2, "done after {} ms", end);
+}
+
+#[test]
+fn both_directions_at_once() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config().block_size(4));
+    let mut ecu = Node::new(&bus, ecu_config().block_size(2));
+
+    let request = message(100);
+    let response = message(300);
+    tester
+        .link
+        .send(&mut tester.shield, &request, at(0))
+        .unwrap();
+    ecu.link.send(&mut ecu.shield, &response, at(0)).unwrap();
+    run(&bus, &mut [&mut tester, &mut ecu], 0, 1000, |nodes| {
+        !nodes[0].link.is_sending() && !nodes[1].link.is_sending()
+    });
+
+    assert_eq!(ecu.received, [request]);
+    assert_eq!(tester.received, [response]);
+}
+
+#[test]
+fn send_errors() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config());
+    let _ecu = Node::new(&bus, ecu_config());
+
+    assert_eq!(
+        tester.link.send(&mut tester.shield, &[], at(0)),
+        Err(IsoTpError::InvalidLength)
+    );
+    assert_eq!(
+        tester.link.send(&mut tester.shield, &message(4096), at(0)),
+        Err(IsoTpError::InvalidLength)
+    );
+
+    tester
+        .link
+        .send(&mut tester.shield, &message(20), at(0))
+        .unwrap();
+    assert_eq!(
+        tester.link.send(&mut tester.shield, &message(20), at(0)),
+        Err(IsoTpError::Busy)
+    );
+}
+
+#[test]
+fn missing_acknowledge_times_out_n_as() {
+    // Nobody acknowledges the first frame, so it never leaves the mailbox.
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config().n_as(50.millis()));
+
+    tester
+        .link
+        .send(&mut tester.shield, &message(20), at(0))
+        .unwrap();
+    run(&bus, &mut [&mut tester], 0, 100, |nodes| {
+        !nodes[0].errors.is_empty()
+    });
+
+    assert_eq!(tester.errors, [IsoTpError::Timeout(Timeout::As)]);
+    assert!(!tester.link.is_sending());
+}
+
+#[test]
+fn missing_flow_control_times_out_n_bs() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config().n_bs(200.millis()));
+    let _silent = probe(&bus);
+
+    tester
+        .link
+        .send(&mut tester.shield, &message(20), at(0))
+        .unwrap();
+    let end = run(&bus, &mut [&mut tester], 0, 300, |nodes| {
+        !nodes[0].errors.is_empty()
+    });
+
+    assert_eq!(tester.errors, [IsoTpError::Timeout(Timeout::Bs)]);
+    assert!((200..=202).contains(&end), "timed out after {} ms", end);
+    assert_eq!(tester.link.stats().errors, 1);
+}
+
+#[test]
+fn wait_frames_and_overflow_abort_the_sender() {
+    let bus = MockBus::new();
+    let mut tester = Node::new(&bus, tester_config().max_waits(2));
+    let mut ecu = probe(&bus);
+
+    tester
+        .link
+        .send(&mut tester.shield, &message(20), at(0))
+        .unwrap();
+    bus.run();
+    for _ in 0..3 {
+        inject(&bus, &mut ecu, &frame(ECU, &[0x31, 0, 0]));
+        tester.service(at(1));
+    }
+    assert_eq!(tester.errors, [IsoTpError::TooManyWaits]);
+
+    while ecu.receive().is_ok() {}
+    tester
+        .link
+        .send(&mut tester.shield, &message(20), at(2))
+        .unwrap();
+    bus.run();
+    inject(&bus, &mut ecu, &frame(ECU, &[0x32, 0, 0]));
+    tester.service(at(3));
+    assert_eq!(tester.errors[1], IsoTpError::Overflow);
+    assert!(!tester.link.is_sending());
+}
+
+#[test]
+fn receiver_errors() {
+    let bus = MockBus::new();
+    let mut ecu = Node::new(&bus, ecu_config().n_cr(100.millis()));
+    let mut tester = probe(&bus);
+    let first = frame(TESTER, &[0x10, 20, 0, 1, 2, 3, 4, 5]);
+
+    // The receiver answers the first frame and gives up when the rest does not follow.
+    inject(&bus, &mut tester, &first);
+    ecu.service(at(0));
+    bus.run();
+    assert_eq!(
+        tester.receive().unwrap().data().unwrap().as_ref(),
+        [0x30, 0, 0, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc]
+    );
+    ecu.service(at(99));
+    assert!(ecu.errors.is_empty());
+    ecu.service(at(100));
+    assert_eq!(ecu.errors, [IsoTpError::Timeout(Timeout::Cr)]);
+
+    // A skipped sequence number drops the message.
+    inject(&bus, &mut tester, &first);
+    inject(
+        &bus,
+        &mut tester,
+        &frame(TESTER, &[0x22, 6, 7, 8, 9, 10, 11, 12]),
+    );
+    ecu.service(at(200));
+    assert_eq!(ecu.errors[1], IsoTpError::WrongSequence);
+    assert!(!ecu.link.is_receiving());
+
+    // A new message replaces an unfinished one.
+    inject(&bus, &mut tester, &first);
+    inject(&bus, &mut tester, &frame(TESTER, &[0x02, 0x3e, 0x00]));
+    ecu.service(at(300));
+    assert_eq!(ecu.errors.len(), 2);
+    assert_eq!(ecu.received, [vec![0x3e, 0x00]]);
+    assert_eq!(ecu.link.stats().errors, 3);
+
+    // Frames with other IDs and stray consecutive frames are ignored.
+    inject(&bus, &mut tester, &frame(0x123, &[0x02, 1, 2]));
+    inject(&bus, &mut tester, &frame(TESTER, &[0x21, 1, 2]));
+    ecu.service(at(400));
+    assert_eq!(ecu.received.len(), 1);
+    assert_eq!(ecu.errors.len(), 2);
+}
+
+#[test]
+fn short_consecutive_frame_drops_the_message() {
+    let bus = MockBus::new();
+    let mut ecu = Node::new(&bus, ecu_config());
+    let mut tester = probe(&bus);
+
+    inject(
+        &bus,
+        &mut tester,
+        &frame(TESTER, &[0x10, 20, 0, 1, 2, 3, 4, 5]),
+    );
+    ecu.service(at(0));
+    bus.run();
+    tester.receive().unwrap();
+
+    // 14 bytes are missing, but the frame only carries 2.
+    inject(&bus, &mut tester, &frame(TESTER, &[0x21, 6, 7]));
+    ecu.service(at(1));
+    assert_eq!(ecu.errors, [IsoTpError::ShortFrame]);
+    assert!(!ecu.link.is_receiving());
+
+    // The rest of the message does not complete it.
+    inject(
+        &bus,
+        &mut tester,
+        &frame(TESTER, &[0x22, 8, 9, 10, 11, 12, 13, 14]),
+    );
+    ecu.service(at(2));
+    assert!(ecu.received.is_empty());
+}
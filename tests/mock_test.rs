@@ -0,0 +1,240 @@
+//! Host tests of the shield against the mock backend. Run with `cargo test-host`.
+
+use bxcan::{ExtendedId, Frame, StandardId};
+use fugit::{ExtU32, TimerInstantU32};
+use stm32f446_rtic::can_shield::{
+    filters::{FilterRule, FilterSet},
+    health::{BusState, HealthMonitor, RecoveryPolicy},
+    mock::{MockBus, MockShield, Round},
+    redundancy::{RedundancyConfig, RedundantBus},
+    CanChannel, Channel, FifoOverrun, Shield,
+};
+
+fn std_frame(id: u16, data: u8) -> Frame {
+    Frame::new_data(StandardId::new(id).unwrap(), [data])
+}
+
+fn at(ms: u32) -> TimerInstantU32<1000> {
+    TimerInstantU32::from_ticks(ms)
+}
+
+/// Runs the bus and the TX interrupts until everything queued is sent.
+fn flush(bus: &MockBus, shield: &mut MockShield) {
+    while bus.run() > 0 || shield.service_tx_interrupts() > 0 {}
+}
+
+fn ids(frames: &[Frame]) -> Vec<u16> {
+    frames
+        .iter()
+        .map(|frame| match frame.id() {
+            bxcan::Id::Standard(id) => id.as_raw(),
+            bxcan::Id::Extended(_) => panic!("extended ID"),
+        })
+        .collect()
+}
+
+#[test]
+fn lowest_id_wins_arbitration() {
+    let bus = MockBus::new();
+    let mut node_a = bus.attach(Channel::Can1);
+    let mut node_b = bus.attach(Channel::Can1);
+    let _listener = bus.attach(Channel::Can1);
+
+    node_a.transmit(&std_frame(0x300, 0)).unwrap();
+    node_b.transmit(&std_frame(0x100, 0)).unwrap();
+    node_a.transmit(&std_frame(0x200, 0)).unwrap();
+
+    assert_eq!(bus.run(), 3);
+    assert_eq!(ids(&bus.take_log()), [0x100, 0x200, 0x300]);
+    assert_eq!(bus.step(), Round::Idle);
+}
+
+#[test]
+fn tx_queue_sends_everything_in_priority_order() {
+    let bus1 = MockBus::new();
+    let bus2 = MockBus::new();
+    let mut shield = MockShield::new(&bus1, &bus2);
+    let mut peer = bus1.attach(Channel::Can1);
+
+    for (i, id) in [0x500, 0x500, 0x100, 0x700, 0x500, 0x050]
+        .into_iter()
+        .enumerate()
+    {
+        shield
+            .transmit(Channel::Can1, &std_frame(id, i as u8))
+            .unwrap();
+    }
+    flush(&bus1, &mut shield);
+
+    // Higher priority frames overtake the queued ones, frames with the same ID keep their order.
+    assert_eq!(
+        ids(&bus1.take_log()),
+        [0x050, 0x100, 0x500, 0x500, 0x500, 0x700]
+    );
+    assert_eq!(shield.tx_pending(Channel::Can1), 0);
+
+    let mut data = Vec::new();
+    loop {
+        match peer.receive() {
+            Ok(frame) => data.push(frame.data().unwrap()[0]),
+            Err(nb::Error::Other(FifoOverrun)) => {}
+            Err(nb::Error::WouldBlock) => break,
+        }
+    }
+    // Only three frames fit into the FIFO of the peer, it never drained it.
+    assert_eq!(data, [5, 2, 0]);
+}
+
+#[test]
+fn fifo_overrun_is_reported() {
+    let bus = MockBus::new();
+    let mut sender = bus.attach(Channel::Can1);
+    let mut receiver = bus.attach(Channel::Can2);
+
+    for i in 0..4 {
+        sender.transmit(&std_frame(0x10, i)).unwrap();
+        bus.run();
+    }
+
+    assert_eq!(receiver.status().rx_pending, 3);
+    assert_eq!(receiver.receive(), Err(nb::Error::Other(FifoOverrun)));
+    assert_eq!(receiver.receive().unwrap().data().unwrap()[0], 0);
+}
+
+#[test]
+fn filters_drop_frames() {
+    let bus = MockBus::new();
+    let mut sender = bus.attach(Channel::Can1);
+    let mut receiver = bus.attach(Channel::Can2);
+    receiver.set_filters(
+        FilterSet::new()
+            .with(FilterRule::StandardMask {
+                id: StandardId::new(0x120).unwrap(),
+                mask: StandardId::new(0x7f0).unwrap(),
+            })
+            .with(FilterRule::Extended(ExtendedId::new(0x1234).unwrap())),
+    );
+
+    sender.transmit(&std_frame(0x123, 1)).unwrap();
+    bus.run();
+    sender.transmit(&std_frame(0x133, 2)).unwrap();
+    bus.run();
+    sender
+        .transmit(&Frame::new_data(ExtendedId::new(0x1234).unwrap(), [3]))
+        .unwrap();
+    bus.run();
+
+    assert_eq!(receiver.receive().unwrap().data().unwrap()[0], 1);
+    assert_eq!(receiver.receive().unwrap().data().unwrap()[0], 3);
+    assert_eq!(receiver.receive(), Err(nb::Error::WouldBlock));
+}
+
+#[test]
+fn remote_frames_only_pass_accept_all() {
+    let bus = MockBus::new();
+    let mut sender = bus.attach(Channel::Can1);
+    let mut all = bus.attach(Channel::Can2);
+    let mut by_id = bus.attach(Channel::Can2);
+    by_id.set_filters(FilterSet::new().with(FilterRule::Standard(StandardId::new(0x123).unwrap())));
+
+    let remote = Frame::new_remote(StandardId::new(0x123).unwrap(), 1);
+    sender.transmit(&remote).unwrap();
+    bus.run();
+
+    assert_eq!(all.receive(), Ok(remote));
+    assert_eq!(by_id.receive(), Err(nb::Error::WouldBlock));
+}
+
+#[test]
+fn missing_acknowledgement_makes_sender_error_passive() {
+    let bus = MockBus::new();
+    let mut lonely = bus.attach(Channel::Can1);
+
+    lonely.transmit(&std_frame(0x10, 0)).unwrap();
+    for _ in 0..100 {
+        assert!(matches!(bus.step(), Round::Error(_)));
+    }
+
+    let status = lonely.error_status();
+    assert_eq!(status.state, BusState::ErrorPassive);
+    assert_eq!(status.tec, 128);
+}
+
+#[test]
+fn manual_recovery_after_bus_off() {
+    let bus1 = MockBus::new();
+    let bus2 = MockBus::new();
+    let mut shield = MockShield::new(&bus1, &bus2);
+    let _peer = bus1.attach(Channel::Can1);
+    shield.can1.set_automatic_bus_off(false);
+
+    let policy = RecoveryPolicy::Manual {
+        backoff: 50.millis(),
+    };
+    let mut monitor = HealthMonitor::<1000>::new(Channel::Can1, policy);
+
+    shield.transmit(Channel::Can1, &std_frame(0x10, 0)).unwrap();
+    bus1.inject_errors(32);
+    for _ in 0..32 {
+        assert!(matches!(bus1.step(), Round::Error(_)));
+    }
+    assert!(shield.can1.error_interrupt_pending());
+
+    let status = monitor.update(shield.channel(Channel::Can1), at(0));
+    assert_eq!(status.state, BusState::BusOff);
+    assert!(!shield.can1.error_interrupt_pending());
+
+    // Bus-off controllers do not send.
+    assert_eq!(bus1.step(), Round::Idle);
+    assert_eq!(bus1.step(), Round::Idle);
+
+    monitor.update(shield.channel(Channel::Can1), at(50));
+    assert_eq!(monitor.stats().restarts, 1);
+
+    // The pending frame goes out once the controller is back.
+    assert_eq!(bus1.run(), 1);
+    monitor.update(shield.channel(Channel::Can1), at(60));
+    assert_eq!(monitor.state(), BusState::ErrorActive);
+}
+
+#[test]
+fn automatic_recovery_after_bus_off() {
+    let bus = MockBus::new();
+    let mut sender = bus.attach(Channel::Can1);
+    let _peer = bus.attach(Channel::Can2);
+
+    sender.set_error_counters(256, 0);
+    assert!(sender.status().bus_off);
+
+    sender.transmit(&std_frame(0x10, 0)).unwrap();
+    assert_eq!(bus.run(), 1);
+    assert_eq!(sender.error_status().state, BusState::ErrorActive);
+}
+
+#[test]
+fn redundant_bus_fails_over_to_can2() {
+    let bus1 = MockBus::new();
+    let bus2 = MockBus::new();
+    let mut shield = MockShield::new(&bus1, &bus2);
+    let mut peer1 = bus1.attach(Channel::Can1);
+    let mut peer2 = bus2.attach(Channel::Can2);
+
+    let mut redundant = RedundantBus::<1000>::new(RedundancyConfig::new(Channel::Can1));
+
+    redundant
+        .transmit(&mut shield, &std_frame(0x42, 0))
+        .unwrap();
+    flush(&bus1, &mut shield);
+    assert!(peer1.receive().is_ok());
+
+    shield.can1.set_automatic_bus_off(false);
+    shield.can1.set_error_counters(256, 0);
+    assert_eq!(redundant.update(&shield, at(10)), Channel::Can2);
+
+    redundant
+        .transmit(&mut shield, &std_frame(0x42, 1))
+        .unwrap();
+    flush(&bus2, &mut shield);
+    assert_eq!(peer2.receive().unwrap().data().unwrap()[0], 1);
+    assert_eq!(peer1.receive(), Err(nb::Error::WouldBlock));
+}
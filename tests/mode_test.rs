@@ -0,0 +1,202 @@
+//! Host tests of the mode manager and its service. Run with `cargo test-host`.
+
+mod common;
+
+use common::{dispatch, tc};
+use stm32f446_rtic::{
+    can_shield::health::BusState,
+    ccsds::SpacePacket,
+    mode::{
+        Condition, Mode, ModeActions, ModeChange, ModeError, ModeManager, Status, Transition,
+        Trigger,
+    },
+    pus::{
+        event::Severity,
+        mode::{self, ModeService},
+        verification::{self, RequestId},
+    },
+};
+
+const MODES: &[Transition] = &[
+    Transition::request(Mode::Nominal, Mode::Safe),
+    Transition::request(Mode::Nominal, Mode::Maintenance),
+    Transition::request(Mode::Safe, Mode::Nominal),
+    Transition::request(Mode::Maintenance, Mode::Nominal),
+    Transition::when(Mode::Nominal, Mode::Safe, Condition::Boots(3)),
+    Transition::when(Mode::Nominal, Mode::Safe, Condition::BusOff),
+    Transition::when(Mode::Maintenance, Mode::Safe, Condition::BusOff),
+];
+
+/// Writes down the actions in the order they are called.
+#[derive(Default)]
+struct Actions(Vec<(&'static str, Mode)>);
+
+impl ModeActions for Actions {
+    fn exit(&mut self, mode: Mode) {
+        self.0.push(("exit", mode));
+    }
+
+    fn enter(&mut self, mode: Mode) {
+        self.0.push(("enter", mode));
+    }
+}
+
+fn status(boots: u32, can1: BusState, can2: BusState) -> Status {
+    Status {
+        boots,
+        bus: [can1, can2],
+    }
+}
+
+fn healthy() -> Status {
+    status(1, BusState::ErrorActive, BusState::ErrorActive)
+}
+
+#[test]
+fn requests_follow_the_table() {
+    let mut actions = Actions::default();
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+
+    assert_eq!(modes.request(Mode::Maintenance, &mut actions), Ok(()));
+    assert_eq!(modes.mode(), Mode::Maintenance);
+    assert_eq!(
+        actions.0,
+        [("exit", Mode::Nominal), ("enter", Mode::Maintenance)]
+    );
+
+    // No way from maintenance to safe mode but through nominal mode
+    assert!(!modes.allows(Mode::Safe));
+    assert_eq!(
+        modes.request(Mode::Safe, &mut actions),
+        Err(ModeError::NotAllowed(Mode::Safe))
+    );
+    assert_eq!(modes.mode(), Mode::Maintenance);
+
+    // The current mode is no change
+    assert_eq!(modes.request(Mode::Maintenance, &mut actions), Ok(()));
+    assert_eq!(actions.0.len(), 2);
+
+    assert_eq!(modes.request(Mode::Nominal, &mut actions), Ok(()));
+    assert_eq!(
+        modes.pop_change(),
+        Some(ModeChange {
+            from: Mode::Nominal,
+            to: Mode::Maintenance,
+            trigger: Trigger::Request,
+        })
+    );
+    let back = modes.pop_change().unwrap();
+    assert_eq!((back.from, back.to), (Mode::Maintenance, Mode::Nominal));
+    assert_eq!(back.severity(), Severity::Informative);
+    assert_eq!(back.to_bytes(), [3, 1, 0]);
+    assert_eq!(modes.pop_change(), None);
+
+    let stats = modes.stats();
+    assert_eq!(
+        (stats.transitions, stats.rejected, stats.dropped),
+        (2, 1, 0)
+    );
+}
+
+#[test]
+fn conditions_force_safe_mode() {
+    let mut actions = Actions::default();
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+
+    assert!(!modes.update(&healthy(), &mut actions));
+    // One channel bus-off still reaches the bus
+    let one = status(1, BusState::BusOff, BusState::ErrorPassive);
+    assert!(!modes.update(&one, &mut actions));
+    assert_eq!(modes.mode(), Mode::Nominal);
+
+    let both = status(1, BusState::BusOff, BusState::BusOff);
+    assert!(modes.update(&both, &mut actions));
+    assert_eq!(modes.mode(), Mode::Safe);
+    assert_eq!(actions.0, [("exit", Mode::Nominal), ("enter", Mode::Safe)]);
+    // Safe mode has no way out but a request
+    assert!(!modes.update(&both, &mut actions));
+
+    let change = modes.pop_change().unwrap();
+    assert_eq!(change.trigger, Trigger::When(Condition::BusOff));
+    assert_eq!(change.severity(), Severity::Medium);
+    assert_eq!(change.to_bytes(), [1, 2, 2]);
+
+    // Too many boots in a row
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+    assert!(!modes.update(
+        &status(2, BusState::ErrorActive, BusState::ErrorActive),
+        &mut actions
+    ));
+    assert!(modes.update(
+        &status(3, BusState::ErrorActive, BusState::ErrorActive),
+        &mut actions
+    ));
+    assert_eq!(modes.pop_change().unwrap().to_bytes(), [1, 2, 1]);
+}
+
+#[test]
+fn changes_wait_to_be_reported() {
+    let mut actions = Actions::default();
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+    for _ in 0..3 {
+        modes.request(Mode::Safe, &mut actions).unwrap();
+        modes.request(Mode::Nominal, &mut actions).unwrap();
+    }
+    assert_eq!(modes.stats().transitions, 6);
+    assert_eq!(modes.stats().dropped, 2);
+
+    let mut reported = 0;
+    while modes.pop_change().is_some() {
+        reported += 1;
+    }
+    assert_eq!(reported, stm32f446_rtic::mode::MAX_PENDING);
+}
+
+#[test]
+fn mode_requests() {
+    let mut actions = Actions::default();
+    let mut modes = ModeManager::new(MODES, Mode::Nominal);
+
+    assert_eq!(
+        dispatch(
+            &mut ModeService::new(&mut modes, &mut actions),
+            &tc(mode::SERVICE, mode::REQUEST, &[2])
+        ),
+        []
+    );
+    assert_eq!(modes.mode(), Mode::Safe);
+    assert_eq!(
+        dispatch(
+            &mut ModeService::new(&mut modes, &mut actions),
+            &tc(mode::SERVICE, mode::REPORT_MODE, &[])
+        ),
+        [(mode::MODE_REPORT, vec![2])]
+    );
+
+    let rejection = |request: &[u8], code: u16| {
+        let mut data = RequestId::of(&SpacePacket::parse(request).unwrap())
+            .to_bytes()
+            .to_vec();
+        data.extend(code.to_be_bytes());
+        (verification::ACCEPTANCE_FAILURE, data)
+    };
+    let maintenance = tc(mode::SERVICE, mode::REQUEST, &[3]);
+    assert_eq!(
+        dispatch(
+            &mut ModeService::new(&mut modes, &mut actions),
+            &maintenance
+        ),
+        [rejection(&maintenance, mode::NOT_ALLOWED)]
+    );
+    for data in [&[0][..], &[4], &[1, 1], &[]] {
+        let request = tc(mode::SERVICE, mode::REQUEST, data);
+        assert_eq!(
+            dispatch(&mut ModeService::new(&mut modes, &mut actions), &request),
+            [rejection(&request, verification::INVALID_DATA)]
+        );
+    }
+    assert_eq!(modes.mode(), Mode::Safe);
+    // Rejected on acceptance, the manager never saw them
+    assert_eq!(modes.stats().rejected, 0);
+    assert_eq!(actions.0, [("exit", Mode::Nominal), ("enter", Mode::Safe)]);
+}
@@ -0,0 +1,417 @@
+//! Host tests of the parameter store against the RAM flash. Run with `cargo test-host`.
+
+mod common;
+
+use common::{dispatch, tc};
+use stm32f446_rtic::{
+    ccsds::SpacePacket,
+    flash::{mock::RamFlash, Flash, FlashError},
+    nvstore::{Entry, ParamStore, ParameterDef, StoreError},
+    pus::{
+        housekeeping::{ParameterSource, Value},
+        parameter::{self, ParameterService},
+        verification::{self, RequestId},
+    },
+};
+
+const NODE_ID: u16 = 0x200;
+const PERIOD: u16 = 0x201;
+const GAIN: u16 = 0x202;
+
+const DEFINITIONS: &[ParameterDef] = &[
+    ParameterDef::new(NODE_ID, Value::U8(0x10)).range(Value::U8(1), Value::U8(127)),
+    ParameterDef::new(PERIOD, Value::U16(1000)),
+    ParameterDef::new(GAIN, Value::F32(0.5)),
+];
+
+/// Sectors small enough to fill quickly.
+const SECTOR_LEN: usize = 128;
+
+fn flash() -> RamFlash {
+    RamFlash::new(&[SECTOR_LEN, SECTOR_LEN])
+}
+
+fn mounted(flash: &mut RamFlash) -> ParamStore<4> {
+    let mut store = ParamStore::new([0, 1], DEFINITIONS);
+    store.mount(flash).unwrap();
+    store
+}
+
+#[test]
+fn defaults_until_set() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(0x10)));
+    assert_eq!(store.get(PERIOD), Some(Value::U16(1000)));
+    assert_eq!(store.get(0x300), None);
+    assert!(!store.is_stored(PERIOD));
+
+    store.set(&mut flash, PERIOD, Value::U16(250)).unwrap();
+    store.set(&mut flash, GAIN, Value::F32(-2.0)).unwrap();
+    assert_eq!(store.get(PERIOD), Some(Value::U16(250)));
+    assert_eq!(store.read(GAIN), Some(Value::F32(-2.0)));
+    assert!(store.is_stored(PERIOD));
+
+    // Setting the same value again writes nothing
+    store.set(&mut flash, PERIOD, Value::U16(250)).unwrap();
+    assert_eq!(store.stats().writes, 2);
+
+    let store = mounted(&mut flash);
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(0x10)));
+    assert_eq!(store.get(PERIOD), Some(Value::U16(250)));
+    assert_eq!(store.get(GAIN), Some(Value::F32(-2.0)));
+    assert_eq!(
+        store.entries().collect::<Vec<_>>(),
+        [
+            Entry {
+                id: NODE_ID,
+                value: Value::U8(0x10),
+                stored: false
+            },
+            Entry {
+                id: PERIOD,
+                value: Value::U16(250),
+                stored: true
+            },
+            Entry {
+                id: GAIN,
+                value: Value::F32(-2.0),
+                stored: true
+            },
+        ]
+    );
+}
+
+#[test]
+fn only_defined_parameters_of_their_type() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+    assert_eq!(
+        store.set(&mut flash, 0x300, Value::U8(1)),
+        Err(StoreError::UnknownParameter(0x300))
+    );
+    assert_eq!(
+        store.set(&mut flash, PERIOD, Value::U32(1)),
+        Err(StoreError::TypeMismatch(PERIOD))
+    );
+    assert_eq!(
+        store.reset(&mut flash, 0x300),
+        Err(StoreError::UnknownParameter(0x300))
+    );
+
+    assert_eq!(
+        store.set(&mut flash, NODE_ID, Value::U8(0)),
+        Err(StoreError::OutOfRange(NODE_ID))
+    );
+    assert_eq!(
+        store.set(&mut flash, NODE_ID, Value::U8(128)),
+        Err(StoreError::OutOfRange(NODE_ID))
+    );
+    assert_eq!(store.stats().writes, 0);
+
+    let mut unmounted = ParamStore::<4>::new([0, 1], DEFINITIONS);
+    assert_eq!(
+        unmounted.set(&mut flash, PERIOD, Value::U16(1)),
+        Err(StoreError::NotMounted)
+    );
+    let mut tiny = RamFlash::new(&[32, 32]);
+    assert_eq!(unmounted.mount(&mut tiny), Err(StoreError::SectorTooSmall));
+}
+
+#[test]
+fn reset_returns_to_defaults() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+    store.set(&mut flash, NODE_ID, Value::U8(3)).unwrap();
+    store.set(&mut flash, PERIOD, Value::U16(10)).unwrap();
+    store.set(&mut flash, GAIN, Value::F32(1.0)).unwrap();
+
+    store.reset(&mut flash, PERIOD).unwrap();
+    assert_eq!(store.get(PERIOD), Some(Value::U16(1000)));
+    let mut store = mounted(&mut flash);
+    assert!(!store.is_stored(PERIOD));
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(3)));
+
+    store.reset_all(&mut flash).unwrap();
+    assert!(store.entries().all(|entry| !entry.stored));
+    let store = mounted(&mut flash);
+    assert!(store.entries().all(|entry| !entry.stored));
+    assert_eq!(store.generation(), 1);
+}
+
+#[test]
+fn stored_values_out_of_range_read_as_default() {
+    const UNLIMITED: &[ParameterDef] = &[ParameterDef::new(NODE_ID, Value::U8(0x10))];
+
+    let mut flash = flash();
+    let mut old = ParamStore::<4>::new([0, 1], UNLIMITED);
+    old.mount(&mut flash).unwrap();
+    old.set(&mut flash, NODE_ID, Value::U8(0)).unwrap();
+
+    let store = mounted(&mut flash);
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(0x10)));
+    assert!(!store.is_stored(NODE_ID));
+}
+
+#[test]
+fn full_sectors_are_compacted_in_turn() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+    store.set(&mut flash, NODE_ID, Value::U8(7)).unwrap();
+
+    for period in 0..200 {
+        store.set(&mut flash, PERIOD, Value::U16(period)).unwrap();
+    }
+    let stats = store.stats();
+    assert!(stats.compactions > 10);
+    assert_eq!(store.generation(), stats.compactions);
+
+    // Both sectors wear evenly
+    let (first, second) = (flash.erase_count(0), flash.erase_count(1));
+    assert!(first.abs_diff(second) <= 1, "{first} and {second} erases");
+    assert_eq!(first + second, stats.compactions);
+
+    let store = mounted(&mut flash);
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(7)));
+    assert_eq!(store.get(PERIOD), Some(Value::U16(199)));
+    assert_eq!(store.generation(), stats.compactions);
+}
+
+/// Stores `count` periods after the node ID, then sets the period to 2000 with the power failing
+/// after `budget` bytes. Returns `true` if the set went through.
+fn set_with_power_cut(flash: &mut RamFlash, count: u16, budget: usize) -> bool {
+    let mut store = mounted(flash);
+    store.set(flash, NODE_ID, Value::U8(5)).unwrap();
+    for period in 1..=count {
+        store.set(flash, PERIOD, Value::U16(period)).unwrap();
+    }
+
+    flash.cut_power_after(budget);
+    let result = store.set(flash, PERIOD, Value::U16(2000));
+    flash.restore_power();
+    result.is_ok()
+}
+
+#[test]
+fn power_loss_keeps_the_old_or_the_new_value() {
+    // Up to a bit over one sector of records, so some sets copy to the other sector
+    for count in 0..16 {
+        let old = match count {
+            0 => Value::U16(1000),
+            _ => Value::U16(count),
+        };
+        for budget in 0.. {
+            let mut flash = flash();
+            let done = set_with_power_cut(&mut flash, count, budget);
+
+            let mut store = mounted(&mut flash);
+            let period = store.get(PERIOD).unwrap();
+            assert!(
+                period == old || period == Value::U16(2000),
+                "{period:?} after {count} sets and {budget} bytes"
+            );
+            assert_eq!(store.get(NODE_ID), Some(Value::U8(5)));
+            if done {
+                assert_eq!(period, Value::U16(2000));
+            }
+
+            // The store stays usable
+            store.set(&mut flash, PERIOD, Value::U16(3000)).unwrap();
+            store.set(&mut flash, GAIN, Value::F32(4.0)).unwrap();
+            let store = mounted(&mut flash);
+            assert_eq!(store.get(PERIOD), Some(Value::U16(3000)));
+            assert_eq!(store.get(GAIN), Some(Value::F32(4.0)));
+            assert_eq!(store.get(NODE_ID), Some(Value::U8(5)));
+
+            if done {
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn corrupted_records_are_skipped() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+    store.set(&mut flash, PERIOD, Value::U16(1)).unwrap();
+    store.set(&mut flash, NODE_ID, Value::U8(2)).unwrap();
+    store.set(&mut flash, PERIOD, Value::U16(3)).unwrap();
+
+    // A bit of the second record's value flips
+    flash.corrupt(0, 16 + 8 + 4, 0x01);
+    let store = mounted(&mut flash);
+    assert_eq!(store.stats().corrupted, 1);
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(0x10)));
+    assert_eq!(store.get(PERIOD), Some(Value::U16(3)));
+
+    // A torn record header hides the rest of the sector, the next set copies what was read
+    flash.corrupt(0, 16 + 3, 0xff);
+    let mut store = mounted(&mut flash);
+    assert_eq!(store.stats().corrupted, 1);
+    assert!(!store.is_stored(PERIOD));
+    store.set(&mut flash, GAIN, Value::F32(8.0)).unwrap();
+    assert_eq!(store.stats().compactions, 1);
+    let store = mounted(&mut flash);
+    assert_eq!(store.stats().corrupted, 0);
+    assert_eq!(store.get(GAIN), Some(Value::F32(8.0)));
+}
+
+#[test]
+fn foreign_sectors_are_formatted() {
+    let mut flash = flash();
+    flash.program(0, 0, b"not a store").unwrap();
+    flash.program(1, 100, &[0]).unwrap();
+
+    let store = mounted(&mut flash);
+    assert!(store.entries().all(|entry| !entry.stored));
+    assert_eq!(flash.erase_count(0), 1);
+    assert_eq!(&flash.sector(0)[..4], b"NVP1");
+
+    // A sector whose header is erased but not the rest is erased before it is used
+    let mut store = store;
+    for period in 0..20 {
+        store.set(&mut flash, PERIOD, Value::U16(period)).unwrap();
+    }
+    assert_eq!(flash.erase_count(1), 1);
+    assert_eq!(store.get(PERIOD), Some(Value::U16(19)));
+
+    let mut failing = RamFlash::new(&[SECTOR_LEN, SECTOR_LEN]);
+    failing.cut_power_after(0);
+    let mut store = ParamStore::<4>::new([0, 1], DEFINITIONS);
+    assert_eq!(
+        store.mount(&mut failing),
+        Err(StoreError::Flash(FlashError::Operation))
+    );
+}
+
+fn failure(subtype: u8, request: &[u8], code: u16) -> (u8, Vec<u8>) {
+    let mut data = RequestId::of(&SpacePacket::parse(request).unwrap())
+        .to_bytes()
+        .to_vec();
+    data.extend(code.to_be_bytes());
+    (subtype, data)
+}
+
+#[test]
+fn parameter_requests() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+
+    let set = tc(
+        parameter::SERVICE,
+        parameter::SET_VALUES,
+        &[2, 0x02, 0x01, 0x01, 0xf4, 0x02, 0x00, 9],
+    );
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &set),
+        []
+    );
+    assert_eq!(store.get(PERIOD), Some(Value::U16(500)));
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(9)));
+
+    let report = tc(
+        parameter::SERVICE,
+        parameter::REPORT_VALUES,
+        &[1, 0x02, 0x01],
+    );
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &report),
+        [(parameter::VALUE_REPORT, vec![1, 0x02, 0x01, 0x01, 0xf4])]
+    );
+    let report_all = tc(parameter::SERVICE, parameter::REPORT_VALUES, &[0]);
+    assert_eq!(
+        dispatch(
+            &mut ParameterService::new(&mut store, &mut flash),
+            &report_all
+        ),
+        [(
+            parameter::VALUE_REPORT,
+            vec![3, 0x02, 0x00, 9, 0x02, 0x01, 0x01, 0xf4, 0x02, 0x02, 0x3f, 0, 0, 0]
+        )]
+    );
+
+    let list = tc(parameter::SERVICE, parameter::LIST, &[]);
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &list),
+        [(
+            parameter::LIST_REPORT,
+            vec![
+                3, 0x02, 0x00, 1, 1, 9, 0x02, 0x01, 2, 1, 0x01, 0xf4, 0x02, 0x02, 7, 0, 0x3f, 0, 0,
+                0
+            ]
+        )]
+    );
+
+    let reset = tc(parameter::SERVICE, parameter::RESET, &[1, 0x02, 0x00]);
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &reset),
+        []
+    );
+    assert_eq!(store.get(NODE_ID), Some(Value::U8(0x10)));
+    assert!(store.is_stored(PERIOD));
+    let reset_all = tc(parameter::SERVICE, parameter::RESET, &[0]);
+    assert_eq!(
+        dispatch(
+            &mut ParameterService::new(&mut store, &mut flash),
+            &reset_all
+        ),
+        []
+    );
+    assert!(!store.is_stored(PERIOD));
+
+    // The changes are in the flash
+    dispatch(&mut ParameterService::new(&mut store, &mut flash), &set);
+    let store = mounted(&mut flash);
+    assert_eq!(store.get(PERIOD), Some(Value::U16(500)));
+}
+
+#[test]
+fn parameter_rejections() {
+    let mut flash = flash();
+    let mut store = mounted(&mut flash);
+
+    for (data, code) in [
+        (&[1, 0x03, 0x00, 1][..], parameter::UNKNOWN_PARAMETER),
+        (&[1, 0x02, 0x01, 0x01][..], verification::INVALID_DATA),
+        (&[1, 0x02, 0x00, 1, 0][..], verification::INVALID_DATA),
+        (&[1, 0x02, 0x00, 0x80][..], parameter::OUT_OF_RANGE),
+        (&[][..], verification::INVALID_DATA),
+    ] {
+        let set = tc(parameter::SERVICE, parameter::SET_VALUES, data);
+        assert_eq!(
+            dispatch(&mut ParameterService::new(&mut store, &mut flash), &set),
+            [failure(verification::ACCEPTANCE_FAILURE, &set, code)]
+        );
+    }
+    let report = tc(
+        parameter::SERVICE,
+        parameter::REPORT_VALUES,
+        &[2, 0x02, 0x00, 0x03, 0x00],
+    );
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &report),
+        [failure(
+            verification::ACCEPTANCE_FAILURE,
+            &report,
+            parameter::UNKNOWN_PARAMETER
+        )]
+    );
+    assert_eq!(store.stats().writes, 0);
+
+    // A flash that fails the write fails the completion
+    let set = tc(
+        parameter::SERVICE,
+        parameter::SET_VALUES,
+        &[1, 0x02, 0x00, 1],
+    );
+    flash.cut_power_after(0);
+    assert_eq!(
+        dispatch(&mut ParameterService::new(&mut store, &mut flash), &set),
+        [failure(
+            verification::COMPLETION_FAILURE,
+            &set,
+            parameter::FLASH_FAILED
+        )]
+    );
+}
@@ -0,0 +1,960 @@
+//! Host tests of the PUS service layer. Run with `cargo test-host`.
+
+mod common;
+
+use common::{now, reports, tc_with, APID, GROUND};
+use stm32f446_rtic::{
+    ccsds::{Cds, CdsFormat, Cuc, CucFormat, PacketBuilder, SpacePacket},
+    pus::{
+        event::{self, Events, Severity},
+        housekeeping::{self, Housekeeping, HousekeepingError, Parameters, Value},
+        ping::{self, Ping},
+        scheduler::{self, Scheduler, SchedulerError},
+        time::{self as time_service, TimeManagement},
+        verification::{self, AckFlags, RequestId},
+        Dispatcher, Failure, PusError, Report, Reporter, Service, Telecommand, TmChannel, TmQueue,
+    },
+    time::{self, Correlation, Met, Utc},
+};
+
+fn verification_report(subtype: u8, tc: &[u8], code: Option<u16>) -> (u8, u8, Vec<u8>) {
+    let id = RequestId::of(&SpacePacket::parse(tc).unwrap());
+    let mut data = id.to_bytes().to_vec();
+    data.extend(code.iter().flat_map(|code| code.to_be_bytes()));
+    (verification::SERVICE, subtype, data)
+}
+
+const UPTIME: u16 = 0x100;
+const TEC: u16 = 0x101;
+const TEMPERATURE: u16 = 0x102;
+
+fn parameters() -> Parameters<8> {
+    let mut parameters = Parameters::new();
+    parameters
+        .register(UPTIME, Value::U32(0x0102_0304))
+        .unwrap();
+    parameters.register(TEC, Value::U8(7)).unwrap();
+    parameters.register(TEMPERATURE, Value::F32(1.5)).unwrap();
+    parameters
+}
+
+/// The SIDs of the parameter reports among `reports`.
+fn sids(reports: &[(u8, u8, Vec<u8>)]) -> Vec<u16> {
+    reports
+        .iter()
+        .filter(|(service, subtype, _)| {
+            (*service, *subtype) == (housekeeping::SERVICE, housekeeping::REPORT)
+        })
+        .map(|(_, _, data)| u16::from_be_bytes([data[0], data[1]]))
+        .collect()
+}
+
+/// A simulated mission clock that starts at [`now`].
+struct Clock(Met);
+
+impl Clock {
+    fn new() -> Self {
+        Self(time::from_cuc(now(), CucFormat::DEFAULT))
+    }
+
+    fn at(&self, seconds: u64) -> Met {
+        self.0 + fugit::ExtU64::secs(seconds)
+    }
+
+    fn advance(&mut self, seconds: u64) -> Met {
+        self.0 = self.at(seconds);
+        self.0
+    }
+}
+
+/// The application data of a scheduler insert: release times after [`now`] and telecommands.
+fn insertion(entries: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut data = vec![entries.len() as u8];
+    for (seconds, tc) in entries {
+        let release = Cuc {
+            coarse: now().coarse + seconds,
+            ..now()
+        };
+        let mut time = [0; 6];
+        release.encode(CucFormat::DEFAULT, &mut time).unwrap();
+        data.extend(time);
+        data.extend(*tc);
+    }
+    data
+}
+
+fn request_id(tc: &[u8]) -> RequestId {
+    RequestId::of(&SpacePacket::parse(tc).unwrap())
+}
+
+/// A service that fails at the given stage.
+struct Faulty {
+    failure: Failure,
+    report_first: bool,
+}
+
+impl Service for Faulty {
+    fn service_type(&self) -> u8 {
+        200
+    }
+
+    fn accept(&self, _: &Telecommand<'_>) -> Result<(), u16> {
+        Ok(())
+    }
+
+    fn handle(&mut self, _: &Telecommand<'_>, tm: &mut Reporter<'_>) -> Result<(), Failure> {
+        if self.report_first {
+            tm.report(200, 2, &[1])?;
+        }
+        Err(self.failure)
+    }
+}
+
+#[test]
+fn ping_with_full_verification() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut ping = Ping;
+    let mut dispatcher = Dispatcher::<4>::new(APID);
+    dispatcher.register(&mut ping).unwrap();
+
+    let request = tc_with(7, ping::SERVICE, ping::PING, AckFlags::ALL, &[]);
+    let mut tm = channel.reporter(&mut queue, now());
+    dispatcher.dispatch(&request, &mut tm).unwrap();
+
+    assert_eq!(
+        reports(&mut queue),
+        [
+            verification_report(verification::ACCEPTANCE_SUCCESS, &request, None),
+            verification_report(verification::START_SUCCESS, &request, None),
+            (ping::SERVICE, ping::PONG, vec![]),
+            verification_report(verification::COMPLETION_SUCCESS, &request, None),
+        ]
+    );
+}
+
+#[test]
+fn reports_count_and_address_the_sender() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut ping = Ping;
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(&mut ping).unwrap();
+
+    let mut tm = channel.reporter(&mut queue, now());
+    for count in 0..2 {
+        let request = tc_with(count, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+        dispatcher.dispatch(&request, &mut tm).unwrap();
+    }
+
+    for (count, packet) in [queue.pop().unwrap(), queue.pop().unwrap()]
+        .iter()
+        .enumerate()
+    {
+        let packet = SpacePacket::parse(packet).unwrap();
+        assert_eq!(packet.sequence_count(), count as u16);
+        let report = Report::parse(packet, CucFormat::DEFAULT).unwrap();
+        assert_eq!(report.header.message_count, count as u16);
+        assert_eq!(report.header.destination, GROUND);
+        assert_eq!(report.header.subtype, ping::PONG);
+    }
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn acceptance_failures() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut ping = Ping;
+    let mut dispatcher = Dispatcher::<4>::new(APID);
+    dispatcher.register(&mut ping).unwrap();
+
+    let unknown_service = tc_with(1, 99, 1, AckFlags::ALL, &[]);
+    let unknown_subtype = tc_with(2, ping::SERVICE, 9, AckFlags::ALL, &[]);
+    let invalid_data = tc_with(3, ping::SERVICE, ping::PING, AckFlags::ALL, &[1]);
+    let mut buf = [0; 16];
+    let malformed = PacketBuilder::telecommand(APID)
+        .sequence_count(4)
+        .build(&[0x20, 17], &mut buf)
+        .unwrap()
+        .to_vec();
+
+    for (request, code) in [
+        (&unknown_service, verification::UNKNOWN_SERVICE),
+        (&unknown_subtype, verification::UNKNOWN_SUBTYPE),
+        (&invalid_data, verification::INVALID_DATA),
+        (&malformed, verification::MALFORMED),
+    ] {
+        let mut tm = channel.reporter(&mut queue, now());
+        assert_eq!(
+            dispatcher.dispatch(request, &mut tm),
+            Err(PusError::Rejected(code))
+        );
+        assert_eq!(
+            reports(&mut queue),
+            [verification_report(
+                verification::ACCEPTANCE_FAILURE,
+                request,
+                Some(code)
+            )]
+        );
+    }
+}
+
+#[test]
+fn other_packets_are_left_alone() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut dispatcher = Dispatcher::<1>::new(APID + 1);
+    let mut tm = channel.reporter(&mut queue, now());
+
+    let request = tc_with(0, ping::SERVICE, ping::PING, AckFlags::ALL, &[]);
+    assert_eq!(
+        dispatcher.dispatch(&request, &mut tm),
+        Err(PusError::WrongApid(APID))
+    );
+
+    let mut buf = [0; 16];
+    let telemetry = PacketBuilder::telemetry(APID + 1)
+        .build(&[1, 2, 3], &mut buf)
+        .unwrap();
+    assert_eq!(
+        dispatcher.dispatch(telemetry, &mut tm),
+        Err(PusError::WrongPacketType)
+    );
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn registration() {
+    let (mut first, mut second, mut events) = (Ping, Ping, Events::<4>::new());
+    let mut hk = Housekeeping::<_, 1>::new(parameters());
+    let mut dispatcher = Dispatcher::<2>::new(APID);
+
+    dispatcher.register(&mut first).unwrap();
+    assert_eq!(
+        dispatcher.register(&mut second),
+        Err(PusError::DuplicateService(ping::SERVICE))
+    );
+    dispatcher.register(&mut events).unwrap();
+    assert!(dispatcher.is_registered(event::SERVICE));
+    assert_eq!(dispatcher.register(&mut hk), Err(PusError::TableFull));
+}
+
+#[test]
+fn execution_failures() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let request = tc_with(0, 200, 1, AckFlags::NONE, &[]);
+
+    // Could not start: only the start failure.
+    let mut faulty = Faulty {
+        failure: Failure::Start(0xc801),
+        report_first: false,
+    };
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(&mut faulty).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    assert_eq!(
+        dispatcher.dispatch(&request, &mut tm),
+        Err(PusError::Failed(Failure::Start(0xc801)))
+    );
+    assert_eq!(
+        reports(&mut queue),
+        [verification_report(
+            verification::START_FAILURE,
+            &request,
+            Some(0xc801)
+        )]
+    );
+
+    // Failed after a report: the start comes first, and the failure is a completion failure.
+    let request = tc_with(1, 200, 1, AckFlags::START, &[]);
+    let mut faulty = Faulty {
+        failure: Failure::Start(0xc802),
+        report_first: true,
+    };
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(&mut faulty).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    assert!(dispatcher.dispatch(&request, &mut tm).is_err());
+    assert_eq!(
+        reports(&mut queue),
+        [
+            verification_report(verification::START_SUCCESS, &request, None),
+            (200, 2, vec![1]),
+            verification_report(verification::COMPLETION_FAILURE, &request, Some(0xc802)),
+        ]
+    );
+}
+
+#[test]
+fn parameter_values() {
+    let mut buf = [0; 4];
+    assert_eq!(Value::I16(-2).encode(&mut buf), Some(2));
+    assert_eq!(buf[..2], [0xff, 0xfe]);
+    assert_eq!(Value::F32(1.5).encode(&mut buf), Some(4));
+    assert_eq!(buf, 1.5f32.to_be_bytes());
+    assert_eq!(Value::U32(1).encode(&mut buf[..3]), None);
+
+    let mut parameters = parameters();
+    assert_eq!(
+        parameters.register(TEC, Value::U8(0)),
+        Err(HousekeepingError::ParameterExists(TEC))
+    );
+    parameters.set(TEC, Value::U8(9)).unwrap();
+    assert_eq!(parameters.get(TEC), Some(Value::U8(9)));
+    assert_eq!(
+        parameters.set(TEC, Value::U32(10)),
+        Err(HousekeepingError::TypeMismatch(TEC))
+    );
+    assert_eq!(parameters.get(TEC), Some(Value::U8(9)));
+    assert_eq!(
+        parameters.set(0x999, Value::U8(0)),
+        Err(HousekeepingError::UnknownParameter(0x999))
+    );
+}
+
+#[test]
+fn structure_definitions() {
+    let mut hk = Housekeeping::<_, 2>::new(parameters());
+
+    hk.define(1, &[UPTIME, TEC], 10).unwrap();
+    assert_eq!(
+        hk.define(1, &[TEC], 10),
+        Err(HousekeepingError::StructureExists(1))
+    );
+    assert_eq!(
+        hk.define(2, &[TEC, 0x999], 10),
+        Err(HousekeepingError::UnknownParameter(0x999))
+    );
+    assert_eq!(
+        hk.define(2, &[TEC], 0),
+        Err(HousekeepingError::InvalidInterval)
+    );
+    assert_eq!(
+        hk.define(2, &[TEC; 17], 1),
+        Err(HousekeepingError::TooManyParameters)
+    );
+    hk.define(2, &[TEMPERATURE], 1).unwrap();
+    assert_eq!(hk.define(3, &[TEC], 1), Err(HousekeepingError::TableFull));
+
+    assert_eq!(hk.parameters(1), Some([UPTIME, TEC].as_slice()));
+    assert_eq!(hk.is_periodic(1), Some(false));
+    hk.delete(1).unwrap();
+    assert!(!hk.is_defined(1));
+    assert_eq!(hk.enable(1), Err(HousekeepingError::UnknownStructure(1)));
+}
+
+#[test]
+fn periodic_reports_follow_their_intervals() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<16>::new();
+    let mut hk = Housekeeping::<_, 4>::new(parameters());
+    hk.define(1, &[UPTIME, TEC, TEMPERATURE], 2).unwrap();
+    hk.define(2, &[TEC], 3).unwrap();
+    hk.define(3, &[TEC], 1).unwrap();
+    hk.enable(1).unwrap();
+    hk.enable(2).unwrap();
+
+    let mut counts = Vec::new();
+    for _ in 0..6 {
+        let mut tm = channel.reporter(&mut queue, now());
+        counts.push(hk.tick(&mut tm).unwrap());
+    }
+    assert_eq!(counts, [0, 1, 1, 1, 0, 2]);
+
+    let sent = reports(&mut queue);
+    assert_eq!(sids(&sent), [1, 2, 1, 1, 2]);
+    let mut expected = vec![0, 1, 1, 2, 3, 4, 7];
+    expected.extend(1.5f32.to_be_bytes());
+    assert_eq!(sent[0].2, expected);
+    assert_eq!(sent[1].2, [0, 2, 7]);
+
+    // Values are sampled at each report, and a new interval starts over.
+    hk.source_mut().set(TEC, Value::U8(8)).unwrap();
+    // A value of another type would change the layout of the reports.
+    assert_eq!(
+        hk.source_mut().set(TEC, Value::U16(0x0909)),
+        Err(HousekeepingError::TypeMismatch(TEC))
+    );
+    hk.set_interval(2, 1).unwrap();
+    hk.disable(1).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    assert_eq!(hk.tick(&mut tm), Ok(1));
+    assert_eq!(reports(&mut queue)[0].2, [0, 2, 8]);
+}
+
+#[test]
+fn housekeeping_requests() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<16>::new();
+    let mut hk = Housekeeping::<_, 4>::new(parameters());
+    hk.define(1, &[TEC], 5).unwrap();
+
+    let requests = [
+        (housekeeping::CREATE, vec![0, 2, 0, 4, 2, 1, 0, 1, 1]),
+        (housekeeping::GENERATE_ONE_SHOT, vec![2, 0, 2, 0, 1]),
+        (housekeeping::ENABLE_PERIODIC, vec![2, 0, 1, 0, 2]),
+        (housekeeping::MODIFY_INTERVAL, vec![1, 0, 2, 0, 1]),
+        (housekeeping::REPORT_STRUCTURES, vec![1, 0, 2]),
+        (housekeeping::DISABLE_PERIODIC, vec![1, 0, 1]),
+        (housekeeping::DELETE, vec![1, 0, 1]),
+    ];
+    {
+        let mut dispatcher = Dispatcher::<1>::new(APID);
+        dispatcher.register(&mut hk).unwrap();
+        let mut tm = channel.reporter(&mut queue, now());
+        for (count, (subtype, data)) in requests.iter().enumerate() {
+            let request = tc_with(
+                count as u16,
+                housekeeping::SERVICE,
+                *subtype,
+                AckFlags::NONE,
+                data,
+            );
+            dispatcher.dispatch(&request, &mut tm).unwrap();
+        }
+    }
+
+    let reports = reports(&mut queue);
+    assert_eq!(sids(&reports), [2, 1]);
+    assert_eq!(reports[0].2, [0, 2, 0x01, 0x02, 0x03, 0x04, 7]);
+    assert_eq!(
+        reports[2],
+        (
+            housekeeping::SERVICE,
+            housekeeping::STRUCTURE_REPORT,
+            vec![0, 2, 1, 0, 1, 2, 1, 0, 1, 1]
+        )
+    );
+    assert!(!hk.is_defined(1));
+    assert_eq!(hk.is_periodic(2), Some(true));
+    assert_eq!(hk.interval(2), Some(1));
+}
+
+#[test]
+fn housekeeping_rejections() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<16>::new();
+    let mut hk = Housekeeping::<_, 1>::new(parameters());
+    hk.define(1, &[TEC], 5).unwrap();
+
+    let rejected = [
+        (
+            housekeeping::GENERATE_ONE_SHOT,
+            vec![1, 0, 3],
+            housekeeping::UNKNOWN_STRUCTURE,
+        ),
+        (
+            housekeeping::GENERATE_ONE_SHOT,
+            vec![2, 0, 1],
+            verification::INVALID_DATA,
+        ),
+        (
+            housekeeping::CREATE,
+            vec![0, 1, 0, 1, 1, 1, 0],
+            housekeeping::STRUCTURE_EXISTS,
+        ),
+        (
+            housekeeping::CREATE,
+            vec![0, 2, 0, 1, 1, 9, 9],
+            housekeeping::UNKNOWN_PARAMETER,
+        ),
+        (
+            housekeeping::CREATE,
+            vec![0, 2, 0, 1, 1, 1, 0],
+            housekeeping::TOO_MANY_STRUCTURES,
+        ),
+        (
+            housekeeping::MODIFY_INTERVAL,
+            vec![1, 0, 1, 0, 0],
+            housekeeping::INVALID_INTERVAL,
+        ),
+        (42, vec![], verification::UNKNOWN_SUBTYPE),
+    ];
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(&mut hk).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    for (count, (subtype, data, code)) in rejected.iter().enumerate() {
+        let request = tc_with(
+            count as u16,
+            housekeeping::SERVICE,
+            *subtype,
+            AckFlags::NONE,
+            data,
+        );
+        assert_eq!(
+            dispatcher.dispatch(&request, &mut tm),
+            Err(PusError::Rejected(*code))
+        );
+    }
+}
+
+#[test]
+fn events_can_be_disabled() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut events = Events::<1>::new();
+
+    let mut tm = channel.reporter(&mut queue, now());
+    events
+        .report(&mut tm, Severity::Medium, 0x1234, &[9, 8])
+        .unwrap();
+
+    {
+        let mut dispatcher = Dispatcher::<1>::new(APID);
+        dispatcher.register(&mut events).unwrap();
+        let disable = tc_with(
+            0,
+            event::SERVICE,
+            event::DISABLE,
+            AckFlags::NONE,
+            &[1, 0x12, 0x34],
+        );
+        dispatcher.dispatch(&disable, &mut tm).unwrap();
+        let disable = tc_with(
+            1,
+            event::SERVICE,
+            event::DISABLE,
+            AckFlags::NONE,
+            &[1, 0, 1],
+        );
+        assert_eq!(
+            dispatcher.dispatch(&disable, &mut tm),
+            Err(PusError::Failed(Failure::Completion(
+                event::TOO_MANY_DISABLED
+            )))
+        );
+    }
+    events.report(&mut tm, Severity::High, 0x1234, &[]).unwrap();
+    events.enable(0x1234);
+    events.report(&mut tm, Severity::High, 0x1234, &[]).unwrap();
+
+    let reports = reports(&mut queue);
+    assert_eq!(
+        reports[0],
+        (
+            event::SERVICE,
+            Severity::Medium as u8,
+            vec![0x12, 0x34, 9, 8]
+        )
+    );
+    assert_eq!(reports[1].1, verification::COMPLETION_FAILURE);
+    assert_eq!(
+        reports[2],
+        (event::SERVICE, Severity::High as u8, vec![0x12, 0x34])
+    );
+    assert_eq!(events.stats().reported, 2);
+    assert_eq!(events.stats().suppressed, 1);
+}
+
+#[test]
+fn full_sink_and_long_reports() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<1>::new();
+    let mut tm = channel.reporter(&mut queue, now());
+
+    assert_eq!(tm.report(1, 1, &[0; 300]), Err(PusError::TooLong));
+    tm.report(1, 1, &[]).unwrap();
+    assert_eq!(tm.report(1, 1, &[]), Err(PusError::SinkFull));
+    assert_eq!(queue.len(), 1);
+}
+
+#[test]
+fn scheduled_telecommands_are_released_in_time_order() {
+    let mut clock = Clock::new();
+    let mut scheduler = Scheduler::<4>::new(CucFormat::DEFAULT);
+    let first = tc_with(1, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let second = tc_with(2, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let third = tc_with(3, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+
+    let now = clock.0;
+    scheduler.insert(clock.at(20), &third, now).unwrap();
+    scheduler.insert(clock.at(10), &first, now).unwrap();
+    scheduler.insert(clock.at(20), &second, now).unwrap();
+    assert_eq!(scheduler.len(), 3);
+    assert_eq!(scheduler.next_release(), Some(clock.at(10)));
+
+    let mut released = Vec::new();
+    for _ in 0..25 {
+        let now = clock.advance(1);
+        while let Some(tc) = scheduler.pop_due(now) {
+            released.push((now, tc.to_vec()));
+        }
+    }
+    // Same release time, released in the order they were inserted
+    assert_eq!(
+        released,
+        [
+            (Clock::new().at(10), first),
+            (Clock::new().at(20), third),
+            (Clock::new().at(20), second),
+        ]
+    );
+    assert!(scheduler.is_empty());
+    assert_eq!(scheduler.next_release(), None);
+}
+
+#[test]
+fn disabled_schedule_holds_telecommands() {
+    let mut clock = Clock::new();
+    let mut scheduler = Scheduler::<4>::new(CucFormat::DEFAULT);
+    let ping = tc_with(1, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    scheduler.insert(clock.at(5), &ping, clock.0).unwrap();
+
+    scheduler.disable();
+    assert!(!scheduler.is_enabled());
+    assert_eq!(scheduler.next_release(), None);
+    assert_eq!(scheduler.pop_due(clock.advance(10)), None);
+
+    scheduler.enable();
+    assert_eq!(scheduler.next_release(), Some(Clock::new().at(5)));
+    assert_eq!(scheduler.pop_due(clock.0).as_deref(), Some(&ping[..]));
+}
+
+#[test]
+fn schedule_can_be_shifted_and_edited() {
+    let mut clock = Clock::new();
+    let mut scheduler = Scheduler::<4>::new(CucFormat::DEFAULT);
+    let first = tc_with(1, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let second = tc_with(2, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let now = clock.0;
+    let first_id = scheduler.insert(clock.at(10), &first, now).unwrap();
+    let second_id = scheduler.insert(clock.at(20), &second, now).unwrap();
+
+    scheduler.shift(first_id, 15_000_000, now).unwrap();
+    assert_eq!(
+        scheduler.summary(),
+        [(clock.at(20), second_id), (clock.at(25), first_id)]
+    );
+
+    // Nothing moves if one of them would end up in the past
+    assert_eq!(
+        scheduler.shift_all(-20_000_000, now),
+        Err(SchedulerError::TimePassed)
+    );
+    scheduler.shift_all(-5_000_000, now).unwrap();
+    assert_eq!(scheduler.next_release(), Some(clock.at(15)));
+
+    scheduler.delete(second_id).unwrap();
+    assert_eq!(
+        scheduler.delete(second_id),
+        Err(SchedulerError::UnknownRequest(second_id))
+    );
+    assert_eq!(scheduler.summary(), [(clock.at(20), first_id)]);
+    assert_eq!(scheduler.pop_due(clock.advance(19)), None);
+    assert_eq!(
+        scheduler.pop_due(clock.advance(1)).as_deref(),
+        Some(&first[..])
+    );
+}
+
+#[test]
+fn insertions_are_checked() {
+    let clock = Clock::new();
+    let mut scheduler = Scheduler::<1>::new(CucFormat::DEFAULT);
+    let ping = tc_with(1, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+
+    assert_eq!(
+        scheduler.insert(clock.0, &ping, clock.0),
+        Err(SchedulerError::TimePassed)
+    );
+    let mut buf = [0; 16];
+    let report = PacketBuilder::telemetry(APID)
+        .build(&[1, 2], &mut buf)
+        .unwrap();
+    assert_eq!(
+        scheduler.insert(clock.at(1), report, clock.0),
+        Err(SchedulerError::NotTelecommand)
+    );
+    assert_eq!(
+        scheduler.insert(clock.at(1), &ping[..ping.len() - 1], clock.0),
+        Err(SchedulerError::NotTelecommand)
+    );
+    let mut buf = [0; 80];
+    let long = PacketBuilder::telecommand(APID)
+        .build(&[0; 70], &mut buf)
+        .unwrap();
+    assert_eq!(
+        scheduler.insert(clock.at(1), long, clock.0),
+        Err(SchedulerError::TooLong)
+    );
+
+    scheduler.insert(clock.at(1), &ping, clock.0).unwrap();
+    assert_eq!(
+        scheduler.insert(clock.at(2), &ping, clock.0),
+        Err(SchedulerError::Full)
+    );
+}
+
+#[test]
+fn scheduling_requests() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut scheduler = Scheduler::<4>::new(CucFormat::DEFAULT);
+    let first = tc_with(100, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let second = tc_with(101, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let mut shift = 5000i32.to_be_bytes().to_vec();
+    shift.extend([1]);
+    shift.extend(request_id(&first).to_bytes());
+
+    let requests = [
+        (scheduler::INSERT, insertion(&[(20, &second), (10, &first)])),
+        (scheduler::TIME_SHIFT, shift),
+        (scheduler::TIME_SHIFT_ALL, (-1000i32).to_be_bytes().to_vec()),
+        (scheduler::DISABLE, vec![]),
+        (scheduler::REPORT_SUMMARY, vec![]),
+    ];
+    {
+        let mut dispatcher = Dispatcher::<1>::new(APID);
+        dispatcher.register(&mut scheduler).unwrap();
+        let mut tm = channel.reporter(&mut queue, now());
+        for (count, (subtype, data)) in requests.iter().enumerate() {
+            let request = tc_with(
+                count as u16,
+                scheduler::SERVICE,
+                *subtype,
+                AckFlags::NONE,
+                data,
+            );
+            dispatcher.dispatch(&request, &mut tm).unwrap();
+        }
+    }
+
+    let reports = reports(&mut queue);
+    let release = |seconds| {
+        let mut time = [0; 6];
+        Cuc {
+            coarse: now().coarse + seconds,
+            ..now()
+        }
+        .encode(CucFormat::DEFAULT, &mut time)
+        .unwrap();
+        time
+    };
+    let mut summary = vec![2];
+    summary.extend(release(14));
+    summary.extend(request_id(&first).to_bytes());
+    summary.extend(release(19));
+    summary.extend(request_id(&second).to_bytes());
+    assert_eq!(
+        reports,
+        [(scheduler::SERVICE, scheduler::SUMMARY_REPORT, summary)]
+    );
+    assert!(!scheduler.is_enabled());
+
+    scheduler.enable();
+    let clock = Clock::new();
+    assert_eq!(scheduler.pop_due(clock.at(14)).as_deref(), Some(&first[..]));
+    assert_eq!(
+        scheduler.pop_due(clock.at(19)).as_deref(),
+        Some(&second[..])
+    );
+}
+
+#[test]
+fn scheduling_rejections() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<16>::new();
+    let mut scheduler = Scheduler::<2>::new(CucFormat::DEFAULT);
+    let ping = tc_with(100, ping::SERVICE, ping::PING, AckFlags::NONE, &[]);
+    let unknown = request_id(&tc_with(
+        101,
+        ping::SERVICE,
+        ping::PING,
+        AckFlags::NONE,
+        &[],
+    ));
+    let mut unknown_shift = 1000i32.to_be_bytes().to_vec();
+    unknown_shift.extend([1]);
+    unknown_shift.extend(unknown.to_bytes());
+    let mut truncated = insertion(&[(10, &ping)]);
+    truncated.pop();
+    let mut long_shift = 1000i32.to_be_bytes().to_vec();
+    long_shift.extend([3]);
+    long_shift.extend([unknown.to_bytes(); 3].concat());
+
+    let rejected = [
+        (scheduler::INSERT, truncated, verification::INVALID_DATA),
+        (
+            scheduler::INSERT,
+            insertion(&[(1, &ping), (2, &ping), (3, &ping)]),
+            scheduler::SCHEDULE_FULL,
+        ),
+        (
+            scheduler::INSERT,
+            insertion(&[(1, &ping[..4])]),
+            verification::INVALID_DATA,
+        ),
+        (
+            scheduler::DELETE,
+            [&[1][..], &unknown.to_bytes()].concat(),
+            scheduler::UNKNOWN_REQUEST,
+        ),
+        (
+            scheduler::TIME_SHIFT,
+            unknown_shift,
+            scheduler::UNKNOWN_REQUEST,
+        ),
+        (
+            scheduler::TIME_SHIFT,
+            long_shift,
+            verification::INVALID_DATA,
+        ),
+        (
+            scheduler::TIME_SHIFT_ALL,
+            vec![0, 1],
+            verification::INVALID_DATA,
+        ),
+        (scheduler::ENABLE, vec![1], verification::INVALID_DATA),
+        (20, vec![], verification::UNKNOWN_SUBTYPE),
+    ];
+    let past = insertion(&[(10, &ping), (0, &ping)]);
+    {
+        let mut dispatcher = Dispatcher::<1>::new(APID);
+        dispatcher.register(&mut scheduler).unwrap();
+        let mut tm = channel.reporter(&mut queue, now());
+        for (count, (subtype, data, code)) in rejected.iter().enumerate() {
+            let request = tc_with(
+                count as u16,
+                scheduler::SERVICE,
+                *subtype,
+                AckFlags::NONE,
+                data,
+            );
+            assert_eq!(
+                dispatcher.dispatch(&request, &mut tm),
+                Err(PusError::Rejected(*code))
+            );
+        }
+        let request = tc_with(
+            50,
+            scheduler::SERVICE,
+            scheduler::INSERT,
+            AckFlags::NONE,
+            &past,
+        );
+        assert_eq!(
+            dispatcher.dispatch(&request, &mut tm),
+            Err(PusError::Failed(Failure::Start(scheduler::TIME_PASSED)))
+        );
+    }
+
+    // Nothing of the insert with a past release time was scheduled
+    assert!(scheduler.is_empty());
+}
+
+/// The application data of a correlation: UTC `utc` at MET `met`.
+fn correlation(met: Cuc, utc: Cds, drift: i32) -> Vec<u8> {
+    let mut data = vec![0; 14];
+    met.encode(CucFormat::DEFAULT, &mut data).unwrap();
+    utc.encode(CdsFormat::DEFAULT, &mut data[6..]).unwrap();
+    data.extend(drift.to_be_bytes());
+    data
+}
+
+#[test]
+fn time_reports_after_correlation() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut service = TimeManagement::new(CucFormat::DEFAULT, CdsFormat::DEFAULT);
+    let utc = Cds {
+        days: 24000,
+        millis: 3_600_000,
+        micros: 0,
+    };
+    let met = Cuc {
+        coarse: 1000,
+        fine: 0,
+    };
+
+    let requests = [
+        (time_service::REPORT_TIME, vec![]),
+        (time_service::CORRELATE, correlation(met, utc, -20)),
+        (time_service::REPORT_TIME, vec![]),
+    ];
+    {
+        let mut dispatcher = Dispatcher::<1>::new(APID);
+        dispatcher.register(&mut service).unwrap();
+        let mut tm = channel.reporter(&mut queue, now());
+        for (count, (subtype, data)) in requests.iter().enumerate() {
+            let request = tc_with(
+                count as u16,
+                time_service::SERVICE,
+                *subtype,
+                AckFlags::NONE,
+                data,
+            );
+            dispatcher.dispatch(&request, &mut tm).unwrap();
+        }
+    }
+
+    let cuc_report = (
+        time_service::SERVICE,
+        time_service::CUC_TIME_REPORT,
+        vec![0x2e, 0, 0, 0x03, 0xe8, 0x80, 0],
+    );
+    // Half a second after the correlation point, the 10 ns of drift are below the resolution
+    let cds_report = (
+        time_service::SERVICE,
+        time_service::CDS_TIME_REPORT,
+        vec![0x41, 0x5d, 0xc0, 0, 0x36, 0xf0, 0x74, 0, 0],
+    );
+    assert_eq!(
+        reports(&mut queue),
+        [cuc_report.clone(), cuc_report, cds_report]
+    );
+    assert_eq!(
+        service.correlation(),
+        Some(
+            Correlation::new(time::from_cuc(met, CucFormat::DEFAULT), Utc::from_cds(utc))
+                .drift(-20)
+        )
+    );
+}
+
+#[test]
+fn time_rejections() {
+    let mut channel = TmChannel::new(APID, CucFormat::DEFAULT);
+    let mut queue = TmQueue::<8>::new();
+    let mut service = TimeManagement::new(CucFormat::DEFAULT, CdsFormat::DEFAULT);
+    let invalid_utc = Cds {
+        days: 1,
+        millis: 86_401_000,
+        micros: 0,
+    };
+    let mut short = correlation(now(), Cds::default(), 0);
+    short.pop();
+
+    let rejected = [
+        (time_service::CORRELATE, short, verification::INVALID_DATA),
+        (
+            time_service::CORRELATE,
+            correlation(now(), invalid_utc, 0),
+            time_service::INVALID_TIME,
+        ),
+        (
+            time_service::REPORT_TIME,
+            vec![0],
+            verification::INVALID_DATA,
+        ),
+        (1, vec![], verification::UNKNOWN_SUBTYPE),
+    ];
+    let mut dispatcher = Dispatcher::<1>::new(APID);
+    dispatcher.register(&mut service).unwrap();
+    let mut tm = channel.reporter(&mut queue, now());
+    for (count, (subtype, data, code)) in rejected.iter().enumerate() {
+        let request = tc_with(
+            count as u16,
+            time_service::SERVICE,
+            *subtype,
+            AckFlags::NONE,
+            data,
+        );
+        assert_eq!(
+            dispatcher.dispatch(&request, &mut tm),
+            Err(PusError::Rejected(*code))
+        );
+    }
+}
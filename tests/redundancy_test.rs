@@ -0,0 +1,113 @@
+//! Host tests of the redundant bus failover and duplicate filter. Run with `cargo test-host`.
+
+use bxcan::{Frame, StandardId};
+use fugit::{ExtU32, TimerInstantU32};
+use stm32f446_rtic::can_shield::{
+    health::BusState::{BusOff, ErrorActive, ErrorPassive},
+    redundancy::{RedundancyConfig, RedundantBus, DEDUP_LEN},
+    Channel,
+};
+
+/// Milliseconds since start.
+fn at(ms: u32) -> TimerInstantU32<1000> {
+    TimerInstantU32::from_ticks(ms)
+}
+
+fn frame(id: u16, sequence: u8) -> Frame {
+    Frame::new_data(StandardId::new(id).unwrap(), [sequence, 0xaa])
+}
+
+#[test]
+fn fails_over_on_bus_off() {
+    let mut bus = RedundantBus::<1000>::new(RedundancyConfig::new(Channel::Can1));
+
+    assert_eq!(
+        bus.evaluate([ErrorPassive, ErrorActive], at(0)),
+        Channel::Can1
+    );
+    assert_eq!(bus.evaluate([BusOff, ErrorActive], at(10)), Channel::Can2);
+    // No fail back by default.
+    assert_eq!(
+        bus.evaluate([ErrorActive, ErrorActive], at(20)),
+        Channel::Can2
+    );
+    // Both buses down, stay where we are.
+    assert_eq!(bus.evaluate([BusOff, BusOff], at(30)), Channel::Can2);
+    assert_eq!(bus.stats().failovers, 1);
+}
+
+#[test]
+fn fails_back_when_enabled() {
+    let config = RedundancyConfig::new(Channel::Can2).fail_back(true);
+    let mut bus = RedundantBus::<1000>::new(config);
+
+    assert_eq!(bus.evaluate([ErrorActive, BusOff], at(0)), Channel::Can1);
+    assert_eq!(
+        bus.evaluate([ErrorActive, ErrorActive], at(10)),
+        Channel::Can2
+    );
+    assert_eq!(bus.stats().failovers, 2);
+}
+
+#[test]
+fn fails_over_on_missing_heartbeats() {
+    let config = RedundancyConfig::new(Channel::Can1).heartbeat_timeout(100.millis());
+    let mut bus = RedundantBus::<1000>::new(config);
+    let healthy = [ErrorActive, ErrorActive];
+
+    assert_eq!(bus.evaluate(healthy, at(0)), Channel::Can1);
+    bus.on_receive(Channel::Can1, &frame(0x10, 0), at(50));
+    bus.on_receive(Channel::Can2, &frame(0x10, 0), at(50));
+    assert_eq!(bus.evaluate(healthy, at(149)), Channel::Can1);
+
+    // Only CAN2 keeps receiving.
+    bus.on_receive(Channel::Can2, &frame(0x10, 1), at(140));
+    assert_eq!(bus.evaluate(healthy, at(150)), Channel::Can2);
+}
+
+#[test]
+fn drops_duplicates() {
+    let mut bus = RedundantBus::<1000>::new(RedundancyConfig::new(Channel::Can1));
+
+    assert!(bus.on_receive(Channel::Can1, &frame(0x10, 7), at(0)));
+    assert!(!bus.on_receive(Channel::Can2, &frame(0x10, 7), at(1)));
+    // Another ID with the same sequence number.
+    assert!(bus.on_receive(Channel::Can2, &frame(0x11, 7), at(1)));
+    assert!(bus.on_receive(Channel::Can2, &frame(0x10, 8), at(2)));
+    assert!(!bus.on_receive(Channel::Can1, &frame(0x10, 8), at(3)));
+    // The sequence number wrapped around after the window.
+    assert!(bus.on_receive(Channel::Can1, &frame(0x10, 8), at(200)));
+
+    assert_eq!(bus.stats().duplicates, 2);
+}
+
+#[test]
+fn forgets_oldest_id() {
+    let mut bus = RedundantBus::<1000>::new(RedundancyConfig::new(Channel::Can1));
+
+    for id in 0..=DEDUP_LEN as u16 {
+        assert!(bus.on_receive(Channel::Can1, &frame(id, 1), at(u32::from(id))));
+    }
+    // ID 0 was replaced, the rest is still known.
+    assert!(bus.on_receive(Channel::Can2, &frame(0, 1), at(40)));
+    assert!(!bus.on_receive(Channel::Can2, &frame(5, 1), at(40)));
+}
+
+#[test]
+fn drops_duplicates_of_a_lagging_bus() {
+    let mut bus = RedundantBus::<1000>::new(RedundancyConfig::new(Channel::Can1));
+
+    // CAN2 is three frames behind CAN1.
+    for sequence in 1..=3 {
+        let now = at(u32::from(sequence));
+        assert!(bus.on_receive(Channel::Can1, &frame(0x10, sequence), now));
+    }
+    for sequence in 1..=3 {
+        assert!(!bus.on_receive(Channel::Can2, &frame(0x10, sequence), at(10)));
+    }
+    // CAN1 stalls and CAN2 gets ahead.
+    assert!(bus.on_receive(Channel::Can2, &frame(0x10, 4), at(11)));
+    assert!(!bus.on_receive(Channel::Can1, &frame(0x10, 4), at(12)));
+
+    assert_eq!(bus.stats().duplicates, 4);
+}
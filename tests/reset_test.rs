@@ -0,0 +1,121 @@
+//! Host tests of the retained crash record. Run with `cargo test-host`.
+
+use stm32f446_rtic::reset::{
+    self, BootRecord, Crash, FaultRecord, PanicRecord, ResetCause, Retained,
+};
+
+const POWER_ON: u32 = 0x0e00_0000;
+const PIN: u32 = 0x0400_0000;
+const SOFTWARE: u32 = 0x1400_0000;
+const IWDG: u32 = 0x2400_0000;
+
+fn fault() -> Crash {
+    Crash::HardFault(FaultRecord {
+        r0: 1,
+        r1: 2,
+        r2: 3,
+        r3: 4,
+        r12: 12,
+        lr: 0x0801_0123,
+        pc: 0x0801_0456,
+        xpsr: 0x0100_0000,
+        cfsr: 0x0001_0000,
+        hfsr: 0x4000_0000,
+    })
+}
+
+#[test]
+fn reset_causes() {
+    assert_eq!(ResetCause::from_csr(POWER_ON), ResetCause::PowerOn);
+    assert_eq!(ResetCause::from_csr(0x0600_0000), ResetCause::BrownOut);
+    assert_eq!(ResetCause::from_csr(PIN), ResetCause::Pin);
+    assert_eq!(ResetCause::from_csr(SOFTWARE), ResetCause::Software);
+    assert_eq!(ResetCause::from_csr(IWDG), ResetCause::IndependentWatchdog);
+    assert_eq!(
+        ResetCause::from_csr(0x4400_0000),
+        ResetCause::WindowWatchdog
+    );
+    assert_eq!(ResetCause::from_csr(0x8400_0000), ResetCause::LowPower);
+    // Flags add up until they are cleared, the watchdog beats the software
+    assert_eq!(
+        ResetCause::from_csr(SOFTWARE | IWDG),
+        ResetCause::IndependentWatchdog
+    );
+    assert_eq!(ResetCause::from_csr(0x0000_0003), ResetCause::Unknown);
+}
+
+#[test]
+fn boots_are_counted() {
+    let mut retained = Retained::new();
+    assert_eq!(
+        retained.boot(POWER_ON),
+        BootRecord {
+            boots: 1,
+            cause: ResetCause::PowerOn,
+            crash: None,
+        }
+    );
+    assert_eq!(retained.boot(PIN).boots, 2);
+    assert_eq!(retained.boot(SOFTWARE).boots, 3);
+
+    // Noise after a power loss starts the count over
+    retained.corrupt(5, 0x40);
+    assert_eq!(retained.boot(POWER_ON).boots, 1);
+    assert_eq!(retained.boot(PIN).boots, 2);
+
+    // A run that settled starts the count over, and keeps its crash
+    retained.record(&fault());
+    retained.settle();
+    let last = retained.boot(SOFTWARE);
+    assert_eq!((last.boots, last.crash), (1, Some(fault())));
+}
+
+#[test]
+fn crash_is_taken_once() {
+    let mut retained = Retained::new();
+    retained.boot(POWER_ON);
+
+    retained.record(&fault());
+    // The panic that follows is a consequence
+    retained.record(&Crash::Panic(PanicRecord::new("src/lib.rs", 1, "later")));
+
+    let last = retained.boot(SOFTWARE);
+    assert_eq!((last.boots, last.crash), (2, Some(fault())));
+    assert_eq!(last.summary(), [0, 0, 0, 2, 4, 2, 0x08, 0x01, 0x04, 0x56]);
+    assert_eq!(retained.boot(PIN).crash, None);
+
+    // A crash before the first boot is kept too
+    let mut retained = Retained::new();
+    retained.record(&fault());
+    let last = retained.boot(SOFTWARE);
+    assert_eq!((last.boots, last.crash), (1, Some(fault())));
+
+    // A damaged crash is lost with the count
+    retained.record(&fault());
+    retained.corrupt(20, 0x01);
+    assert_eq!(retained.boot(SOFTWARE).crash, None);
+}
+
+#[test]
+fn panics_are_cut_to_fit() {
+    let file = "/home/user/.cargo/registry/src/index.crates.io/heapless-0.7.17/src/vec.rs";
+    let message = format!("index out of bounds: {}", "ü".repeat(40));
+    let panic = PanicRecord::new(file, 123, &message);
+    assert_eq!(panic.line, 123);
+    assert_eq!(panic.file(), "es.io/heapless-0.7.17/src/vec.rs");
+    assert!(file.ends_with(panic.file()));
+    // Whole characters only, two bytes each
+    assert_eq!(panic.message().len(), reset::MAX_MESSAGE_LEN - 1);
+    assert!(message.starts_with(panic.message()));
+
+    let mut retained = Retained::new();
+    retained.boot(POWER_ON);
+    retained.record(&Crash::Panic(panic));
+    let last = retained.boot(SOFTWARE);
+    assert_eq!(last.crash, Some(Crash::Panic(panic)));
+    assert_eq!(last.summary()[4..], [4, 1, 0, 0, 0, 123]);
+
+    let bytes = Crash::Panic(PanicRecord::new("main.rs", 7, "oops")).to_bytes();
+    assert_eq!(bytes, *b"\x01\x00\x00\x00\x07\x07\x04main.rsoops");
+    assert_eq!(fault().to_bytes().len(), 41);
+}
@@ -0,0 +1,200 @@
+//! Host tests of the CAN time synchronization against the mock backend. Run with
+//! `cargo test-host`.
+
+use bxcan::{Data, Frame, StandardId};
+use fugit::ExtU32;
+use stm32f446_rtic::{
+    can_shield::{
+        mock::{MockBus, MockShield},
+        rx::{self, RxQueue},
+        CanChannel, Channel, Shield,
+    },
+    time::{
+        sync::{SyncConfig, SyncError, SyncMaster, SyncSlave},
+        Met,
+    },
+};
+
+const SYNC_ID: u16 = 0x080;
+
+fn id(raw: u16) -> StandardId {
+    StandardId::new(raw).unwrap()
+}
+
+fn config() -> SyncConfig {
+    SyncConfig::new(Channel::Can1, id(SYNC_ID))
+}
+
+fn frame(raw: u16, data: &[u8]) -> Frame {
+    Frame::new_data(id(raw), Data::new(data).unwrap())
+}
+
+/// MET of the master `ms` milliseconds into the test.
+fn master_met(ms: u32) -> Met {
+    Met::from_ticks(1_000_000_000 + u64::from(ms) * 1000)
+}
+
+/// MET of the slave, which started later and runs 50 ppm fast.
+fn slave_met(ms: u32) -> Met {
+    let micros = u64::from(ms) * 1000;
+    Met::from_ticks(5_000 + micros + micros * 50 / 1_000_000)
+}
+
+#[test]
+fn slave_follows_the_master() {
+    let bus = MockBus::new();
+    let mut master_shield = MockShield::new(&bus, &MockBus::new());
+    let mut slave_shield = MockShield::new(&bus, &MockBus::new());
+    let mut master = SyncMaster::new(config());
+    let mut slave = SyncSlave::new(config());
+
+    let mut queue = RxQueue::new();
+    let (mut producer, mut consumer) = rx::split(&mut queue, Channel::Can1);
+
+    assert_eq!(slave.to_master(slave_met(0)), None);
+
+    for ms in 0..2000 {
+        if ms % 100 == 0 {
+            master.send_sync(&mut master_shield).unwrap();
+        }
+        bus.step();
+
+        // The RX interrupt of the slave
+        let now = slave_met(ms);
+        producer.drain_with(&mut slave_shield.can1, |frame| slave.on_frame(frame, now));
+
+        // The TX interrupt of the master
+        if master_shield.can1.tx_interrupt_pending() {
+            master
+                .on_tx_interrupt(&mut master_shield, master_met(ms))
+                .unwrap();
+            master_shield.service_tx_interrupts();
+        }
+    }
+
+    // The synchronization frames never reach the application
+    assert!(consumer.receive().is_none());
+
+    assert_eq!(master.stats().syncs, 20);
+    assert_eq!(master.stats().missed, 0);
+
+    let stats = slave.stats();
+    assert_eq!(stats.syncs, 20);
+    assert_eq!(stats.missed, 0);
+    assert!(
+        (stats.drift_ppb + 49_997).abs() < 100,
+        "{}",
+        stats.drift_ppb
+    );
+    assert!(stats.error_us.abs() <= 1, "{}", stats.error_us);
+    assert!(stats.max_error_us <= 5, "{}", stats.max_error_us);
+
+    for ms in [1950, 2500, 4000] {
+        let master_time = slave.to_master(slave_met(ms)).unwrap();
+        let error = master_time.ticks() as i64 - master_met(ms).ticks() as i64;
+        assert!(error.abs() <= 2, "{} µs off at {} ms", error, ms);
+    }
+}
+
+#[test]
+fn slave_drops_unmatched_follow_ups() {
+    let bus = MockBus::new();
+    let mut slave_shield = MockShield::new(&bus, &MockBus::new());
+    let mut slave = SyncSlave::new(config().timeout(1000.millis()));
+    let mut probe = bus.attach(Channel::Can1);
+
+    let mut queue = RxQueue::new();
+    let (mut producer, consumer) = rx::split(&mut queue, Channel::Can1);
+
+    let mut deliver = |frame: &Frame, at: Met, slave: &mut SyncSlave| {
+        probe.transmit(frame).unwrap();
+        bus.run();
+        producer.drain_with(&mut slave_shield.can1, |frame| slave.on_frame(frame, at))
+    };
+    let follow_up = |sequence: u8, micros: u64| {
+        let mut data = [0x20 | sequence; 8];
+        data[1..].copy_from_slice(&micros.to_be_bytes()[1..]);
+        frame(SYNC_ID, &data)
+    };
+
+    // A FOLLOW_UP without SYNC, and one with the wrong sequence number
+    assert_eq!(
+        deliver(&follow_up(1, 100), Met::from_ticks(0), &mut slave),
+        0
+    );
+    deliver(&frame(SYNC_ID, &[0x12]), Met::from_ticks(10), &mut slave);
+    deliver(&follow_up(3, 100), Met::from_ticks(20), &mut slave);
+    assert_eq!(slave.stats().missed, 2);
+    assert_eq!(slave.stats().syncs, 0);
+
+    // Other frames, and malformed ones on the SYNC ID, are queued as usual
+    assert_eq!(
+        deliver(&frame(0x100, &[1, 2]), Met::from_ticks(30), &mut slave),
+        1
+    );
+    assert_eq!(
+        deliver(&frame(SYNC_ID, &[0x11, 0]), Met::from_ticks(40), &mut slave),
+        1
+    );
+    assert_eq!(consumer.len(), 2);
+
+    deliver(&frame(SYNC_ID, &[0x14]), Met::from_ticks(1_000), &mut slave);
+    deliver(&follow_up(4, 501_000), Met::from_ticks(2_000), &mut slave);
+    assert_eq!(slave.stats().syncs, 1);
+    assert_eq!(slave.stats().offset_us, 500_000);
+
+    assert_eq!(
+        slave.to_master(Met::from_ticks(11_000)),
+        Some(Met::from_ticks(511_000))
+    );
+    assert!(slave.is_synchronized(Met::from_ticks(1_001_000)));
+    assert_eq!(slave.to_master(Met::from_ticks(1_001_001)), None);
+}
+
+#[test]
+fn master_waits_for_an_idle_transmitter() {
+    let bus = MockBus::new();
+    let mut master_shield = MockShield::new(&bus, &MockBus::new());
+    let _listener = bus.attach(Channel::Can1);
+    let mut master = SyncMaster::new(config());
+
+    for n in 0..4 {
+        master_shield
+            .transmit(Channel::Can1, &frame(0x200, &[n]))
+            .unwrap();
+    }
+    assert_eq!(master.send_sync(&mut master_shield), Err(SyncError::Busy));
+    assert_eq!(master.stats().missed, 1);
+
+    // Nothing is confirmed before the SYNC frame went out
+    while master_shield.tx_pending(Channel::Can1) > 0
+        || !master_shield
+            .channel(Channel::Can1)
+            .status()
+            .transmitter_idle
+    {
+        bus.step();
+        master
+            .on_tx_interrupt(&mut master_shield, master_met(0))
+            .unwrap();
+        master_shield.service_tx_interrupts();
+    }
+    bus.take_log();
+
+    master.send_sync(&mut master_shield).unwrap();
+    bus.step();
+    master
+        .on_tx_interrupt(&mut master_shield, master_met(7))
+        .unwrap();
+    master_shield.service_tx_interrupts();
+    bus.run();
+
+    let log = bus.take_log();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].data().unwrap().as_ref(), [0x11]);
+    assert_eq!(
+        log[1].data().unwrap().as_ref(),
+        [0x21, 0, 0, 0, 0x3b, 0x9a, 0xe5, 0x58]
+    );
+    assert_eq!(master.stats().syncs, 1);
+}
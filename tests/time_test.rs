@@ -0,0 +1,144 @@
+//! Host tests of the onboard timekeeping. Run with `cargo test-host`.
+
+use fugit::TimerInstantU32;
+use quickcheck::quickcheck;
+use stm32f446_rtic::{
+    ccsds::{Cds, Cuc, CucFormat},
+    time::{self, rtc::Calendar, Correlation, Met, MissionClock, Utc},
+};
+
+const HZ: u32 = 1_000_000;
+
+fn ticks(ticks: u32) -> TimerInstantU32<HZ> {
+    TimerInstantU32::from_ticks(ticks)
+}
+
+#[test]
+fn clock_extends_the_monotonic_timer() {
+    let mut clock = MissionClock::<HZ>::new();
+    assert_eq!(clock.update(ticks(0)), Met::from_ticks(0));
+    assert_eq!(clock.update(ticks(u32::MAX)), Met::from_ticks(0xffff_ffff));
+    assert_eq!(clock.update(ticks(5)), Met::from_ticks(0x1_0000_0005));
+    assert_eq!(clock.update(ticks(3)), Met::from_ticks(0x2_0000_0003));
+}
+
+#[test]
+fn clock_starts_at_the_boot_time() {
+    let mut clock = MissionClock::<180_000_000>::new();
+    clock.set_boot_time(Met::from_ticks(86_400_000_000));
+    assert_eq!(
+        clock.update(TimerInstantU32::from_ticks(180_000_000)),
+        Met::from_ticks(86_401_000_000)
+    );
+}
+
+#[test]
+fn met_as_cuc() {
+    let met = Met::from_ticks(1_000_500_000);
+    let cuc = time::to_cuc(met, CucFormat::DEFAULT);
+    assert_eq!(
+        cuc,
+        Cuc {
+            coarse: 1000,
+            fine: 0x8000
+        }
+    );
+    assert_eq!(time::from_cuc(cuc, CucFormat::DEFAULT), met);
+}
+
+#[test]
+fn correlation_with_drift() {
+    let utc = Utc::from_cds(Cds {
+        days: 24000,
+        millis: 0,
+        micros: 0,
+    });
+    let correlation = Correlation::new(Met::from_ticks(10_000_000), utc).drift(50_000);
+    assert_eq!(correlation.to_utc(Met::from_ticks(10_000_000)), utc);
+
+    // 50 µs per second of MET, before and after the correlation point
+    assert_eq!(
+        correlation.to_utc(Met::from_ticks(110_000_000)).to_cds(),
+        Cds {
+            days: 24000,
+            millis: 100_005,
+            micros: 0,
+        }
+    );
+    assert_eq!(
+        correlation.to_utc(Met::from_ticks(9_000_000)).to_cds(),
+        Cds {
+            days: 23999,
+            millis: 86_398_999,
+            micros: 950,
+        }
+    );
+    assert_eq!(
+        Correlation::new(Met::from_ticks(10), Utc(5)).to_utc(Met::from_ticks(0)),
+        Utc(0)
+    );
+}
+
+#[test]
+fn calendar_follows_the_rtc_leap_years() {
+    let start = Calendar {
+        year: 1,
+        month: 1,
+        day: 1,
+        hours: 0,
+        minutes: 0,
+        seconds: 0,
+    };
+    assert_eq!(Calendar::from_met_seconds(0), Some(start));
+    assert_eq!(start.met_seconds(), Some(0));
+
+    // Year 4 is the first leap year of the RTC
+    let leap_day = Calendar {
+        year: 4,
+        month: 2,
+        day: 29,
+        hours: 23,
+        minutes: 59,
+        seconds: 59,
+    };
+    let seconds = (3 * 365 + 59) * 86_400 + 86_399;
+    assert_eq!(Calendar::from_met_seconds(seconds), Some(leap_day));
+    assert_eq!(leap_day.met_seconds(), Some(seconds));
+
+    // Year 0 is the RTC after a backup domain reset
+    assert_eq!(Calendar { year: 0, ..start }.met_seconds(), None);
+    assert_eq!(
+        Calendar {
+            year: 5,
+            day: 29,
+            ..leap_day
+        }
+        .met_seconds(),
+        None
+    );
+    assert_eq!(Calendar::from_met_seconds(99 * 366 * 86_400), None);
+}
+
+quickcheck! {
+    fn calendar_round_trip(seconds: u32) -> bool {
+        // The calendar holds 98 years
+        let seconds = u64::from(seconds) % (98 * 365 * 86_400);
+        let calendar = Calendar::from_met_seconds(seconds).unwrap();
+        calendar.met_seconds() == Some(seconds)
+    }
+
+    fn clock_is_monotonic(steps: Vec<u32>) -> bool {
+        // Less than a full wrap between updates
+        let steps = steps.into_iter().map(|step| step % u32::MAX);
+        let mut clock = MissionClock::<HZ>::new();
+        let mut now = 0u32;
+        let mut last = clock.update(ticks(now));
+        steps.into_iter().all(|step| {
+            now = now.wrapping_add(step);
+            let met = clock.update(ticks(now));
+            let elapsed = met.ticks() - last.ticks();
+            last = met;
+            elapsed == u64::from(step)
+        })
+    }
+}
@@ -0,0 +1,45 @@
+//! Host tests of the bit timing calculation. Run with `cargo test-host`.
+
+use fugit::RateExtU32;
+use stm32f446_rtic::can_shield::timing::{calculate, BitTiming, TimingError};
+
+#[test]
+fn matches_known_value_at_45mhz() {
+    // 0x001b0002 is the value the shield used to hard-code for 1 MBit/s at 45 MHz.
+    let timing = calculate(45.MHz(), 1_000_000, 875).unwrap();
+    assert_eq!(timing.btr() & 0x00ff_ffff, 0x001b_0002);
+    assert_eq!(timing.bitrate(45.MHz()), 1_000_000);
+}
+
+#[test]
+fn common_bitrates_at_other_clocks() {
+    for pclk1 in [42.MHz(), 45.MHz(), 48.MHz(), 50.MHz()] {
+        for bitrate in [1_000_000, 500_000, 250_000, 125_000] {
+            let timing = calculate(pclk1, bitrate, 875).unwrap();
+            assert_eq!(timing.bitrate(pclk1), bitrate);
+            assert!(timing.sample_point().abs_diff(875) <= 50);
+            assert!(timing.sjw <= timing.tseg2);
+        }
+    }
+}
+
+#[test]
+fn btr_round_trip() {
+    let timing = calculate(45.MHz(), 500_000, 800).unwrap();
+    assert_eq!(BitTiming::from_btr(timing.btr()), timing);
+}
+
+#[test]
+fn rejects_invalid_requests() {
+    assert_eq!(calculate(45.MHz(), 0, 875), Err(TimingError::InvalidBitrate));
+    assert_eq!(
+        calculate(45.MHz(), 2_000_000, 875),
+        Err(TimingError::InvalidBitrate)
+    );
+    assert_eq!(
+        calculate(45.MHz(), 1_000_000, 950),
+        Err(TimingError::InvalidSamplePoint)
+    );
+    assert_eq!(calculate(4.MHz(), 1_000_000, 875), Err(TimingError::ClockTooSlow));
+    assert_eq!(calculate(45.MHz(), 950_000, 875), Err(TimingError::NoSolution));
+}
@@ -0,0 +1,96 @@
+//! Host tests of the software transmit queue against the mock backend. Run with
+//! `cargo test-host`.
+
+use bxcan::{Frame, StandardId};
+use stm32f446_rtic::can_shield::{
+    mock::{MockBus, MockChannel, Round},
+    tx::{TxError, TxQueue, TX_QUEUE_LEN},
+    Channel,
+};
+
+fn frame(id: u16, data: u8) -> Frame {
+    Frame::new_data(StandardId::new(id).unwrap(), [data])
+}
+
+fn ids(frames: &[Frame]) -> Vec<(u16, u8)> {
+    frames
+        .iter()
+        .map(|frame| match frame.id() {
+            bxcan::Id::Standard(id) => (id.as_raw(), frame.data().unwrap()[0]),
+            bxcan::Id::Extended(_) => unreachable!(),
+        })
+        .collect()
+}
+
+/// A controller on a bus with a peer acknowledging its frames.
+fn attach() -> (MockBus, MockChannel, MockChannel) {
+    let bus = MockBus::new();
+    let can = bus.attach(Channel::Can1);
+    let peer = bus.attach(Channel::Can2);
+    (bus, can, peer)
+}
+
+/// Sends one frame at a time and runs the TX interrupt after each, until nothing is left.
+fn run(bus: &MockBus, can: &mut MockChannel, queue: &mut TxQueue) {
+    while let Round::Delivered(_) = bus.step() {
+        queue.on_interrupt(can);
+    }
+}
+
+#[test]
+fn same_id_keeps_fifo_order() {
+    let (bus, mut can, _peer) = attach();
+    let mut queue = TxQueue::new();
+
+    for i in 0..10 {
+        queue.transmit(&mut can, &frame(0x500, i)).unwrap();
+    }
+    run(&bus, &mut can, &mut queue);
+
+    let expected: Vec<(u16, u8)> = (0..10).map(|i| (0x500, i)).collect();
+    assert_eq!(ids(&bus.take_log()), expected);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn higher_priority_goes_first() {
+    let (bus, mut can, _peer) = attach();
+    let mut queue = TxQueue::new();
+
+    queue.transmit(&mut can, &frame(0x700, 0)).unwrap();
+    queue.transmit(&mut can, &frame(0x600, 0)).unwrap();
+    queue.transmit(&mut can, &frame(0x500, 0)).unwrap();
+    // The mailboxes are full, 0x100 displaces 0x700.
+    queue.transmit(&mut can, &frame(0x100, 0)).unwrap();
+    queue.transmit(&mut can, &frame(0x700, 1)).unwrap();
+
+    assert_eq!(queue.stats().requeued, 1);
+    run(&bus, &mut can, &mut queue);
+
+    assert_eq!(
+        ids(&bus.take_log()),
+        [(0x100, 0), (0x500, 0), (0x600, 0), (0x700, 0), (0x700, 1)]
+    );
+    assert_eq!(queue.stats().queued, 5);
+    assert!(!can.tx_interrupt_pending());
+}
+
+#[test]
+fn full_queue_is_reported() {
+    let (bus, mut can, _peer) = attach();
+    let mut queue = TxQueue::new();
+
+    // The first frame goes straight into a mailbox, the others wait for it to leave.
+    for i in 0..=TX_QUEUE_LEN {
+        queue.transmit(&mut can, &frame(0x500, i as u8)).unwrap();
+    }
+    assert_eq!(queue.len(), TX_QUEUE_LEN);
+    assert_eq!(
+        queue.transmit(&mut can, &frame(0x500, 0xff)),
+        Err(TxError::QueueFull)
+    );
+    assert_eq!(queue.stats().rejected, 1);
+
+    run(&bus, &mut can, &mut queue);
+    assert_eq!(bus.take_log().len(), TX_QUEUE_LEN + 1);
+}
@@ -0,0 +1,121 @@
+//! Host tests of the watchdog supervisor. Run with `cargo test-host`.
+
+use embedded_hal::watchdog::Watchdog;
+use fugit::ExtU32;
+use stm32f446_rtic::{
+    reset::{Crash, Retained, StarvedRecord},
+    time::Met,
+    watchdog::{Pair, Supervisor, WatchdogError},
+};
+
+/// Counts its kicks.
+#[derive(Default)]
+struct Counter(u32);
+
+impl Watchdog for Counter {
+    fn feed(&mut self) {
+        self.0 += 1;
+    }
+}
+
+fn at(ms: u64) -> Met {
+    Met::from_ticks(ms * 1000)
+}
+
+#[test]
+fn kicks_while_every_task_checks_in() {
+    let mut watchdog = Counter::default();
+    let mut supervisor = Supervisor::<2>::new();
+    let fast = supervisor.register("fast", 100.millis(), at(0)).unwrap();
+    let slow = supervisor.register("slow", 1000.millis(), at(0)).unwrap();
+    assert_eq!(
+        supervisor.register("third", 100.millis(), at(0)),
+        Err(WatchdogError::TableFull)
+    );
+
+    for ms in (50..=2000).step_by(50) {
+        supervisor.check_in(fast, at(ms));
+        if ms % 500 == 0 {
+            supervisor.check_in(slow, at(ms));
+        }
+        assert_eq!(supervisor.poll(at(ms), &mut watchdog), Ok(()));
+    }
+    assert_eq!(watchdog.0, 40);
+    assert_eq!(supervisor.stats().kicks, 40);
+    assert_eq!(supervisor.stats().check_ins, 44);
+    assert_eq!(supervisor.starved(), None);
+}
+
+#[test]
+fn starved_task_stops_the_kicks() {
+    let mut watchdog = Counter::default();
+    let mut supervisor = Supervisor::<2>::new();
+    let fast = supervisor.register("fast", 100.millis(), at(0)).unwrap();
+    let stuck = supervisor.register("receive", 300.millis(), at(0)).unwrap();
+
+    // Right at the deadline is still in time
+    supervisor.check_in(fast, at(250));
+    assert_eq!(supervisor.poll(at(300), &mut watchdog), Ok(()));
+    supervisor.check_in(fast, at(300));
+
+    let starved = supervisor.poll(at(301), &mut watchdog).unwrap_err();
+    assert_eq!((starved.task, starved.name), (stuck, "receive"));
+    assert_eq!(starved.last, at(0));
+    assert_eq!(
+        starved.crash(),
+        Crash::Starved(StarvedRecord::new(1, "receive"))
+    );
+
+    // Checking in late does not bring the kicks back
+    supervisor.check_in(stuck, at(350));
+    assert_eq!(supervisor.poll(at(350), &mut watchdog), Err(starved));
+    assert_eq!(supervisor.starved(), Some(starved));
+    assert_eq!(watchdog.0, 1);
+}
+
+#[test]
+fn paused_task_is_not_watched() {
+    let mut watchdog = Counter::default();
+    let mut supervisor = Supervisor::<1>::new();
+    let task = supervisor.register("collect", 100.millis(), at(0)).unwrap();
+
+    assert_eq!(supervisor.since_check_in(task, at(40)), Some(40.millis()));
+    supervisor.pause(task);
+    assert_eq!(supervisor.poll(at(1000), &mut watchdog), Ok(()));
+    assert_eq!(supervisor.since_check_in(task, at(1000)), None);
+    // The deadline counts from the resume
+    supervisor.resume(task, at(1000));
+    assert_eq!(supervisor.poll(at(1100), &mut watchdog), Ok(()));
+    assert!(supervisor.poll(at(1101), &mut watchdog).is_err());
+    assert_eq!(watchdog.0, 2);
+}
+
+#[test]
+fn next_boot_names_the_starved_task() {
+    let mut supervisor = Supervisor::<1>::new();
+    supervisor
+        .register("a task with a long name", 10.millis(), at(0))
+        .unwrap();
+    let starved = supervisor
+        .poll(at(20), &mut Counter::default())
+        .unwrap_err();
+
+    let mut retained = Retained::new();
+    retained.boot(0x0e00_0000);
+    retained.record(&starved.crash());
+    let last = retained.boot(0x2400_0000);
+    let Some(Crash::Starved(record)) = last.crash else {
+        panic!("{:?}", last.crash)
+    };
+    assert_eq!((record.task, record.name()), (0, "a task with a lo"));
+    assert_eq!(last.summary()[4..], [5, 3, 0, 0, 0, 0]);
+    assert_eq!(starved.crash().to_bytes(), *b"\x03\x00\x10a task with a lo");
+}
+
+#[test]
+fn pair_kicks_both() {
+    let mut pair = Pair(Counter::default(), Counter::default());
+    let mut supervisor = Supervisor::<1>::new();
+    supervisor.poll(at(0), &mut pair).unwrap();
+    assert_eq!((pair.0 .0, pair.1 .0), (1, 1));
+}